@@ -0,0 +1,602 @@
+#![warn(clippy::all, rust_2018_idioms)]
+
+use std::{
+    env, fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    thread,
+    time::Duration,
+};
+
+use nand2tetris::{
+    condition::Condition,
+    condition_parse::parse_condition,
+    hack_to_asm::disassemble_to_asm,
+    hardware::{AnyHardware, Hardware, Instruction, Word},
+    hardware_parse::{assemble_hack_file, parse_binary_file},
+    jack,
+    os::OsPolicy,
+    snapshot::Snapshot,
+    vm::{Program, StopReason, VM},
+    vm_parse::errors_to_string,
+    vm_to_hack::program_to_asm,
+    vm_validate::validate,
+    wasm_hardware::WasmHardware,
+    wasm_vm::WasmVm,
+    watchpoint::Watchpoint,
+};
+
+const USAGE: &str = "usage:
+    n2t assemble <file.asm> [-o <file.hack>]
+    n2t disassemble <file.hack> [-o <file.asm>]
+    n2t translate <dir|file.vm> [-o <file.asm>]
+    n2t run <file.hack|file.asm> [--steps N] [--dump-ram START..END] [--wasm] [SNAPSHOTS]
+        [--coverage <file>] [--watch WATCHPOINT]... [--break CONDITION]...
+    n2t run-vm <dir|file.vm> [--steps N] [--dump-ram START..END] [--wasm] [SNAPSHOTS]
+        [--profile <file.folded>] [--coverage <file>] [--watch WATCHPOINT]...
+        [--break CONDITION]... [--os CLASS=POLICY]... [--heap] [--heap-in-ram] [--sanitize]
+
+Directories given to translate and run-vm may contain .vm files or, if there are none, .jack files.
+
+SNAPSHOTS: [--load-snapshot <file>] [--save-snapshot <file>]
+    Start from a machine state saved earlier, or save the state after running.
+    The WASM VM can load snapshots but not save them.
+
+--profile prints how many commands each function ran, and writes the call stacks in the folded
+format used by flamegraph tools. It needs the interpreter, so it can't be combined with --wasm.
+
+--coverage prints how many commands of each file and function were executed, and writes a listing
+with every command or instruction prefixed by its hit count, or ##### if it never ran. It can't be
+combined with --wasm either.
+
+WATCHPOINT: START..END or ADDRESS, followed by :write (the default), :read or :change
+    Stop running after the first instruction or command which accesses the addresses, and
+    print what it did. Watchpoints need the interpreter too.
+
+CONDITION: an expression such as 'RAM[0] > 300 && D == -1' or 'function == \"Main.main\"'
+    Stop running after the first instruction or command which makes it true. It can compare
+    A, D, M, PC, SP, LCL, ARG, THIS, THAT, RAM[i], LCL[i], ARG[i], THIS[i], THAT[i] and TEMP[i],
+    and hits, the number of steps it was true on when ignoring comparisons with hits. Conditions
+    need the interpreter too.
+
+--os sets where the functions of an OS class (Array, Keyboard, Math, Memory, Output, Screen,
+String or Sys) come from: native, program, or prefer-program, the default, which runs the
+functions the program defines and natively runs the rest.
+
+--heap prints the live allocations, free holes and fragmentation of the native OS heap after
+running. Allocations still live when the program halts are reported as leaks. It needs the
+interpreter as well.
+
+--heap-in-ram keeps the native heap's segment headers in RAM, laid out like the Jack OS's free
+list, so that the program and RAM dumps can see them. It needs the interpreter too.
+
+--sanitize stops with an error on double frees, and on this and that accesses to freed heap
+blocks or outside any block, naming where the block was freed. It needs the interpreter too.
+
+exit codes: 0 on success, 1 if loading or running fails, 2 on bad arguments";
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    input: PathBuf,
+    output: Option<PathBuf>,
+    steps: u64,
+    dump_ram: Range<Word>,
+    wasm: bool,
+    load_snapshot: Option<PathBuf>,
+    save_snapshot: Option<PathBuf>,
+    profile: Option<PathBuf>,
+    coverage: Option<PathBuf>,
+    watch: Vec<Watchpoint>,
+    breaks: Vec<Condition>,
+    os_policy: OsPolicy,
+    heap: bool,
+    heap_in_ram: bool,
+    sanitize: bool,
+}
+
+enum Error {
+    Usage(String),
+    Failed(String),
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::Failed(message)
+    }
+}
+
+fn parse_range(s: &str) -> Result<Range<Word>, String> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| format!("expected START..END, got {s}"))?;
+    let start = start.parse().map_err(|e| format!("{start}: {e}"))?;
+    let end = end.parse().map_err(|e| format!("{end}: {e}"))?;
+
+    Ok(start..end)
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut input = None;
+    let mut options = Options {
+        input: PathBuf::new(),
+        output: None,
+        steps: 1_000_000,
+        dump_ram: 0..16,
+        wasm: false,
+        load_snapshot: None,
+        save_snapshot: None,
+        profile: None,
+        coverage: None,
+        watch: vec![],
+        breaks: vec![],
+        os_policy: OsPolicy::default(),
+        heap: false,
+        heap_in_ram: false,
+        sanitize: false,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("missing value for {arg}"))
+        };
+        match arg.as_str() {
+            "-o" => options.output = Some(PathBuf::from(value()?)),
+            "--steps" => {
+                let steps = value()?;
+                options.steps = steps.parse().map_err(|e| format!("{steps}: {e}"))?;
+            }
+            "--dump-ram" => options.dump_ram = parse_range(value()?)?,
+            "--wasm" => options.wasm = true,
+            "--load-snapshot" => options.load_snapshot = Some(PathBuf::from(value()?)),
+            "--save-snapshot" => options.save_snapshot = Some(PathBuf::from(value()?)),
+            "--profile" => options.profile = Some(PathBuf::from(value()?)),
+            "--coverage" => options.coverage = Some(PathBuf::from(value()?)),
+            "--watch" => options.watch.push(Watchpoint::parse(value()?)?),
+            "--break" => options.breaks.push(parse_condition(value()?)?),
+            "--heap" => options.heap = true,
+            "--heap-in-ram" => options.heap_in_ram = true,
+            "--sanitize" => options.sanitize = true,
+            "--os" => {
+                let class_policy = value()?;
+                let (class_name, policy) = class_policy
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected CLASS=POLICY, got {class_policy}"))?;
+                options.os_policy.set_class(class_name, policy.parse()?)?;
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {arg}")),
+        }
+    }
+    options.input = input.ok_or("missing input path")?;
+
+    Ok(options)
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+fn write(path: &Path, contents: &str) -> Result<(), String> {
+    fs::write(path, contents).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+fn assemble_file(path: &Path) -> Result<Vec<Instruction>, String> {
+    assemble_hack_file(&read(path)?).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+fn load_hack_file(path: &Path) -> Result<Vec<Instruction>, String> {
+    parse_binary_file(&read(path)?).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+fn files_with_extension(path: &Path, extension: &str) -> Result<Vec<PathBuf>, String> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_owned()]);
+    }
+
+    let mut paths = fs::read_dir(path)
+        .map_err(|e| format!("{}: {e}", path.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|e| e == extension))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    Ok(paths)
+}
+
+fn load_vm(path: &Path) -> Result<VM, String> {
+    let paths = files_with_extension(path, "vm")?;
+    let all_file_commands = match paths.first() {
+        None => jack::compile_dir(path)?,
+        Some(first) if first.extension().is_some_and(|e| e == "jack") => {
+            jack::compile_paths(&paths)?
+        }
+        Some(_) => {
+            let file_contents = paths
+                .iter()
+                .map(|path| {
+                    let file_name = path.file_name().unwrap().to_str().unwrap().to_owned();
+                    Ok((file_name, read(path)?))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+
+            return VM::from_file_contents(file_contents)
+                .map_err(|errors| errors_to_string(&errors));
+        }
+    };
+    if all_file_commands.is_empty() {
+        return Err(format!("{}: no .vm or .jack files found", path.display()));
+    }
+
+    Ok(VM::from_all_file_commands(all_file_commands))
+}
+
+fn load_snapshot(options: &Options) -> Result<Option<Snapshot>, String> {
+    let Some(path) = &options.load_snapshot else {
+        return Ok(None);
+    };
+
+    Snapshot::parse(&read(path)?)
+        .map(Some)
+        .map_err(|e| format!("{}: {e}", path.display()))
+}
+
+fn save_snapshot(options: &Options, snapshot: impl FnOnce() -> Snapshot) -> Result<(), String> {
+    match &options.save_snapshot {
+        Some(path) => write(path, &snapshot().to_string()),
+        None => Ok(()),
+    }
+}
+
+fn output_path(options: &Options, extension: &str) -> PathBuf {
+    if let Some(output) = &options.output {
+        return output.clone();
+    }
+
+    if options.input.is_dir() {
+        let input = fs::canonicalize(&options.input).unwrap_or_else(|_| options.input.clone());
+        let name = input.file_name().unwrap_or_default();
+        options.input.join(name).with_extension(extension)
+    } else {
+        options.input.with_extension(extension)
+    }
+}
+
+fn wait_until_ready(mut is_ready: impl FnMut() -> bool) {
+    while !is_ready() {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn dump_ram(range: Range<Word>, mut get_ram_value: impl FnMut(Word) -> Word) {
+    for address in range {
+        println!("RAM[{address}] = {}", get_ram_value(address));
+    }
+}
+
+fn assemble(options: &Options) -> Result<(), Error> {
+    let hack = assemble_file(&options.input)?
+        .iter()
+        .map(|instruction| format!("{:016b}\n", instruction.to_legacy()))
+        .collect::<String>();
+
+    Ok(write(&output_path(options, "hack"), &hack)?)
+}
+
+fn disassemble(options: &Options) -> Result<(), Error> {
+    let asm = disassemble_to_asm(&load_hack_file(&options.input)?)?;
+
+    Ok(write(&output_path(options, "asm"), &asm)?)
+}
+
+/// Problems found before running are only reported, since they may be on paths never taken.
+fn report_diagnostics(program: &Program) {
+    for diagnostic in validate(program) {
+        eprintln!("{diagnostic}");
+    }
+}
+
+fn translate(options: &Options) -> Result<(), Error> {
+    let vm = load_vm(&options.input)?;
+    report_diagnostics(&vm.program);
+
+    Ok(write(
+        &output_path(options, "asm"),
+        &program_to_asm(&vm.program)?,
+    )?)
+}
+
+fn run(options: &Options) -> Result<(), Error> {
+    let instructions = match options.input.extension() {
+        Some(e) if e == "hack" => load_hack_file(&options.input)?,
+        Some(e) if e == "asm" => assemble_file(&options.input)?,
+        _ => {
+            return Err(Error::Usage(format!(
+                "{}: expected a .hack or .asm file",
+                options.input.display()
+            )));
+        }
+    };
+
+    let mut hardware: Box<dyn AnyHardware> = if options.wasm {
+        if options.coverage.is_some() || !options.watch.is_empty() || !options.breaks.is_empty() {
+            return Err(Error::Usage(
+                "the WASM backend can't collect coverage, watch memory or check conditions"
+                    .to_owned(),
+            ));
+        }
+        Box::new(WasmHardware::from_instructions(&instructions))
+    } else {
+        let mut hardware = Hardware::default();
+        hardware.load_program(&instructions);
+        Box::new(hardware)
+    };
+    wait_until_ready(|| hardware.is_ready());
+    if let Some(snapshot) = load_snapshot(options)? {
+        hardware.restore_snapshot(&snapshot)?;
+    }
+    hardware.set_coverage(options.coverage.is_some());
+    for watchpoint in &options.watch {
+        hardware.add_watchpoint(watchpoint.clone())?;
+    }
+    for condition in &options.breaks {
+        hardware
+            .add_condition(condition.clone())
+            .map_err(Error::Usage)?;
+    }
+    let stopped = hardware.run(options.steps);
+    if let Some(watch_hit) = hardware.watch_hit() {
+        println!(
+            "watchpoint {}: {watch_hit} at PC {}",
+            watch_hit.index, watch_hit.location
+        );
+    } else if stopped {
+        println!("breakpoint hit at PC {}", hardware.pc());
+    }
+    if let (Some(path), Some(coverage)) = (&options.coverage, hardware.coverage()) {
+        let listing = coverage.rom_listing(&instructions, instructions.len());
+        let covered = (0..instructions.len())
+            .filter(|&address| coverage.hits(address) > 0)
+            .count();
+        println!("{covered}/{} instructions executed", instructions.len());
+        write(path, &listing)?;
+    }
+    dump_ram(options.dump_ram.clone(), |address| {
+        hardware.get_ram_value(address)
+    });
+
+    Ok(save_snapshot(options, || hardware.save_snapshot())?)
+}
+
+/// Where a VM stopped, which is past the last command once the program has run off its end.
+fn location(vm: &VM, command_index: usize) -> String {
+    match (
+        vm.program.source_line(command_index),
+        vm.program.all_commands.get(command_index),
+    ) {
+        (Some((file, source_line)), Some(command)) => {
+            format!("{} line {} ({command})", file.name, source_line.line_number)
+        }
+        _ => "the end of the program".to_owned(),
+    }
+}
+
+fn stop_message(vm: &VM, stop_reason: &StopReason) -> Option<String> {
+    match stop_reason {
+        StopReason::Watchpoint(watch_hit) => Some(format!(
+            "watchpoint {}: {watch_hit} at {}",
+            watch_hit.index,
+            location(vm, watch_hit.location)
+        )),
+        StopReason::Breakpoint(index) => Some(format!(
+            "breakpoint {index}: {} before {}",
+            vm.get_breakpoints()[*index].value(),
+            location(vm, vm.run_state.current_command_index)
+        )),
+        _ => None,
+    }
+}
+
+fn run_vm(options: &Options) -> Result<(), Error> {
+    let mut vm = load_vm(&options.input)?;
+    vm.set_os_policy(options.os_policy.clone());
+    report_diagnostics(&vm.program);
+    let snapshot = load_snapshot(options)?;
+
+    if options.wasm {
+        if options.save_snapshot.is_some()
+            || options.profile.is_some()
+            || options.coverage.is_some()
+            || !options.watch.is_empty()
+            || !options.breaks.is_empty()
+            || options.heap
+            || options.heap_in_ram
+            || options.sanitize
+        {
+            return Err(Error::Usage(
+                "the WASM VM can only load snapshots, dump the RAM and run a number of steps"
+                    .to_owned(),
+            ));
+        }
+        let mut vm = WasmVm::from_program(vm.program);
+        wait_until_ready(|| vm.is_ready());
+        if let Some(snapshot) = snapshot {
+            vm.restore_snapshot(&snapshot)?;
+        }
+        vm.run(options.steps);
+        dump_ram(options.dump_ram.clone(), |address| {
+            vm.get_ram_value(address)
+        });
+    } else {
+        if let Some(snapshot) = snapshot {
+            vm.restore_snapshot(&snapshot)?;
+        }
+        vm.set_profiling(options.profile.is_some());
+        vm.set_coverage(options.coverage.is_some());
+        vm.set_sanitizing(options.sanitize);
+        if options.heap_in_ram {
+            vm.set_heap_in_ram(true)
+                .map_err(|trap| Error::Failed(trap.to_string()))?;
+        }
+        for watchpoint in &options.watch {
+            vm.add_watchpoint(watchpoint);
+        }
+        for condition in &options.breaks {
+            vm.add_condition(condition.clone()).map_err(Error::Usage)?;
+        }
+        let stop_reason = vm.run(options.steps);
+        if let Some(message) = stop_message(&vm, &stop_reason) {
+            println!("{message}");
+        }
+        if let (Some(path), Some(profiler)) = (&options.profile, vm.profiler()) {
+            print!("{}", profiler.report());
+            write(path, &profiler.folded_stacks())?;
+        }
+        if let (Some(path), Some(coverage)) = (&options.coverage, vm.coverage()) {
+            print!("{}", coverage.report(&vm.program));
+            write(path, &coverage.vm_listing(&vm.program))?;
+        }
+        if options.heap {
+            let os = &vm.run_state.os;
+            if stop_reason == StopReason::Halted {
+                let leaks = os.allocations();
+                if !leaks.is_empty() {
+                    println!(
+                        "{} allocations ({} words) leaked at program end",
+                        leaks.len(),
+                        leaks.iter().map(|block| block.size as i64).sum::<i64>()
+                    );
+                }
+            }
+            print!("{}", os.heap_report());
+        }
+        if stop_reason == StopReason::Error {
+            return Err(Error::Failed(vm.error().unwrap().to_string()));
+        }
+        dump_ram(options.dump_ram.clone(), |address| {
+            vm.get_ram_value(address)
+        });
+        save_snapshot(options, || vm.save_snapshot())?;
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let Some((command, args)) = args.split_first() else {
+        eprintln!("{USAGE}");
+        return ExitCode::from(2);
+    };
+
+    let result = parse_options(args)
+        .map_err(Error::Usage)
+        .and_then(|options| match command.as_str() {
+            "assemble" => assemble(&options),
+            "disassemble" => disassemble(&options),
+            "translate" => translate(&options),
+            "run" => run(&options),
+            "run-vm" => run_vm(&options),
+            _ => Err(Error::Usage(format!("unknown command {command}"))),
+        });
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Error::Usage(message)) => {
+            eprintln!("{message}\n\n{USAGE}");
+            ExitCode::from(2)
+        }
+        Err(Error::Failed(message)) => {
+            eprintln!("{message}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nand2tetris::os::ClassPolicy;
+
+    #[test]
+    fn test_parse_options() {
+        let args = [
+            "prog.hack",
+            "--steps",
+            "100",
+            "--dump-ram",
+            "256..260",
+            "--wasm",
+            "--load-snapshot",
+            "intro.snapshot",
+        ]
+        .map(str::to_owned);
+
+        assert_eq!(
+            parse_options(&args),
+            Ok(Options {
+                input: PathBuf::from("prog.hack"),
+                output: None,
+                steps: 100,
+                dump_ram: 256..260,
+                wasm: true,
+                load_snapshot: Some(PathBuf::from("intro.snapshot")),
+                save_snapshot: None,
+                profile: None,
+                coverage: None,
+                watch: vec![],
+                breaks: vec![],
+                os_policy: OsPolicy::default(),
+                heap: false,
+                heap_in_ram: false,
+                sanitize: false,
+            })
+        );
+        assert!(parse_options(&["prog.hack".to_owned(), "--steps".to_owned()]).is_err());
+        assert!(
+            parse_options(&["dir".to_owned(), "--heap-in-ram".to_owned()])
+                .unwrap()
+                .heap_in_ram
+        );
+        assert!(parse_options(&["--dump-ram".to_owned(), "1-2".to_owned()]).is_err());
+
+        let options = parse_options(&[
+            "dir".to_owned(),
+            "--os".to_owned(),
+            "Math=program".to_owned(),
+        ]);
+        assert_eq!(
+            options.unwrap().os_policy.class("Math"),
+            ClassPolicy::Program
+        );
+        assert!(
+            parse_options(&[
+                "dir".to_owned(),
+                "--os".to_owned(),
+                "Main=native".to_owned()
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_break_at_end() {
+        let mut vm = VM::from_file_contents(vec![(
+            "SimpleAdd.vm".to_owned(),
+            "push constant 7\npush constant 8\nadd".to_owned(),
+        )])
+        .unwrap();
+        vm.add_condition(parse_condition("RAM[256] == 15").unwrap())
+            .unwrap();
+        let stop_reason = vm.run(100);
+
+        assert_eq!(stop_reason, StopReason::Breakpoint(0));
+        assert_eq!(
+            stop_message(&vm, &stop_reason).unwrap(),
+            "breakpoint 0: RAM[256] == 15 before the end of the program"
+        );
+    }
+}
@@ -0,0 +1,461 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::hardware::{RAM, Word};
+
+/// A value a condition can read by name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Variable {
+    A,
+    D,
+    /// `RAM[A]`.
+    M,
+    PC,
+    SP,
+    LCL,
+    ARG,
+    THIS,
+    THAT,
+    /// How many steps the breakpoint was counted on, see `Condition::check`.
+    Hits,
+}
+
+impl Display for Variable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Variable::A => "A",
+            Variable::D => "D",
+            Variable::M => "M",
+            Variable::PC => "PC",
+            Variable::SP => "SP",
+            Variable::LCL => "LCL",
+            Variable::ARG => "ARG",
+            Variable::THIS => "THIS",
+            Variable::THAT => "THAT",
+            Variable::Hits => "hits",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Where an indexed read starts: `RAM` at 0, `TEMP` at 5, and the others at the address held by
+/// the pointer of the same name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Segment {
+    RAM,
+    LCL,
+    ARG,
+    THIS,
+    THAT,
+    TEMP,
+}
+
+impl Display for Segment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Segment::RAM => "RAM",
+            Segment::LCL => "LCL",
+            Segment::ARG => "ARG",
+            Segment::THIS => "THIS",
+            Segment::THAT => "THAT",
+            Segment::TEMP => "TEMP",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Number(i64),
+    Variable(Variable),
+    Memory {
+        segment: Segment,
+        index: Box<Operand>,
+    },
+    Add(Box<Operand>, Box<Operand>),
+    Subtract(Box<Operand>, Box<Operand>),
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Number(number) => write!(f, "{number}"),
+            Operand::Variable(variable) => write!(f, "{variable}"),
+            Operand::Memory { segment, index } => write!(f, "{segment}[{index}]"),
+            Operand::Add(left, right) => write!(f, "{left} + {}", Parenthesized(right)),
+            Operand::Subtract(left, right) => write!(f, "{left} - {}", Parenthesized(right)),
+        }
+    }
+}
+
+/// Puts parentheses around sums and differences on the right of another one.
+struct Parenthesized<'a>(&'a Operand);
+
+impl Display for Parenthesized<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Operand::Add(..) | Operand::Subtract(..) => write!(f, "({})", self.0),
+            operand => write!(f, "{operand}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    fn compare(self, left: i64, right: i64) -> bool {
+        match self {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+        }
+    }
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let operator = match self {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+        };
+        write!(f, "{operator}")
+    }
+}
+
+/// A breakpoint condition such as `RAM[0] > 300 && D == -1`, parsed by
+/// `condition_parse::parse_condition`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Condition {
+    Compare {
+        left: Operand,
+        comparison: Comparison,
+        right: Operand,
+    },
+    /// `function == "name"`, or `function != "name"` if `equal` is false.
+    Function {
+        name: String,
+        equal: bool,
+    },
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Compare {
+                left,
+                comparison,
+                right,
+            } => write!(f, "{left} {comparison} {right}"),
+            Condition::Function { name, equal } => {
+                write!(
+                    f,
+                    "function {} \"{name}\"",
+                    if *equal { "==" } else { "!=" }
+                )
+            }
+            Condition::Not(condition) => write!(f, "!({condition})"),
+            Condition::And(left, right) => {
+                for (index, condition) in [left, right].into_iter().enumerate() {
+                    if index > 0 {
+                        write!(f, " && ")?;
+                    }
+                    match condition.as_ref() {
+                        Condition::Or(..) => write!(f, "({condition})")?,
+                        condition => write!(f, "{condition}")?,
+                    }
+                }
+                Ok(())
+            }
+            Condition::Or(left, right) => write!(f, "{left} || {right}"),
+        }
+    }
+}
+
+/// The state of the machine a condition is evaluated against.
+pub trait Context {
+    /// The value of `A`, `D` or `PC`, or `None` if the machine doesn't have the register.
+    fn register(&self, variable: Variable) -> Option<Word>;
+    fn ram(&self) -> &RAM;
+    /// The name of the function being run, or `None` if the machine doesn't know about functions.
+    fn function_name(&self) -> Option<&str>;
+}
+
+impl Operand {
+    fn uses_hits(&self) -> bool {
+        match self {
+            Operand::Number(_) => false,
+            Operand::Variable(variable) => *variable == Variable::Hits,
+            Operand::Memory { index, .. } => index.uses_hits(),
+            Operand::Add(left, right) | Operand::Subtract(left, right) => {
+                left.uses_hits() || right.uses_hits()
+            }
+        }
+    }
+
+    fn evaluate(&self, context: &impl Context, hits: u64) -> Result<i64, String> {
+        let ram = context.ram();
+        match self {
+            Operand::Number(number) => Ok(*number),
+            Operand::Variable(Variable::Hits) => Ok(hits as i64),
+            Operand::Variable(Variable::M) => {
+                read(ram, Operand::Variable(Variable::A).evaluate(context, hits)?)
+            }
+            Operand::Variable(Variable::SP) => read(ram, 0),
+            Operand::Variable(Variable::LCL) => read(ram, 1),
+            Operand::Variable(Variable::ARG) => read(ram, 2),
+            Operand::Variable(Variable::THIS) => read(ram, 3),
+            Operand::Variable(Variable::THAT) => read(ram, 4),
+            Operand::Variable(register) => context
+                .register(*register)
+                .map(i64::from)
+                .ok_or_else(|| format!("there is no {register} register")),
+            Operand::Memory { segment, index } => {
+                let start = match segment {
+                    Segment::RAM => 0,
+                    Segment::LCL => read(ram, 1)?,
+                    Segment::ARG => read(ram, 2)?,
+                    Segment::THIS => read(ram, 3)?,
+                    Segment::THAT => read(ram, 4)?,
+                    Segment::TEMP => 5,
+                };
+                read(ram, start + index.evaluate(context, hits)?)
+            }
+            Operand::Add(left, right) => {
+                Ok(left.evaluate(context, hits)? + right.evaluate(context, hits)?)
+            }
+            Operand::Subtract(left, right) => {
+                Ok(left.evaluate(context, hits)? - right.evaluate(context, hits)?)
+            }
+        }
+    }
+}
+
+/// Reads without logging the read, so that evaluating conditions doesn't trigger watchpoints.
+fn read(ram: &RAM, address: i64) -> Result<i64, String> {
+    usize::try_from(address)
+        .ok()
+        .and_then(|address| ram.contents.get(address))
+        .map(|&value| value as i64)
+        .ok_or_else(|| format!("RAM[{address}] is out of range"))
+}
+
+impl Condition {
+    pub fn uses_hits(&self) -> bool {
+        match self {
+            Condition::Compare { left, right, .. } => left.uses_hits() || right.uses_hits(),
+            Condition::Function { .. } => false,
+            Condition::Not(condition) => condition.uses_hits(),
+            Condition::And(left, right) | Condition::Or(left, right) => {
+                left.uses_hits() || right.uses_hits()
+            }
+        }
+    }
+
+    /// Evaluates every part of the condition, so that a variable the machine doesn't have is an
+    /// error even if it wouldn't decide the result. If `hits` is `None`, comparisons involving
+    /// `hits` are assumed to hold, or not to hold under a `!`, so that they don't decide the
+    /// result.
+    pub fn evaluate(&self, context: &impl Context, hits: Option<u64>) -> Result<bool, String> {
+        self.evaluate_assuming(context, hits, true)
+    }
+
+    fn evaluate_assuming(
+        &self,
+        context: &impl Context,
+        hits: Option<u64>,
+        assumed: bool,
+    ) -> Result<bool, String> {
+        match self {
+            Condition::Compare {
+                left,
+                comparison,
+                right,
+            } => {
+                let is_met = comparison.compare(
+                    left.evaluate(context, hits.unwrap_or(0))?,
+                    right.evaluate(context, hits.unwrap_or(0))?,
+                );
+                if hits.is_none() && (left.uses_hits() || right.uses_hits()) {
+                    return Ok(assumed);
+                }
+                Ok(is_met)
+            }
+            Condition::Function { name, equal } => {
+                let function_name = context
+                    .function_name()
+                    .ok_or("there are no functions to compare with")?;
+                Ok((function_name == name) == *equal)
+            }
+            Condition::Not(condition) => Ok(!condition.evaluate_assuming(context, hits, !assumed)?),
+            Condition::And(left, right) => {
+                let left = left.evaluate_assuming(context, hits, assumed)?;
+                Ok(right.evaluate_assuming(context, hits, assumed)? && left)
+            }
+            Condition::Or(left, right) => {
+                let left = left.evaluate_assuming(context, hits, assumed)?;
+                Ok(right.evaluate_assuming(context, hits, assumed)? || left)
+            }
+        }
+    }
+
+    /// Counts the current step towards `hits` if the condition would be met with every
+    /// comparison involving `hits` in its favour, and then returns whether it is met. So
+    /// `PC == 100 && hits == 3` is met the third time `PC` is 100. A condition which can't be
+    /// evaluated, e.g. because it reads outside of the RAM, isn't met.
+    pub fn check(&self, context: &impl Context, hits: &mut u64) -> bool {
+        let is_counted = self.evaluate(context, None) == Ok(true);
+        if is_counted {
+            *hits += 1;
+        }
+        if !self.uses_hits() {
+            return is_counted;
+        }
+
+        self.evaluate(context, Some(*hits)) == Ok(true)
+    }
+}
+
+/// A condition which stops a run on the step it becomes met, like the VM's breakpoints do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConditionalBreakpoint {
+    pub condition: Condition,
+    pub hits: u64,
+    was_met: bool,
+}
+
+impl ConditionalBreakpoint {
+    pub fn new(condition: Condition) -> Self {
+        Self {
+            condition,
+            hits: 0,
+            was_met: false,
+        }
+    }
+
+    /// Checks the condition after a step, returning whether it became met.
+    pub fn check(&mut self, context: &impl Context) -> bool {
+        let is_met = self.condition.check(context, &mut self.hits);
+        let became_met = is_met && !self.was_met;
+        self.was_met = is_met;
+
+        became_met
+    }
+
+    pub fn reset(&mut self) {
+        self.hits = 0;
+        self.was_met = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::condition_parse::parse_condition;
+
+    struct TestContext {
+        d: Word,
+        ram: RAM,
+    }
+
+    impl Context for TestContext {
+        fn register(&self, variable: Variable) -> Option<Word> {
+            (variable == Variable::D).then_some(self.d)
+        }
+
+        fn ram(&self) -> &RAM {
+            &self.ram
+        }
+
+        fn function_name(&self) -> Option<&str> {
+            Some("Main.main")
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut context = TestContext {
+            d: -1,
+            ram: RAM::default(),
+        };
+        context.ram.contents[0] = 301;
+        context.ram.contents[2] = 400;
+        context.ram.contents[401] = -5;
+        let evaluate = |condition: &str| {
+            parse_condition(condition)
+                .unwrap()
+                .evaluate(&context, Some(0))
+        };
+
+        assert_eq!(evaluate("RAM[0] > 300 && D == -1"), Ok(true));
+        assert_eq!(evaluate("ARG[1] < 0"), Ok(true));
+        assert_eq!(evaluate("RAM[ARG + 1] == ARG[SP - 300]"), Ok(true));
+        assert_eq!(
+            evaluate("function == \"Main.main\" && !(SP <= 301)"),
+            Ok(false)
+        );
+        assert_eq!(
+            evaluate("function != \"Main.main\" || LCL[3] != 0"),
+            Ok(false)
+        );
+        assert!(evaluate("D == 0 && A == 0").is_err());
+        assert!(evaluate("RAM[-1] == 0").is_err());
+    }
+
+    #[test]
+    fn test_check_hits() {
+        let context = TestContext {
+            d: 0,
+            ram: RAM::default(),
+        };
+        let condition = parse_condition("D == 0 && hits >= 3").unwrap();
+        let mut hits = 0;
+        let checks = (0..4)
+            .map(|_| condition.check(&context, &mut hits))
+            .collect::<Vec<_>>();
+
+        assert_eq!(checks, [false, false, true, true]);
+        assert_eq!(hits, 4);
+    }
+
+    #[test]
+    fn test_check_negated_hits() {
+        let context = TestContext {
+            d: 0,
+            ram: RAM::default(),
+        };
+        let checks = |condition: &str| {
+            let condition = parse_condition(condition).unwrap();
+            let mut hits = 0;
+            let checks = (0..4)
+                .map(|_| condition.check(&context, &mut hits))
+                .collect::<Vec<_>>();
+            (checks, hits)
+        };
+
+        assert_eq!(
+            checks("D == 0 && !(hits >= 3)"),
+            checks("D == 0 && hits < 3")
+        );
+        assert_eq!(
+            checks("D == 0 && hits < 3"),
+            (vec![true, true, false, false], 4)
+        );
+    }
+}
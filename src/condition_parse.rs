@@ -0,0 +1,187 @@
+use crate::{
+    condition::*,
+    parse_utils::{IResult, keyword, skip, symbol, token},
+};
+
+use nom::{
+    Finish,
+    branch::alt,
+    bytes::complete::{is_not, tag},
+    character::complete::{self, char},
+    combinator::{all_consuming, map, value},
+    error::convert_error,
+    multi::many0,
+    sequence::{delimited, pair, preceded, terminated, tuple},
+};
+
+fn variable(input: &str) -> IResult<&str, Variable> {
+    alt((
+        value(Variable::Hits, keyword("hits")),
+        value(Variable::SP, keyword("SP")),
+        value(Variable::LCL, keyword("LCL")),
+        value(Variable::ARG, keyword("ARG")),
+        value(Variable::THIS, keyword("THIS")),
+        value(Variable::THAT, keyword("THAT")),
+        value(Variable::PC, keyword("PC")),
+        value(Variable::A, keyword("A")),
+        value(Variable::D, keyword("D")),
+        value(Variable::M, keyword("M")),
+    ))(input)
+}
+
+fn segment(input: &str) -> IResult<&str, Segment> {
+    token(alt((
+        value(Segment::RAM, tag("RAM")),
+        value(Segment::LCL, tag("LCL")),
+        value(Segment::ARG, tag("ARG")),
+        value(Segment::THIS, tag("THIS")),
+        value(Segment::THAT, tag("THAT")),
+        value(Segment::TEMP, tag("TEMP")),
+    )))(input)
+}
+
+fn atom(input: &str) -> IResult<&str, Operand> {
+    alt((
+        map(
+            pair(segment, delimited(symbol('['), operand, symbol(']'))),
+            |(segment, index)| Operand::Memory {
+                segment,
+                index: Box::new(index),
+            },
+        ),
+        map(variable, Operand::Variable),
+        map(token(complete::i64), Operand::Number),
+        delimited(symbol('('), operand, symbol(')')),
+    ))(input)
+}
+
+fn operand(input: &str) -> IResult<&str, Operand> {
+    map(
+        pair(atom, many0(pair(alt((symbol('+'), symbol('-'))), atom))),
+        |(first, rest)| {
+            rest.into_iter()
+                .fold(first, |left, (operator, right)| match operator {
+                    '+' => Operand::Add(Box::new(left), Box::new(right)),
+                    _ => Operand::Subtract(Box::new(left), Box::new(right)),
+                })
+        },
+    )(input)
+}
+
+fn comparison(input: &str) -> IResult<&str, Comparison> {
+    token(alt((
+        value(Comparison::Equal, tag("==")),
+        value(Comparison::NotEqual, tag("!=")),
+        value(Comparison::LessOrEqual, tag("<=")),
+        value(Comparison::Less, tag("<")),
+        value(Comparison::GreaterOrEqual, tag(">=")),
+        value(Comparison::Greater, tag(">")),
+    )))(input)
+}
+
+fn function_comparison(input: &str) -> IResult<&str, Condition> {
+    map(
+        tuple((
+            keyword("function"),
+            token(alt((value(true, tag("==")), value(false, tag("!="))))),
+            token(delimited(char('"'), is_not("\""), char('"'))),
+        )),
+        |(_, equal, name)| Condition::Function {
+            name: name.to_owned(),
+            equal,
+        },
+    )(input)
+}
+
+fn unary(input: &str) -> IResult<&str, Condition> {
+    alt((
+        map(preceded(symbol('!'), unary), |condition| {
+            Condition::Not(Box::new(condition))
+        }),
+        delimited(symbol('('), condition, symbol(')')),
+        function_comparison,
+        map(
+            tuple((operand, comparison, operand)),
+            |(left, comparison, right)| Condition::Compare {
+                left,
+                comparison,
+                right,
+            },
+        ),
+    ))(input)
+}
+
+fn conjunction(input: &str) -> IResult<&str, Condition> {
+    map(
+        pair(unary, many0(preceded(token(tag("&&")), unary))),
+        |(first, rest)| {
+            rest.into_iter().fold(first, |left, right| {
+                Condition::And(Box::new(left), Box::new(right))
+            })
+        },
+    )(input)
+}
+
+fn condition(input: &str) -> IResult<&str, Condition> {
+    map(
+        pair(conjunction, many0(preceded(token(tag("||")), conjunction))),
+        |(first, rest)| {
+            rest.into_iter().fold(first, |left, right| {
+                Condition::Or(Box::new(left), Box::new(right))
+            })
+        },
+    )(input)
+}
+
+pub fn parse_condition(input: &str) -> Result<Condition, String> {
+    all_consuming(terminated(condition, skip))(input)
+        .finish()
+        .map(|(_, condition)| condition)
+        .map_err(|e| convert_error(input, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_condition() {
+        let ram = |index| Operand::Memory {
+            segment: Segment::RAM,
+            index: Box::new(Operand::Number(index)),
+        };
+        assert_eq!(
+            parse_condition("RAM[0] > 300 && D == -1 || !(hits>=10)"),
+            Ok(Condition::Or(
+                Box::new(Condition::And(
+                    Box::new(Condition::Compare {
+                        left: ram(0),
+                        comparison: Comparison::Greater,
+                        right: Operand::Number(300),
+                    }),
+                    Box::new(Condition::Compare {
+                        left: Operand::Variable(Variable::D),
+                        comparison: Comparison::Equal,
+                        right: Operand::Number(-1),
+                    }),
+                )),
+                Box::new(Condition::Not(Box::new(Condition::Compare {
+                    left: Operand::Variable(Variable::Hits),
+                    comparison: Comparison::GreaterOrEqual,
+                    right: Operand::Number(10),
+                }))),
+            ))
+        );
+
+        for condition in [
+            "ARG[1] < 0",
+            "function == \"Main.main\" && LCL[2] != 0",
+            "(A == 1 || M == 2) && RAM[SP - 1] <= THAT + 4 - (TEMP[0] - 1)",
+        ] {
+            assert_eq!(parse_condition(condition).unwrap().to_string(), condition);
+        }
+
+        assert!(parse_condition("RAM[0] >").is_err());
+        assert!(parse_condition("ARGS == 0").is_err());
+    }
+}
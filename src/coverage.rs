@@ -0,0 +1,226 @@
+use std::{fmt::Write, ops::Range};
+
+use crate::{
+    hardware::Instruction,
+    vm::{File, Program},
+};
+
+/// How many times each VM command or ROM address was executed, by index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Coverage {
+    hits: Vec<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoverageSummary {
+    pub name: String,
+    /// The number of times its commands were executed in total.
+    pub hits: u64,
+    /// The number of its commands which were executed at least once.
+    pub covered: usize,
+    pub total: usize,
+}
+
+impl CoverageSummary {
+    pub fn percent(&self) -> f64 {
+        100.0 * self.covered as f64 / self.total.max(1) as f64
+    }
+}
+
+impl Coverage {
+    pub fn new(length: usize) -> Self {
+        Self {
+            hits: vec![0; length],
+        }
+    }
+
+    pub fn hit(&mut self, index: usize) {
+        if let Some(hits) = self.hits.get_mut(index) {
+            *hits += 1;
+        }
+    }
+
+    pub fn hits(&self, index: usize) -> u64 {
+        self.hits.get(index).copied().unwrap_or(0)
+    }
+
+    fn summary(&self, name: &str, range: Range<usize>) -> CoverageSummary {
+        let hits = &self.hits[range];
+        CoverageSummary {
+            name: name.to_owned(),
+            hits: hits.iter().sum(),
+            covered: hits.iter().filter(|&&hits| hits > 0).count(),
+            total: hits.len(),
+        }
+    }
+
+    pub fn files(&self, program: &Program) -> Vec<CoverageSummary> {
+        program
+            .files
+            .iter()
+            .map(|file| {
+                let start = file.starting_command_index;
+                let end = start + file.commands(&program.all_commands).len();
+                self.summary(&file.name, start..end)
+            })
+            .collect()
+    }
+
+    /// A function's commands run until the next function in its file, or the end of the file.
+    pub fn functions(&self, program: &Program) -> Vec<CoverageSummary> {
+        program
+            .files
+            .iter()
+            .flat_map(|file| self.file_functions(program, file))
+            .collect()
+    }
+
+    fn file_functions(&self, program: &Program, file: &File) -> Vec<CoverageSummary> {
+        let file_end = file.starting_command_index + file.commands(&program.all_commands).len();
+        let mut functions = program
+            .function_metadata
+            .iter()
+            .filter(|metadata| {
+                (file.starting_command_index..file_end).contains(&metadata.command_index)
+            })
+            .collect::<Vec<_>>();
+        functions.sort_by_key(|metadata| metadata.command_index);
+
+        functions
+            .iter()
+            .enumerate()
+            .map(|(index, metadata)| {
+                let end = functions
+                    .get(index + 1)
+                    .map_or(file_end, |next| next.command_index);
+                self.summary(&metadata.name, metadata.command_index..end)
+            })
+            .collect()
+    }
+
+    /// Each file's summary, followed by indented summaries of its functions.
+    pub fn report(&self, program: &Program) -> String {
+        let mut report = String::new();
+        for (file, summary) in program.files.iter().zip(self.files(program)) {
+            Self::write_summary(&mut report, "", &summary);
+            for function in self.file_functions(program, file) {
+                Self::write_summary(&mut report, "  ", &function);
+            }
+        }
+
+        report
+    }
+
+    fn write_summary(report: &mut String, indent: &str, summary: &CoverageSummary) {
+        writeln!(
+            report,
+            "{:<40} {:>6}/{:<6} {:>5.1}% {:>12} hits",
+            format!("{indent}{}", summary.name),
+            summary.covered,
+            summary.total,
+            summary.percent(),
+            summary.hits
+        )
+        .unwrap();
+    }
+
+    /// Each command prefixed with its hit count, or `#####` if it was never executed, followed
+    /// by the line it was written on.
+    pub fn vm_listing(&self, program: &Program) -> String {
+        let mut listing = String::new();
+        for file in &program.files {
+            writeln!(listing, "// {}", file.name).unwrap();
+            let commands = file.commands(&program.all_commands);
+            for (offset, (command, source_line)) in
+                commands.iter().zip(file.source_lines()).enumerate()
+            {
+                let hits = self.hits(file.starting_command_index + offset);
+                let mut line = format!(
+                    "{:>10} | {:>5} | {command}",
+                    Self::hit_count(hits),
+                    source_line.line_number
+                );
+                if let Some(comment) = &source_line.comment {
+                    write!(line, " // {comment}").unwrap();
+                }
+                writeln!(listing, "{line}").unwrap();
+            }
+        }
+
+        listing
+    }
+
+    /// The first `length` instructions of the ROM, prefixed like in `vm_listing`.
+    pub fn rom_listing(&self, rom: &[Instruction], length: usize) -> String {
+        let mut listing = String::new();
+        for (address, instruction) in rom.iter().take(length).enumerate() {
+            writeln!(
+                listing,
+                "{:>10} | {address:>5} | {instruction}",
+                Self::hit_count(self.hits(address))
+            )
+            .unwrap();
+        }
+
+        listing
+    }
+
+    fn hit_count(hits: u64) -> String {
+        if hits == 0 {
+            "#####".to_owned()
+        } else {
+            hits.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_vm_coverage() {
+        let mut vm = VM::from_file_contents(vec![
+            (
+                "Sys.vm".to_owned(),
+                "function Sys.init 0\npush constant 1\ncall Main.check 1\npop temp 0\nlabel END\ngoto END"
+                    .to_owned(),
+            ),
+            (
+                "Main.vm".to_owned(),
+                "function Main.check 0\npush argument 0\nif-goto POSITIVE\npush constant 0\nreturn\nlabel POSITIVE // taken\npush constant 1\nreturn\nfunction Main.unused 0\npush constant 0\nreturn"
+                    .to_owned(),
+            ),
+        ])
+        .unwrap();
+        vm.set_coverage(true);
+        vm.run(12);
+
+        let coverage = vm.coverage().unwrap();
+        let summary = |name: &str, hits, covered, total| CoverageSummary {
+            name: name.to_owned(),
+            hits,
+            covered,
+            total,
+        };
+        assert_eq!(
+            coverage.files(&vm.program),
+            vec![summary("Sys", 6, 6, 6), summary("Main", 6, 6, 11)]
+        );
+        assert_eq!(
+            coverage.functions(&vm.program),
+            vec![
+                summary("Sys.init", 6, 6, 6),
+                summary("Main.check", 6, 6, 8),
+                summary("Main.unused", 0, 0, 3),
+            ]
+        );
+        let listing = coverage.vm_listing(&vm.program);
+        assert!(listing.contains("\n     ##### |     4 | push constant 0\n"));
+        assert!(listing.contains("\n         1 |     6 | label POSITIVE // taken\n"));
+
+        vm.reset();
+        assert_eq!(vm.coverage().unwrap().hits(0), 0);
+    }
+}
@@ -1,5 +1,10 @@
 use eframe::egui::DroppedFile;
 
+use crate::hardware_parse::AssemblyError;
+use crate::jack;
+use crate::snapshot::Snapshot;
+use crate::vm_parse::errors_to_string;
+
 use super::instant::Instant;
 
 use super::EmulatorApp;
@@ -8,7 +13,12 @@ use super::common_state::{
 };
 use super::hardware_reducer::reduce_breakpoint_hardware;
 use super::hardware_state::HardwareState;
-use super::vm_reducer::reduce_vm_file_selected;
+use super::shared_ui::execute;
+use super::vm_reducer::{
+    reduce_breakpoint_vm, reduce_cursor_moved, reduce_heap_in_ram_toggled,
+    reduce_os_policy_changed, reduce_problems_toggled, reduce_profiling_toggled,
+    reduce_run_to_clicked, reduce_sanitizing_toggled, reduce_vm_file_selected,
+};
 use super::vm_state::VMState;
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -23,13 +33,63 @@ pub fn get_contents(dropped_file: &DroppedFile) -> String {
     String::from_utf8(bytes).unwrap()
 }
 
+fn vm_state_from_file_contents(file_contents: Vec<(String, String)>) -> Result<VMState, String> {
+    if file_contents
+        .iter()
+        .all(|(name, _)| name.to_lowercase().ends_with(".jack"))
+    {
+        Ok(VMState::from_all_file_commands(jack::compile_files(
+            file_contents,
+        )?))
+    } else {
+        VMState::from_file_contents(file_contents).map_err(|errors| errors_to_string(&errors))
+    }
+}
+
+fn load_vm_state(app: &mut EmulatorApp, file_contents: Vec<(String, String)>) {
+    match vm_state_from_file_contents(file_contents) {
+        Ok(vm_state) => {
+            app.state = AppState::VM(vm_state);
+            app.shared_state = Default::default();
+        }
+        Err(e) => app.shared_state.load_error = Some(e),
+    }
+}
+
+fn load_hardware_state(
+    app: &mut EmulatorApp,
+    name: &str,
+    state: Result<HardwareState, AssemblyError>,
+) {
+    match state {
+        Ok(hardware_state) => {
+            app.state = AppState::Hardware(hardware_state);
+            app.shared_state = Default::default();
+        }
+        Err(e) => app.shared_state.load_error = Some(format!("{name}: {e}")),
+    }
+}
+
 pub fn reduce(app: &mut EmulatorApp, action: &Action) {
     match action {
         Action::Common(common_action) => match &mut app.state {
             AppState::Hardware(hardware_state) => {
                 reduce_common(hardware_state, &mut app.shared_state, common_action)
             }
-            AppState::VM(vm_state) => reduce_common(vm_state, &mut app.shared_state, common_action),
+            AppState::VM(vm_state) => {
+                // Any other way of running or stopping cancels a step over, step out or run to
+                // cursor in progress.
+                if matches!(
+                    common_action,
+                    CommonAction::StepClicked
+                        | CommonAction::RunClicked
+                        | CommonAction::PauseClicked
+                        | CommonAction::ResetClicked
+                ) {
+                    vm_state.run_target = None;
+                }
+                reduce_common(vm_state, &mut app.shared_state, common_action)
+            }
             AppState::Start => panic!(
                 "Received common action {:?} when in state AppState::Start",
                 common_action
@@ -39,21 +99,16 @@ pub fn reduce(app: &mut EmulatorApp, action: &Action) {
             AppState::Hardware(hardware_state) => {
                 reduce_breakpoint_hardware(hardware_state, breakpoint_action)
             }
-            AppState::VM(_) => todo!(),
+            AppState::VM(vm_state) => reduce_breakpoint_vm(vm_state, breakpoint_action),
             AppState::Start => todo!(),
         },
-        Action::FilesPicked(file_contents) => {
-            app.state = AppState::VM(VMState::from_file_contents(file_contents.clone()));
-            app.shared_state = Default::default();
-        }
+        Action::FilesPicked(file_contents) => load_vm_state(app, file_contents.clone()),
         Action::FilePicked { name, contents } => {
             let lowercase_name = name.to_lowercase();
             if lowercase_name.ends_with(".hack") {
-                app.state = AppState::Hardware(HardwareState::from_hack_file_contents(contents));
-                app.shared_state = Default::default();
+                load_hardware_state(app, name, HardwareState::from_hack_file_contents(contents));
             } else if lowercase_name.ends_with(".asm") {
-                app.state = AppState::Hardware(HardwareState::from_file_contents(contents));
-                app.shared_state = Default::default();
+                load_hardware_state(app, name, HardwareState::from_file_contents(contents));
             } else {
                 println!("{:?}", name);
             }
@@ -62,24 +117,25 @@ pub fn reduce(app: &mut EmulatorApp, action: &Action) {
             let first_file_lowercase = dropped_files[0].name.to_lowercase();
             if dropped_files.len() == 1 && first_file_lowercase.ends_with(".asm") {
                 let file_contents = get_contents(&dropped_files[0]);
-                app.state = AppState::Hardware(HardwareState::from_file_contents(&file_contents));
-                app.shared_state = Default::default();
+                let state = HardwareState::from_file_contents(&file_contents);
+                load_hardware_state(app, &dropped_files[0].name, state);
             } else if dropped_files.len() == 1 && first_file_lowercase.ends_with(".hack") {
                 let file_contents = get_contents(&dropped_files[0]);
-                app.state =
-                    AppState::Hardware(HardwareState::from_hack_file_contents(&file_contents));
-                app.shared_state = Default::default();
+                let state = HardwareState::from_hack_file_contents(&file_contents);
+                load_hardware_state(app, &dropped_files[0].name, state);
             } else if dropped_files
                 .iter()
                 .all(|d| d.name.to_lowercase().ends_with(".vm"))
+                || dropped_files
+                    .iter()
+                    .all(|d| d.name.to_lowercase().ends_with(".jack"))
             {
                 let file_contents = dropped_files
                     .iter()
                     .map(|dropped_file| (dropped_file.name.clone(), get_contents(dropped_file)))
                     .collect();
 
-                app.state = AppState::VM(VMState::from_file_contents(file_contents));
-                app.shared_state = Default::default();
+                load_vm_state(app, file_contents);
             } else {
                 println!("{:?}", dropped_files);
             }
@@ -92,6 +148,83 @@ pub fn reduce(app: &mut EmulatorApp, action: &Action) {
             AppState::VM(vm_state) => reduce_vm_file_selected(vm_state, file),
             AppState::Start => todo!(),
         },
+        Action::ProfilingToggled(profiling) => match &mut app.state {
+            AppState::VM(vm_state) => reduce_profiling_toggled(vm_state, *profiling),
+            AppState::Hardware(_) | AppState::Start => {
+                panic!("Received action {:?} outside of AppState::VM", action)
+            }
+        },
+        Action::ProblemsToggled(open) => match &mut app.state {
+            AppState::VM(vm_state) => reduce_problems_toggled(vm_state, *open),
+            AppState::Hardware(_) | AppState::Start => {
+                panic!("Received action {:?} outside of AppState::VM", action)
+            }
+        },
+        Action::SanitizingToggled(sanitizing) => match &mut app.state {
+            AppState::VM(vm_state) => reduce_sanitizing_toggled(vm_state, *sanitizing),
+            AppState::Hardware(_) | AppState::Start => {
+                panic!("Received action {:?} outside of AppState::VM", action)
+            }
+        },
+        Action::HeapInRamToggled(in_ram) => match &mut app.state {
+            AppState::VM(vm_state) => reduce_heap_in_ram_toggled(vm_state, *in_ram),
+            AppState::Hardware(_) | AppState::Start => {
+                panic!("Received action {:?} outside of AppState::VM", action)
+            }
+        },
+        Action::CursorMoved(command_index) => match &mut app.state {
+            AppState::VM(vm_state) => reduce_cursor_moved(vm_state, *command_index),
+            AppState::Hardware(_) | AppState::Start => {
+                panic!("Received action {:?} outside of AppState::VM", action)
+            }
+        },
+        Action::RunToClicked(target) => match &mut app.state {
+            AppState::VM(vm_state) => {
+                reduce_run_to_clicked(vm_state, &mut app.shared_state, *target)
+            }
+            AppState::Hardware(_) | AppState::Start => {
+                panic!("Received action {:?} outside of AppState::VM", action)
+            }
+        },
+        Action::OsPolicyChanged(class_name, policy) => match &mut app.state {
+            AppState::VM(vm_state) => reduce_os_policy_changed(vm_state, class_name, *policy),
+            AppState::Hardware(_) | AppState::Start => {
+                panic!("Received action {:?} outside of AppState::VM", action)
+            }
+        },
+        Action::SaveSnapshotClicked => {
+            let snapshot = match &mut app.state {
+                AppState::Hardware(hardware_state) => hardware_state.save_snapshot(),
+                AppState::VM(vm_state) => vm_state.save_snapshot(),
+                AppState::Start => return,
+            };
+            let task = rfd::AsyncFileDialog::new()
+                .add_filter("Snapshot", &["snapshot"])
+                .set_file_name("state.snapshot")
+                .save_file();
+            execute(async move {
+                if let Some(file) = task.await {
+                    let _ = file.write(snapshot.to_string().as_bytes()).await;
+                }
+            });
+        }
+        Action::SnapshotPicked(contents) => {
+            let result = Snapshot::parse(contents).and_then(|snapshot| match &mut app.state {
+                AppState::Hardware(hardware_state) => hardware_state.restore_snapshot(&snapshot),
+                AppState::VM(vm_state) => vm_state.restore_snapshot(&snapshot),
+                AppState::Start => Err("load a program before its snapshot".to_owned()),
+            });
+            match result {
+                Ok(()) => {
+                    app.shared_state.run_started = false;
+                    app.shared_state.scroll_once = true;
+                }
+                Err(e) => app.shared_state.load_error = Some(e),
+            }
+        }
+        Action::LoadErrorDismissed => {
+            app.shared_state.load_error = None;
+        }
         Action::CloseFile => {
             app.state = Default::default();
             app.shared_state = Default::default();
@@ -117,6 +250,32 @@ pub fn reduce_common(
             shared_state.run_started = false;
             shared_state.scroll_once = true;
         }
+        CommonAction::RecordingToggled(recording) => {
+            state.set_recording(*recording);
+        }
+        CommonAction::StepBackClicked => {
+            state.run_back(1);
+            shared_state.scroll_once = true;
+        }
+        CommonAction::RunBackClicked => {
+            state.run_back(u64::MAX);
+            shared_state.run_started = false;
+            shared_state.scroll_once = true;
+        }
+        CommonAction::TimelineMoved(new_position) => {
+            if let Some((position, _)) = state.timeline() {
+                if *new_position < position {
+                    state.run_back((position - new_position) as u64);
+                } else {
+                    state.run((new_position - position) as u64);
+                }
+            }
+            shared_state.run_started = false;
+            shared_state.scroll_once = true;
+        }
+        CommonAction::CoverageToggled(covering) => {
+            state.set_coverage(*covering);
+        }
         CommonAction::BreakpointsClicked => {
             shared_state.breakpoints_open = !shared_state.breakpoints_open;
         }
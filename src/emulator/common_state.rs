@@ -3,6 +3,8 @@ use super::instant::Instant;
 use super::vm_state::VMState;
 use crate::{
     hardware::{self, RAM, Word},
+    os::ClassPolicy,
+    snapshot::Snapshot,
     vm,
 };
 use eframe::egui::{DroppedFile, Key, Modifiers};
@@ -26,6 +28,17 @@ pub trait CommonState {
     fn run(&mut self, step_count: u64) -> bool;
     fn set_ram_value(&mut self, address: i16, value: i16);
     fn reset(&mut self);
+    fn set_recording(&mut self, recording: bool);
+    /// The position in the recorded timeline and its length, if recording.
+    fn timeline(&mut self) -> Option<(usize, usize)>;
+    /// Undoes up to `step_count` recorded steps, returning true if it stopped early.
+    fn run_back(&mut self, step_count: u64) -> bool;
+    fn set_coverage(&mut self, covering: bool);
+    fn is_covering(&mut self) -> bool;
+    /// Whether breakpoints can be edited in the breakpoints window.
+    fn has_breakpoints(&self) -> bool;
+    fn save_snapshot(&mut self) -> Snapshot;
+    fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), String>;
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -34,6 +47,11 @@ pub enum CommonAction {
     RunClicked,
     PauseClicked,
     ResetClicked,
+    RecordingToggled(bool),
+    StepBackClicked,
+    RunBackClicked,
+    TimelineMoved(usize),
+    CoverageToggled(bool),
     BreakpointsClicked,
     BreakpointsClosed,
     SpeedSliderMoved(u64),
@@ -50,6 +68,11 @@ pub enum BreakpointAction {
     AddClicked,
     BreakpointChanged(Breakpoint),
     RemoveClicked(usize),
+    Toggled(Breakpoint),
+    ConditionEdited(String),
+    WatchpointEdited(String),
+    WatchpointAddClicked,
+    WatchpointRemoveClicked(usize),
 }
 
 #[derive(Debug)]
@@ -60,6 +83,16 @@ pub enum Action {
     Breakpoint(BreakpointAction),
     Common(CommonAction),
     VMFileSelected(String),
+    ProfilingToggled(bool),
+    SanitizingToggled(bool),
+    HeapInRamToggled(bool),
+    CursorMoved(usize),
+    RunToClicked(vm::RunTarget),
+    OsPolicyChanged(String, ClassPolicy),
+    ProblemsToggled(bool),
+    SaveSnapshotClicked,
+    SnapshotPicked(String),
+    LoadErrorDismissed,
     CloseFile,
     Quit,
 }
@@ -77,6 +110,7 @@ pub struct SharedState {
     pub run_started: bool,
     pub scroll_once: bool,
     pub breakpoints_open: bool,
+    pub load_error: Option<String>,
 }
 
 impl Default for SharedState {
@@ -86,6 +120,7 @@ impl Default for SharedState {
             run_started: false,
             scroll_once: true,
             breakpoints_open: false,
+            load_error: None,
         }
     }
 }
@@ -16,7 +16,12 @@ pub fn reduce_breakpoint_hardware(hardware_state: &mut HardwareState, action: &B
         BreakpointAction::BreakpointChanged(Breakpoint::Hardware(new_breakpoint)) => {
             hardware_state.selected_breakpoint = new_breakpoint.clone();
         }
-        BreakpointAction::BreakpointChanged(Breakpoint::VM(_)) => {
+        BreakpointAction::BreakpointChanged(Breakpoint::VM(_))
+        | BreakpointAction::Toggled(_)
+        | BreakpointAction::ConditionEdited(_)
+        | BreakpointAction::WatchpointEdited(_)
+        | BreakpointAction::WatchpointAddClicked
+        | BreakpointAction::WatchpointRemoveClicked(_) => {
             panic!("Invalid action {action:?} in hardware state");
         }
     }
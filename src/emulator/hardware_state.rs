@@ -1,4 +1,7 @@
 use crate::hardware::{AnyHardware, Breakpoint, BreakpointVar, Hardware, Instruction, UWord};
+use crate::hardware_parse::AssemblyError;
+use crate::history::DEFAULT_CAPACITY;
+use crate::snapshot::Snapshot;
 
 use crate::wasm_hardware::WasmHardware;
 
@@ -36,24 +39,24 @@ impl Default for HardwareState {
 }
 
 impl HardwareState {
-    pub fn from_file_contents(contents: &str) -> Self {
-        HardwareState {
+    pub fn from_file_contents(contents: &str) -> Result<Self, AssemblyError> {
+        Ok(HardwareState {
             selected_breakpoint: Breakpoint {
                 var: BreakpointVar::A,
                 value: 0,
             },
-            hardware: Box::new(HardwareImpl::from_file_contents(contents)),
-        }
+            hardware: Box::new(HardwareImpl::from_file_contents(contents)?),
+        })
     }
 
-    pub fn from_hack_file_contents(contents: &str) -> Self {
-        HardwareState {
+    pub fn from_hack_file_contents(contents: &str) -> Result<Self, AssemblyError> {
+        Ok(HardwareState {
             selected_breakpoint: Breakpoint {
                 var: BreakpointVar::A,
                 value: 0,
             },
-            hardware: Box::new(HardwareImpl::from_hack_file_contents(contents)),
-        }
+            hardware: Box::new(HardwareImpl::from_hack_file_contents(contents)?),
+        })
     }
 }
 
@@ -69,4 +72,37 @@ impl CommonState for HardwareState {
     fn reset(&mut self) {
         self.hardware.reset();
     }
+
+    fn set_recording(&mut self, recording: bool) {
+        self.hardware
+            .set_recording(recording.then_some(DEFAULT_CAPACITY));
+    }
+
+    fn timeline(&mut self) -> Option<(usize, usize)> {
+        self.hardware.timeline()
+    }
+
+    fn run_back(&mut self, step_count: u64) -> bool {
+        self.hardware.run_back(step_count)
+    }
+
+    fn set_coverage(&mut self, covering: bool) {
+        self.hardware.set_coverage(covering);
+    }
+
+    fn is_covering(&mut self) -> bool {
+        self.hardware.coverage().is_some()
+    }
+
+    fn has_breakpoints(&self) -> bool {
+        false
+    }
+
+    fn save_snapshot(&mut self) -> Snapshot {
+        self.hardware.save_snapshot()
+    }
+
+    fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        self.hardware.restore_snapshot(snapshot)
+    }
 }
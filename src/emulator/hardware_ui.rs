@@ -25,6 +25,7 @@ impl HardwareState {
                 return;
             }
             let ram_copy = self.hardware.copy_ram();
+            let coverage = self.hardware.coverage().cloned();
 
             let available_width = ui.available_width();
             let thin_layout = available_width < 768.0;
@@ -54,6 +55,7 @@ impl HardwareState {
                                                         rom,
                                                         &(0..=((MEM_SIZE - 1) as Word)),
                                                         pc,
+                                                        coverage.as_ref(),
                                                         shared_state.scroll_once,
                                                     );
                                                 });
@@ -20,7 +20,7 @@ use std::time::Duration;
 
 use common_reducer::reduce;
 use common_reducer::steps_to_run;
-use common_state::{Action, AppState, PerformanceData, StepRunnable};
+use common_state::{Action, AppState, CommonState, PerformanceData, StepRunnable};
 use shared_ui::{Screen, draw_shared};
 use vm_ui::draw_vm;
 
@@ -64,11 +64,16 @@ impl eframe::App for EmulatorApp {
 
         let mut action = None;
 
+        let common_state: Option<&mut dyn CommonState> = match &mut self.state {
+            AppState::Hardware(state) => Some(state),
+            AppState::VM(state) => Some(state),
+            AppState::Start => None,
+        };
         draw_shared(
             &self.shared_state,
             ctx,
             &self.performance_data,
-            !matches!(self.state, AppState::Start),
+            common_state,
             &mut action,
             &self.async_actions.0,
         );
@@ -133,55 +138,61 @@ impl eframe::App for EmulatorApp {
                             let file_contents = file_contents_from_dir(&include_dir::include_dir!(
                                 "$CARGO_MANIFEST_DIR/Raytracer"
                             ));
-                            self.state = AppState::VM(VMState::from_file_contents(file_contents));
+                            self.state =
+                                AppState::VM(VMState::from_file_contents(file_contents).unwrap());
                             self.shared_state = Default::default();
                         }
                         if ui.button("VM Example 2: Hackenstein").clicked() {
                             let file_contents = file_contents_from_dir(&include_dir::include_dir!(
                                 "$CARGO_MANIFEST_DIR/hackenstein3DVM"
                             ));
-                            self.state = AppState::VM(VMState::from_file_contents(file_contents));
+                            self.state =
+                                AppState::VM(VMState::from_file_contents(file_contents).unwrap());
                             self.shared_state = Default::default();
                         }
                         if ui.button("VM Example 3: Dino").clicked() {
                             let file_contents = file_contents_from_dir(&include_dir::include_dir!(
                                 "$CARGO_MANIFEST_DIR/Dino"
                             ));
-                            self.state = AppState::VM(VMState::from_file_contents(file_contents));
+                            self.state =
+                                AppState::VM(VMState::from_file_contents(file_contents).unwrap());
                             self.shared_state = Default::default();
                         }
                         if ui.button("VM Example 4: 2048").clicked() {
                             let file_contents = file_contents_from_dir(&include_dir::include_dir!(
                                 "$CARGO_MANIFEST_DIR/2048"
                             ));
-                            self.state = AppState::VM(VMState::from_file_contents(file_contents));
+                            self.state =
+                                AppState::VM(VMState::from_file_contents(file_contents).unwrap());
                             self.shared_state = Default::default();
                         }
                         if ui.button("VM Example 5: Ray Marcher").clicked() {
                             let file_contents = file_contents_from_dir(&include_dir::include_dir!(
                                 "$CARGO_MANIFEST_DIR/Raymarcher"
                             ));
-                            self.state = AppState::VM(VMState::from_file_contents(file_contents));
+                            self.state =
+                                AppState::VM(VMState::from_file_contents(file_contents).unwrap());
                             self.shared_state = Default::default();
                         }
                         if ui.button("VM Test").clicked() {
                             let file_contents = file_contents_from_dir(&include_dir::include_dir!(
                                 "$CARGO_MANIFEST_DIR/VmTest"
                             ));
-                            self.state = AppState::VM(VMState::from_file_contents(file_contents));
+                            self.state =
+                                AppState::VM(VMState::from_file_contents(file_contents).unwrap());
                             self.shared_state = Default::default();
                         }
                         if ui.button("Hack Example 1: Ray Marcher").clicked() {
                             let file_contents = include_str!("../../r_soj.hack");
                             self.state = AppState::Hardware(
-                                HardwareState::from_hack_file_contents(file_contents),
+                                HardwareState::from_hack_file_contents(file_contents).unwrap(),
                             );
                             self.shared_state = Default::default();
                         }
                         if ui.button("Hack Example 2: Game of Life").clicked() {
                             let file_contents = include_str!("../../life-128-hibit.hack");
                             self.state = AppState::Hardware(
-                                HardwareState::from_hack_file_contents(file_contents),
+                                HardwareState::from_hack_file_contents(file_contents).unwrap(),
                             );
                             self.shared_state = Default::default();
                         }
@@ -1,7 +1,8 @@
 use super::instant::Instant;
 use crate::{
+    coverage::Coverage,
     hardware::{Instruction, RAM, Word},
-    vm::{Program, RunState},
+    vm::{self, Program, RunState},
 };
 use core::slice;
 use eframe::{
@@ -11,10 +12,13 @@ use eframe::{
 };
 use egui_extras::{Column, TableBuilder};
 use futures::future::join_all;
+use hashbrown::HashSet;
 use std::{future::Future, sync::mpsc::Sender};
 use std::{ops::RangeInclusive, sync::Arc};
 
-use super::common_state::{Action, CommonAction, PerformanceData, SharedState, UIStyle};
+use super::common_state::{
+    Action, CommonAction, CommonState, PerformanceData, SharedState, UIStyle,
+};
 
 pub struct Screen {
     program: glow::Program,
@@ -220,23 +224,41 @@ pub fn draw_shared(
     state: &SharedState,
     ctx: &egui::Context,
     performance_data: &PerformanceData,
-    is_file_loaded: bool,
+    mut common_state: Option<&mut dyn CommonState>,
     action: &mut Option<Action>,
     async_actions_sender: &Sender<Action>,
 ) {
+    let is_file_loaded = common_state.is_some();
+    let timeline = common_state.as_mut().and_then(|state| state.timeline());
+    let has_breakpoints = common_state
+        .as_ref()
+        .is_some_and(|state| state.has_breakpoints());
+    let covering = common_state.is_some_and(|state| state.is_covering());
+    if let Some(load_error) = &state.load_error {
+        egui::Window::new("Load Error")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.monospace(load_error);
+                if ui.button("OK").clicked() {
+                    *action = Some(Action::LoadErrorDismissed);
+                }
+            });
+    }
+
     egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
         // The top panel is often a good place for a menu bar:
         // #[cfg(not(target_arch = "wasm32"))]
         {
             egui::MenuBar::new().ui(ui, |ui| {
                 ui.menu_button("File", |ui| {
-                    if ui.button("Load VM Files").clicked() {
+                    if ui.button("Load VM/Jack Files").clicked() {
                         ui.close();
                         let mut dialog = rfd::AsyncFileDialog::new();
                         if let Ok(current_dir) = std::env::current_dir() {
                             dialog = dialog.set_directory(current_dir);
                         }
-                        let task = dialog.add_filter("VM", &[&"vm"]).pick_files();
+                        let task = dialog.add_filter("VM/Jack", &[&"vm", &"jack"]).pick_files();
                         let ctx = ctx.clone();
                         let async_actions_sender = async_actions_sender.clone();
                         execute(async move {
@@ -275,6 +297,27 @@ pub fn draw_shared(
                     }
 
                     ui.add_enabled_ui(is_file_loaded, |ui| {
+                        if ui.button("Save Snapshot").clicked() {
+                            ui.close();
+                            *action = Some(Action::SaveSnapshotClicked);
+                        }
+                        if ui.button("Load Snapshot").clicked() {
+                            ui.close();
+                            let task = rfd::AsyncFileDialog::new()
+                                .add_filter("Snapshot", &["snapshot"])
+                                .pick_file();
+                            let ctx = ctx.clone();
+                            let async_actions_sender = async_actions_sender.clone();
+                            execute(async move {
+                                if let Some(file) = task.await {
+                                    let contents =
+                                        String::from_utf8_lossy(&file.read().await).into_owned();
+                                    let _ =
+                                        async_actions_sender.send(Action::SnapshotPicked(contents));
+                                    ctx.request_repaint();
+                                }
+                            });
+                        }
                         if ui.button("Close File(s)").clicked() {
                             ui.close();
                             *action = Some(Action::CloseFile)
@@ -303,9 +346,38 @@ pub fn draw_shared(
                 if ui.button("Reset").clicked() {
                     *action = Some(Action::Common(CommonAction::ResetClicked));
                 }
-                // if ui.button("Breakpoints").clicked() {
-                //     *action = Some(Action::Common(CommonAction::BreakpointsClicked));
-                // }
+                let mut recording = timeline.is_some();
+                if ui.checkbox(&mut recording, "Record").changed() {
+                    *action = Some(Action::Common(CommonAction::RecordingToggled(recording)));
+                }
+                let can_step_back = timeline.is_some_and(|(position, _)| position > 0);
+                if ui
+                    .add_enabled(can_step_back, egui::Button::new("Step Back"))
+                    .clicked()
+                {
+                    *action = Some(Action::Common(CommonAction::StepBackClicked));
+                }
+                if ui
+                    .add_enabled(can_step_back, egui::Button::new("Run Back"))
+                    .clicked()
+                {
+                    *action = Some(Action::Common(CommonAction::RunBackClicked));
+                }
+                if let Some((position, length)) = timeline {
+                    let mut new_position = position;
+                    ui.label("Timeline:");
+                    ui.add(Slider::new(&mut new_position, 0..=length));
+                    if new_position != position {
+                        *action = Some(Action::Common(CommonAction::TimelineMoved(new_position)));
+                    }
+                }
+                let mut new_covering = covering;
+                if ui.checkbox(&mut new_covering, "Coverage").changed() {
+                    *action = Some(Action::Common(CommonAction::CoverageToggled(new_covering)));
+                }
+                if has_breakpoints && ui.button("Breakpoints").clicked() {
+                    *action = Some(Action::Common(CommonAction::BreakpointsClicked));
+                }
 
                 let mut new_steps_per_second = state.desired_steps_per_second;
                 let height = ui.text_style_height(&egui::TextStyle::Body);
@@ -352,15 +424,31 @@ pub fn draw_shared(
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-fn execute<F: Future<Output = ()> + Send + 'static>(f: F) {
+pub fn execute<F: Future<Output = ()> + Send + 'static>(f: F) {
     std::thread::spawn(move || futures::executor::block_on(f));
 }
 
 #[cfg(target_arch = "wasm32")]
-fn execute<F: Future<Output = ()> + 'static>(f: F) {
+pub fn execute<F: Future<Output = ()> + 'static>(f: F) {
     wasm_bindgen_futures::spawn_local(f);
 }
 
+/// Shows code which was never executed in the warning color, if coverage is being collected.
+fn coverage_label(ui: &mut egui::Ui, text: String, hits: Option<u64>) -> egui::Response {
+    match hits {
+        Some(0) => {
+            let text = egui::RichText::new(text)
+                .monospace()
+                .color(ui.visuals().warn_fg_color);
+            ui.label(text).on_hover_text("Never executed")
+        }
+        Some(hits) => ui
+            .monospace(text)
+            .on_hover_text(format!("Executed {hits} times")),
+        None => ui.monospace(text),
+    }
+}
+
 pub trait EmulatorWidgets {
     fn ram_grid(
         &mut self,
@@ -377,14 +465,21 @@ pub trait EmulatorWidgets {
         rom: &[Instruction; 32 * 1024],
         range: &RangeInclusive<Word>,
         highlight_address: Word,
+        coverage: Option<&Coverage>,
         scroll_to_row: bool,
     );
+    #[allow(clippy::too_many_arguments)]
     fn vm_grid(
         &mut self,
         program: &Program,
         current_file_index: usize,
         current_command_index: usize,
+        breakpoints: &[vm::Breakpoint],
+        coverage: Option<&Coverage>,
+        cursor: Option<usize>,
         selected_file: &mut String,
+        clicked_line: &mut Option<usize>,
+        clicked_command: &mut Option<usize>,
         scroll_to_row: bool,
     );
 }
@@ -465,6 +560,7 @@ impl EmulatorWidgets for egui::Ui {
         rom: &[Instruction; 32 * 1024],
         range: &RangeInclusive<Word>,
         highlight_address: Word,
+        coverage: Option<&Coverage>,
         scroll_to_address: bool,
     ) {
         self.push_id(caption, |ui| {
@@ -508,7 +604,11 @@ impl EmulatorWidgets for egui::Ui {
                                     ui.monospace(row_index.to_string());
                                 });
                                 row.col(|ui| {
-                                    ui.monospace(rom[row_index].to_string());
+                                    coverage_label(
+                                        ui,
+                                        rom[row_index].to_string(),
+                                        coverage.map(|coverage| coverage.hits(row_index)),
+                                    );
                                 });
                             },
                         );
@@ -522,7 +622,12 @@ impl EmulatorWidgets for egui::Ui {
         program: &Program,
         current_file_index: usize,
         current_command_index: usize,
+        breakpoints: &[vm::Breakpoint],
+        coverage: Option<&Coverage>,
+        cursor: Option<usize>,
         selected_file: &mut String,
+        clicked_line: &mut Option<usize>,
+        clicked_command: &mut Option<usize>,
         scroll_to_row: bool,
     ) {
         self.push_id("VM", |ui| {
@@ -542,6 +647,17 @@ impl EmulatorWidgets for egui::Ui {
                 let file_index = program.file_name_to_index[selected_file];
                 let file = &program.files[file_index];
                 let commands = file.commands(&program.all_commands);
+                let source_lines = file.source_lines();
+                let breakpoint_lines: HashSet<usize> = breakpoints
+                    .iter()
+                    .filter_map(|breakpoint| match breakpoint {
+                        vm::Breakpoint::Line {
+                            file_name,
+                            line_number,
+                        } if file_name == selected_file => Some(*line_number as usize),
+                        _ => None,
+                    })
+                    .collect();
 
                 let available_height = ui.available_height();
                 let mut builder = TableBuilder::new(ui)
@@ -559,7 +675,8 @@ impl EmulatorWidgets for egui::Ui {
                     .striped(true)
                     .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
                     .column(Column::initial(45.0).at_least(45.0))
-                    .column(Column::remainder().at_least(70.0))
+                    .column(Column::initial(160.0).at_least(70.0))
+                    .column(Column::remainder())
                     .header(header_height, |mut header| {
                         header.col(|ui| {
                             ui.label("Line");
@@ -567,6 +684,9 @@ impl EmulatorWidgets for egui::Ui {
                         header.col(|ui| {
                             ui.label("Command");
                         });
+                        header.col(|ui| {
+                            ui.label("Comment");
+                        });
                     })
                     .body(|body| {
                         body.rows(row_height, commands.len(), |mut row| {
@@ -574,11 +694,40 @@ impl EmulatorWidgets for egui::Ui {
                             let is_highlighted = file_index == current_file_index
                                 && row_index == current_command_index - file.starting_command_index;
                             row.set_selected(is_highlighted);
+                            let source_line = &source_lines[row_index];
+                            row.col(|ui| {
+                                let line_number = source_line.line_number;
+                                let text = if breakpoint_lines.contains(&line_number) {
+                                    format!("●{line_number}")
+                                } else {
+                                    line_number.to_string()
+                                };
+                                let label = egui::Label::new(egui::RichText::new(text).monospace())
+                                    .sense(egui::Sense::click());
+                                if ui.add(label).on_hover_text("Toggle breakpoint").clicked() {
+                                    *clicked_line = Some(line_number);
+                                }
+                            });
                             row.col(|ui| {
-                                ui.monospace(row_index.to_string());
+                                let command_index = file.starting_command_index + row_index;
+                                let text = if cursor == Some(command_index) {
+                                    format!("▸ {}", commands[row_index])
+                                } else {
+                                    commands[row_index].to_string()
+                                };
+                                let hits = coverage.map(|coverage| coverage.hits(command_index));
+                                if coverage_label(ui, text, hits)
+                                    .interact(egui::Sense::click())
+                                    .on_hover_text("Set cursor")
+                                    .clicked()
+                                {
+                                    *clicked_command = Some(command_index);
+                                }
                             });
                             row.col(|ui| {
-                                ui.monospace(commands[row_index].to_string());
+                                if let Some(comment) = &source_line.comment {
+                                    ui.weak(comment);
+                                }
                             });
                         });
                     });
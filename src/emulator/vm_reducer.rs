@@ -4,20 +4,120 @@ pub fn reduce_vm_file_selected(vm_state: &mut VMState, selected_file: &str) {
     selected_file.clone_into(&mut vm_state.selected_file);
 }
 
-use super::common_state::{Breakpoint, BreakpointAction};
+pub fn reduce_profiling_toggled(vm_state: &mut VMState, profiling: bool) {
+    vm_state.vm.set_profiling(profiling);
+}
+
+pub fn reduce_sanitizing_toggled(vm_state: &mut VMState, sanitizing: bool) {
+    vm_state.vm.set_sanitizing(sanitizing);
+}
+
+pub fn reduce_heap_in_ram_toggled(vm_state: &mut VMState, in_ram: bool) {
+    vm_state
+        .vm
+        .set_heap_in_ram(in_ram)
+        .expect("the native heap is inside the RAM");
+}
+
+pub fn reduce_os_policy_changed(vm_state: &mut VMState, class_name: &str, policy: ClassPolicy) {
+    let mut os_policy = vm_state.vm.program.os_policy.clone();
+    os_policy
+        .set_class(class_name, policy)
+        .expect("the UI only lists OS classes");
+    vm_state.vm.set_os_policy(os_policy);
+    // Whether a call is to an OS function, and so its argument count, depends on the policy.
+    vm_state.diagnostics = validate(&vm_state.vm.program);
+}
+
+pub fn reduce_problems_toggled(vm_state: &mut VMState, open: bool) {
+    vm_state.problems_open = open;
+}
+
+pub fn reduce_cursor_moved(vm_state: &mut VMState, command_index: usize) {
+    vm_state.cursor = (vm_state.cursor != Some(command_index)).then_some(command_index);
+}
+
+pub fn reduce_run_to_clicked(
+    vm_state: &mut VMState,
+    shared_state: &mut SharedState,
+    target: RunTarget,
+) {
+    vm_state.run_target = Some(target);
+    shared_state.run_started = true;
+}
+
+use super::common_state::{Breakpoint, BreakpointAction, SharedState};
+use crate::{
+    condition_parse::parse_condition,
+    os::ClassPolicy,
+    vm::{self, RunTarget, StopReason},
+    vm_validate::validate,
+    watchpoint::Watchpoint,
+};
 
 pub fn reduce_breakpoint_vm(vm_state: &mut VMState, action: &BreakpointAction) {
     match action {
-        BreakpointAction::AddClicked => {
-            // vm_state.vm.add_breakpoint(&vm_state.selected_breakpoint);
-        }
+        BreakpointAction::AddClicked => match &vm_state.selected_breakpoint {
+            vm::Breakpoint::Condition(condition) => {
+                if let Err(error) = vm_state.vm.add_condition(condition.clone()) {
+                    vm_state.condition_error = Some(error);
+                }
+            }
+            breakpoint => vm_state.vm.add_breakpoint(breakpoint),
+        },
         BreakpointAction::RemoveClicked(row_index) => {
-            // vm_state.vm.remove_breakpoint(*row_index);
+            vm_state.vm.remove_breakpoint(*row_index);
+            vm_state.stop_reason = StopReason::StepsExhausted;
+        }
+        BreakpointAction::Toggled(Breakpoint::VM(breakpoint)) => {
+            match vm_state
+                .vm
+                .get_breakpoints()
+                .iter()
+                .position(|b| b == breakpoint)
+            {
+                Some(index) => {
+                    vm_state.vm.remove_breakpoint(index);
+                    vm_state.stop_reason = StopReason::StepsExhausted;
+                }
+                None => vm_state.vm.add_breakpoint(breakpoint),
+            }
         }
         BreakpointAction::BreakpointChanged(Breakpoint::VM(new_breakpoint)) => {
+            if let vm::Breakpoint::Condition(condition) = new_breakpoint {
+                vm_state.condition_text = condition.to_string();
+                vm_state.condition_error = None;
+            }
             vm_state.selected_breakpoint = new_breakpoint.clone();
         }
-        BreakpointAction::BreakpointChanged(Breakpoint::Hardware(_)) => {
+        BreakpointAction::ConditionEdited(text) => {
+            text.clone_into(&mut vm_state.condition_text);
+            match parse_condition(text) {
+                Ok(condition) => {
+                    vm_state.selected_breakpoint = vm::Breakpoint::Condition(condition);
+                    vm_state.condition_error = None;
+                }
+                Err(error) => vm_state.condition_error = Some(error),
+            }
+        }
+        BreakpointAction::WatchpointEdited(text) => {
+            text.clone_into(&mut vm_state.watchpoint_text);
+            vm_state.watchpoint_error = Watchpoint::parse(text).err();
+        }
+        BreakpointAction::WatchpointAddClicked => {
+            match Watchpoint::parse(&vm_state.watchpoint_text) {
+                Ok(watchpoint) => vm_state.vm.add_watchpoint(&watchpoint),
+                Err(error) => vm_state.watchpoint_error = Some(error),
+            }
+        }
+        BreakpointAction::WatchpointRemoveClicked(row_index) => {
+            vm_state.vm.remove_watchpoint(*row_index);
+            if let StopReason::Watchpoint(_) = vm_state.stop_reason {
+                vm_state.stop_reason = StopReason::StepsExhausted;
+            }
+        }
+        BreakpointAction::BreakpointChanged(Breakpoint::Hardware(_))
+        | BreakpointAction::Toggled(Breakpoint::Hardware(_)) => {
             panic!("Invalid action {action:?} in VM state");
         }
     }
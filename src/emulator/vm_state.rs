@@ -1,33 +1,78 @@
-use crate::vm::{Breakpoint, VM};
+use crate::history::DEFAULT_CAPACITY;
+use crate::snapshot::Snapshot;
+use crate::vm::{Breakpoint, RunTarget, StopReason, VM, VMCommand};
+use crate::vm_parse::VMParseError;
+use crate::vm_validate::{Diagnostic, validate};
 
 use super::common_state::CommonState;
 
-type VMImpl = crate::wasm_vm::WasmVm;
-// type VMImpl = VM;
+// Breakpoints need the interpreter, since the WASM module runs whole basic blocks at once.
+// type VMImpl = crate::wasm_vm::WasmVm;
+type VMImpl = VM;
 
 pub struct VMState {
     pub vm: VMImpl,
     pub selected_file: String,
     pub selected_breakpoint: Breakpoint,
+    pub stop_reason: StopReason,
+    /// The command index selected for running to.
+    pub cursor: Option<usize>,
+    /// Where a step over, step out or run to cursor in progress stops.
+    pub run_target: Option<RunTarget>,
+    /// The text of the condition being edited, which is only copied into `selected_breakpoint`
+    /// once it parses.
+    pub condition_text: String,
+    pub condition_error: Option<String>,
+    /// The watchpoint being edited, as for `condition_text`.
+    pub watchpoint_text: String,
+    pub watchpoint_error: Option<String>,
+    /// What the validator found when the program was loaded.
+    pub diagnostics: Vec<Diagnostic>,
+    pub problems_open: bool,
 }
 
 impl VMState {
-    pub fn from_file_contents(file_contents: Vec<(String, String)>) -> Self {
-        let vm = VMImpl::from_file_contents(file_contents);
+    pub fn from_file_contents(
+        file_contents: Vec<(String, String)>,
+    ) -> Result<Self, Vec<VMParseError>> {
+        Ok(Self::from_vm(VMImpl::from_file_contents(file_contents)?))
+    }
+
+    pub fn from_all_file_commands(all_file_commands: Vec<(String, Vec<VMCommand>)>) -> Self {
+        Self::from_vm(VMImpl::from_all_file_commands(all_file_commands))
+    }
+
+    fn from_vm(vm: VMImpl) -> Self {
         let selected_file = "Sys".to_owned(); //vm.current_file_name().to_owned();
         let selected_breakpoint = Breakpoint::SP(0);
+        let diagnostics = validate(&vm.program);
         VMState {
             vm,
             selected_file,
             selected_breakpoint,
+            stop_reason: StopReason::StepsExhausted,
+            cursor: None,
+            run_target: None,
+            condition_text: String::new(),
+            condition_error: None,
+            watchpoint_text: String::new(),
+            watchpoint_error: None,
+            problems_open: !diagnostics.is_empty(),
+            diagnostics,
         }
     }
 }
 
 impl CommonState for VMState {
     fn run(&mut self, step_count: u64) -> bool {
-        self.vm.run(step_count);
-        false
+        self.stop_reason = match self.run_target {
+            Some(target) => self.vm.run_to(target, step_count),
+            None => self.vm.run(step_count),
+        };
+        if self.stop_reason != StopReason::StepsExhausted {
+            self.run_target = None;
+        }
+        self.stop_reason != StopReason::StepsExhausted
     }
 
     fn set_ram_value(&mut self, address: i16, value: i16) {
@@ -36,5 +81,42 @@ impl CommonState for VMState {
 
     fn reset(&mut self) {
         self.vm.reset();
+        self.stop_reason = StopReason::StepsExhausted;
+    }
+
+    fn set_recording(&mut self, recording: bool) {
+        self.vm.set_recording(recording.then_some(DEFAULT_CAPACITY));
+    }
+
+    fn timeline(&mut self) -> Option<(usize, usize)> {
+        self.vm.timeline()
+    }
+
+    fn run_back(&mut self, step_count: u64) -> bool {
+        self.stop_reason = self.vm.run_back(step_count);
+        self.stop_reason != StopReason::StepsExhausted
+    }
+
+    fn set_coverage(&mut self, covering: bool) {
+        self.vm.set_coverage(covering);
+    }
+
+    fn is_covering(&mut self) -> bool {
+        self.vm.coverage().is_some()
+    }
+
+    fn has_breakpoints(&self) -> bool {
+        true
+    }
+
+    fn save_snapshot(&mut self) -> Snapshot {
+        self.vm.save_snapshot()
+    }
+
+    fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        self.vm.restore_snapshot(snapshot)?;
+        self.stop_reason = StopReason::StepsExhausted;
+
+        Ok(())
     }
 }
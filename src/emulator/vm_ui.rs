@@ -1,8 +1,10 @@
 use std::sync::Arc;
 
+use crate::condition::{Comparison, Condition, Operand, Variable};
 use crate::emulator::common_state::CommonAction;
 use crate::hardware::{MEM_SIZE, Word};
-use crate::vm::{self, Register};
+use crate::os::{ClassPolicy, OS_CLASSES};
+use crate::vm::{self, Register, StopReason};
 use eframe::egui;
 use egui_extras::{Column, Size, StripBuilder, TableBuilder};
 
@@ -11,6 +13,112 @@ use super::common_state::{Breakpoint, BreakpointAction, SharedState, UIStyle};
 use super::shared_ui::{EmulatorWidgets, Screen, draw_screen};
 use super::vm_state::VMState;
 
+fn call_stack(ui: &mut egui::Ui, state: &VMState, action: &mut Option<Action>) {
+    ui.horizontal(|ui| {
+        ui.label("Call Stack");
+        let mut profiling = state.vm.profiler().is_some();
+        if ui.checkbox(&mut profiling, "Profile").changed() {
+            *action = Some(Action::ProfilingToggled(profiling));
+        }
+        let mut sanitizing = state.vm.is_sanitizing();
+        if ui
+            .checkbox(&mut sanitizing, "Sanitize")
+            .on_hover_text("Catch double frees and accesses to freed or unallocated heap memory")
+            .changed()
+        {
+            *action = Some(Action::SanitizingToggled(sanitizing));
+        }
+        let mut heap_in_ram = state.vm.is_heap_in_ram();
+        if ui
+            .checkbox(&mut heap_in_ram, "Heap in RAM")
+            .on_hover_text("Keep the native heap's segment headers in RAM like the Jack OS")
+            .changed()
+        {
+            *action = Some(Action::HeapInRamToggled(heap_in_ram));
+        }
+        ui.menu_button("OS", |ui| os_classes(ui, state, action));
+        if !state.diagnostics.is_empty()
+            && ui
+                .selectable_label(
+                    state.problems_open,
+                    format!("Problems ({})", state.diagnostics.len()),
+                )
+                .clicked()
+        {
+            *action = Some(Action::ProblemsToggled(!state.problems_open));
+        }
+    });
+    egui::ScrollArea::vertical()
+        .auto_shrink(false)
+        .show(ui, |ui| {
+            for (index, frame) in state.vm.backtrace().iter().enumerate() {
+                let line_number = state
+                    .vm
+                    .program
+                    .source_line(frame.command_index)
+                    .map_or(0, |(_, source_line)| source_line.line_number);
+                egui::CollapsingHeader::new(format!(
+                    "{} ({}, line {line_number})",
+                    frame.function_name, frame.file_name
+                ))
+                .id_salt(index)
+                .default_open(index == 0)
+                .show(ui, |ui| {
+                    egui::Grid::new(index).striped(true).show(ui, |ui| {
+                        for (name, base, values) in [
+                            ("ARG", frame.arg, &frame.arguments),
+                            ("LCL", frame.lcl, &frame.locals),
+                        ] {
+                            ui.monospace(name);
+                            ui.monospace(base.to_string());
+                            ui.end_row();
+                            for (offset, value) in values.iter().enumerate() {
+                                ui.monospace(format!("{name}[{offset}]"));
+                                ui.monospace(value.to_string());
+                                ui.end_row();
+                            }
+                        }
+                    });
+                });
+            }
+        });
+}
+
+/// Where the functions of each OS class come from, with a policy picker for each.
+fn os_classes(ui: &mut egui::Ui, state: &VMState, action: &mut Option<Action>) {
+    let program = &state.vm.program;
+    egui::Grid::new("os classes").striped(true).show(ui, |ui| {
+        for class_name in OS_CLASSES {
+            ui.monospace(class_name);
+            let old_policy = program.os_policy.class(class_name);
+            let mut policy = old_policy;
+            egui::ComboBox::from_id_salt(class_name)
+                .selected_text(policy.to_string())
+                .show_ui(ui, |ui| {
+                    for option in ClassPolicy::ALL {
+                        ui.selectable_value(&mut policy, option, option.to_string());
+                    }
+                });
+            if policy != old_policy {
+                *action = Some(Action::OsPolicyChanged(class_name.to_owned(), policy));
+            }
+
+            let defined = program.class_functions(class_name).collect::<Vec<_>>();
+            let source = match policy {
+                ClassPolicy::Native => "native",
+                ClassPolicy::Program => "program",
+                ClassPolicy::PreferProgram if defined.is_empty() => "native",
+                ClassPolicy::PreferProgram => "program, native for the rest",
+            };
+            let label = ui.label(source);
+            if !defined.is_empty() {
+                label.on_hover_text(format!("Defined by the program: {}", defined.join(", ")));
+            }
+            ui.end_row();
+        }
+    });
+}
+
 pub fn draw_vm(
     state: &mut VMState,
     ctx: &egui::Context,
@@ -45,19 +153,61 @@ pub fn draw_vm(
                 strip.empty();
             } else {
                 strip.cell(|ui| {
-                    let mut selected_file = state.selected_file.clone();
-                    let current_file_index = state.vm.current_file_index();
-                    let current_command_index = state.vm.current_command_index();
-                    ui.vm_grid(
-                        &state.vm.program,
-                        current_file_index,
-                        current_command_index,
-                        &mut selected_file,
-                        shared_state.scroll_once,
-                    );
-                    if selected_file != state.selected_file {
-                        *action = Some(Action::VMFileSelected(selected_file));
-                    }
+                    egui::TopBottomPanel::bottom("call stack panel")
+                        .default_height(ui.available_height() / 3.0)
+                        .resizable(true)
+                        .show_inside(ui, |ui| {
+                            call_stack(ui, state, action);
+                        });
+
+                    egui::CentralPanel::default().show_inside(ui, |ui| {
+                        ui.horizontal(|ui| {
+                            if ui.button("Step Over").clicked() {
+                                *action = Some(Action::RunToClicked(state.vm.step_over_target()));
+                            }
+                            if ui.button("Step Out").clicked() {
+                                *action = Some(Action::RunToClicked(state.vm.step_out_target()));
+                            }
+                            if let Some(cursor) = state.cursor {
+                                if ui.button("Run to Cursor").clicked() {
+                                    *action =
+                                        Some(Action::RunToClicked(vm::RunTarget::Command(cursor)));
+                                }
+                            } else {
+                                ui.add_enabled(false, egui::Button::new("Run to Cursor"))
+                                    .on_disabled_hover_text("Click a command to set the cursor");
+                            }
+                        });
+                        let mut selected_file = state.selected_file.clone();
+                        let mut clicked_line = None;
+                        let mut clicked_command = None;
+                        let current_file_index = state.vm.current_file_index();
+                        let current_command_index = state.vm.current_command_index();
+                        ui.vm_grid(
+                            &state.vm.program,
+                            current_file_index,
+                            current_command_index,
+                            state.vm.get_breakpoints(),
+                            state.vm.coverage(),
+                            state.cursor,
+                            &mut selected_file,
+                            &mut clicked_line,
+                            &mut clicked_command,
+                            shared_state.scroll_once,
+                        );
+                        if selected_file != state.selected_file {
+                            *action = Some(Action::VMFileSelected(selected_file));
+                        } else if let Some(line_number) = clicked_line {
+                            *action = Some(Action::Breakpoint(BreakpointAction::Toggled(
+                                Breakpoint::VM(vm::Breakpoint::Line {
+                                    file_name: selected_file,
+                                    line_number: line_number as Word,
+                                }),
+                            )));
+                        } else if let Some(command_index) = clicked_command {
+                            *action = Some(Action::CursorMoved(command_index));
+                        }
+                    });
                 });
                 strip.cell(|ui| {
                     let current_file_name = state.vm.current_file_name().to_owned();
@@ -216,137 +366,321 @@ pub fn draw_vm(
         .resizable(true)
         .default_width(1000.0)
         .show(ctx, |ui| {
-            //         let breakpoints = state.vm.get_breakpoints();
-            //         ui.horizontal(|ui| {
-            //             let mut new_selected_breakpoint = state.selected_breakpoint;
-            //             let selected_text = state.selected_breakpoint.variable_name();
-            //             egui::ComboBox::from_id_source("Variable")
-            //                 .selected_text(selected_text)
-            //                 .width(50.0)
-            //                 .show_ui(ui, |ui| {
-            //                     for breakpoint_type in [
-            //                         vm::Breakpoint::SP(0),
-            //                         vm::Breakpoint::CurrentFunction("".to_owned()),
-            //                         vm::Breakpoint::Line {
-            //                             file_name: "".to_owned(),
-            //                             line_number: 0,
-            //                         },
-            //                         vm::Breakpoint::RAM {
-            //                             address: 0,
-            //                             value: 0,
-            //                         },
-            //                         vm::Breakpoint::LCL(0),
-            //                         vm::Breakpoint::Local {
-            //                             offset: 0,
-            //                             value: 0,
-            //                         },
-            //                         vm::Breakpoint::ARG(0),
-            //                         vm::Breakpoint::Argument {
-            //                             offset: 0,
-            //                             value: 0,
-            //                         },
-            //                         vm::Breakpoint::This(0),
-            //                         vm::Breakpoint::ThisPointer {
-            //                             offset: 0,
-            //                             value: 0,
-            //                         },
-            //                         vm::Breakpoint::That(0),
-            //                         vm::Breakpoint::ThatPointer {
-            //                             offset: 0,
-            //                             value: 0,
-            //                         },
-            //                         vm::Breakpoint::Temp {
-            //                             offset: 0,
-            //                             value: 0,
-            //                         },
-            //                     ] {
-            //                         ui.selectable_value(
-            //                             &mut new_selected_breakpoint,
-            //                             breakpoint_type,
-            //                             breakpoint_type.variable_name(),
-            //                         );
-            //                     }
-            //                 });
+            let breakpoints = state.vm.get_breakpoints();
+            ui.horizontal(|ui| {
+                let mut new_selected_breakpoint = state.selected_breakpoint.clone();
+                let selected_text = state.selected_breakpoint.variable_name();
+                egui::ComboBox::from_id_salt("Variable")
+                    .selected_text(selected_text)
+                    .width(50.0)
+                    .show_ui(ui, |ui| {
+                        for breakpoint_type in [
+                            vm::Breakpoint::SP(0),
+                            vm::Breakpoint::CurrentFunction("".to_owned()),
+                            vm::Breakpoint::Line {
+                                file_name: state.selected_file.clone(),
+                                line_number: 1,
+                            },
+                            vm::Breakpoint::RAM {
+                                address: 0,
+                                value: 0,
+                            },
+                            vm::Breakpoint::LCL(0),
+                            vm::Breakpoint::Local {
+                                offset: 0,
+                                value: 0,
+                            },
+                            vm::Breakpoint::ARG(0),
+                            vm::Breakpoint::Argument {
+                                offset: 0,
+                                value: 0,
+                            },
+                            vm::Breakpoint::This(0),
+                            vm::Breakpoint::ThisPointer {
+                                offset: 0,
+                                value: 0,
+                            },
+                            vm::Breakpoint::That(0),
+                            vm::Breakpoint::ThatPointer {
+                                offset: 0,
+                                value: 0,
+                            },
+                            vm::Breakpoint::Temp {
+                                offset: 0,
+                                value: 0,
+                            },
+                            vm::Breakpoint::Condition(Condition::Compare {
+                                left: Operand::Variable(Variable::SP),
+                                comparison: Comparison::Equal,
+                                right: Operand::Number(256),
+                            }),
+                        ] {
+                            let variable_name = breakpoint_type.variable_name();
+                            ui.selectable_value(
+                                &mut new_selected_breakpoint,
+                                breakpoint_type,
+                                variable_name,
+                            );
+                        }
+                    });
 
-            //             if let Some(address) = state.selected_breakpoint.address() {
-            //                 ui.label("[");
-            //                 let mut new_address_text = address.to_string();
-            //                 ui.add(
-            //                     egui::TextEdit::singleline(&mut new_address_text).desired_width(50.0),
-            //                 );
-            //                 if let Ok(new_address) = new_address_text.parse::<Word>() {
-            //                     new_selected_breakpoint.change_address(new_address);
-            //                 }
-            //                 ui.label("]");
-            //             }
+                if let Some(address) = state.selected_breakpoint.address() {
+                    ui.label("[");
+                    let mut new_address_text = address.to_string();
+                    ui.add(egui::TextEdit::singleline(&mut new_address_text).desired_width(50.0));
+                    if let Ok(new_address) = new_address_text.parse::<Word>() {
+                        new_selected_breakpoint.change_address(new_address);
+                    }
+                    ui.label("]");
+                }
 
-            //             ui.label("=");
+                if let vm::Breakpoint::Condition(_) = state.selected_breakpoint {
+                    let mut new_condition_text = state.condition_text.clone();
+                    ui.add(
+                        egui::TextEdit::singleline(&mut new_condition_text)
+                            .desired_width(300.0)
+                            .hint_text("RAM[0] > 300 && hits >= 10"),
+                    );
+                    if new_condition_text != state.condition_text {
+                        *action = Some(Action::Breakpoint(BreakpointAction::ConditionEdited(
+                            new_condition_text,
+                        )));
+                    }
+                } else {
+                    ui.label("=");
 
-            //             let mut new_value_text = state.selected_breakpoint.value().to_string();
-            //             ui.add(egui::TextEdit::singleline(&mut new_value_text).desired_width(50.0));
-            //             if let Ok(new_value) = new_value_text.parse::<Word>() {
-            //                 // if new_value != state.selected_breakpoint.value() {
-            //                 //     new_selected_breakpoint.change_value(new_value);
-            //                 // }
-            //             }
+                    let value_text = state.selected_breakpoint.value();
+                    let mut new_value_text = value_text.clone();
+                    ui.add(egui::TextEdit::singleline(&mut new_value_text).desired_width(100.0));
+                    if new_value_text != value_text {
+                        new_selected_breakpoint.change_value(new_value_text);
+                    }
+                }
 
-            //             if new_selected_breakpoint != state.selected_breakpoint {
-            //                 *action = Some(Action::Breakpoint(BreakpointAction::BreakpointChanged(Breakpoint::VM(new_selected_breakpoint)
-            //                 )));
-            //             }
+                if new_selected_breakpoint != state.selected_breakpoint {
+                    *action = Some(Action::Breakpoint(BreakpointAction::BreakpointChanged(
+                        Breakpoint::VM(new_selected_breakpoint),
+                    )));
+                }
 
-            //             if ui.button("Add").clicked() {
-            //                 *action = Some(Action::Breakpoint(BreakpointAction::AddClicked));
-            //             }
-            //         });
-            //         ui.label("Breakpoints:");
-            //         let header_height = ui.text_style_height(&egui::TextStyle::Body);
-            //         let row_height = ui.text_style_height(&egui::TextStyle::Monospace)
-            //             + 2.0 * ui.spacing().button_padding.x;
-            //         TableBuilder::new(ui)
-            //             .striped(true)
-            //             .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
-            //             .column(Column::exact(100.0))
-            //             .column(Column::exact(100.0))
-            //             .column(Column::exact(70.0))
-            //             .header(header_height, |mut header| {
-            //                 header.col(|ui| {
-            //                     ui.label("Variable");
-            //                 });
-            //                 header.col(|ui| {
-            //                     ui.label("Value");
-            //                 });
-            //                 header.col(|_| {});
-            //             })
-            //             .body(|body| {
-            //                 body.rows(row_height, usize::max(breakpoints.len(), 10), |mut row| {
-            //                     let row_index = row.index();
-            //                     let breakpoint = breakpoints.get(row_index);
-            //                     row.col(|ui| {
-            //                         ui.monospace(
-            //                             breakpoint
-            //                                 .map(|b| b.var.to_string())
-            //                                 .unwrap_or("".to_string()),
-            //                         );
-            //                     });
-            //                     row.col(|ui| {
-            //                         ui.monospace(
-            //                             breakpoint
-            //                                 .map(|b| b.value.to_string())
-            //                                 .unwrap_or("".to_string()),
-            //                         );
-            //                     });
-            //                     row.col(|ui| {
-            //                         if breakpoint.is_some() && ui.button("Remove").clicked() {
-            //                             *action = Some(Action::Breakpoint(
-            //                                 BreakpointAction::RemoveClicked(row_index),
-            //                             ));
-            //                         }
-            //                     });
-            //                 });
-            //             });
+                if ui
+                    .add_enabled(state.condition_error.is_none(), egui::Button::new("Add"))
+                    .clicked()
+                {
+                    *action = Some(Action::Breakpoint(BreakpointAction::AddClicked));
+                }
+            });
+            if let Some(condition_error) = &state.condition_error {
+                ui.colored_label(ui.visuals().warn_fg_color, condition_error);
+            }
+            ui.label("Breakpoints:");
+            let header_height = ui.text_style_height(&egui::TextStyle::Body);
+            let row_height = ui.text_style_height(&egui::TextStyle::Monospace)
+                + 2.0 * ui.spacing().button_padding.x;
+            TableBuilder::new(ui)
+                .striped(true)
+                .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+                .column(Column::exact(100.0))
+                .column(Column::initial(300.0).at_least(100.0).resizable(true))
+                .column(Column::exact(70.0))
+                .header(header_height, |mut header| {
+                    header.col(|ui| {
+                        ui.label("Variable");
+                    });
+                    header.col(|ui| {
+                        ui.label("Value");
+                    });
+                    header.col(|_| {});
+                })
+                .body(|body| {
+                    body.rows(row_height, usize::max(breakpoints.len(), 10), |mut row| {
+                        let row_index = row.index();
+                        let breakpoint = breakpoints.get(row_index);
+                        row.set_selected(state.stop_reason == StopReason::Breakpoint(row_index));
+                        row.col(|ui| {
+                            ui.monospace(breakpoint.map(|b| b.variable_name()).unwrap_or_default());
+                        });
+                        row.col(|ui| {
+                            let label =
+                                ui.monospace(breakpoint.map(|b| b.value()).unwrap_or_default());
+                            if let Some(vm::Breakpoint::Condition(_)) = breakpoint {
+                                label.on_hover_text(format!(
+                                    "{} hits",
+                                    state.vm.breakpoint_hits(row_index)
+                                ));
+                            }
+                        });
+                        row.col(|ui| {
+                            if breakpoint.is_some() && ui.button("Remove").clicked() {
+                                *action = Some(Action::Breakpoint(
+                                    BreakpointAction::RemoveClicked(row_index),
+                                ));
+                            }
+                        });
+                    });
+                });
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.label("Watch");
+                let mut new_watchpoint_text = state.watchpoint_text.clone();
+                ui.add(
+                    egui::TextEdit::singleline(&mut new_watchpoint_text)
+                        .desired_width(200.0)
+                        .hint_text("300 or 256..300:change"),
+                );
+                if new_watchpoint_text != state.watchpoint_text {
+                    *action = Some(Action::Breakpoint(BreakpointAction::WatchpointEdited(
+                        new_watchpoint_text,
+                    )));
+                }
+                if ui
+                    .add_enabled(
+                        state.watchpoint_error.is_none() && !state.watchpoint_text.is_empty(),
+                        egui::Button::new("Add"),
+                    )
+                    .clicked()
+                {
+                    *action = Some(Action::Breakpoint(BreakpointAction::WatchpointAddClicked));
+                }
+            });
+            if let Some(watchpoint_error) = &state.watchpoint_error {
+                ui.colored_label(ui.visuals().warn_fg_color, watchpoint_error);
+            }
+            if let StopReason::Watchpoint(watch_hit) = &state.stop_reason {
+                let location = match (
+                    state.vm.program.source_line(watch_hit.location),
+                    state.vm.program.all_commands.get(watch_hit.location),
+                ) {
+                    (Some((file, source_line)), Some(command)) => {
+                        format!("{} line {} ({command})", file.name, source_line.line_number)
+                    }
+                    _ => "the end of the program".to_owned(),
+                };
+                ui.colored_label(
+                    ui.visuals().warn_fg_color,
+                    format!("watchpoint {}: {watch_hit} at {location}", watch_hit.index),
+                );
+            }
+            ui.label("Watchpoints:");
+            let watchpoints = state.vm.get_watchpoints();
+            TableBuilder::new(ui)
+                .id_salt("Watchpoints")
+                .striped(true)
+                .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+                .column(Column::initial(400.0).at_least(100.0).resizable(true))
+                .column(Column::exact(70.0))
+                .body(|body| {
+                    body.rows(row_height, usize::max(watchpoints.len(), 5), |mut row| {
+                        let row_index = row.index();
+                        let watchpoint = watchpoints.get(row_index);
+                        row.set_selected(matches!(
+                            &state.stop_reason,
+                            StopReason::Watchpoint(watch_hit) if watch_hit.index == row_index
+                        ));
+                        row.col(|ui| {
+                            ui.monospace(watchpoint.map(|w| w.to_string()).unwrap_or_default());
+                        });
+                        row.col(|ui| {
+                            if watchpoint.is_some() && ui.button("Remove").clicked() {
+                                *action = Some(Action::Breakpoint(
+                                    BreakpointAction::WatchpointRemoveClicked(row_index),
+                                ));
+                            }
+                        });
+                    });
+                });
         });
 
+    if let Some(profiler) = state.vm.profiler() {
+        egui::Window::new("Profiler")
+            .resizable(true)
+            .default_width(600.0)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label(format!(
+                        "{} commands, max call depth {}",
+                        profiler.total(),
+                        profiler.max_depth()
+                    ));
+                    if ui.button("Copy Report").clicked() {
+                        ui.ctx().copy_text(profiler.report());
+                    }
+                    if ui.button("Copy Folded Stacks").clicked() {
+                        ui.ctx().copy_text(profiler.folded_stacks());
+                    }
+                });
+                let functions = profiler.functions();
+                TableBuilder::new(ui)
+                    .striped(true)
+                    .column(Column::remainder().at_least(200.0))
+                    .columns(Column::auto(), 4)
+                    .header(20.0, |mut header| {
+                        for title in ["Function", "Calls", "Exclusive", "Inclusive", "Per Call"] {
+                            header.col(|ui| {
+                                ui.strong(title);
+                            });
+                        }
+                    })
+                    .body(|body| {
+                        body.rows(20.0, functions.len(), |mut row| {
+                            let function = &functions[row.index()];
+                            row.col(|ui| {
+                                ui.monospace(&function.name);
+                            });
+                            for value in [
+                                function.calls.to_string(),
+                                function.exclusive.to_string(),
+                                function.inclusive.to_string(),
+                                format!("{:.1}", function.per_call()),
+                            ] {
+                                row.col(|ui| {
+                                    ui.monospace(value);
+                                });
+                            }
+                        });
+                    });
+            });
+    }
+
+    if state.problems_open {
+        egui::Window::new("Problems")
+            .collapsible(false)
+            .show(ctx, |ui| {
+                egui::ScrollArea::vertical()
+                    .max_height(300.0)
+                    .show(ui, |ui| {
+                        for diagnostic in &state.diagnostics {
+                            if ui
+                                .link(egui::RichText::new(diagnostic.to_string()).monospace())
+                                .clicked()
+                            {
+                                *action =
+                                    Some(Action::VMFileSelected(diagnostic.file_name.clone()));
+                            }
+                        }
+                    });
+                if ui.button("OK").clicked() {
+                    *action = Some(Action::ProblemsToggled(false));
+                }
+            });
+    }
+
+    if let Some(error) = state.vm.error() {
+        egui::Window::new("Runtime Error")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.monospace(error.to_string());
+                ui.label("Call stack:");
+                for function_name in error.call_stack.iter().rev() {
+                    ui.monospace(function_name);
+                }
+                if ui.button("Reset").clicked() {
+                    *action = Some(Action::Common(CommonAction::ResetClicked));
+                }
+            });
+    }
+
     if shared_state.breakpoints_open != breakpoints_open {
         assert!(shared_state.breakpoints_open);
         *action = Some(Action::Common(CommonAction::BreakpointsClosed));
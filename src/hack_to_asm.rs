@@ -0,0 +1,187 @@
+use hashbrown::HashSet;
+
+use crate::{
+    hack_to_wasm::jump_targets,
+    hardware::{Instruction, InstructionType, RAM, Word},
+    hardware_parse::{AssemblyInstruction, format_assembly},
+};
+
+const POINTER_NAMES: [&str; 5] = ["SP", "LCL", "ARG", "THIS", "THAT"];
+
+fn label_name(address: usize) -> String {
+    format!("L{address}")
+}
+
+fn uses_m(instruction: &Instruction) -> bool {
+    instruction.dst_has_m() || instruction.op_name().contains('M')
+}
+
+/// Finds the A-instructions whose value is used as a RAM address by a following instruction.
+/// Also reports whether any of the first five registers is dereferenced as a pointer, in which
+/// case the program is assumed to follow the VM's conventions for them.
+fn memory_accesses(instructions: &[Instruction]) -> (HashSet<usize>, bool) {
+    let mut accesses = HashSet::new();
+    let mut has_pointers = false;
+    let mut a_index = None;
+    for (index, instruction) in instructions.iter().enumerate() {
+        if instruction.instruction_type() == InstructionType::A {
+            a_index = Some(index);
+            continue;
+        }
+
+        if let Some(a_index) = a_index
+            && uses_m(instruction)
+        {
+            accesses.insert(a_index);
+            if instruction.dst_has_a()
+                && instruction.op_name().contains('M')
+                && (instructions[a_index].loaded_value() as usize) < POINTER_NAMES.len()
+            {
+                has_pointers = true;
+            }
+        }
+
+        if instruction.dst_has_a() {
+            a_index = None;
+        }
+    }
+
+    (accesses, has_pointers)
+}
+
+/// Turns a ROM back into assembly. Jump targets get synthetic labels, and values used as RAM
+/// addresses are replaced with the predefined symbols. Assembling the result yields the same ROM.
+pub fn disassemble(instructions: &[Instruction]) -> Result<Vec<AssemblyInstruction>, String> {
+    let jump_targets = jump_targets(instructions);
+    let labels: HashSet<usize> = jump_targets
+        .values()
+        .copied()
+        .filter(|&target| target <= instructions.len())
+        .collect();
+    let (memory_accesses, has_pointers) = memory_accesses(instructions);
+
+    let mut assembly_instructions = vec![];
+    for (index, instruction) in instructions.iter().enumerate() {
+        if labels.contains(&index) {
+            assembly_instructions.push(AssemblyInstruction::Label(label_name(index)));
+        }
+
+        if instruction.instruction_type() == InstructionType::C {
+            if instruction.op_name() == "???" {
+                return Err(format!("unknown computation in instruction {index}"));
+            }
+            assembly_instructions.push(AssemblyInstruction::Instruction(*instruction));
+            continue;
+        }
+
+        let value = instruction.loaded_value();
+        let symbol = match value {
+            _ if jump_targets
+                .get(&index)
+                .is_some_and(|target| labels.contains(target)) =>
+            {
+                Some(label_name(value as usize))
+            }
+            RAM::SCREEN => Some("SCREEN".to_owned()),
+            RAM::KBD => Some("KBD".to_owned()),
+            0..=15 if memory_accesses.contains(&index) => {
+                Some(match POINTER_NAMES.get(value as usize) {
+                    Some(name) if has_pointers => (*name).to_owned(),
+                    _ => format!("R{value}"),
+                })
+            }
+            _ => None,
+        };
+        assembly_instructions.push(match symbol {
+            Some(symbol) => AssemblyInstruction::AtIdentifierInstruction(symbol),
+            None => AssemblyInstruction::AtNumberInstruction(value as Word),
+        });
+    }
+
+    if labels.contains(&instructions.len()) {
+        assembly_instructions.push(AssemblyInstruction::Label(label_name(instructions.len())));
+    }
+
+    Ok(assembly_instructions)
+}
+
+pub fn disassemble_to_asm(instructions: &[Instruction]) -> Result<String, String> {
+    Ok(format_assembly(&disassemble(instructions)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware_parse::assemble_hack_file;
+
+    #[test]
+    fn test_round_trip() {
+        let program = "
+            @R0
+            D=M
+            @R1
+            D=D-M
+            @OUTPUT_FIRST
+            D;JGT
+            @R1
+            D=M
+            @OUTPUT_D
+            0;JMP
+            (OUTPUT_FIRST)
+            @R0
+            D=M
+            (OUTPUT_D)
+            @R2
+            M=D
+            @SCREEN
+            D=A
+            (INFINITE_LOOP)
+            @INFINITE_LOOP
+            0;JMP
+            ";
+        let instructions = assemble_hack_file(program).unwrap();
+
+        let asm = disassemble_to_asm(&instructions).unwrap();
+
+        assert_eq!(assemble_hack_file(&asm), Ok(instructions));
+        assert_eq!(
+            asm,
+            "    @R0
+    D=M
+    @R1
+    D=D-M
+    @L10
+    D;JGT
+    @R1
+    D=M
+    @L12
+    0;JMP
+(L10)
+    @R0
+    D=M
+(L12)
+    @R2
+    M=D
+    @SCREEN
+    D=A
+(L16)
+    @L16
+    0;JMP
+"
+        );
+    }
+
+    #[test]
+    fn test_pointers() {
+        let instructions =
+            assemble_hack_file("@SP\nAM=M-1\nD=M\n@3\nD=D+A\n@LCL\nA=M\nM=D").unwrap();
+
+        let asm = disassemble_to_asm(&instructions).unwrap();
+
+        assert_eq!(
+            asm,
+            "    @SP\n    AM=M-1\n    D=M\n    @3\n    D=D+A\n    @LCL\n    A=M\n    M=D\n"
+        );
+        assert_eq!(assemble_hack_file(&asm), Ok(instructions));
+    }
+}
@@ -443,30 +443,35 @@ fn hack_to_dynamic_cases(
     (cases, HashMap::new())
 }
 
+/// Maps every A-instruction whose loaded value is later used as a jump target to that target.
+pub fn jump_targets(instructions: &[crate::hardware::Instruction]) -> HashMap<usize, usize> {
+    let mut targets = HashMap::new();
+    let mut a_index = None;
+    for (index, instruction) in instructions.iter().enumerate() {
+        if instruction.instruction_type() == crate::hardware::InstructionType::A {
+            a_index = Some(index);
+        } else {
+            if instruction.jump_condition() != crate::hardware::JumpCondition::NoJump
+                && let Some(a_index) = a_index
+            {
+                targets.insert(a_index, instructions[a_index].loaded_value() as usize);
+            }
+
+            if instruction.dst_has_a() {
+                a_index = None;
+            }
+        }
+    }
+
+    targets
+}
+
 fn hack_to_static_cases(
     instructions: &[crate::hardware::Instruction],
     loop_id: Id<'static>,
 ) -> (Vec<Vec<Instruction<'static>>>, HashMap<usize, i32>) {
-    let mut targets = HashSet::new();
+    let mut targets: HashSet<_> = jump_targets(instructions).into_values().collect();
     targets.insert(0);
-    {
-        let mut a_value = None;
-        for instruction in instructions.iter() {
-            if instruction.instruction_type() == crate::hardware::InstructionType::A {
-                a_value = Some(instruction.loaded_value() as i32);
-            } else {
-                if instruction.jump_condition() != crate::hardware::JumpCondition::NoJump
-                    && let Some(target) = a_value
-                {
-                    targets.insert(target as usize);
-                }
-
-                if instruction.dst_has_a() {
-                    a_value = None;
-                }
-            }
-        }
-    }
     targets.insert(instructions.len());
 
     let mut sorted: Vec<_> = targets.into_iter().collect();
@@ -1,4 +1,7 @@
-use std::ops::{Index, IndexMut};
+use std::{
+    cell::RefCell,
+    ops::{Index, IndexMut},
+};
 
 #[cfg(not(feature = "bit32"))]
 pub type Word = i16;
@@ -10,7 +13,14 @@ pub type Word = i32;
 #[cfg(feature = "bit32")]
 pub type UWord = u32;
 
-use crate::hardware_parse::assemble_hack_file;
+use crate::{
+    condition::{self, Condition, ConditionalBreakpoint, Variable},
+    coverage::Coverage,
+    hardware_parse::{AssemblyError, assemble_hack_file, parse_binary_file},
+    history::History,
+    snapshot::{Snapshot, to_word},
+    watchpoint::{self, WatchHit, Watchpoint},
+};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Instruction {
@@ -70,6 +80,11 @@ impl Instruction {
         Instruction { raw }
     }
 
+    #[allow(clippy::unnecessary_cast)] // UWord is wider with the bit32 feature
+    pub fn to_legacy(&self) -> u16 {
+        (((self.raw >> (Word::BITS - 1)) << 15) | (self.raw & ((1 << 15) - 1))) as u16
+    }
+
     pub fn create(
         dst_registers: DestinationRegisters,
         calculation_value: UWord,
@@ -264,21 +279,37 @@ impl JumpCondition {
 pub const MEM_SIZE: usize = 32 * 1024;
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug)]
 pub struct RAM {
     pub contents: Box<[Word; MEM_SIZE]>,
+    journal: Option<Vec<(Word, Word)>>,
+    reads: Option<RefCell<Vec<Word>>>,
 }
 
+impl PartialEq for RAM {
+    fn eq(&self, other: &Self) -> bool {
+        self.contents == other.contents
+    }
+}
+
+impl Eq for RAM {}
+
 impl Index<Word> for RAM {
     type Output = Word;
 
     fn index(&self, index: Word) -> &Self::Output {
+        if let Some(reads) = &self.reads {
+            reads.borrow_mut().push(index);
+        }
         &self.contents[index as usize]
     }
 }
 
 impl IndexMut<Word> for RAM {
     fn index_mut(&mut self, index: Word) -> &mut Self::Output {
+        if let Some(journal) = &mut self.journal {
+            journal.push((index, self.contents[index as usize]));
+        }
         &mut self.contents[index as usize]
     }
 }
@@ -289,6 +320,47 @@ impl RAM {
     pub const KBD: Word = Self::SCREEN + Self::SCREEN_ROW_LENGTH * 256;
     pub const SCREEN_ROW_LENGTH: Word = 512 / Word::BITS as Word;
 
+    pub fn zeroed() -> Self {
+        Self {
+            contents: Box::new([0; MEM_SIZE]),
+            journal: None,
+            reads: None,
+        }
+    }
+
+    /// While journaling, the previous value of every word written to through `IndexMut` is
+    /// logged, so that the writes can be undone.
+    pub fn set_journaling(&mut self, journaling: bool) {
+        self.journal = journaling.then(Vec::new);
+    }
+
+    /// Returns the writes logged since the last call, oldest first.
+    pub fn take_journal(&mut self) -> Vec<(Word, Word)> {
+        self.journal
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// While logging reads, the address of every word read through `Index` is logged.
+    pub fn set_logging_reads(&mut self, logging_reads: bool) {
+        self.reads = logging_reads.then(RefCell::default);
+    }
+
+    /// Returns the addresses read since the last call, oldest first.
+    pub fn take_reads(&mut self) -> Vec<Word> {
+        self.reads
+            .as_mut()
+            .map(|reads| std::mem::take(reads.get_mut()))
+            .unwrap_or_default()
+    }
+
+    pub fn undo(&mut self, journal: &[(Word, Word)]) {
+        for &(address, value) in journal.iter().rev() {
+            self.contents[address as usize] = value;
+        }
+    }
+
     pub fn get_pixel(&self, x: Word, y: Word) -> bool {
         (self[Self::SCREEN + y * Self::SCREEN_ROW_LENGTH + x / (Word::BITS as Word)]
             & (1 << (x % (Word::BITS as Word))))
@@ -321,11 +393,77 @@ pub trait AnyHardware {
     fn get_ram_value(&mut self, address: Word) -> Word;
     fn set_ram_value(&mut self, address: Word, value: Word);
     fn pc(&mut self) -> Word;
+    fn set_pc(&mut self, pc: Word);
     fn step(&mut self) -> bool;
     fn load_program(&mut self, program: &[Instruction]);
     fn run_program(&mut self);
     fn run(&mut self, step_count: u64) -> bool;
     fn reset(&mut self);
+    /// Starts recording up to `capacity` steps, or stops recording if it's `None`.
+    fn set_recording(&mut self, capacity: Option<usize>);
+    /// The position in the recorded timeline and its length, if recording.
+    fn timeline(&mut self) -> Option<(usize, usize)>;
+    /// Undoes up to `step_count` recorded steps, returning true if a breakpoint was hit or there
+    /// was nothing left to undo.
+    fn run_back(&mut self, step_count: u64) -> bool;
+    fn ticks(&mut self) -> u64;
+    /// Also starts the recorded history over, since its steps no longer lead to the new state.
+    fn set_registers(&mut self, a: Word, d: Word, pc: Word, ticks: u64);
+    /// Starts counting how many times each ROM address is executed, or stops it.
+    fn set_coverage(&mut self, covering: bool);
+    fn coverage(&mut self) -> Option<&Coverage>;
+    fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> Result<(), String>;
+    /// The watchpoint hit by the last step, if any.
+    fn watch_hit(&mut self) -> Option<WatchHit>;
+    fn add_condition(&mut self, condition: Condition) -> Result<(), String>;
+
+    fn save_snapshot(&mut self) -> Snapshot {
+        let mut snapshot = Snapshot::new("hardware");
+        snapshot.push("a", [self.a() as i64]);
+        snapshot.push("d", [self.d() as i64]);
+        snapshot.push("pc", [self.pc() as i64]);
+        snapshot.push("ticks", [self.ticks() as i64]);
+        let rom = self
+            .rom()
+            .iter()
+            .map(|instruction| instruction.raw as Word)
+            .collect::<Vec<_>>();
+        snapshot.push_words("rom", &rom);
+        snapshot.push_words("ram", &self.copy_ram().contents[..]);
+
+        snapshot
+    }
+
+    /// The ROM isn't loaded from the snapshot, so that the WASM backend doesn't need to be
+    /// recompiled, but it has to match the one the snapshot was taken with.
+    fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        snapshot.expect_kind("hardware")?;
+        let mut rom = vec![0; MEM_SIZE];
+        snapshot.get_words("rom", &mut rom)?;
+        if self
+            .rom()
+            .iter()
+            .zip(rom)
+            .any(|(instruction, raw)| instruction.raw != raw as UWord)
+        {
+            return Err("snapshot was taken from a different program".to_owned());
+        }
+        let mut ram = RAM::zeroed();
+        snapshot.get_words("ram", &mut ram.contents[..])?;
+        let ticks = snapshot.get_one("ticks")?;
+
+        for (address, value) in ram.contents.iter().enumerate() {
+            self.set_ram_value(address as Word, *value);
+        }
+        self.set_registers(
+            to_word(snapshot.get_one("a")?)?,
+            to_word(snapshot.get_one("d")?)?,
+            to_word(snapshot.get_one("pc")?)?,
+            u64::try_from(ticks).map_err(|_| format!("{ticks} ticks is out of range"))?,
+        );
+
+        Ok(())
+    }
 }
 
 impl AnyHardware for Hardware {
@@ -361,6 +499,10 @@ impl AnyHardware for Hardware {
         self.pc
     }
 
+    fn set_pc(&mut self, pc: Word) {
+        self.pc = pc;
+    }
+
     fn get_ram_value(&mut self, address: Word) -> Word {
         self.ram[address]
     }
@@ -370,7 +512,14 @@ impl AnyHardware for Hardware {
     }
 
     fn step(&mut self) -> bool {
+        let (a, d, pc) = (self.a, self.d, self.pc);
         self.ticks += 1;
+        // Only the accesses made by this step are checked against the watchpoints.
+        self.ram.take_journal();
+        self.ram.take_reads();
+        if let Some(coverage) = &mut self.coverage {
+            coverage.hit(pc as UWord as usize);
+        }
         let instruction = *self.current_instruction();
         match instruction.instruction_type() {
             InstructionType::A => {
@@ -387,14 +536,21 @@ impl AnyHardware for Hardware {
                 self.set(instruction, result);
             }
         }
-
-        for breakpoint in &self.breakpoints {
-            if self.get_breakpoint_var(&breakpoint.var) == breakpoint.value {
-                return true;
-            }
+        let writes = self.ram.take_journal();
+        let reads = self.ram.take_reads();
+        self.watch_hit = watchpoint::check(
+            &self.watchpoints,
+            &self.ram,
+            &writes,
+            &reads,
+            pc as UWord as usize,
+        );
+        if let Some(history) = &mut self.history {
+            history.push(HardwareStep { a, d, pc, writes });
         }
+        let condition_met = self.check_conditions();
 
-        false
+        self.watch_hit.is_some() || condition_met || self.is_at_breakpoint()
     }
 
     fn run(&mut self, step_count: u64) -> bool {
@@ -423,15 +579,103 @@ impl AnyHardware for Hardware {
     }
 
     fn reset(&mut self) {
+        let capacity = self.history.as_ref().map(History::capacity);
+        let covering = self.coverage.is_some();
         *self = Hardware {
             rom: self.rom.clone(),
             breakpoints: self.breakpoints.clone(),
+            watchpoints: self.watchpoints.clone(),
+            conditions: self.conditions.clone(),
             length: self.length,
             ..Default::default()
         };
+        self.conditions
+            .iter_mut()
+            .for_each(ConditionalBreakpoint::reset);
+        self.set_recording(capacity);
+        self.set_coverage(covering);
+    }
+
+    fn set_recording(&mut self, capacity: Option<usize>) {
+        self.history = capacity.map(History::new);
+        self.update_logging();
+    }
+
+    fn timeline(&mut self) -> Option<(usize, usize)> {
+        let history = self.history.as_ref()?;
+
+        Some((history.position(), history.len()))
+    }
+
+    fn ticks(&mut self) -> u64 {
+        self.ticks
+    }
+
+    fn set_registers(&mut self, a: Word, d: Word, pc: Word, ticks: u64) {
+        self.a = a;
+        self.d = d;
+        self.pc = pc;
+        self.ticks = ticks;
+        self.set_recording(self.history.as_ref().map(History::capacity));
+    }
+
+    fn set_coverage(&mut self, covering: bool) {
+        self.coverage = covering.then(|| Coverage::new(MEM_SIZE));
+    }
+
+    fn coverage(&mut self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> Result<(), String> {
+        self.watchpoints.push(watchpoint);
+        self.update_logging();
+
+        Ok(())
+    }
+
+    fn watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit
+    }
+
+    fn add_condition(&mut self, condition: Condition) -> Result<(), String> {
+        condition.evaluate(self, None)?;
+        self.conditions.push(ConditionalBreakpoint::new(condition));
+
+        Ok(())
+    }
+
+    fn run_back(&mut self, step_count: u64) -> bool {
+        // Writes made outside of a step, e.g. by the keyboard, aren't undone.
+        self.ram.take_journal();
+        for _ in 0..step_count {
+            let Some(step) = self.history.as_mut().and_then(History::pop) else {
+                return true;
+            };
+            self.ram.undo(&step.writes);
+            self.a = step.a;
+            self.d = step.d;
+            self.pc = step.pc;
+            self.ticks -= 1;
+
+            if self.is_at_breakpoint() {
+                return true;
+            }
+        }
+
+        false
     }
 }
 
+/// What a step overwrote, so that it can be undone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HardwareStep {
+    a: Word,
+    d: Word,
+    pc: Word,
+    writes: Vec<(Word, Word)>,
+}
+
 #[derive(Clone, PartialEq, Eq)]
 pub struct Hardware {
     pub a: Word,
@@ -442,6 +686,11 @@ pub struct Hardware {
     pub breakpoints: Vec<Breakpoint>,
     pub length: usize,
     pub ticks: u64,
+    pub history: Option<History<HardwareStep>>,
+    pub coverage: Option<Coverage>,
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Option<WatchHit>,
+    conditions: Vec<ConditionalBreakpoint>,
 }
 
 impl Default for Hardware {
@@ -451,12 +700,15 @@ impl Default for Hardware {
             d: 0,
             pc: 0,
             rom: Box::new([Instruction { raw: 0 }; 32 * 1024]),
-            ram: RAM {
-                contents: Box::new([0; 32 * 1024]),
-            },
+            ram: RAM::zeroed(),
             breakpoints: vec![],
             length: 32 * 1024,
             ticks: 0,
+            history: None,
+            coverage: None,
+            watchpoints: vec![],
+            watch_hit: None,
+            conditions: vec![],
         }
     }
 }
@@ -534,6 +786,48 @@ impl Hardware {
         }
     }
 
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) {
+        self.watchpoints.remove(index);
+        self.update_logging();
+    }
+
+    /// The RAM logs writes for the history and the watchpoints, and reads only for the latter.
+    fn update_logging(&mut self) {
+        let (writes, reads) = watchpoint::logging_needed(&self.watchpoints);
+        self.ram.set_journaling(self.history.is_some() || writes);
+        self.ram.set_logging_reads(reads);
+    }
+
+    pub fn conditions(&self) -> &[ConditionalBreakpoint] {
+        &self.conditions
+    }
+
+    pub fn remove_condition(&mut self, index: usize) {
+        self.conditions.remove(index);
+    }
+
+    /// Every condition is checked, so that all of their hit counts stay up to date.
+    fn check_conditions(&mut self) -> bool {
+        let mut conditions = std::mem::take(&mut self.conditions);
+        let mut any_met = false;
+        for condition in &mut conditions {
+            any_met |= condition.check(self);
+        }
+        self.conditions = conditions;
+
+        any_met
+    }
+
+    fn is_at_breakpoint(&self) -> bool {
+        self.breakpoints
+            .iter()
+            .any(|breakpoint| self.get_breakpoint_var(&breakpoint.var) == breakpoint.value)
+    }
+
     pub fn get_breakpoint_var(&self, breakpoint_var: &BreakpointVar) -> Word {
         match breakpoint_var {
             BreakpointVar::A => self.a,
@@ -544,30 +838,18 @@ impl Hardware {
         }
     }
 
-    pub fn from_file_contents(contents: &str) -> Self {
+    pub fn from_file_contents(contents: &str) -> Result<Self, AssemblyError> {
         let mut instance = Self::default();
-        let instructions = assemble_hack_file(contents).unwrap().1;
-
-        instance.length = instructions.len();
-        for (i, instruction) in instructions.into_iter().enumerate() {
-            instance.rom[i] = instruction;
-        }
+        instance.load_program(&assemble_hack_file(contents)?);
 
-        instance
+        Ok(instance)
     }
 
-    pub fn from_hack_file_contents(contents: &str) -> Self {
+    pub fn from_hack_file_contents(contents: &str) -> Result<Self, AssemblyError> {
         let mut instance = Self::default();
+        instance.load_program(&parse_binary_file(contents)?);
 
-        for (i, raw) in contents
-            .lines()
-            .map(|l| UWord::from_str_radix(l.trim(), 2).unwrap())
-            .enumerate()
-        {
-            instance.rom[i] = Instruction { raw };
-        }
-
-        instance
+        Ok(instance)
     }
 
     pub fn get_breakpoints(&self) -> &Vec<Breakpoint> {
@@ -583,6 +865,25 @@ impl Hardware {
     }
 }
 
+impl condition::Context for Hardware {
+    fn register(&self, variable: Variable) -> Option<Word> {
+        match variable {
+            Variable::A => Some(self.a),
+            Variable::D => Some(self.d),
+            Variable::PC => Some(self.pc),
+            _ => None,
+        }
+    }
+
+    fn ram(&self) -> &RAM {
+        &self.ram
+    }
+
+    fn function_name(&self) -> Option<&str> {
+        None
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Copy, Debug)]
 pub enum BreakpointVar {
     A,
@@ -613,6 +914,15 @@ pub struct Breakpoint {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::condition_parse::parse_condition;
+    use crate::wasm_hardware::WasmHardware;
+
+    #[test]
+    fn test_legacy_round_trip() {
+        for legacy in [0, 12, 59344, 60039] {
+            assert_eq!(Instruction::from_legacy(legacy).to_legacy(), legacy);
+        }
+    }
 
     #[test]
     fn test_increment_hardware() {
@@ -700,17 +1010,24 @@ mod tests {
         test_integration(&mut hardware);
     }
 
-    fn test_integration(emulator: &mut impl AnyHardware) {
-        let program = [
+    /// Multiplies RAM[13] by RAM[14] into RAM[15] and then loops at 13.
+    fn multiply_program() -> [Instruction; 14] {
+        [
             15, 60040, 14, 64528, 15, 58114, 13, 64528, 15, 61576, 14, 64648, 2, 60039,
-        ];
-        emulator.load_program(
-            &program
-                .iter()
-                .copied()
-                .map(Instruction::from_legacy)
-                .collect::<Vec<_>>(),
-        );
+        ]
+        .map(Instruction::from_legacy)
+    }
+
+    fn multiply_hardware(a: Word, b: Word) -> Hardware {
+        let mut hardware = Hardware::default();
+        hardware.load_program(&multiply_program());
+        hardware.set_ram_value(13, a);
+        hardware.set_ram_value(14, b);
+        hardware
+    }
+
+    fn test_integration(emulator: &mut impl AnyHardware) {
+        emulator.load_program(&multiply_program());
 
         emulator.set_ram_value(13, 34);
         emulator.set_ram_value(14, 12);
@@ -738,4 +1055,131 @@ mod tests {
 
         assert_eq!(emulator.pc(), 0);
     }
+
+    #[test]
+    fn test_run_back_hardware() {
+        let mut hardware = multiply_hardware(3, 2);
+        let start = hardware.clone();
+        hardware.set_recording(Some(100));
+
+        hardware.run(30);
+        assert_eq!(hardware.get_ram_value(15), 6);
+        assert_eq!(hardware.timeline(), Some((30, 30)));
+
+        hardware.breakpoints.push(Breakpoint {
+            var: BreakpointVar::RAM(15),
+            value: 0,
+        });
+        assert!(hardware.run_back(30));
+        assert_eq!(hardware.timeline(), Some((9, 30)));
+        assert_eq!(hardware.get_ram_value(15), 0);
+
+        hardware.breakpoints.clear();
+        assert!(hardware.run_back(30));
+        assert_eq!(hardware.timeline(), Some((0, 30)));
+        assert_eq!((hardware.a, hardware.d, hardware.pc), (0, 0, 0));
+        assert_eq!((&hardware.ram, hardware.ticks), (&start.ram, start.ticks));
+    }
+
+    #[test]
+    fn test_coverage_hardware() {
+        let program = multiply_program();
+        let mut hardware = multiply_hardware(3, 0);
+        hardware.set_coverage(true);
+        hardware.run(30);
+
+        let coverage = hardware.coverage().unwrap();
+        assert_eq!(
+            (coverage.hits(5), coverage.hits(6), coverage.hits(15)),
+            (1, 0, 1)
+        );
+        let listing = coverage.rom_listing(&program, program.len());
+        assert!(listing.starts_with("         1 |     0 | @15\n"));
+        assert!(listing.ends_with("     ##### |    13 | 0;JMP\n"));
+
+        hardware.set_ram_value(14, 0);
+        hardware.reset();
+        assert_eq!(hardware.coverage().unwrap().hits(0), 0);
+    }
+
+    #[test]
+    fn test_watchpoints_hardware() {
+        let mut hardware = multiply_hardware(3, 2);
+
+        hardware
+            .add_watchpoint(Watchpoint::parse("15:change").unwrap())
+            .unwrap();
+        assert!(hardware.run(100));
+        let watch_hit = hardware.watch_hit().unwrap();
+        assert_eq!(watch_hit.to_string(), "change of RAM[15]: 0 -> 3");
+        assert_eq!(watch_hit.location, 9);
+
+        hardware.remove_watchpoint(0);
+        hardware
+            .add_watchpoint(Watchpoint::parse("13..14:read").unwrap())
+            .unwrap();
+        assert!(hardware.run(100));
+        let watch_hit = hardware.watch_hit().unwrap();
+        assert_eq!(watch_hit.to_string(), "read of RAM[13] = 3");
+        assert_eq!((watch_hit.location, hardware.pc), (7, 8));
+    }
+
+    #[test]
+    fn test_conditions_hardware() {
+        let mut hardware = multiply_hardware(3, 2);
+
+        assert!(
+            hardware
+                .add_condition(parse_condition("function == \"Main.main\"").unwrap())
+                .is_err()
+        );
+        hardware
+            .add_condition(parse_condition("PC == 9 && hits == 2").unwrap())
+            .unwrap();
+        assert!(hardware.run(100));
+        assert_eq!((hardware.pc, hardware.get_ram_value(15)), (9, 3));
+        assert_eq!(hardware.conditions()[0].hits, 2);
+
+        hardware.reset();
+        assert_eq!(hardware.conditions()[0].hits, 0);
+    }
+
+    #[test]
+    fn test_snapshot_hardware() {
+        let program = multiply_program();
+        let mut hardware = multiply_hardware(3, 2);
+        hardware.run(10);
+
+        let snapshot = Snapshot::parse(&hardware.save_snapshot().to_string()).unwrap();
+        let mut restored = Hardware::default();
+        restored.load_program(&program);
+        restored.restore_snapshot(&snapshot).unwrap();
+        assert_eq!(restored, hardware);
+
+        let mut other = Hardware::default();
+        other.load_program(&program[1..]);
+        assert!(other.restore_snapshot(&snapshot).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_wasm_hardware() {
+        let mut wasm_hardware = WasmHardware::from_instructions(&multiply_program());
+        wasm_hardware.set_ram_value(13, 3);
+        wasm_hardware.set_ram_value(14, 2);
+        wasm_hardware.run(10);
+        let ticks = wasm_hardware.ticks();
+        assert!(ticks >= 10);
+
+        let mut hardware = multiply_hardware(0, 0);
+        hardware
+            .restore_snapshot(&wasm_hardware.save_snapshot())
+            .unwrap();
+        assert_eq!(hardware.ticks, ticks);
+
+        hardware.run(5);
+        wasm_hardware
+            .restore_snapshot(&hardware.save_snapshot())
+            .unwrap();
+        assert_eq!(wasm_hardware.ticks(), ticks + 5);
+    }
 }
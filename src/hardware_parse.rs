@@ -5,11 +5,11 @@ use crate::{
 
 use hashbrown::HashMap;
 use nom::{
-    Parser,
+    Offset, Parser,
     branch::alt,
     bytes::complete::{is_not, tag},
     character::complete::{alphanumeric1, char, space0},
-    combinator::{cut, map, recognize, success, value},
+    combinator::{all_consuming, cut, map, recognize, success, value, verify},
     error::{ParseError, VerboseError},
     multi::{many1, many1_count},
     sequence::{delimited, preceded, terminated, tuple},
@@ -24,7 +24,16 @@ pub enum AssemblyInstruction {
 }
 
 fn parse_identifier(input: &str) -> IResult<&str, &str> {
-    recognize(many1_count(alt((alphanumeric1, tag("_"), tag(".")))))(input)
+    verify(
+        recognize(many1_count(alt((
+            alphanumeric1,
+            tag("_"),
+            tag("."),
+            tag("$"),
+            tag(":"),
+        )))),
+        |identifier: &str| !identifier.starts_with(|c: char| c.is_ascii_digit()),
+    )(input)
 }
 
 fn create_c_instruction(args: (DestinationRegisters, UWord, JumpCondition)) -> AssemblyInstruction {
@@ -53,6 +62,28 @@ fn parse_at_identifier_instruction(input: &str) -> IResult<&str, AssemblyInstruc
     ))
 }
 
+impl std::fmt::Display for AssemblyInstruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssemblyInstruction::Instruction(instruction) => write!(f, "{instruction}"),
+            AssemblyInstruction::Label(label) => write!(f, "({label})"),
+            AssemblyInstruction::AtIdentifierInstruction(identifier) => write!(f, "@{identifier}"),
+            AssemblyInstruction::AtNumberInstruction(number) => write!(f, "@{number}"),
+        }
+    }
+}
+
+/// Formats instructions as an .asm file, indenting everything except labels.
+pub fn format_assembly(assembly_instructions: &[AssemblyInstruction]) -> String {
+    assembly_instructions
+        .iter()
+        .map(|instruction| match instruction {
+            AssemblyInstruction::Label(_) => format!("{instruction}\n"),
+            _ => format!("    {instruction}\n"),
+        })
+        .collect()
+}
+
 fn parse_label(input: &str) -> IResult<&str, AssemblyInstruction> {
     let (remainder, identifier) = parse_identifier(input)?;
 
@@ -237,11 +268,133 @@ pub fn parse_instructions(input: &str) -> IResult<&str, Vec<AssemblyInstruction>
     non_comment_lines(instruction)(input)
 }
 
-pub fn assemble_hack_file(input: &str) -> IResult<&str, Vec<Instruction>> {
-    map(parse_instructions, |v| assemble(&v))(input)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssemblyError {
+    pub line: usize,
+    pub column: usize,
+    pub text: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}: \"{}\"",
+            self.line, self.column, self.message, self.text
+        )
+    }
+}
+
+/// Works out which part of an unparsable line is wrong, returning it with an explanation.
+fn diagnose(text: &str) -> (&str, String) {
+    if let Some(value) = text.strip_prefix('@') {
+        return if value.parse::<i64>().is_ok() {
+            (value, format!("A-constant out of range 0..={}", Word::MAX))
+        } else {
+            (value, "invalid symbol".to_owned())
+        };
+    }
+
+    if text.starts_with('(') {
+        return (text, "invalid label".to_owned());
+    }
+
+    let (destination, rest) = match text.split_once('=') {
+        Some((destination, rest)) => (Some(destination), rest),
+        None => (None, text),
+    };
+    if let Some(destination) = destination
+        && all_consuming(parse_destination_registers)(&text[..=destination.len()]).is_err()
+    {
+        return (destination.trim(), "bad destination".to_owned());
+    }
+
+    let (computation, jump) = match rest.split_once(';') {
+        Some((computation, jump)) => (computation, Some(jump)),
+        None => (rest, None),
+    };
+    if all_consuming(parse_calculation)(computation).is_err() {
+        return (computation.trim(), "unknown comp mnemonic".to_owned());
+    }
+
+    if let Some(jump) = jump {
+        return (jump.trim(), "unknown jump".to_owned());
+    }
+
+    (text, "invalid instruction".to_owned())
+}
+
+fn parse_line(line: usize, code: &str) -> Result<Option<AssemblyInstruction>, AssemblyError> {
+    let text = code.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+
+    match all_consuming(instruction)(text) {
+        Ok((_, instruction)) => Ok(Some(instruction)),
+        Err(_) => {
+            let (offending_text, message) = diagnose(text);
+            Err(AssemblyError {
+                line,
+                column: code.offset(offending_text) + 1,
+                text: offending_text.to_owned(),
+                message,
+            })
+        }
+    }
 }
 
-pub fn assemble(assembly_instructions: &[AssemblyInstruction]) -> Vec<Instruction> {
+pub fn assemble_hack_file(input: &str) -> Result<Vec<Instruction>, AssemblyError> {
+    let mut assembly_instructions = vec![];
+    let mut positions = vec![];
+    for (line_index, line) in input.lines().enumerate() {
+        let code = line.split("//").next().unwrap();
+        if let Some(instruction) = parse_line(line_index + 1, code)? {
+            let column = code.len() - code.trim_start().len() + 1;
+            positions.push((line_index + 1, column));
+            assembly_instructions.push(instruction);
+        }
+    }
+
+    let symbol_table = symbol_table(&assembly_instructions).map_err(|index| {
+        let (line, column) = positions[index];
+        AssemblyError {
+            line,
+            column,
+            text: assembly_instructions[index].to_string(),
+            message: "duplicate label".to_owned(),
+        }
+    })?;
+
+    Ok(resolve(&assembly_instructions, symbol_table))
+}
+
+/// Parses the binary text format, one instruction per line.
+pub fn parse_binary_file(input: &str) -> Result<Vec<Instruction>, AssemblyError> {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(line_index, line)| {
+            let text = line.trim();
+            UWord::from_str_radix(text, 2)
+                .map(Instruction::new)
+                .map_err(|e| AssemblyError {
+                    line: line_index + 1,
+                    column: line.offset(text) + 1,
+                    text: text.to_owned(),
+                    message: format!("invalid binary instruction ({e})"),
+                })
+        })
+        .collect()
+}
+
+/// Builds the predefined symbols together with the program's labels. A label which is defined
+/// twice, or shadows a predefined symbol, is reported by the index of its instruction.
+fn symbol_table(
+    assembly_instructions: &[AssemblyInstruction],
+) -> Result<HashMap<&str, Word>, usize> {
     let mut at_identifier_map: HashMap<&str, Word> = HashMap::from([
         ("R0", 0),
         ("R1", 1),
@@ -269,18 +422,25 @@ pub fn assemble(assembly_instructions: &[AssemblyInstruction]) -> Vec<Instructio
     ]);
 
     let mut index = 0;
-    for assembly_instruction in assembly_instructions.iter() {
+    for (i, assembly_instruction) in assembly_instructions.iter().enumerate() {
         let AssemblyInstruction::Label(label) = assembly_instruction else {
             index += 1;
             continue;
         };
         if at_identifier_map.contains_key(label.as_str()) {
-            panic!("already encountered label {label}");
+            return Err(i);
         }
 
         at_identifier_map.insert(label.as_str(), index);
     }
 
+    Ok(at_identifier_map)
+}
+
+fn resolve<'a>(
+    assembly_instructions: &'a [AssemblyInstruction],
+    mut at_identifier_map: HashMap<&'a str, Word>,
+) -> Vec<Instruction> {
     let mut rom: Vec<Instruction> = vec![];
 
     let mut static_var_index = 16;
@@ -306,6 +466,13 @@ pub fn assemble(assembly_instructions: &[AssemblyInstruction]) -> Vec<Instructio
     rom
 }
 
+pub fn assemble(assembly_instructions: &[AssemblyInstruction]) -> Result<Vec<Instruction>, String> {
+    let symbol_table = symbol_table(assembly_instructions)
+        .map_err(|index| format!("duplicate label {}", assembly_instructions[index]))?;
+
+    Ok(resolve(assembly_instructions, symbol_table))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -352,7 +519,7 @@ mod tests {
         .map(|raw| Instruction::from_legacy(*raw))
         .collect();
 
-        assert_eq!(assemble_hack_file(program), Ok(("", expected_program)))
+        assert_eq!(assemble_hack_file(program), Ok(expected_program))
     }
 
     #[test]
@@ -432,4 +599,61 @@ mod tests {
         assert_eq!(compare_no_whitespace("a b   c", "abd"), Error);
         assert_eq!(compare_no_whitespace("  ", "def"), Incomplete);
     }
+
+    fn assembly_error(line: usize, column: usize, text: &str, message: &str) -> AssemblyError {
+        AssemblyError {
+            line,
+            column,
+            text: text.to_owned(),
+            message: message.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_assembly_errors() {
+        assert_eq!(
+            assemble_hack_file("@1\n  D=D*A // multiply"),
+            Err(assembly_error(2, 5, "D*A", "unknown comp mnemonic"))
+        );
+        assert_eq!(
+            assemble_hack_file("@1\n\nMX=D;JMP"),
+            Err(assembly_error(3, 1, "MX", "bad destination"))
+        );
+        assert_eq!(
+            assemble_hack_file("0;JMPS"),
+            Err(assembly_error(1, 3, "JMPS", "unknown jump"))
+        );
+        assert_eq!(
+            assemble_hack_file("(LOOP)\n@LOOP\n 0;JMP\n (LOOP)"),
+            Err(assembly_error(4, 2, "(LOOP)", "duplicate label"))
+        );
+        assert_eq!(
+            assemble_hack_file("(R1)"),
+            Err(assembly_error(1, 1, "(R1)", "duplicate label"))
+        );
+        assert!(
+            assemble_hack_file("@40000")
+                .unwrap_err()
+                .message
+                .starts_with("A-constant out of range")
+        );
+        assert_eq!(assemble_hack_file("@-1").unwrap_err().text, "-1".to_owned());
+        assert_eq!(
+            assemble_hack_file("@1abc").unwrap_err().message,
+            "invalid symbol"
+        );
+    }
+
+    #[test]
+    fn test_binary_file_errors() {
+        assert_eq!(
+            parse_binary_file("0000000000000001\n  0000000000000012"),
+            Err(assembly_error(
+                2,
+                3,
+                "0000000000000012",
+                "invalid binary instruction (invalid digit found in string)"
+            ))
+        );
+    }
 }
@@ -0,0 +1,1167 @@
+use std::{
+    fs,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+
+use hashbrown::HashMap;
+
+use crate::{
+    hardware::{AnyHardware, Hardware, Instruction, InstructionType, Word},
+    hdl_parse::parse_chip,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChipDefinition {
+    pub name: String,
+    pub inputs: Vec<PinDeclaration>,
+    pub outputs: Vec<PinDeclaration>,
+    pub body: ChipBody,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PinDeclaration {
+    pub name: String,
+    pub width: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChipBody {
+    Parts(Vec<Part>),
+    Builtin { name: String, clocked: Vec<String> },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Part {
+    pub name: String,
+    pub connections: Vec<Connection>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Connection {
+    pub pin: PinReference,
+    pub signal: Signal,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PinReference {
+    pub name: String,
+    /// Inclusive bit range, `None` for the whole bus.
+    pub range: Option<RangeInclusive<usize>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Signal {
+    True,
+    False,
+    Pin(PinReference),
+}
+
+impl ChipDefinition {
+    fn pin(&self, name: &str) -> Option<(&PinDeclaration, bool)> {
+        self.inputs
+            .iter()
+            .find(|pin| pin.name == name)
+            .map(|pin| (pin, true))
+            .or_else(|| {
+                self.outputs
+                    .iter()
+                    .find(|pin| pin.name == name)
+                    .map(|pin| (pin, false))
+            })
+    }
+}
+
+fn pins(pins: &[(&str, usize)]) -> Vec<PinDeclaration> {
+    pins.iter()
+        .map(|&(name, width)| PinDeclaration {
+            name: name.to_owned(),
+            width,
+        })
+        .collect()
+}
+
+fn builtin_definition(name: &str) -> Option<ChipDefinition> {
+    let (inputs, outputs, clocked) = match name {
+        "Nand" => (pins(&[("a", 1), ("b", 1)]), pins(&[("out", 1)]), vec![]),
+        "DFF" => (pins(&[("in", 1)]), pins(&[("out", 1)]), vec!["in"]),
+        "ROM32K" => (pins(&[("address", 15)]), pins(&[("out", 16)]), vec![]),
+        "Keyboard" => (vec![], pins(&[("out", 16)]), vec![]),
+        _ => {
+            let address_width = memory_address_width(name)?;
+            (
+                pins(&[("in", 16), ("load", 1), ("address", address_width)]),
+                pins(&[("out", 16)]),
+                vec!["in", "load"],
+            )
+        }
+    };
+
+    Some(ChipDefinition {
+        name: name.to_owned(),
+        inputs,
+        outputs,
+        body: ChipBody::Builtin {
+            name: name.to_owned(),
+            clocked: clocked.into_iter().map(str::to_owned).collect(),
+        },
+    })
+}
+
+fn memory_address_width(name: &str) -> Option<usize> {
+    match name {
+        "RAM8" => Some(3),
+        "RAM64" => Some(6),
+        "RAM512" => Some(9),
+        "RAM4K" => Some(12),
+        "RAM16K" => Some(14),
+        "Screen" => Some(13),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ChipLibrary {
+    definitions: HashMap<String, ChipDefinition>,
+}
+
+impl ChipLibrary {
+    pub fn from_paths(paths: &[PathBuf]) -> Result<Self, String> {
+        let files = paths
+            .iter()
+            .map(|path| {
+                Ok((
+                    path.file_name().unwrap().to_str().unwrap().to_owned(),
+                    fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?,
+                ))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Self::from_file_contents(files)
+    }
+
+    pub fn from_dir(dir: &Path) -> Result<Self, String> {
+        let paths = fs::read_dir(dir)
+            .map_err(|e| format!("{}: {e}", dir.display()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|e| e == "hdl"))
+            .collect::<Vec<_>>();
+
+        Self::from_paths(&paths)
+    }
+
+    pub fn from_file_contents(file_contents: Vec<(String, String)>) -> Result<Self, String> {
+        let mut library = Self::default();
+        for (file_name, contents) in file_contents {
+            let definition = parse_chip(&contents).map_err(|e| format!("{file_name}: {e}"))?;
+            let name = file_name
+                .rsplit_once('.')
+                .map_or(file_name.as_str(), |(name, _)| name);
+            if definition.name != name {
+                return Err(format!(
+                    "{file_name}: chip {} must be declared in {}.hdl",
+                    definition.name, definition.name
+                ));
+            }
+            library.add(definition);
+        }
+
+        Ok(library)
+    }
+
+    pub fn add(&mut self, definition: ChipDefinition) {
+        self.definitions.insert(definition.name.clone(), definition);
+    }
+
+    /// Chips without an HDL file, or whose HDL file declares them `BUILTIN`, use the builtin
+    /// implementation.
+    pub fn definition(&self, name: &str) -> Result<ChipDefinition, String> {
+        match self.definitions.get(name) {
+            Some(ChipDefinition {
+                body: ChipBody::Builtin { name, .. },
+                ..
+            }) => builtin_definition(name).ok_or_else(|| format!("unknown builtin chip {name}")),
+            Some(definition) => Ok(definition.clone()),
+            None => builtin_definition(name).ok_or_else(|| format!("unknown chip {name}")),
+        }
+    }
+
+    pub fn build(&self, name: &str) -> Result<Chip, String> {
+        let definition = self.definition(name)?;
+        let mut builder = Builder {
+            library: self,
+            parent: vec![FALSE, TRUE],
+            primitives: vec![],
+            depth: 0,
+        };
+        let top_pins: HashMap<String, Vec<usize>> = definition
+            .inputs
+            .iter()
+            .chain(&definition.outputs)
+            .map(|pin| (pin.name.clone(), builder.new_wires(pin.width)))
+            .collect();
+        builder.instantiate(&definition, &top_pins)?;
+
+        builder.finish(&definition, &top_pins)
+    }
+}
+
+const FALSE: usize = 0;
+const TRUE: usize = 1;
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Debug)]
+enum Primitive {
+    Nand {
+        a: usize,
+        b: usize,
+        out: usize,
+    },
+    Dff {
+        input: usize,
+        out: usize,
+    },
+    Memory {
+        name: String,
+        address: Vec<usize>,
+        input: Vec<usize>,
+        load: usize,
+        out: Vec<usize>,
+    },
+    Keyboard {
+        out: Vec<usize>,
+    },
+}
+
+impl Primitive {
+    fn wires_mut(&mut self) -> Vec<&mut usize> {
+        match self {
+            Primitive::Nand { a, b, out } => vec![a, b, out],
+            Primitive::Dff { input, out } => vec![input, out],
+            Primitive::Memory {
+                address,
+                input,
+                load,
+                out,
+                ..
+            } => address
+                .iter_mut()
+                .chain(input.iter_mut())
+                .chain(std::iter::once(load))
+                .chain(out.iter_mut())
+                .collect(),
+            Primitive::Keyboard { out } => out.iter_mut().collect(),
+        }
+    }
+
+    fn outputs(&self) -> Vec<usize> {
+        match self {
+            Primitive::Nand { out, .. } | Primitive::Dff { out, .. } => vec![*out],
+            Primitive::Memory { out, .. } | Primitive::Keyboard { out } => out.clone(),
+        }
+    }
+
+    /// The wires the primitive's outputs depend on within the same clock phase.
+    fn combinational_inputs(&self) -> Vec<usize> {
+        match self {
+            Primitive::Nand { a, b, .. } => vec![*a, *b],
+            Primitive::Dff { .. } | Primitive::Keyboard { .. } => vec![],
+            Primitive::Memory { address, .. } => address.clone(),
+        }
+    }
+}
+
+struct Builder<'a> {
+    library: &'a ChipLibrary,
+    parent: Vec<usize>,
+    primitives: Vec<Primitive>,
+    depth: usize,
+}
+
+fn select(wires: &[usize], range: &Option<RangeInclusive<usize>>) -> Result<Vec<usize>, String> {
+    match range {
+        None => Ok(wires.to_vec()),
+        Some(range) => wires
+            .get(range.clone())
+            .map(<[usize]>::to_vec)
+            .ok_or_else(|| {
+                format!(
+                    "sub-bus [{}..{}] out of range for width {}",
+                    range.start(),
+                    range.end(),
+                    wires.len()
+                )
+            }),
+    }
+}
+
+impl Builder<'_> {
+    fn new_wires(&mut self, count: usize) -> Vec<usize> {
+        let start = self.parent.len();
+        self.parent.extend(start..start + count);
+
+        (start..start + count).collect()
+    }
+
+    fn find(&mut self, wire: usize) -> usize {
+        let mut root = wire;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        let mut current = wire;
+        while self.parent[current] != root {
+            let next = self.parent[current];
+            self.parent[current] = root;
+            current = next;
+        }
+
+        root
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        // Keep the constants as roots so they are easy to recognize.
+        if a < b {
+            self.parent[b] = a;
+        } else {
+            self.parent[a] = b;
+        }
+    }
+
+    fn instantiate(
+        &mut self,
+        definition: &ChipDefinition,
+        pins: &HashMap<String, Vec<usize>>,
+    ) -> Result<(), String> {
+        let parts = match &definition.body {
+            ChipBody::Builtin { name, .. } => {
+                self.instantiate_builtin(name, pins);
+                return Ok(());
+            }
+            ChipBody::Parts(parts) => parts,
+        };
+        if self.depth > MAX_DEPTH {
+            return Err(format!("{}: chip contains itself", definition.name));
+        }
+        self.depth += 1;
+
+        let part_definitions = parts
+            .iter()
+            .map(|part| self.library.definition(&part.name))
+            .collect::<Result<Vec<_>, String>>()
+            .map_err(|e| format!("{}: {e}", definition.name))?;
+
+        let mut signals = pins.clone();
+        for (part, part_definition) in parts.iter().zip(&part_definitions) {
+            for connection in &part.connections {
+                let Signal::Pin(signal) = &connection.signal else {
+                    continue;
+                };
+                let Some((pin, false)) = part_definition.pin(&connection.pin.name) else {
+                    continue;
+                };
+                if definition
+                    .inputs
+                    .iter()
+                    .any(|input| input.name == signal.name)
+                {
+                    return Err(format!(
+                        "{}: {}: cannot drive input pin {}",
+                        definition.name, part.name, signal.name
+                    ));
+                }
+                if signals.contains_key(&signal.name) {
+                    continue;
+                }
+                if signal.range.is_some() {
+                    return Err(format!(
+                        "{}: {}: internal pin {} cannot be subscripted",
+                        definition.name, part.name, signal.name
+                    ));
+                }
+                let width = select(&vec![0; pin.width], &connection.pin.range)
+                    .map_err(|e| format!("{}: {}: {e}", definition.name, part.name))?
+                    .len();
+                let wires = self.new_wires(width);
+                signals.insert(signal.name.clone(), wires);
+            }
+        }
+
+        for (part, part_definition) in parts.iter().zip(&part_definitions) {
+            self.connect_part(part, part_definition, &signals)
+                .map_err(|e| format!("{}: {}: {e}", definition.name, part.name))?;
+        }
+
+        self.depth -= 1;
+
+        Ok(())
+    }
+
+    fn connect_part(
+        &mut self,
+        part: &Part,
+        part_definition: &ChipDefinition,
+        signals: &HashMap<String, Vec<usize>>,
+    ) -> Result<(), String> {
+        let part_pins: HashMap<String, Vec<usize>> = part_definition
+            .inputs
+            .iter()
+            .chain(&part_definition.outputs)
+            .map(|pin| (pin.name.clone(), self.new_wires(pin.width)))
+            .collect();
+
+        for Connection { pin, signal } in &part.connections {
+            let (_, is_input) = part_definition
+                .pin(&pin.name)
+                .ok_or_else(|| format!("unknown pin {}", pin.name))?;
+            let pin_wires = select(&part_pins[&pin.name], &pin.range)?;
+            let signal_wires = match signal {
+                Signal::True | Signal::False if !is_input => {
+                    return Err(format!("output pin {} connected to a constant", pin.name));
+                }
+                Signal::True => vec![TRUE; pin_wires.len()],
+                Signal::False => vec![FALSE; pin_wires.len()],
+                Signal::Pin(signal) => select(
+                    signals
+                        .get(&signal.name)
+                        .ok_or_else(|| format!("undefined pin {}", signal.name))?,
+                    &signal.range,
+                )?,
+            };
+            if pin_wires.len() != signal_wires.len() {
+                return Err(format!(
+                    "width mismatch between {} ({}) and {} ({})",
+                    pin.name,
+                    pin_wires.len(),
+                    match signal {
+                        Signal::Pin(signal) => signal.name.as_str(),
+                        _ => "constant",
+                    },
+                    signal_wires.len()
+                ));
+            }
+            for (a, b) in pin_wires.into_iter().zip(signal_wires) {
+                self.union(a, b);
+            }
+        }
+
+        self.instantiate(part_definition, &part_pins)
+    }
+
+    fn instantiate_builtin(&mut self, name: &str, pins: &HashMap<String, Vec<usize>>) {
+        let primitive = match name {
+            "Nand" => Primitive::Nand {
+                a: pins["a"][0],
+                b: pins["b"][0],
+                out: pins["out"][0],
+            },
+            "DFF" => Primitive::Dff {
+                input: pins["in"][0],
+                out: pins["out"][0],
+            },
+            "Keyboard" => Primitive::Keyboard {
+                out: pins["out"].clone(),
+            },
+            "ROM32K" => Primitive::Memory {
+                name: name.to_owned(),
+                address: pins["address"].clone(),
+                input: vec![],
+                load: FALSE,
+                out: pins["out"].clone(),
+            },
+            _ => Primitive::Memory {
+                name: name.to_owned(),
+                address: pins["address"].clone(),
+                input: pins["in"].clone(),
+                load: pins["load"][0],
+                out: pins["out"].clone(),
+            },
+        };
+        self.primitives.push(primitive);
+    }
+
+    fn finish(
+        mut self,
+        definition: &ChipDefinition,
+        top_pins: &HashMap<String, Vec<usize>>,
+    ) -> Result<Chip, String> {
+        let mut roots = HashMap::new();
+        for wire in 0..self.parent.len() {
+            let root = self.find(wire);
+            let next_index = roots.len();
+            roots.entry(root).or_insert(next_index);
+        }
+        let resolve = |wire: usize| roots[&self.parent[wire]];
+        let mut primitives = std::mem::take(&mut self.primitives);
+        for primitive in &mut primitives {
+            for wire in primitive.wires_mut() {
+                *wire = resolve(*wire);
+            }
+        }
+        let resolve_pins = |pins: &[PinDeclaration]| {
+            pins.iter()
+                .map(|pin| {
+                    let wires = top_pins[&pin.name].iter().map(|&w| resolve(w)).collect();
+                    (pin.name.clone(), wires)
+                })
+                .collect::<Vec<(String, Vec<usize>)>>()
+        };
+        let inputs = resolve_pins(&definition.inputs);
+        let outputs = resolve_pins(&definition.outputs);
+        let wire_count = roots.len();
+
+        let mut driver = vec![None; wire_count];
+        let inputs_wires = inputs.iter().flat_map(|(_, wires)| wires);
+        for &wire in inputs_wires.chain(&[FALSE, TRUE]) {
+            driver[wire] = Some(usize::MAX);
+        }
+        for (i, primitive) in primitives.iter().enumerate() {
+            for wire in primitive.outputs() {
+                if driver[wire].is_some() {
+                    return Err(format!("{}: pin driven more than once", definition.name));
+                }
+                driver[wire] = Some(i);
+            }
+        }
+
+        // Order the combinational primitives so that each one is evaluated after its inputs.
+        let is_combinational = |p: &Primitive| !matches!(p, Primitive::Dff { .. });
+        let mut dependents = vec![vec![]; primitives.len()];
+        let mut pending_inputs = vec![0; primitives.len()];
+        for (i, primitive) in primitives.iter().enumerate() {
+            for wire in primitive.combinational_inputs() {
+                if let Some(Some(j)) = driver.get(wire)
+                    && *j != usize::MAX
+                    && is_combinational(&primitives[*j])
+                {
+                    dependents[*j].push(i);
+                    pending_inputs[i] += 1;
+                }
+            }
+        }
+        let mut order: Vec<usize> = (0..primitives.len())
+            .filter(|&i| is_combinational(&primitives[i]) && pending_inputs[i] == 0)
+            .collect();
+        let mut next = 0;
+        while next < order.len() {
+            for &dependent in &dependents[order[next]] {
+                pending_inputs[dependent] -= 1;
+                if pending_inputs[dependent] == 0 {
+                    order.push(dependent);
+                }
+            }
+            next += 1;
+        }
+        if order.len() != primitives.iter().filter(|p| is_combinational(p)).count() {
+            return Err(format!("{}: combinational loop", definition.name));
+        }
+
+        let mut gates = vec![];
+        let mut memories = vec![];
+        let mut dffs = vec![];
+        for (i, primitive) in primitives.iter().enumerate() {
+            match primitive {
+                Primitive::Dff { input, out } => dffs.push(Dff {
+                    input: *input,
+                    out: *out,
+                    state: false,
+                    next: false,
+                }),
+                Primitive::Memory {
+                    name,
+                    address,
+                    input,
+                    load,
+                    out,
+                } => memories.push((
+                    i,
+                    Memory {
+                        name: name.clone(),
+                        address: address.clone(),
+                        input: input.clone(),
+                        load: *load,
+                        out: out.clone(),
+                        contents: vec![0; 1 << address.len()],
+                        pending_write: None,
+                    },
+                )),
+                _ => {}
+            }
+        }
+        for i in order {
+            gates.push(match &primitives[i] {
+                Primitive::Nand { a, b, out } => Gate::Nand {
+                    a: *a,
+                    b: *b,
+                    out: *out,
+                },
+                Primitive::Memory { .. } => {
+                    Gate::MemoryRead(memories.iter().position(|(j, _)| *j == i).unwrap())
+                }
+                Primitive::Keyboard { out } => Gate::Keyboard { out: out.clone() },
+                Primitive::Dff { .. } => unreachable!(),
+            });
+        }
+
+        let mut wires = vec![false; wire_count];
+        wires[TRUE] = true;
+        let mut chip = Chip {
+            name: definition.name.clone(),
+            inputs,
+            outputs,
+            wires,
+            gates,
+            dffs,
+            memories: memories.into_iter().map(|(_, memory)| memory).collect(),
+            keyboard: 0,
+        };
+        chip.eval();
+
+        Ok(chip)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Gate {
+    Nand { a: usize, b: usize, out: usize },
+    MemoryRead(usize),
+    Keyboard { out: Vec<usize> },
+}
+
+#[derive(Clone, Debug)]
+struct Dff {
+    input: usize,
+    out: usize,
+    state: bool,
+    next: bool,
+}
+
+#[derive(Clone, Debug)]
+struct Memory {
+    name: String,
+    address: Vec<usize>,
+    input: Vec<usize>,
+    load: usize,
+    out: Vec<usize>,
+    contents: Vec<Word>,
+    pending_write: Option<(usize, Word)>,
+}
+
+fn read_bits(wires: &[bool], bits: &[usize]) -> Word {
+    let value = bits
+        .iter()
+        .enumerate()
+        .fold(0u16, |value, (i, &bit)| value | ((wires[bit] as u16) << i));
+
+    if bits.len() == 16 {
+        value as i16 as Word
+    } else {
+        value as Word
+    }
+}
+
+fn write_bits(wires: &mut [bool], bits: &[usize], value: Word) {
+    for (i, &bit) in bits.iter().enumerate() {
+        wires[bit] = (value >> i) & 1 != 0;
+    }
+}
+
+/// A flattened gate-level simulation of a chip, built by `ChipLibrary::build`.
+#[derive(Clone, Debug)]
+pub struct Chip {
+    pub name: String,
+    inputs: Vec<(String, Vec<usize>)>,
+    outputs: Vec<(String, Vec<usize>)>,
+    wires: Vec<bool>,
+    gates: Vec<Gate>,
+    dffs: Vec<Dff>,
+    memories: Vec<Memory>,
+    keyboard: Word,
+}
+
+impl Chip {
+    fn pin(&self, name: &str) -> Result<&[usize], String> {
+        self.inputs
+            .iter()
+            .chain(&self.outputs)
+            .find(|(pin_name, _)| pin_name == name)
+            .map(|(_, wires)| wires.as_slice())
+            .ok_or_else(|| format!("{}: unknown pin {name}", self.name))
+    }
+
+    pub fn set(&mut self, name: &str, value: Word) -> Result<(), String> {
+        let (_, bits) = self
+            .inputs
+            .iter()
+            .find(|(pin_name, _)| pin_name == name)
+            .ok_or_else(|| format!("{}: unknown input pin {name}", self.name))?;
+        write_bits(&mut self.wires, bits, value);
+
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<Word, String> {
+        Ok(read_bits(&self.wires, self.pin(name)?))
+    }
+
+    pub fn eval(&mut self) {
+        let wires = &mut self.wires;
+        for gate in &self.gates {
+            match gate {
+                Gate::Nand { a, b, out } => wires[*out] = !(wires[*a] && wires[*b]),
+                Gate::MemoryRead(index) => {
+                    let memory = &self.memories[*index];
+                    let address = read_bits(wires, &memory.address) as u16 as usize;
+                    write_bits(wires, &memory.out, memory.contents[address]);
+                }
+                Gate::Keyboard { out } => write_bits(wires, out, self.keyboard),
+            }
+        }
+    }
+
+    /// The rising clock edge: clocked chips sample their inputs.
+    pub fn tick(&mut self) {
+        self.eval();
+        for dff in &mut self.dffs {
+            dff.next = self.wires[dff.input];
+        }
+        for memory in &mut self.memories {
+            memory.pending_write =
+                (!memory.input.is_empty() && self.wires[memory.load]).then(|| {
+                    (
+                        read_bits(&self.wires, &memory.address) as u16 as usize,
+                        read_bits(&self.wires, &memory.input),
+                    )
+                });
+        }
+    }
+
+    /// The falling clock edge: clocked chips commit their new state.
+    pub fn tock(&mut self) {
+        for dff in &mut self.dffs {
+            dff.state = dff.next;
+            self.wires[dff.out] = dff.state;
+        }
+        for memory in &mut self.memories {
+            if let Some((address, value)) = memory.pending_write.take() {
+                memory.contents[address] = value;
+            }
+        }
+        self.eval();
+    }
+
+    pub fn ticktock(&mut self) {
+        self.tick();
+        self.tock();
+    }
+
+    pub fn set_keyboard(&mut self, value: Word) {
+        self.keyboard = value;
+    }
+
+    /// Contents of the first builtin memory chip with the given name, e.g. `RAM16K` or `Screen`.
+    pub fn memory(&self, name: &str) -> Option<&[Word]> {
+        self.memories
+            .iter()
+            .find(|memory| memory.name == name)
+            .map(|memory| memory.contents.as_slice())
+    }
+
+    pub fn memory_mut(&mut self, name: &str) -> Option<&mut [Word]> {
+        self.memories
+            .iter_mut()
+            .find(|memory| memory.name == name)
+            .map(|memory| memory.contents.as_mut_slice())
+    }
+
+    pub fn load_rom(&mut self, program: &[Instruction]) {
+        if let Some(rom) = self.memory_mut("ROM32K") {
+            for (word, instruction) in rom.iter_mut().zip(program) {
+                *word = instruction.loaded_value();
+            }
+        }
+        self.eval();
+    }
+}
+
+/// Runs `program` on a CPU chip with the pins of the standard `CPU.hdl` and on `Hardware`
+/// side by side, returning a description of the first step where they disagree.
+pub fn cross_check_cpu(
+    cpu: &mut Chip,
+    program: &[Instruction],
+    step_count: u64,
+) -> Result<(), String> {
+    let mut hardware = Hardware::default();
+    hardware.load_program(program);
+    cpu.set("reset", 1)?;
+    cpu.ticktock();
+    cpu.set("reset", 0)?;
+
+    for step in 0..step_count {
+        let pc = hardware.pc;
+        let instruction = hardware.rom[pc as usize];
+        cpu.set("instruction", instruction.loaded_value())?;
+        cpu.eval();
+        let address = cpu.get("addressM")?;
+        cpu.set("inM", hardware.ram[address])?;
+        cpu.eval();
+        let write_m = cpu.get("writeM")? != 0;
+        let out_m = cpu.get("outM")?;
+
+        let a = hardware.a;
+        hardware.step();
+        let mismatch = |message: String| format!("step {step}, PC {pc} ({instruction}): {message}");
+
+        let expected_write =
+            instruction.instruction_type() == InstructionType::C && instruction.dst_has_m();
+        if write_m != expected_write {
+            return Err(mismatch(format!(
+                "writeM is {write_m}, expected {expected_write}"
+            )));
+        }
+        if write_m && address != a & 0x7FFF {
+            return Err(mismatch(format!(
+                "addressM is {address}, expected {}",
+                a & 0x7FFF
+            )));
+        }
+        if write_m && out_m != hardware.ram[a] {
+            return Err(mismatch(format!(
+                "outM is {out_m}, expected {}",
+                hardware.ram[a]
+            )));
+        }
+
+        cpu.ticktock();
+        let cpu_pc = cpu.get("pc")?;
+        if cpu_pc != hardware.pc & 0x7FFF {
+            return Err(mismatch(format!(
+                "next pc is {cpu_pc}, expected {}",
+                hardware.pc
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware_parse::assemble_hack_file;
+
+    /// A 16-bit chip made of 16 copies of `gate`, whose pins have the same names as the chip's.
+    fn bitwise16(
+        name: &str,
+        gate: &str,
+        bus_pins: &[&str],
+        shared_pin: Option<&str>,
+    ) -> (String, String) {
+        let declarations = bus_pins
+            .iter()
+            .map(|pin| format!("{pin}[16]"))
+            .chain(shared_pin.map(str::to_owned))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let parts = (0..16)
+            .map(|i| {
+                let connections = bus_pins
+                    .iter()
+                    .map(|pin| format!("{pin}={pin}[{i}]"))
+                    .chain(shared_pin.map(|pin| format!("{pin}={pin}")))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("{gate}({connections}, out=out[{i}]);")
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        (
+            format!("{name}.hdl"),
+            format!("CHIP {name} {{ IN {declarations}; OUT out[16]; PARTS: {parts} }}"),
+        )
+    }
+
+    fn hdl_files() -> Vec<(String, String)> {
+        let mut files: Vec<(String, String)> = [
+            ("Not", "IN in; OUT out; PARTS: Nand(a=in, b=in, out=out);"),
+            (
+                "And",
+                "IN a, b; OUT out; PARTS: Nand(a=a, b=b, out=n); Not(in=n, out=out);",
+            ),
+            (
+                "Or",
+                "IN a, b; OUT out;
+                PARTS:
+                Not(in=a, out=na);
+                Not(in=b, out=nb);
+                Nand(a=na, b=nb, out=out);",
+            ),
+            (
+                "Xor",
+                "IN a, b; OUT out;
+                PARTS:
+                Or(a=a, b=b, out=o);
+                Nand(a=a, b=b, out=n);
+                And(a=o, b=n, out=out);",
+            ),
+            (
+                "Mux",
+                "IN a, b, sel; OUT out;
+                PARTS:
+                Not(in=sel, out=nsel);
+                And(a=a, b=nsel, out=x);
+                And(a=b, b=sel, out=y);
+                Or(a=x, b=y, out=out);",
+            ),
+            (
+                "Or8Way",
+                "IN in[8]; OUT out;
+                PARTS:
+                Or(a=in[0], b=in[1], out=o1);
+                Or(a=o1, b=in[2], out=o2);
+                Or(a=o2, b=in[3], out=o3);
+                Or(a=o3, b=in[4], out=o4);
+                Or(a=o4, b=in[5], out=o5);
+                Or(a=o5, b=in[6], out=o6);
+                Or(a=o6, b=in[7], out=out);",
+            ),
+            (
+                "HalfAdder",
+                "IN a, b; OUT sum, carry;
+                PARTS:
+                Xor(a=a, b=b, out=sum);
+                And(a=a, b=b, out=carry);",
+            ),
+            (
+                "FullAdder",
+                "IN a, b, c; OUT sum, carry;
+                PARTS:
+                HalfAdder(a=a, b=b, sum=s1, carry=c1);
+                HalfAdder(a=s1, b=c, sum=sum, carry=c2);
+                Or(a=c1, b=c2, out=carry);",
+            ),
+            (
+                "Inc16",
+                "IN in[16]; OUT out[16]; PARTS: Add16(a=in, b[0]=true, out=out);",
+            ),
+            (
+                "ALU",
+                "IN x[16], y[16], zx, nx, zy, ny, f, no;
+                OUT out[16], zr, ng;
+                PARTS:
+                Mux16(a=x, b=false, sel=zx, out=x1);
+                Not16(in=x1, out=notx1);
+                Mux16(a=x1, b=notx1, sel=nx, out=x2);
+                Mux16(a=y, b=false, sel=zy, out=y1);
+                Not16(in=y1, out=noty1);
+                Mux16(a=y1, b=noty1, sel=ny, out=y2);
+                Add16(a=x2, b=y2, out=sum);
+                And16(a=x2, b=y2, out=conjunction);
+                Mux16(a=conjunction, b=sum, sel=f, out=o1);
+                Not16(in=o1, out=noto1);
+                Mux16(a=o1, b=noto1, sel=no, out=out, out[15]=ng, out[0..7]=low, out[8..15]=high);
+                Or8Way(in=low, out=nzlow);
+                Or8Way(in=high, out=nzhigh);
+                Or(a=nzlow, b=nzhigh, out=nz);
+                Not(in=nz, out=zr);",
+            ),
+            (
+                "Bit",
+                "IN in, load; OUT out;
+                PARTS:
+                Mux(a=previous, b=in, sel=load, out=next);
+                DFF(in=next, out=previous, out=out);",
+            ),
+            (
+                "PC",
+                "IN in[16], load, inc, reset; OUT out[16];
+                PARTS:
+                Inc16(in=previous, out=incremented);
+                Mux16(a=previous, b=incremented, sel=inc, out=w1);
+                Mux16(a=w1, b=in, sel=load, out=w2);
+                Mux16(a=w2, b=false, sel=reset, out=w3);
+                Register(in=w3, load=true, out=out, out=previous);",
+            ),
+            (
+                "CPU",
+                "IN inM[16], instruction[16], reset;
+                OUT outM[16], writeM, addressM[15], pc[15];
+                PARTS:
+                Not(in=instruction[15], out=isA);
+                Mux16(a=aluOut, b=instruction, sel=isA, out=aIn);
+                Or(a=isA, b=instruction[5], out=loadA);
+                Register(in=aIn, load=loadA, out=aOut, out[0..14]=addressM);
+                And(a=instruction[15], b=instruction[4], out=loadD);
+                Register(in=aluOut, load=loadD, out=dOut);
+                Mux16(a=aOut, b=inM, sel=instruction[12], out=am);
+                ALU(x=dOut, y=am, zx=instruction[11], nx=instruction[10], zy=instruction[9],
+                    ny=instruction[8], f=instruction[7], no=instruction[6],
+                    out=aluOut, out=outM, zr=zr, ng=ng);
+                And(a=instruction[15], b=instruction[3], out=writeM);
+                Or(a=zr, b=ng, out=notPositive);
+                Not(in=notPositive, out=positive);
+                And(a=instruction[0], b=positive, out=jgt);
+                And(a=instruction[1], b=zr, out=jeq);
+                And(a=instruction[2], b=ng, out=jlt);
+                Or(a=jgt, b=jeq, out=jge);
+                Or(a=jge, b=jlt, out=anyJump);
+                And(a=anyJump, b=instruction[15], out=jump);
+                PC(in=aOut, load=jump, inc=true, reset=reset, out[0..14]=pc);",
+            ),
+        ]
+        .into_iter()
+        .map(|(name, body)| (format!("{name}.hdl"), format!("CHIP {name} {{ {body} }}")))
+        .collect();
+
+        files.push(bitwise16("Not16", "Not", &["in"], None));
+        files.push(bitwise16("And16", "And", &["a", "b"], None));
+        files.push(bitwise16("Mux16", "Mux", &["a", "b"], Some("sel")));
+        files.push(bitwise16("Register", "Bit", &["in"], Some("load")));
+        let adders = (1..16)
+            .map(|i| {
+                format!(
+                    "FullAdder(a=a[{i}], b=b[{i}], c=c{}, sum=out[{i}], carry=c{i});",
+                    i - 1
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        files.push((
+            "Add16.hdl".to_owned(),
+            format!(
+                "CHIP Add16 {{ IN a[16], b[16]; OUT out[16];
+                PARTS: HalfAdder(a=a[0], b=b[0], sum=out[0], carry=c0); {adders} }}"
+            ),
+        ));
+
+        files
+    }
+
+    #[test]
+    fn test_combinational() {
+        let library = ChipLibrary::from_file_contents(hdl_files()).unwrap();
+        let mut xor = library.build("Xor").unwrap();
+        for (a, b, out) in [(0, 0, 0), (0, 1, 1), (1, 0, 1), (1, 1, 0)] {
+            xor.set("a", a).unwrap();
+            xor.set("b", b).unwrap();
+            xor.eval();
+            assert_eq!(xor.get("out"), Ok(out));
+        }
+
+        let mut alu = library.build("ALU").unwrap();
+        alu.set("x", 5).unwrap();
+        alu.set("y", 7).unwrap();
+        // x-y
+        for (pin, value) in [
+            ("zx", 0),
+            ("nx", 1),
+            ("zy", 0),
+            ("ny", 0),
+            ("f", 1),
+            ("no", 1),
+        ] {
+            alu.set(pin, value).unwrap();
+        }
+        alu.eval();
+        assert_eq!(alu.get("out"), Ok(-2));
+        assert_eq!(alu.get("ng"), Ok(1));
+        assert_eq!(alu.get("zr"), Ok(0));
+    }
+
+    #[test]
+    fn test_clocked() {
+        let library = ChipLibrary::from_file_contents(hdl_files()).unwrap();
+        let mut register = library.build("Register").unwrap();
+        register.set("in", -123).unwrap();
+        register.set("load", 1).unwrap();
+        register.tick();
+        assert_eq!(register.get("out"), Ok(0));
+        register.tock();
+        assert_eq!(register.get("out"), Ok(-123));
+        register.set("in", 5).unwrap();
+        register.set("load", 0).unwrap();
+        register.ticktock();
+        assert_eq!(register.get("out"), Ok(-123));
+
+        let mut ram = library.build("RAM8").unwrap();
+        ram.set("address", 3).unwrap();
+        ram.set("in", 42).unwrap();
+        ram.set("load", 1).unwrap();
+        ram.ticktock();
+        ram.set("load", 0).unwrap();
+        ram.set("address", 2).unwrap();
+        ram.eval();
+        assert_eq!(ram.get("out"), Ok(0));
+        ram.set("address", 3).unwrap();
+        ram.eval();
+        assert_eq!(ram.get("out"), Ok(42));
+    }
+
+    #[test]
+    fn test_cpu_cross_check() {
+        let library = ChipLibrary::from_file_contents(hdl_files()).unwrap();
+        let mut cpu = library.build("CPU").unwrap();
+        // R2 = R0 * R1, then loop forever
+        let program = assemble_hack_file(
+            r#"
+            @6
+            D=A
+            @R0
+            M=D
+            @7
+            D=A
+            @R1
+            M=D
+            @R2
+            M=0
+            (LOOP)
+            @R1
+            D=M
+            @END
+            D;JLE
+            @R0
+            D=M
+            @R2
+            M=D+M
+            @R1
+            M=M-1
+            @LOOP
+            0;JMP
+            (END)
+            @END
+            0;JMP
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(cross_check_cpu(&mut cpu, &program, 200), Ok(()));
+    }
+
+    #[test]
+    fn test_cross_check_mismatch() {
+        let mut files = hdl_files();
+        for (name, contents) in files.iter_mut() {
+            if name == "CPU.hdl" {
+                // JGT jumps on zero as well
+                *contents = contents.replace("b=positive, out=jgt", "b=true, out=jgt");
+            }
+        }
+        let library = ChipLibrary::from_file_contents(files).unwrap();
+        let mut cpu = library.build("CPU").unwrap();
+        let program = assemble_hack_file("@0\nD=A\n@0\nD;JGT\n@0\n0;JMP").unwrap();
+
+        assert!(cross_check_cpu(&mut cpu, &program, 10).is_err());
+    }
+
+    #[test]
+    fn test_combinational_loop() {
+        let library = ChipLibrary::from_file_contents(vec![(
+            "Loop.hdl".to_owned(),
+            "CHIP Loop { IN a; OUT out; PARTS: Nand(a=a, b=x, out=x, out=out); }".to_owned(),
+        )])
+        .unwrap();
+
+        assert!(library.build("Loop").is_err());
+    }
+}
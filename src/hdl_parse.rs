@@ -0,0 +1,307 @@
+use crate::{
+    hdl::*,
+    parse_utils::{IResult, keyword, skip, symbol, token, word},
+};
+
+use nom::{
+    Finish,
+    branch::alt,
+    bytes::complete::tag,
+    character::complete,
+    combinator::{all_consuming, cut, map, opt, value},
+    error::convert_error,
+    multi::{many0, separated_list0, separated_list1},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
+};
+
+fn identifier(input: &str) -> IResult<&str, String> {
+    map(token(word), str::to_owned)(input)
+}
+
+fn number(input: &str) -> IResult<&str, usize> {
+    map(token(complete::u16), |n| n as usize)(input)
+}
+
+fn pin_declaration(input: &str) -> IResult<&str, PinDeclaration> {
+    map(
+        pair(
+            identifier,
+            opt(delimited(symbol('['), cut(number), cut(symbol(']')))),
+        ),
+        |(name, width)| PinDeclaration {
+            name,
+            width: width.unwrap_or(1),
+        },
+    )(input)
+}
+
+fn pin_declarations<'a>(
+    kind: &'static str,
+) -> impl FnMut(&'a str) -> IResult<&'a str, Vec<PinDeclaration>> {
+    map(
+        opt(preceded(
+            keyword(kind),
+            cut(terminated(
+                separated_list0(symbol(','), pin_declaration),
+                symbol(';'),
+            )),
+        )),
+        Option::unwrap_or_default,
+    )
+}
+
+fn pin_reference(input: &str) -> IResult<&str, PinReference> {
+    map(
+        pair(
+            identifier,
+            opt(delimited(
+                symbol('['),
+                cut(pair(number, opt(preceded(token(tag("..")), number)))),
+                cut(symbol(']')),
+            )),
+        ),
+        |(name, range)| PinReference {
+            name,
+            range: range.map(|(start, end)| start..=end.unwrap_or(start)),
+        },
+    )(input)
+}
+
+fn connection(input: &str) -> IResult<&str, Connection> {
+    map(
+        separated_pair(
+            pin_reference,
+            cut(symbol('=')),
+            cut(alt((
+                value(Signal::True, keyword("true")),
+                value(Signal::False, keyword("false")),
+                map(pin_reference, Signal::Pin),
+            ))),
+        ),
+        |(pin, signal)| Connection { pin, signal },
+    )(input)
+}
+
+fn part(input: &str) -> IResult<&str, Part> {
+    map(
+        pair(
+            identifier,
+            cut(terminated(
+                delimited(
+                    symbol('('),
+                    separated_list0(symbol(','), connection),
+                    symbol(')'),
+                ),
+                symbol(';'),
+            )),
+        ),
+        |(name, connections)| Part { name, connections },
+    )(input)
+}
+
+fn builtin(input: &str) -> IResult<&str, ChipBody> {
+    map(
+        pair(
+            delimited(keyword("BUILTIN"), cut(identifier), cut(symbol(';'))),
+            opt(delimited(
+                keyword("CLOCKED"),
+                cut(separated_list1(symbol(','), identifier)),
+                cut(symbol(';')),
+            )),
+        ),
+        |(name, clocked)| ChipBody::Builtin {
+            name,
+            clocked: clocked.unwrap_or_default(),
+        },
+    )(input)
+}
+
+fn chip(input: &str) -> IResult<&str, ChipDefinition> {
+    map(
+        preceded(
+            keyword("CHIP"),
+            cut(tuple((
+                identifier,
+                preceded(symbol('{'), pin_declarations("IN")),
+                pin_declarations("OUT"),
+                terminated(
+                    preceded(
+                        opt(pair(keyword("PARTS"), symbol(':'))),
+                        alt((builtin, map(many0(part), ChipBody::Parts))),
+                    ),
+                    symbol('}'),
+                ),
+            ))),
+        ),
+        |(name, inputs, outputs, body)| ChipDefinition {
+            name,
+            inputs,
+            outputs,
+            body,
+        },
+    )(input)
+}
+
+pub fn parse_chip(input: &str) -> Result<ChipDefinition, String> {
+    all_consuming(terminated(chip, skip))(input)
+        .finish()
+        .map(|(_, chip)| chip)
+        .map_err(|e| convert_error(input, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chip() {
+        let hdl = r#"
+        /** Multiplexor. */
+        CHIP Mux16 {
+            IN a[16], b[16], sel;
+            OUT out[16];
+
+            PARTS:
+            // only the low byte
+            Mux(a=a[0], b=b[0], sel=sel, out=out[0]);
+            Mux16(a[0..7]=a[8..15], b=false, sel=true, out=out);
+        }"#;
+
+        assert_eq!(
+            parse_chip(hdl),
+            Ok(ChipDefinition {
+                name: "Mux16".to_owned(),
+                inputs: vec![
+                    PinDeclaration {
+                        name: "a".to_owned(),
+                        width: 16
+                    },
+                    PinDeclaration {
+                        name: "b".to_owned(),
+                        width: 16
+                    },
+                    PinDeclaration {
+                        name: "sel".to_owned(),
+                        width: 1
+                    },
+                ],
+                outputs: vec![PinDeclaration {
+                    name: "out".to_owned(),
+                    width: 16
+                }],
+                body: ChipBody::Parts(vec![
+                    Part {
+                        name: "Mux".to_owned(),
+                        connections: vec![
+                            Connection {
+                                pin: PinReference {
+                                    name: "a".to_owned(),
+                                    range: None
+                                },
+                                signal: Signal::Pin(PinReference {
+                                    name: "a".to_owned(),
+                                    range: Some(0..=0)
+                                }),
+                            },
+                            Connection {
+                                pin: PinReference {
+                                    name: "b".to_owned(),
+                                    range: None
+                                },
+                                signal: Signal::Pin(PinReference {
+                                    name: "b".to_owned(),
+                                    range: Some(0..=0)
+                                }),
+                            },
+                            Connection {
+                                pin: PinReference {
+                                    name: "sel".to_owned(),
+                                    range: None
+                                },
+                                signal: Signal::Pin(PinReference {
+                                    name: "sel".to_owned(),
+                                    range: None
+                                }),
+                            },
+                            Connection {
+                                pin: PinReference {
+                                    name: "out".to_owned(),
+                                    range: None
+                                },
+                                signal: Signal::Pin(PinReference {
+                                    name: "out".to_owned(),
+                                    range: Some(0..=0)
+                                }),
+                            },
+                        ],
+                    },
+                    Part {
+                        name: "Mux16".to_owned(),
+                        connections: vec![
+                            Connection {
+                                pin: PinReference {
+                                    name: "a".to_owned(),
+                                    range: Some(0..=7)
+                                },
+                                signal: Signal::Pin(PinReference {
+                                    name: "a".to_owned(),
+                                    range: Some(8..=15)
+                                }),
+                            },
+                            Connection {
+                                pin: PinReference {
+                                    name: "b".to_owned(),
+                                    range: None
+                                },
+                                signal: Signal::False,
+                            },
+                            Connection {
+                                pin: PinReference {
+                                    name: "sel".to_owned(),
+                                    range: None
+                                },
+                                signal: Signal::True,
+                            },
+                            Connection {
+                                pin: PinReference {
+                                    name: "out".to_owned(),
+                                    range: None
+                                },
+                                signal: Signal::Pin(PinReference {
+                                    name: "out".to_owned(),
+                                    range: None
+                                }),
+                            },
+                        ],
+                    },
+                ]),
+            })
+        );
+    }
+
+    #[test]
+    fn test_builtin() {
+        let hdl = r#"
+        CHIP DFF {
+            IN in;
+            OUT out;
+            BUILTIN DFF;
+            CLOCKED in;
+        }"#;
+
+        assert_eq!(
+            parse_chip(hdl).unwrap().body,
+            ChipBody::Builtin {
+                name: "DFF".to_owned(),
+                clocked: vec!["in".to_owned()]
+            }
+        );
+    }
+
+    #[test]
+    fn test_missing_semicolon() {
+        assert!(
+            parse_chip("CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out) }").is_err()
+        );
+    }
+}
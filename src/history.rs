@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+
+pub const DEFAULT_CAPACITY: usize = 1_000_000;
+
+/// What the most recent steps overwrote, oldest first, so that they can be undone. Once
+/// `capacity` steps are recorded, recording another forgets the oldest one.
+///
+/// Undone steps are only counted, since running forward from where they were undone replays them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct History<T> {
+    steps: VecDeque<T>,
+    capacity: usize,
+    undone_step_count: usize,
+}
+
+impl<T> History<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            steps: VecDeque::new(),
+            capacity,
+            undone_step_count: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn push(&mut self, step: T) {
+        if self.steps.len() >= self.capacity {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(step);
+        self.undone_step_count = self.undone_step_count.saturating_sub(1);
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        let step = self.steps.pop_back()?;
+        self.undone_step_count += 1;
+
+        Some(step)
+    }
+
+    /// The number of steps which can be undone.
+    pub fn position(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// The number of steps in the timeline, including the ones which were undone.
+    pub fn len(&self) -> usize {
+        self.steps.len() + self.undone_step_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let mut history = History::new(3);
+        for step in 0..5 {
+            history.push(step);
+        }
+
+        assert_eq!((history.position(), history.len()), (3, 3));
+        assert_eq!(history.pop(), Some(4));
+        assert_eq!(history.pop(), Some(3));
+        assert_eq!((history.position(), history.len()), (1, 3));
+
+        history.push(3);
+        assert_eq!((history.position(), history.len()), (2, 3));
+        assert_eq!(history.pop(), Some(3));
+        assert_eq!(history.pop(), Some(2));
+        assert_eq!(history.pop(), None);
+        assert_eq!((history.position(), history.len()), (0, 3));
+    }
+}
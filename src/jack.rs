@@ -0,0 +1,265 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{hardware::Word, jack_parse::parse_class, jack_to_vm::class_to_vm, vm::VMCommand};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Class {
+    pub name: String,
+    pub class_var_decs: Vec<ClassVarDec>,
+    pub subroutine_decs: Vec<SubroutineDec>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClassVarKind {
+    Static,
+    Field,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClassVarDec {
+    pub kind: ClassVarKind,
+    pub var_type: Type,
+    pub names: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    Char,
+    Boolean,
+    ClassName(String),
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Char => write!(f, "char"),
+            Type::Boolean => write!(f, "boolean"),
+            Type::ClassName(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubroutineKind {
+    Constructor,
+    Function,
+    Method,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubroutineDec {
+    pub kind: SubroutineKind,
+    /// `None` for `void` subroutines.
+    pub return_type: Option<Type>,
+    pub name: String,
+    pub parameters: Vec<(Type, String)>,
+    pub var_decs: Vec<VarDec>,
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VarDec {
+    pub var_type: Type,
+    pub names: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Statement {
+    Let {
+        var_name: String,
+        index: Option<Expression>,
+        value: Expression,
+    },
+    If {
+        condition: Expression,
+        if_statements: Vec<Statement>,
+        else_statements: Option<Vec<Statement>>,
+    },
+    While {
+        condition: Expression,
+        statements: Vec<Statement>,
+    },
+    Do(SubroutineCall),
+    Return(Option<Expression>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expression {
+    pub term: Term,
+    pub operations: Vec<(BinaryOp, Term)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Term {
+    IntegerConstant(Word),
+    StringConstant(String),
+    KeywordConstant(KeywordConstant),
+    VarName(String),
+    ArrayAccess {
+        var_name: String,
+        index: Box<Expression>,
+    },
+    SubroutineCall(SubroutineCall),
+    Parenthesized(Box<Expression>),
+    Unary(UnaryOp, Box<Term>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubroutineCall {
+    /// The class or variable name before the dot, if any.
+    pub receiver: Option<String>,
+    pub name: String,
+    pub arguments: Vec<Expression>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeywordConstant {
+    True,
+    False,
+    Null,
+    This,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    And,
+    Or,
+    Lt,
+    Gt,
+    Eq,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+pub fn compile_paths(paths: &[PathBuf]) -> Result<Vec<(String, Vec<VMCommand>)>, String> {
+    let files = paths
+        .iter()
+        .map(|path| {
+            Ok((
+                path.file_name().unwrap().to_str().unwrap().to_owned(),
+                fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?,
+            ))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    compile_files(files)
+}
+
+pub fn compile_dir(dir: &Path) -> Result<Vec<(String, Vec<VMCommand>)>, String> {
+    let mut paths = fs::read_dir(dir)
+        .map_err(|e| format!("{}: {e}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|e| e == "jack"))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    compile_paths(&paths)
+}
+
+/// Compiles `(file name, contents)` pairs of Jack classes into the per-file commands taken by
+/// `VM::from_all_file_commands`.
+pub fn compile_files(
+    file_contents: Vec<(String, String)>,
+) -> Result<Vec<(String, Vec<VMCommand>)>, String> {
+    file_contents
+        .into_iter()
+        .map(|(file_name, contents)| {
+            let name = file_name
+                .rsplit_once('.')
+                .map_or(file_name.as_str(), |(name, _)| name)
+                .to_owned();
+            let class = parse_class(&contents).map_err(|e| format!("{file_name}: {e}"))?;
+            if class.name != name {
+                return Err(format!(
+                    "{file_name}: class {} must be declared in {}.jack",
+                    class.name, class.name
+                ));
+            }
+            let commands = class_to_vm(&class).map_err(|e| format!("{file_name}: {e}"))?;
+
+            Ok((name, commands))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_compile_and_run() {
+        let sys = r#"
+        class Sys {
+            function void init() {
+                do Main.main();
+                while (true) {}
+                return;
+            }
+        }"#;
+        let main = r#"
+        class Main {
+            function void main() {
+                var Adder adder;
+                let adder = Adder.new(30);
+                do Memory.poke(100, adder.add(12));
+                do Memory.poke(101, Main.sum(10));
+                return;
+            }
+
+            /** Sums 1..n recursively. */
+            function int sum(int n) {
+                if (n = 0) {
+                    return 0;
+                }
+                return n + Main.sum(n - 1);
+            }
+        }"#;
+        let adder = r#"
+        class Adder {
+            field int base;
+
+            constructor Adder new(int b) {
+                let base = b;
+                return this;
+            }
+
+            method int add(int x) {
+                return base + x;
+            }
+        }"#;
+
+        let all_file_commands = compile_files(vec![
+            ("Sys.jack".to_owned(), sys.to_owned()),
+            ("Main.jack".to_owned(), main.to_owned()),
+            ("Adder.jack".to_owned(), adder.to_owned()),
+        ])
+        .unwrap();
+
+        let mut vm = VM::from_all_file_commands(all_file_commands);
+        vm.run(1000);
+
+        assert_eq!(vm.get_ram_value(100), 42);
+        assert_eq!(vm.get_ram_value(101), 55);
+    }
+
+    #[test]
+    fn test_class_name_mismatch() {
+        let result = compile_files(vec![("Foo.jack".to_owned(), "class Bar {}".to_owned())]);
+
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,479 @@
+use crate::{
+    hardware::Word,
+    jack::*,
+    parse_utils::{IResult, is_not0, keyword, skip, symbol, token, word},
+};
+
+use nom::{
+    Finish,
+    branch::alt,
+    character::complete::{self, alphanumeric1, char},
+    combinator::{all_consuming, cut, map, map_res, not, opt, peek, value, verify},
+    error::{context, convert_error},
+    multi::{many0, separated_list0, separated_list1},
+    sequence::{delimited, pair, preceded, terminated, tuple},
+};
+
+const KEYWORDS: &[&str] = &[
+    "class",
+    "constructor",
+    "function",
+    "method",
+    "field",
+    "static",
+    "var",
+    "int",
+    "char",
+    "boolean",
+    "void",
+    "true",
+    "false",
+    "null",
+    "this",
+    "let",
+    "do",
+    "if",
+    "else",
+    "while",
+    "return",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Token {
+    Keyword(String),
+    Symbol(char),
+    IntegerConstant(Word),
+    StringConstant(String),
+    Identifier(String),
+}
+
+fn identifier(input: &str) -> IResult<&str, String> {
+    map(
+        token(verify(word, |w: &str| !KEYWORDS.contains(&w))),
+        str::to_owned,
+    )(input)
+}
+
+fn integer_constant(input: &str) -> IResult<&str, Word> {
+    token(map_res(
+        terminated(complete::u16, not(peek(alphanumeric1))),
+        |i| {
+            if i <= 32767 {
+                Ok(i as Word)
+            } else {
+                Err("integer constant out of range")
+            }
+        },
+    ))(input)
+}
+
+fn string_constant(input: &str) -> IResult<&str, String> {
+    map(
+        token(delimited(char('"'), is_not0("\"\r\n"), char('"'))),
+        str::to_owned,
+    )(input)
+}
+
+fn any_token(input: &str) -> IResult<&str, Token> {
+    alt((
+        map(integer_constant, Token::IntegerConstant),
+        map(string_constant, Token::StringConstant),
+        map(identifier, Token::Identifier),
+        map(token(word), |w| Token::Keyword(w.to_owned())),
+        map(
+            token(complete::one_of("{}()[].,;+-*/&|<>=~")),
+            Token::Symbol,
+        ),
+    ))(input)
+}
+
+pub fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    all_consuming(terminated(many0(any_token), skip))(input)
+        .finish()
+        .map(|(_, tokens)| tokens)
+        .map_err(|e| convert_error(input, e))
+}
+
+fn var_type(input: &str) -> IResult<&str, Type> {
+    alt((
+        value(Type::Int, keyword("int")),
+        value(Type::Char, keyword("char")),
+        value(Type::Boolean, keyword("boolean")),
+        map(identifier, Type::ClassName),
+    ))(input)
+}
+
+fn var_names(input: &str) -> IResult<&str, Vec<String>> {
+    terminated(separated_list1(symbol(','), identifier), cut(symbol(';')))(input)
+}
+
+fn class_var_dec(input: &str) -> IResult<&str, ClassVarDec> {
+    map(
+        tuple((
+            alt((
+                value(ClassVarKind::Static, keyword("static")),
+                value(ClassVarKind::Field, keyword("field")),
+            )),
+            cut(var_type),
+            cut(var_names),
+        )),
+        |(kind, var_type, names)| ClassVarDec {
+            kind,
+            var_type,
+            names,
+        },
+    )(input)
+}
+
+fn var_dec(input: &str) -> IResult<&str, VarDec> {
+    map(
+        preceded(keyword("var"), cut(pair(var_type, var_names))),
+        |(var_type, names)| VarDec { var_type, names },
+    )(input)
+}
+
+fn binary_op(input: &str) -> IResult<&str, BinaryOp> {
+    token(alt((
+        value(BinaryOp::Add, char('+')),
+        value(BinaryOp::Sub, char('-')),
+        value(BinaryOp::Mul, char('*')),
+        value(BinaryOp::Div, char('/')),
+        value(BinaryOp::And, char('&')),
+        value(BinaryOp::Or, char('|')),
+        value(BinaryOp::Lt, char('<')),
+        value(BinaryOp::Gt, char('>')),
+        value(BinaryOp::Eq, char('=')),
+    )))(input)
+}
+
+fn unary_op(input: &str) -> IResult<&str, UnaryOp> {
+    token(alt((
+        value(UnaryOp::Neg, char('-')),
+        value(UnaryOp::Not, char('~')),
+    )))(input)
+}
+
+fn keyword_constant(input: &str) -> IResult<&str, KeywordConstant> {
+    alt((
+        value(KeywordConstant::True, keyword("true")),
+        value(KeywordConstant::False, keyword("false")),
+        value(KeywordConstant::Null, keyword("null")),
+        value(KeywordConstant::This, keyword("this")),
+    ))(input)
+}
+
+fn expression_list(input: &str) -> IResult<&str, Vec<Expression>> {
+    delimited(
+        symbol('('),
+        separated_list0(symbol(','), expression),
+        cut(symbol(')')),
+    )(input)
+}
+
+fn subroutine_call(input: &str) -> IResult<&str, SubroutineCall> {
+    map(
+        tuple((
+            opt(terminated(identifier, symbol('.'))),
+            identifier,
+            expression_list,
+        )),
+        |(receiver, name, arguments)| SubroutineCall {
+            receiver,
+            name,
+            arguments,
+        },
+    )(input)
+}
+
+fn array_index(input: &str) -> IResult<&str, Expression> {
+    delimited(symbol('['), cut(expression), cut(symbol(']')))(input)
+}
+
+fn term(input: &str) -> IResult<&str, Term> {
+    context(
+        "term",
+        alt((
+            map(integer_constant, Term::IntegerConstant),
+            map(string_constant, Term::StringConstant),
+            map(keyword_constant, Term::KeywordConstant),
+            map(subroutine_call, Term::SubroutineCall),
+            map(pair(identifier, array_index), |(var_name, index)| {
+                Term::ArrayAccess {
+                    var_name,
+                    index: Box::new(index),
+                }
+            }),
+            map(identifier, Term::VarName),
+            map(
+                delimited(symbol('('), cut(expression), cut(symbol(')'))),
+                |e| Term::Parenthesized(Box::new(e)),
+            ),
+            map(pair(unary_op, cut(term)), |(op, t)| {
+                Term::Unary(op, Box::new(t))
+            }),
+        )),
+    )(input)
+}
+
+fn expression(input: &str) -> IResult<&str, Expression> {
+    map(
+        pair(term, many0(pair(binary_op, cut(term)))),
+        |(term, operations)| Expression { term, operations },
+    )(input)
+}
+
+fn statements(input: &str) -> IResult<&str, Vec<Statement>> {
+    delimited(symbol('{'), many0(statement), cut(symbol('}')))(input)
+}
+
+fn let_statement(input: &str) -> IResult<&str, Statement> {
+    map(
+        preceded(
+            keyword("let"),
+            cut(tuple((
+                identifier,
+                opt(array_index),
+                preceded(symbol('='), expression),
+                symbol(';'),
+            ))),
+        ),
+        |(var_name, index, value, _)| Statement::Let {
+            var_name,
+            index,
+            value,
+        },
+    )(input)
+}
+
+fn condition(input: &str) -> IResult<&str, Expression> {
+    delimited(symbol('('), expression, symbol(')'))(input)
+}
+
+fn if_statement(input: &str) -> IResult<&str, Statement> {
+    map(
+        preceded(
+            keyword("if"),
+            cut(tuple((
+                condition,
+                statements,
+                opt(preceded(keyword("else"), cut(statements))),
+            ))),
+        ),
+        |(condition, if_statements, else_statements)| Statement::If {
+            condition,
+            if_statements,
+            else_statements,
+        },
+    )(input)
+}
+
+fn while_statement(input: &str) -> IResult<&str, Statement> {
+    map(
+        preceded(keyword("while"), cut(pair(condition, statements))),
+        |(condition, statements)| Statement::While {
+            condition,
+            statements,
+        },
+    )(input)
+}
+
+fn do_statement(input: &str) -> IResult<&str, Statement> {
+    map(
+        preceded(keyword("do"), cut(terminated(subroutine_call, symbol(';')))),
+        Statement::Do,
+    )(input)
+}
+
+fn return_statement(input: &str) -> IResult<&str, Statement> {
+    map(
+        preceded(
+            keyword("return"),
+            cut(terminated(opt(expression), symbol(';'))),
+        ),
+        Statement::Return,
+    )(input)
+}
+
+fn statement(input: &str) -> IResult<&str, Statement> {
+    context(
+        "statement",
+        alt((
+            let_statement,
+            if_statement,
+            while_statement,
+            do_statement,
+            return_statement,
+        )),
+    )(input)
+}
+
+fn parameter_list(input: &str) -> IResult<&str, Vec<(Type, String)>> {
+    delimited(
+        symbol('('),
+        separated_list0(symbol(','), pair(var_type, identifier)),
+        symbol(')'),
+    )(input)
+}
+
+fn subroutine_dec(input: &str) -> IResult<&str, SubroutineDec> {
+    map(
+        pair(
+            alt((
+                value(SubroutineKind::Constructor, keyword("constructor")),
+                value(SubroutineKind::Function, keyword("function")),
+                value(SubroutineKind::Method, keyword("method")),
+            )),
+            cut(tuple((
+                alt((value(None, keyword("void")), map(var_type, Some))),
+                identifier,
+                parameter_list,
+                preceded(symbol('{'), many0(var_dec)),
+                terminated(many0(statement), symbol('}')),
+            ))),
+        ),
+        |(kind, (return_type, name, parameters, var_decs, statements))| SubroutineDec {
+            kind,
+            return_type,
+            name,
+            parameters,
+            var_decs,
+            statements,
+        },
+    )(input)
+}
+
+fn class(input: &str) -> IResult<&str, Class> {
+    map(
+        preceded(
+            keyword("class"),
+            cut(tuple((
+                identifier,
+                symbol('{'),
+                many0(class_var_dec),
+                many0(context("subroutine", subroutine_dec)),
+                symbol('}'),
+            ))),
+        ),
+        |(name, _, class_var_decs, subroutine_decs, _)| Class {
+            name,
+            class_var_decs,
+            subroutine_decs,
+        },
+    )(input)
+}
+
+pub fn parse_class(input: &str) -> Result<Class, String> {
+    all_consuming(terminated(class, skip))(input)
+        .finish()
+        .map(|(_, class)| class)
+        .map_err(|e| convert_error(input, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("let x = y[3] + \"hi\"; // comment\n/* block\n comment */ return;"),
+            Ok(vec![
+                Token::Keyword("let".to_owned()),
+                Token::Identifier("x".to_owned()),
+                Token::Symbol('='),
+                Token::Identifier("y".to_owned()),
+                Token::Symbol('['),
+                Token::IntegerConstant(3),
+                Token::Symbol(']'),
+                Token::Symbol('+'),
+                Token::StringConstant("hi".to_owned()),
+                Token::Symbol(';'),
+                Token::Keyword("return".to_owned()),
+                Token::Symbol(';'),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_keyword_prefix_identifier() {
+        assert_eq!(identifier(" dox"), Ok(("", "dox".to_owned())));
+        assert!(identifier(" do").is_err());
+    }
+
+    #[test]
+    fn test_integer_out_of_range() {
+        assert!(integer_constant("32768").is_err());
+        assert_eq!(integer_constant("32767"), Ok(("", 32767)));
+    }
+
+    #[test]
+    fn test_expression() {
+        assert_eq!(
+            expression("-a[1] * Foo.bar(x, 2)"),
+            Ok((
+                "",
+                Expression {
+                    term: Term::Unary(
+                        UnaryOp::Neg,
+                        Box::new(Term::ArrayAccess {
+                            var_name: "a".to_owned(),
+                            index: Box::new(Expression {
+                                term: Term::IntegerConstant(1),
+                                operations: vec![],
+                            }),
+                        })
+                    ),
+                    operations: vec![(
+                        BinaryOp::Mul,
+                        Term::SubroutineCall(SubroutineCall {
+                            receiver: Some("Foo".to_owned()),
+                            name: "bar".to_owned(),
+                            arguments: vec![
+                                Expression {
+                                    term: Term::VarName("x".to_owned()),
+                                    operations: vec![],
+                                },
+                                Expression {
+                                    term: Term::IntegerConstant(2),
+                                    operations: vec![],
+                                },
+                            ],
+                        })
+                    )],
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_class() {
+        let code = r#"
+        /** A point. */
+        class Point {
+            field int x, y;
+            static Point origin;
+
+            method int getX() { return x; }
+
+            function void main() {
+                var Point p;
+                if (~(p = null)) { do p.getX(); } else { let origin = p; }
+                return;
+            }
+        }"#;
+        let class = parse_class(code).unwrap();
+
+        assert_eq!(class.name, "Point");
+        assert_eq!(class.class_var_decs.len(), 2);
+        assert_eq!(class.class_var_decs[0].names, vec!["x", "y"]);
+        assert_eq!(class.subroutine_decs.len(), 2);
+        assert_eq!(class.subroutine_decs[1].kind, SubroutineKind::Function);
+        assert_eq!(class.subroutine_decs[1].return_type, None);
+        assert_eq!(class.subroutine_decs[1].statements.len(), 2);
+    }
+
+    #[test]
+    fn test_missing_semicolon() {
+        assert!(parse_class("class A { function void f() { return } }").is_err());
+    }
+}
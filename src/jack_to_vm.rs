@@ -0,0 +1,514 @@
+use hashbrown::HashMap;
+
+use crate::{
+    hardware::Word,
+    jack::*,
+    vm::{PopSegment, PushSegment, VMCommand},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    Static,
+    Field,
+    Argument,
+    Local,
+}
+
+impl SymbolKind {
+    fn push_segment(&self) -> PushSegment {
+        match self {
+            SymbolKind::Static => PushSegment::Static,
+            SymbolKind::Field => PushSegment::This,
+            SymbolKind::Argument => PushSegment::Argument,
+            SymbolKind::Local => PushSegment::Local,
+        }
+    }
+
+    fn pop_segment(&self) -> PopSegment {
+        match self {
+            SymbolKind::Static => PopSegment::Static,
+            SymbolKind::Field => PopSegment::This,
+            SymbolKind::Argument => PopSegment::Argument,
+            SymbolKind::Local => PopSegment::Local,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub var_type: Type,
+    pub kind: SymbolKind,
+    pub index: Word,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    class_scope: HashMap<String, Symbol>,
+    subroutine_scope: HashMap<String, Symbol>,
+}
+
+impl SymbolTable {
+    pub fn start_subroutine(&mut self) {
+        self.subroutine_scope.clear();
+    }
+
+    pub fn define(&mut self, name: &str, var_type: &Type, kind: SymbolKind) -> Result<(), String> {
+        let index = self.var_count(kind);
+        let scope = match kind {
+            SymbolKind::Static | SymbolKind::Field => &mut self.class_scope,
+            SymbolKind::Argument | SymbolKind::Local => &mut self.subroutine_scope,
+        };
+        if scope.contains_key(name) {
+            return Err(format!("duplicate declaration of {name}"));
+        }
+        scope.insert(
+            name.to_owned(),
+            Symbol {
+                var_type: var_type.clone(),
+                kind,
+                index,
+            },
+        );
+
+        Ok(())
+    }
+
+    pub fn var_count(&self, kind: SymbolKind) -> Word {
+        let scope = match kind {
+            SymbolKind::Static | SymbolKind::Field => &self.class_scope,
+            SymbolKind::Argument | SymbolKind::Local => &self.subroutine_scope,
+        };
+
+        scope.values().filter(|s| s.kind == kind).count() as Word
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.subroutine_scope
+            .get(name)
+            .or_else(|| self.class_scope.get(name))
+    }
+}
+
+struct ClassCompiler<'a> {
+    class_name: &'a str,
+    symbols: SymbolTable,
+    commands: Vec<VMCommand>,
+    subroutine_kind: SubroutineKind,
+    if_counter: usize,
+    while_counter: usize,
+}
+
+fn push(segment: PushSegment, offset: Word) -> VMCommand {
+    VMCommand::Push { segment, offset }
+}
+
+fn pop(segment: PopSegment, offset: Word) -> VMCommand {
+    VMCommand::Pop { segment, offset }
+}
+
+fn call(function_name: String, argument_count: usize) -> VMCommand {
+    VMCommand::Call {
+        function_name,
+        argument_count: argument_count as Word,
+    }
+}
+
+fn label(name: String) -> VMCommand {
+    VMCommand::Label { name }
+}
+
+fn goto(label_name: String) -> VMCommand {
+    VMCommand::Goto { label_name }
+}
+
+fn if_goto(label_name: String) -> VMCommand {
+    VMCommand::IfGoto { label_name }
+}
+
+impl ClassCompiler<'_> {
+    fn symbol(&self, name: &str) -> Result<&Symbol, String> {
+        let symbol = self
+            .symbols
+            .get(name)
+            .ok_or_else(|| format!("undefined variable {name}"))?;
+        if symbol.kind == SymbolKind::Field && self.subroutine_kind == SubroutineKind::Function {
+            return Err(format!("field {name} used in a function"));
+        }
+
+        Ok(symbol)
+    }
+
+    fn push_variable(&mut self, name: &str) -> Result<(), String> {
+        let symbol = self.symbol(name)?;
+        let command = push(symbol.kind.push_segment(), symbol.index);
+        self.commands.push(command);
+
+        Ok(())
+    }
+
+    fn subroutine(&mut self, subroutine: &SubroutineDec) -> Result<(), String> {
+        self.symbols.start_subroutine();
+        self.subroutine_kind = subroutine.kind;
+        self.if_counter = 0;
+        self.while_counter = 0;
+
+        if subroutine.kind == SubroutineKind::Method {
+            self.symbols.define(
+                "this",
+                &Type::ClassName(self.class_name.to_owned()),
+                SymbolKind::Argument,
+            )?;
+        }
+        for (var_type, name) in &subroutine.parameters {
+            self.symbols.define(name, var_type, SymbolKind::Argument)?;
+        }
+        for var_dec in &subroutine.var_decs {
+            for name in &var_dec.names {
+                self.symbols
+                    .define(name, &var_dec.var_type, SymbolKind::Local)?;
+            }
+        }
+
+        self.commands.push(VMCommand::Function {
+            name: format!("{}.{}", self.class_name, subroutine.name),
+            local_var_count: self.symbols.var_count(SymbolKind::Local),
+        });
+        match subroutine.kind {
+            SubroutineKind::Constructor => {
+                let field_count = self.symbols.var_count(SymbolKind::Field);
+                self.commands.extend([
+                    push(PushSegment::Constant, field_count),
+                    call("Memory.alloc".to_owned(), 1),
+                    pop(PopSegment::Pointer, 0),
+                ]);
+            }
+            SubroutineKind::Method => {
+                self.commands
+                    .extend([push(PushSegment::Argument, 0), pop(PopSegment::Pointer, 0)]);
+            }
+            SubroutineKind::Function => {}
+        }
+
+        self.statements(&subroutine.statements)
+            .map_err(|e| format!("{}.{}: {e}", self.class_name, subroutine.name))
+    }
+
+    fn statements(&mut self, statements: &[Statement]) -> Result<(), String> {
+        for statement in statements {
+            self.statement(statement)?;
+        }
+
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), String> {
+        match statement {
+            Statement::Let {
+                var_name,
+                index: None,
+                value,
+            } => {
+                self.expression(value)?;
+                let symbol = self.symbol(var_name)?;
+                let command = pop(symbol.kind.pop_segment(), symbol.index);
+                self.commands.push(command);
+            }
+            Statement::Let {
+                var_name,
+                index: Some(index),
+                value,
+            } => {
+                self.push_variable(var_name)?;
+                self.expression(index)?;
+                self.commands.push(VMCommand::Add);
+                self.expression(value)?;
+                self.commands.extend([
+                    pop(PopSegment::Temp, 0),
+                    pop(PopSegment::Pointer, 1),
+                    push(PushSegment::Temp, 0),
+                    pop(PopSegment::That, 0),
+                ]);
+            }
+            Statement::If {
+                condition,
+                if_statements,
+                else_statements,
+            } => {
+                let n = self.if_counter;
+                self.if_counter += 1;
+                self.expression(condition)?;
+                self.commands.extend([
+                    if_goto(format!("IF_TRUE{n}")),
+                    goto(format!("IF_FALSE{n}")),
+                    label(format!("IF_TRUE{n}")),
+                ]);
+                self.statements(if_statements)?;
+                if let Some(else_statements) = else_statements {
+                    self.commands
+                        .extend([goto(format!("IF_END{n}")), label(format!("IF_FALSE{n}"))]);
+                    self.statements(else_statements)?;
+                    self.commands.push(label(format!("IF_END{n}")));
+                } else {
+                    self.commands.push(label(format!("IF_FALSE{n}")));
+                }
+            }
+            Statement::While {
+                condition,
+                statements,
+            } => {
+                let n = self.while_counter;
+                self.while_counter += 1;
+                self.commands.push(label(format!("WHILE_EXP{n}")));
+                self.expression(condition)?;
+                self.commands
+                    .extend([VMCommand::Not, if_goto(format!("WHILE_END{n}"))]);
+                self.statements(statements)?;
+                self.commands.extend([
+                    goto(format!("WHILE_EXP{n}")),
+                    label(format!("WHILE_END{n}")),
+                ]);
+            }
+            Statement::Do(subroutine_call) => {
+                self.subroutine_call(subroutine_call)?;
+                self.commands.push(pop(PopSegment::Temp, 0));
+            }
+            Statement::Return(expression) => {
+                if let Some(expression) = expression {
+                    self.expression(expression)?;
+                } else {
+                    self.commands.push(push(PushSegment::Constant, 0));
+                }
+                self.commands.push(VMCommand::Return);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn expression(&mut self, expression: &Expression) -> Result<(), String> {
+        self.term(&expression.term)?;
+        for (op, term) in &expression.operations {
+            self.term(term)?;
+            self.commands.push(match op {
+                BinaryOp::Add => VMCommand::Add,
+                BinaryOp::Sub => VMCommand::Sub,
+                BinaryOp::Mul => call("Math.multiply".to_owned(), 2),
+                BinaryOp::Div => call("Math.divide".to_owned(), 2),
+                BinaryOp::And => VMCommand::And,
+                BinaryOp::Or => VMCommand::Or,
+                BinaryOp::Lt => VMCommand::Lt,
+                BinaryOp::Gt => VMCommand::Gt,
+                BinaryOp::Eq => VMCommand::Eq,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn term(&mut self, term: &Term) -> Result<(), String> {
+        match term {
+            Term::IntegerConstant(value) => self.commands.push(push(PushSegment::Constant, *value)),
+            Term::StringConstant(s) => {
+                self.commands.extend([
+                    push(PushSegment::Constant, s.chars().count() as Word),
+                    call("String.new".to_owned(), 1),
+                ]);
+                for c in s.chars() {
+                    self.commands.extend([
+                        push(PushSegment::Constant, c as Word),
+                        call("String.appendChar".to_owned(), 2),
+                    ]);
+                }
+            }
+            Term::KeywordConstant(KeywordConstant::True) => {
+                self.commands
+                    .extend([push(PushSegment::Constant, 0), VMCommand::Not]);
+            }
+            Term::KeywordConstant(KeywordConstant::False | KeywordConstant::Null) => {
+                self.commands.push(push(PushSegment::Constant, 0));
+            }
+            Term::KeywordConstant(KeywordConstant::This) => {
+                if self.subroutine_kind == SubroutineKind::Function {
+                    return Err("this used in a function".to_owned());
+                }
+                self.commands.push(push(PushSegment::Pointer, 0));
+            }
+            Term::VarName(name) => self.push_variable(name)?,
+            Term::ArrayAccess { var_name, index } => {
+                self.push_variable(var_name)?;
+                self.expression(index)?;
+                self.commands.extend([
+                    VMCommand::Add,
+                    pop(PopSegment::Pointer, 1),
+                    push(PushSegment::That, 0),
+                ]);
+            }
+            Term::SubroutineCall(subroutine_call) => self.subroutine_call(subroutine_call)?,
+            Term::Parenthesized(expression) => self.expression(expression)?,
+            Term::Unary(op, term) => {
+                self.term(term)?;
+                self.commands.push(match op {
+                    UnaryOp::Neg => VMCommand::Neg,
+                    UnaryOp::Not => VMCommand::Not,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn subroutine_call(&mut self, subroutine_call: &SubroutineCall) -> Result<(), String> {
+        let SubroutineCall {
+            receiver,
+            name,
+            arguments,
+        } = subroutine_call;
+        let (function_name, is_method) = match receiver {
+            None => {
+                if self.subroutine_kind == SubroutineKind::Function {
+                    return Err(format!("method {name} called from a function"));
+                }
+                self.commands.push(push(PushSegment::Pointer, 0));
+                (format!("{}.{name}", self.class_name), true)
+            }
+            Some(receiver) => match self.symbols.get(receiver) {
+                Some(Symbol {
+                    var_type: Type::ClassName(class_name),
+                    ..
+                }) => {
+                    let class_name = class_name.clone();
+                    self.push_variable(receiver)?;
+                    (format!("{class_name}.{name}"), true)
+                }
+                Some(symbol) => {
+                    return Err(format!(
+                        "cannot call {name} on {receiver} of type {}",
+                        symbol.var_type
+                    ));
+                }
+                None => (format!("{receiver}.{name}"), false),
+            },
+        };
+
+        for argument in arguments {
+            self.expression(argument)?;
+        }
+        self.commands
+            .push(call(function_name, arguments.len() + is_method as usize));
+
+        Ok(())
+    }
+}
+
+pub fn class_to_vm(class: &Class) -> Result<Vec<VMCommand>, String> {
+    let mut compiler = ClassCompiler {
+        class_name: &class.name,
+        symbols: SymbolTable::default(),
+        commands: vec![],
+        subroutine_kind: SubroutineKind::Function,
+        if_counter: 0,
+        while_counter: 0,
+    };
+
+    for class_var_dec in &class.class_var_decs {
+        let kind = match class_var_dec.kind {
+            ClassVarKind::Static => SymbolKind::Static,
+            ClassVarKind::Field => SymbolKind::Field,
+        };
+        for name in &class_var_dec.names {
+            compiler
+                .symbols
+                .define(name, &class_var_dec.var_type, kind)?;
+        }
+    }
+
+    for subroutine in &class.subroutine_decs {
+        compiler.subroutine(subroutine)?;
+    }
+
+    Ok(compiler.commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jack_parse::parse_class;
+    use crate::vm_parse::parse_commands;
+
+    #[test]
+    fn test_symbol_table() {
+        let mut symbols = SymbolTable::default();
+        symbols.define("a", &Type::Int, SymbolKind::Field).unwrap();
+        symbols.define("b", &Type::Int, SymbolKind::Static).unwrap();
+        symbols.define("c", &Type::Int, SymbolKind::Field).unwrap();
+        symbols.define("a", &Type::Char, SymbolKind::Local).unwrap();
+
+        assert_eq!(symbols.get("c").unwrap().index, 1);
+        assert_eq!(symbols.get("b").unwrap().index, 0);
+        assert_eq!(symbols.get("a").unwrap().kind, SymbolKind::Local);
+        assert!(symbols.define("b", &Type::Int, SymbolKind::Field).is_err());
+
+        symbols.start_subroutine();
+
+        assert_eq!(symbols.get("a").unwrap().kind, SymbolKind::Field);
+    }
+
+    #[test]
+    fn test_method() {
+        let class = parse_class(
+            r#"
+            class Counter {
+                field int count;
+                field Array items;
+
+                method void add(int x) {
+                    let items[count] = x;
+                    if (count < 10) {
+                        let count = count + 1;
+                    }
+                    return;
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let expected = parse_commands(
+            r#"
+            function Counter.add 0
+            push argument 0
+            pop pointer 0
+            push this 1
+            push this 0
+            add
+            push argument 1
+            pop temp 0
+            pop pointer 1
+            push temp 0
+            pop that 0
+            push this 0
+            push constant 10
+            lt
+            if-goto IF_TRUE0
+            goto IF_FALSE0
+            label IF_TRUE0
+            push this 0
+            push constant 1
+            add
+            pop this 0
+            label IF_FALSE0
+            push constant 0
+            return"#,
+        )
+        .unwrap()
+        .1;
+
+        assert_eq!(class_to_vm(&class), Ok(expected));
+    }
+
+    #[test]
+    fn test_undefined_variable() {
+        let class = parse_class("class A { function int f() { return x; } }").unwrap();
+
+        assert!(class_to_vm(&class).is_err());
+    }
+}
@@ -1,15 +1,32 @@
 pub(crate) mod characters;
 
+pub mod condition;
+pub mod condition_parse;
+pub mod coverage;
+pub mod hack_to_asm;
 pub mod hack_to_wasm;
 pub mod hardware;
 pub mod hardware_parse;
-mod os;
+pub mod hdl;
+pub mod hdl_parse;
+pub mod history;
+pub mod jack;
+pub mod jack_parse;
+pub mod jack_to_vm;
+pub mod os;
 pub(crate) mod parse_utils;
+pub mod profiler;
+pub mod snapshot;
+pub mod test_script;
+pub mod test_script_parse;
 pub mod vm;
 pub mod vm_parse;
+pub mod vm_validate;
+pub mod watchpoint;
 
 pub mod any_wasm;
 
+pub mod vm_to_hack;
 pub mod vm_to_wasm;
 pub mod wasm_hardware;
 // #[cfg(target_arch = "wasm32")]
@@ -1,16 +1,118 @@
+use std::collections::BTreeMap;
+
 use hashbrown::HashMap;
 
 use crate::{
     characters::character_bitmaps,
     hardware::{RAM, Word},
-    vm::{PushSegment, RunState},
+    snapshot::{Snapshot, to_word},
+    vm::{PushSegment, RunState, Trap},
 };
 
+/// How many steps `Sys.wait` waits for each millisecond.
+pub const WAIT_STEPS_PER_MILLISECOND: u64 = 1000;
+
+/// What the error codes of the Jack OS mean, whether they're passed to `Sys.error` or reported
+/// by the native OS.
+pub fn error_message(code: Word) -> Option<&'static str> {
+    Some(match code {
+        1 => "Sys.wait: duration must be positive",
+        2 => "Array.new: array size must be positive",
+        3 => "Math.divide: division by zero",
+        4 => "Math.sqrt: cannot compute the square root of a negative number",
+        5 => "Memory.alloc: allocated memory size must be positive",
+        6 => "Memory.alloc: heap overflow",
+        7 => "Screen.drawPixel: illegal pixel coordinates",
+        8 => "Screen.drawLine: illegal line coordinates",
+        9 => "Screen.drawRectangle: illegal rectangle coordinates",
+        12 => "Screen.drawCircle: illegal center coordinates",
+        13 => "Screen.drawCircle: illegal radius",
+        14 => "String.new: maximum length must be non-negative",
+        15 => "String.charAt: string index out of bounds",
+        16 => "String.setCharAt: string index out of bounds",
+        17 => "String.appendChar: string is full",
+        18 => "String.eraseLastChar: string is empty",
+        19 => "String.setInt: insufficient string capacity",
+        20 => "Output.moveCursor: illegal cursor location",
+        _ => return None,
+    })
+}
+
+/// The classes of the Jack OS, which the native OS implements.
+pub const OS_CLASSES: [&str; 8] = [
+    "Array", "Keyboard", "Math", "Memory", "Output", "Screen", "String", "Sys",
+];
+
+/// Where the functions of an OS class come from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClassPolicy {
+    /// The native functions run even if the program defines its own.
+    Native,
+    /// The program has to define the functions, e.g. when it's an OS being tested.
+    Program,
+    /// The functions which the program defines replace the native ones.
+    #[default]
+    PreferProgram,
+}
+
+impl ClassPolicy {
+    pub const ALL: [ClassPolicy; 3] = [
+        ClassPolicy::Native,
+        ClassPolicy::Program,
+        ClassPolicy::PreferProgram,
+    ];
+}
+
+impl std::fmt::Display for ClassPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ClassPolicy::Native => "native",
+            ClassPolicy::Program => "program",
+            ClassPolicy::PreferProgram => "prefer-program",
+        })
+    }
+}
+
+impl std::str::FromStr for ClassPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|policy| policy.to_string() == s)
+            .ok_or_else(|| format!("expected native, program or prefer-program, got {s}"))
+    }
+}
+
+/// The policy of each OS class, which defaults to `ClassPolicy::PreferProgram`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OsPolicy {
+    classes: HashMap<String, ClassPolicy>,
+}
+
+impl OsPolicy {
+    pub fn class(&self, class_name: &str) -> ClassPolicy {
+        self.classes.get(class_name).copied().unwrap_or_default()
+    }
+
+    pub fn set_class(&mut self, class_name: &str, policy: ClassPolicy) -> Result<(), String> {
+        if !OS_CLASSES.contains(&class_name) {
+            return Err(format!("{class_name} isn't an OS class"));
+        }
+        self.classes.insert(class_name.to_owned(), policy);
+
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub struct OS {
     memory: Memory,
     screen: Screen,
     output: Output,
+    keyboard: Keyboard,
+    /// The steps left until a `Sys.wait` in progress returns.
+    wait_steps: Option<u64>,
 }
 
 impl Default for OS {
@@ -19,147 +121,422 @@ impl Default for OS {
             memory: Memory::new(RAM::HEAP, RAM::SCREEN - RAM::HEAP),
             screen: Screen { color: true },
             output: Output { row: 0, col: 0 },
+            keyboard: Keyboard::default(),
+            wait_steps: None,
         }
     }
 }
 
-type Func = fn(&mut RunState) -> Word;
+/// What a call to an OS function did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OsCall {
+    /// The function isn't part of the OS.
+    NotOs,
+    /// The return value was pushed.
+    Returned,
+    /// The function is waiting, e.g. for a key, and has to be called again on the next step.
+    Blocked,
+    Halted,
+}
 
-impl RunState {
-    pub fn call_os(&mut self, function_name: &str) -> bool {
-        let function = match function_name {
-            "Math.init" => Self::noop,
-            "Math.multiply" => Self::math_multiply,
-            "Math.divide" => Self::math_divide,
-            "Math.min" => Self::math_min,
-            "Math.max" => Self::math_max,
-            "Math.sqrt" => Self::math_sqrt,
-            "Math.abs" => Self::math_abs,
-            "Array.new" => Self::memory_alloc,
-            "Array.dispose" => Self::memory_dealloc,
-            "Keyboard.keyPressed" => Self::keyboard_key_pressed,
-            "Screen.init" => Self::noop,
-            "Screen.clearScreen" => Self::screen_clear_screen,
-            "Screen.setColor" => Self::screen_set_color,
-            "Screen.drawPixel" => Self::screen_draw_pixel,
-            "Screen.drawLine" => Self::screen_draw_line,
-            "Screen.drawRectangle" => Self::screen_draw_rectangle,
-            "Screen.drawCircle" => Self::screen_draw_circle,
-            "Memory.init" => Self::noop,
-            "Memory.peek" => Self::memory_peek,
-            "Memory.poke" => Self::memory_poke,
-            "Memory.alloc" => Self::memory_alloc,
-            "Memory.deAlloc" => Self::memory_dealloc,
-            "String.new" => Self::string_new,
-            "String.dispose" => Self::memory_dealloc,
-            "String.length" => Self::string_length,
-            "String.charAt" => Self::string_char_at,
-            "String.setCharAt" => Self::string_set_char_at,
-            "String.appendChar" => Self::string_append_char,
-            "String.eraseLastChar" => Self::string_erase_last_char,
-            "String.intValue" => Self::string_int_value,
-            "String.setInt" => Self::string_set_int,
-            "String.backSpace" => Self::string_backspace,
-            "String.doubleQuote" => Self::string_double_quote,
-            "String.newLine" => Self::string_new_line,
-            "Output.init" => Self::noop,
-            "Output.moveCursor" => Self::output_move_cursor,
-            "Output.printChar" => Self::output_print_char,
-            "Output.printString" => Self::output_print_string,
-            "Output.printInt" => Self::output_print_int,
-            "Output.println" => Self::output_println,
-            "Output.backSpace" => Self::output_backspace,
-            "Sys.error" => {
-                panic!()
+/// A block of heap memory, with the address of its first word and its size in words.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeapBlock {
+    pub address: Word,
+    pub size: Word,
+}
+
+impl OS {
+    /// Starts or stops tracking freed blocks, so that freeing them again or accessing them is
+    /// caught.
+    pub fn set_sanitizing(&mut self, sanitizing: bool) {
+        if sanitizing != self.is_sanitizing() {
+            self.memory.freed = sanitizing.then(BTreeMap::new);
+        }
+    }
+
+    pub fn is_sanitizing(&self) -> bool {
+        self.memory.freed.is_some()
+    }
+
+    pub fn is_heap_in_ram(&self) -> bool {
+        self.memory.in_ram
+    }
+
+    /// With the sanitizer on, checks that an access to `address` in the heap is inside a live
+    /// block.
+    pub fn check_heap_access(&self, address: Word) -> Result<(), Trap> {
+        let memory = &self.memory;
+        let Some(freed) = &memory.freed else {
+            return Ok(());
+        };
+        if !(RAM::HEAP..memory.end).contains(&address) {
+            return Ok(());
+        }
+        let live = memory.allocs.range(..=address).next_back();
+        if live.is_some_and(|(&start, &size)| address < start + size) {
+            return Ok(());
+        }
+
+        match freed
+            .range(..=address)
+            .next_back()
+            .filter(|&(&start, &(size, _))| address < start + size)
+        {
+            Some((&block, &(_, freed_at))) => Err(Trap::UseAfterFree {
+                address,
+                block,
+                freed_at,
+            }),
+            None => Err(Trap::InvalidHeapAccess(address)),
+        }
+    }
+
+    /// The live allocations in address order, with the sizes they were allocated with.
+    pub fn allocations(&self) -> Vec<HeapBlock> {
+        self.memory
+            .allocs
+            .iter()
+            .map(|(&address, &size)| HeapBlock { address, size })
+            .collect()
+    }
+
+    /// The free space in address order, where each hole's address is where a block allocated
+    /// from it would start.
+    pub fn holes(&self) -> Vec<HeapBlock> {
+        self.memory
+            .segments
+            .iter()
+            .filter(|&(_, &free)| free > 0)
+            .map(|(&start, &free)| HeapBlock {
+                address: start + SEGMENT_HEADER_SIZE,
+                size: free,
+            })
+            .collect()
+    }
+
+    /// The share of the free words outside the largest hole, from 0 to 1.
+    pub fn fragmentation(&self) -> f64 {
+        let holes = self.holes();
+        let free = holes.iter().map(|hole| hole.size as f64).sum::<f64>();
+        let largest = holes.iter().map(|hole| hole.size).max().unwrap_or(0);
+
+        if free == 0.0 {
+            0.0
+        } else {
+            1.0 - largest as f64 / free
+        }
+    }
+
+    /// A summary of the heap followed by the live allocations, which are leaks once the program
+    /// has ended.
+    pub fn heap_report(&self) -> String {
+        let allocations = self.allocations();
+        let holes = self.holes();
+        let mut report = format!(
+            "{} allocations using {} words, {} holes with {} free words, {:.1}% fragmented\n",
+            allocations.len(),
+            allocations
+                .iter()
+                .map(|block| block.size as i64)
+                .sum::<i64>(),
+            holes.len(),
+            holes.iter().map(|hole| hole.size as i64).sum::<i64>(),
+            self.fragmentation() * 100.0
+        );
+        for block in allocations {
+            report += &format!("{:>5}: {} words\n", block.address, block.size);
+        }
+
+        report
+    }
+
+    pub fn save(&self, snapshot: &mut Snapshot) {
+        let pairs = |map: &BTreeMap<Word, Word>| {
+            map.iter()
+                .flat_map(|(&k, &v)| [k as i64, v as i64])
+                .collect::<Vec<_>>()
+        };
+        snapshot.push("os_color", [self.screen.color as i64]);
+        snapshot.push(
+            "os_cursor",
+            [self.output.row as i64, self.output.col as i64],
+        );
+        snapshot.push("os_segments", pairs(&self.memory.segments));
+        snapshot.push("os_allocs", pairs(&self.memory.allocs));
+        snapshot.push("os_heap_in_ram", [self.memory.in_ram as i64]);
+        let keyboard = &self.keyboard;
+        snapshot.push(
+            "os_keyboard",
+            [keyboard.key as i64, keyboard.cursor_shown as i64],
+        );
+        if let Some(line) = &keyboard.line {
+            snapshot.push("os_line", line.iter().map(|&c| c as i64));
+        }
+        if let Some(wait_steps) = self.wait_steps {
+            snapshot.push("os_wait", [wait_steps as i64]);
+        }
+    }
+
+    pub fn restore(snapshot: &Snapshot) -> Result<Self, String> {
+        let pairs = |key: &str| {
+            let values = snapshot.get(key)?;
+            if values.len() % 2 != 0 {
+                return Err(format!("{key} should have pairs of values"));
             }
-            _ => return false,
+            values
+                .chunks(2)
+                .map(|pair| Ok((to_word(pair[0])?, to_word(pair[1])?)))
+                .collect::<Result<Vec<_>, String>>()
+        };
+        let [row, col] = *snapshot.get("os_cursor")? else {
+            return Err("os_cursor should have a row and a column".to_owned());
         };
+        // Snapshots taken before the keyboard was implemented don't have its state.
+        let [key, cursor_shown] = *snapshot.get("os_keyboard").unwrap_or(&[0, 0]) else {
+            return Err("os_keyboard should have a key and whether the cursor is shown".to_owned());
+        };
+        let line = snapshot
+            .get("os_line")
+            .ok()
+            .map(|line| line.iter().map(|&c| to_word(c)).collect())
+            .transpose()?;
+        let wait_steps = snapshot
+            .get_one("os_wait")
+            .ok()
+            .map(|steps| u64::try_from(steps).map_err(|e| format!("os_wait: {e}")))
+            .transpose()?;
+
+        Ok(Self {
+            memory: Memory {
+                segments: pairs("os_segments")?.into_iter().collect(),
+                allocs: pairs("os_allocs")?.into_iter().collect(),
+                end: RAM::SCREEN,
+                in_ram: snapshot.get_one("os_heap_in_ram")? != 0,
+                freed: None,
+            },
+            screen: Screen {
+                color: snapshot.get_one("os_color")? != 0,
+            },
+            output: Output {
+                row: to_word(row)?,
+                col: to_word(col)?,
+            },
+            keyboard: Keyboard {
+                key: to_word(key)?,
+                cursor_shown: cursor_shown != 0,
+                line,
+            },
+            wait_steps,
+        })
+    }
+}
 
-        self.call(function);
-        true
+type Func = fn(&mut RunState) -> Result<Word, Trap>;
+/// A function which returns `None` while it's waiting.
+type BlockingFunc = fn(&mut RunState) -> Result<Option<Word>, Trap>;
+
+const SCREEN_WIDTH: Word = 512;
+const SCREEN_HEIGHT: Word = 256;
+
+/// How a native OS function runs.
+enum OsFunction {
+    Returning(Func),
+    Blocking(BlockingFunc),
+    Halt,
+}
+
+/// How many arguments the native OS function `function_name` takes, if there is one.
+pub fn os_argument_count(function_name: &str) -> Option<Word> {
+    RunState::os_function(function_name).map(|(_, argument_count)| argument_count)
+}
+
+impl RunState {
+    /// Runs `function_name` natively if it's part of the OS.
+    pub fn call_os(&mut self, function_name: &str) -> Result<OsCall, Trap> {
+        let Some((function, _)) = Self::os_function(function_name) else {
+            return Ok(OsCall::NotOs);
+        };
+        let return_value = match function {
+            OsFunction::Returning(function) => function(self)?,
+            OsFunction::Blocking(function) => match function(self)? {
+                Some(return_value) => return_value,
+                None => return Ok(OsCall::Blocked),
+            },
+            OsFunction::Halt => return Ok(OsCall::Halted),
+        };
+        self.ram.push(return_value)?;
+
+        Ok(OsCall::Returned)
+    }
+
+    fn os_function(function_name: &str) -> Option<(OsFunction, Word)> {
+        Some(match function_name {
+            "Math.init" => (OsFunction::Returning(Self::noop), 0),
+            "Math.multiply" => (OsFunction::Returning(Self::math_multiply), 2),
+            "Math.divide" => (OsFunction::Returning(Self::math_divide), 2),
+            "Math.min" => (OsFunction::Returning(Self::math_min), 2),
+            "Math.max" => (OsFunction::Returning(Self::math_max), 2),
+            "Math.sqrt" => (OsFunction::Returning(Self::math_sqrt), 1),
+            "Math.abs" => (OsFunction::Returning(Self::math_abs), 1),
+            "Array.new" => (OsFunction::Returning(Self::array_new), 1),
+            "Array.dispose" => (OsFunction::Returning(Self::memory_dealloc), 1),
+            "Keyboard.init" => (OsFunction::Returning(Self::noop), 0),
+            "Keyboard.keyPressed" => (OsFunction::Returning(Self::keyboard_key_pressed), 0),
+            "Keyboard.readChar" => (OsFunction::Blocking(Self::keyboard_read_char), 0),
+            "Keyboard.readLine" => (OsFunction::Blocking(Self::keyboard_read_line), 1),
+            "Keyboard.readInt" => (OsFunction::Blocking(Self::keyboard_read_int), 1),
+            "Screen.init" => (OsFunction::Returning(Self::noop), 0),
+            "Screen.clearScreen" => (OsFunction::Returning(Self::screen_clear_screen), 0),
+            "Screen.setColor" => (OsFunction::Returning(Self::screen_set_color), 1),
+            "Screen.drawPixel" => (OsFunction::Returning(Self::screen_draw_pixel), 2),
+            "Screen.drawLine" => (OsFunction::Returning(Self::screen_draw_line), 4),
+            "Screen.drawRectangle" => (OsFunction::Returning(Self::screen_draw_rectangle), 4),
+            "Screen.drawCircle" => (OsFunction::Returning(Self::screen_draw_circle), 3),
+            "Memory.init" => (OsFunction::Returning(Self::noop), 0),
+            "Memory.peek" => (OsFunction::Returning(Self::memory_peek), 1),
+            "Memory.poke" => (OsFunction::Returning(Self::memory_poke), 2),
+            "Memory.alloc" => (OsFunction::Returning(Self::memory_alloc), 1),
+            "Memory.deAlloc" => (OsFunction::Returning(Self::memory_dealloc), 1),
+            "String.new" => (OsFunction::Returning(Self::string_new), 1),
+            "String.dispose" => (OsFunction::Returning(Self::memory_dealloc), 1),
+            "String.length" => (OsFunction::Returning(Self::string_length), 1),
+            "String.charAt" => (OsFunction::Returning(Self::string_char_at), 2),
+            "String.setCharAt" => (OsFunction::Returning(Self::string_set_char_at), 3),
+            "String.appendChar" => (OsFunction::Returning(Self::string_append_char), 2),
+            "String.eraseLastChar" => (OsFunction::Returning(Self::string_erase_last_char), 1),
+            "String.intValue" => (OsFunction::Returning(Self::string_int_value), 1),
+            "String.setInt" => (OsFunction::Returning(Self::string_set_int), 2),
+            "String.backSpace" => (OsFunction::Returning(Self::string_backspace), 0),
+            "String.doubleQuote" => (OsFunction::Returning(Self::string_double_quote), 0),
+            "String.newLine" => (OsFunction::Returning(Self::string_new_line), 0),
+            "Output.init" => (OsFunction::Returning(Self::noop), 0),
+            "Output.moveCursor" => (OsFunction::Returning(Self::output_move_cursor), 2),
+            "Output.printChar" => (OsFunction::Returning(Self::output_print_char), 1),
+            "Output.printString" => (OsFunction::Returning(Self::output_print_string), 1),
+            "Output.printInt" => (OsFunction::Returning(Self::output_print_int), 1),
+            "Output.println" => (OsFunction::Returning(Self::output_println), 0),
+            "Output.backSpace" => (OsFunction::Returning(Self::output_backspace), 0),
+            "Sys.error" => (OsFunction::Returning(Self::sys_error), 1),
+            "Sys.wait" => (OsFunction::Blocking(Self::sys_wait), 1),
+            "Sys.halt" => (OsFunction::Halt, 0),
+            _ => return None,
+        })
     }
 
-    fn call(&mut self, f: Func) {
-        let return_value = f(self);
-        self.ram.push(return_value);
+    fn noop(&mut self) -> Result<Word, Trap> {
+        Ok(0)
     }
 
-    fn noop(&mut self) -> Word {
-        0
+    fn sys_error(&mut self) -> Result<Word, Trap> {
+        let code = self.ram.get(0, PushSegment::Argument, 0)?;
+
+        Err(Trap::SysError(code))
     }
 
-    fn math_multiply(&mut self) -> Word {
-        let x = self.ram.get(0, PushSegment::Argument, 0);
-        let y = self.ram.get(0, PushSegment::Argument, 1);
+    fn sys_wait(&mut self) -> Result<Option<Word>, Trap> {
+        let duration = self.ram.get(0, PushSegment::Argument, 0)?;
+        if duration < 0 {
+            return Err(Trap::InvalidArgument(1));
+        }
+
+        let wait_steps = self
+            .os
+            .wait_steps
+            .get_or_insert(duration as u64 * WAIT_STEPS_PER_MILLISECOND);
+        if *wait_steps == 0 {
+            self.os.wait_steps = None;
+            return Ok(Some(0));
+        }
+        *wait_steps -= 1;
 
-        x * y
+        Ok(None)
     }
 
-    fn math_divide(&mut self) -> Word {
-        let x = self.ram.get(0, PushSegment::Argument, 0);
-        let y = self.ram.get(0, PushSegment::Argument, 1);
+    fn math_multiply(&mut self) -> Result<Word, Trap> {
+        let x = self.ram.get(0, PushSegment::Argument, 0)?;
+        let y = self.ram.get(0, PushSegment::Argument, 1)?;
+
+        Ok(x.wrapping_mul(y))
+    }
+
+    fn math_divide(&mut self) -> Result<Word, Trap> {
+        let x = self.ram.get(0, PushSegment::Argument, 0)?;
+        let y = self.ram.get(0, PushSegment::Argument, 1)?;
 
         if y == 0 {
-            panic!()
+            return Err(Trap::DivisionByZero);
         }
 
-        x / y
+        Ok(x.wrapping_div(y))
     }
 
-    fn math_min(&mut self) -> Word {
-        let x = self.ram.get(0, PushSegment::Argument, 0);
-        let y = self.ram.get(0, PushSegment::Argument, 1);
+    fn math_min(&mut self) -> Result<Word, Trap> {
+        let x = self.ram.get(0, PushSegment::Argument, 0)?;
+        let y = self.ram.get(0, PushSegment::Argument, 1)?;
 
-        x.min(y)
+        Ok(x.min(y))
     }
 
-    fn math_max(&mut self) -> Word {
-        let x = self.ram.get(0, PushSegment::Argument, 0);
-        let y = self.ram.get(0, PushSegment::Argument, 1);
+    fn math_max(&mut self) -> Result<Word, Trap> {
+        let x = self.ram.get(0, PushSegment::Argument, 0)?;
+        let y = self.ram.get(0, PushSegment::Argument, 1)?;
 
-        x.max(y)
+        Ok(x.max(y))
     }
 
-    fn math_sqrt(&mut self) -> Word {
-        let x = self.ram.get(0, PushSegment::Argument, 0);
+    fn math_sqrt(&mut self) -> Result<Word, Trap> {
+        let x = self.ram.get(0, PushSegment::Argument, 0)?;
 
         if x < 0 {
-            panic!();
+            return Err(Trap::InvalidArgument(4));
         }
 
-        (x as f64).sqrt().floor() as Word
+        Ok((x as f64).sqrt().floor() as Word)
     }
 
-    fn math_abs(&mut self) -> Word {
-        let x = self.ram.get(0, PushSegment::Argument, 0);
-        x.abs()
+    fn math_abs(&mut self) -> Result<Word, Trap> {
+        let x = self.ram.get(0, PushSegment::Argument, 0)?;
+        Ok(x.wrapping_abs())
     }
 
-    fn screen_clear_screen(&mut self) -> Word {
-        self.ram.contents[(RAM::SCREEN as usize)..(RAM::KBD as usize)].fill(0);
+    fn screen_clear_screen(&mut self) -> Result<Word, Trap> {
+        for address in RAM::SCREEN..RAM::KBD {
+            self.ram[address] = 0;
+        }
 
-        0
+        Ok(0)
     }
 
-    fn screen_set_color(&mut self) -> Word {
-        self.os.screen.color = self.ram.get(0, PushSegment::Argument, 0) != 0;
+    fn screen_set_color(&mut self) -> Result<Word, Trap> {
+        self.os.screen.color = self.ram.get(0, PushSegment::Argument, 0)? != 0;
+
+        Ok(0)
+    }
 
-        0
+    fn is_on_screen(x: Word, y: Word) -> bool {
+        (0..SCREEN_WIDTH).contains(&x) && (0..SCREEN_HEIGHT).contains(&y)
     }
 
-    fn screen_draw_pixel(&mut self) -> Word {
-        let x = self.ram.get(0, PushSegment::Argument, 0);
-        let y = self.ram.get(0, PushSegment::Argument, 1);
+    fn screen_draw_pixel(&mut self) -> Result<Word, Trap> {
+        let x = self.ram.get(0, PushSegment::Argument, 0)?;
+        let y = self.ram.get(0, PushSegment::Argument, 1)?;
+        if !Self::is_on_screen(x, y) {
+            return Err(Trap::InvalidArgument(7));
+        }
         self.ram.set_pixel(x, y, self.os.screen.color);
 
-        0
+        Ok(0)
     }
 
-    fn screen_draw_line(&mut self) -> Word {
-        let x1 = self.ram.get(0, PushSegment::Argument, 0);
-        let y1 = self.ram.get(0, PushSegment::Argument, 1);
-        let x2 = self.ram.get(0, PushSegment::Argument, 2);
-        let y2 = self.ram.get(0, PushSegment::Argument, 3);
+    fn screen_draw_line(&mut self) -> Result<Word, Trap> {
+        let x1 = self.ram.get(0, PushSegment::Argument, 0)?;
+        let y1 = self.ram.get(0, PushSegment::Argument, 1)?;
+        let x2 = self.ram.get(0, PushSegment::Argument, 2)?;
+        let y2 = self.ram.get(0, PushSegment::Argument, 3)?;
+        if !Self::is_on_screen(x1, y1) || !Self::is_on_screen(x2, y2) {
+            return Err(Trap::InvalidArgument(8));
+        }
 
         let dx = (x2 - x1).abs();
         let sx = (x2 - x1).signum();
@@ -191,14 +568,17 @@ impl RunState {
                 y += sy
             }
         }
-        0
+        Ok(0)
     }
 
-    fn screen_draw_rectangle(&mut self) -> Word {
-        let x1 = self.ram.get(0, PushSegment::Argument, 0);
-        let y1 = self.ram.get(0, PushSegment::Argument, 1);
-        let x2 = self.ram.get(0, PushSegment::Argument, 2);
-        let y2 = self.ram.get(0, PushSegment::Argument, 3);
+    fn screen_draw_rectangle(&mut self) -> Result<Word, Trap> {
+        let x1 = self.ram.get(0, PushSegment::Argument, 0)?;
+        let y1 = self.ram.get(0, PushSegment::Argument, 1)?;
+        let x2 = self.ram.get(0, PushSegment::Argument, 2)?;
+        let y2 = self.ram.get(0, PushSegment::Argument, 3)?;
+        if !Self::is_on_screen(x1, y1) || !Self::is_on_screen(x2, y2) {
+            return Err(Trap::InvalidArgument(9));
+        }
 
         for y in y1..y2 {
             for x in x1..=x2 {
@@ -206,169 +586,258 @@ impl RunState {
             }
         }
 
-        0
+        Ok(0)
     }
 
-    fn screen_draw_circle(&mut self) -> Word {
-        let center_x = self.ram.get(0, PushSegment::Argument, 0);
-        let center_y = self.ram.get(0, PushSegment::Argument, 1);
-        let radius = self.ram.get(0, PushSegment::Argument, 2);
+    fn screen_draw_circle(&mut self) -> Result<Word, Trap> {
+        let center_x = self.ram.get(0, PushSegment::Argument, 0)?;
+        let center_y = self.ram.get(0, PushSegment::Argument, 1)?;
+        let radius = self.ram.get(0, PushSegment::Argument, 2)?;
+        if !Self::is_on_screen(center_x, center_y) {
+            return Err(Trap::InvalidArgument(12));
+        }
+        if !(0..=181).contains(&radius) {
+            return Err(Trap::InvalidArgument(13));
+        }
+
         let r2 = radius * radius;
         for y in (center_y - radius)..=(center_y + radius) {
             let y2 = (y - center_y).abs() * (y - center_y).abs();
             let x_dist = ((r2 - y2).abs() as f64).sqrt().floor() as Word;
             for x in (center_x - x_dist)..=(center_x + x_dist) {
-                self.ram.set_pixel(x, y, self.os.screen.color);
+                if Self::is_on_screen(x, y) {
+                    self.ram.set_pixel(x, y, self.os.screen.color);
+                }
             }
         }
 
-        0
+        Ok(0)
+    }
+
+    fn keyboard_key_pressed(&mut self) -> Result<Word, Trap> {
+        Ok(self.ram[RAM::KBD])
+    }
+
+    fn keyboard_read_char(&mut self) -> Result<Option<Word>, Trap> {
+        Ok(Keyboard::read_char(self))
+    }
+
+    fn keyboard_read_line(&mut self) -> Result<Option<Word>, Trap> {
+        let Some(line) = Keyboard::read_line(self)? else {
+            return Ok(None);
+        };
+        let s = VMString::new(self, line.len() as Word)?;
+        for c in line {
+            s.append_char(self, c)?;
+        }
+
+        Ok(Some(s.address))
     }
 
-    fn keyboard_key_pressed(&mut self) -> Word {
-        self.ram[RAM::KBD]
+    fn keyboard_read_int(&mut self) -> Result<Option<Word>, Trap> {
+        Ok(Keyboard::read_line(self)?.map(|line| int_value(&line)))
     }
 
-    fn memory_peek(&mut self) -> Word {
-        let address = self.ram.get(0, PushSegment::Argument, 0);
+    fn memory_peek(&mut self) -> Result<Word, Trap> {
+        let address = self.ram.get(0, PushSegment::Argument, 0)?;
 
-        self.ram[address]
+        self.ram.read_at(address, 0)
     }
 
-    fn memory_poke(&mut self) -> Word {
-        let address = self.ram.get(0, PushSegment::Argument, 0);
-        let value = self.ram.get(0, PushSegment::Argument, 1);
+    fn memory_poke(&mut self) -> Result<Word, Trap> {
+        let address = self.ram.get(0, PushSegment::Argument, 0)?;
+        let value = self.ram.get(0, PushSegment::Argument, 1)?;
 
-        self.ram[address] = value;
+        self.ram.write_at(address, 0, value)?;
 
-        0
+        Ok(0)
     }
 
-    fn memory_alloc(&mut self) -> Word {
-        let size = self.ram.get(0, PushSegment::Argument, 0);
-        self.os.memory.alloc(size).unwrap()
+    fn array_new(&mut self) -> Result<Word, Trap> {
+        let size = self.ram.get(0, PushSegment::Argument, 0)?;
+        if size <= 0 {
+            return Err(Trap::InvalidArgument(2));
+        }
+
+        self.heap_alloc(size)
     }
 
-    fn memory_dealloc(&mut self) -> Word {
-        let object = self.ram.get(0, PushSegment::Argument, 0);
-        if self.os.memory.dealloc(object) {
-            0
-        } else {
-            panic!()
+    fn memory_alloc(&mut self) -> Result<Word, Trap> {
+        let size = self.ram.get(0, PushSegment::Argument, 0)?;
+        if size <= 0 {
+            return Err(Trap::InvalidArgument(5));
         }
+
+        self.heap_alloc(size)
     }
 
-    fn string_new(&mut self) -> Word {
-        let initial_capacity = self.ram.get(0, PushSegment::Argument, 0);
-        if let Some(s) = VMString::new(self, initial_capacity) {
-            s.address
-        } else {
-            panic!()
+    fn memory_dealloc(&mut self) -> Result<Word, Trap> {
+        let object = self.ram.get(0, PushSegment::Argument, 0)?;
+        let memory = &mut self.os.memory;
+        let size = memory.allocs.get(&object).copied();
+        if !memory.dealloc(object) {
+            return Err(
+                match memory.freed.as_ref().and_then(|freed| freed.get(&object)) {
+                    Some(&(_, freed_at)) => Trap::DoubleFree {
+                        address: object,
+                        freed_at,
+                    },
+                    None => Trap::InvalidDealloc(object),
+                },
+            );
+        }
+        if let (Some(freed), Some(size)) = (&mut memory.freed, size) {
+            freed.insert(object, (size, self.current_command_index));
+        }
+        self.write_segment_headers(object - SEGMENT_HEADER_SIZE)?;
+
+        Ok(0)
+    }
+
+    fn heap_alloc(&mut self, size: Word) -> Result<Word, Trap> {
+        let address = self.os.memory.alloc(size).ok_or(Trap::OutOfMemory(size))?;
+        self.write_segment_headers(address - SEGMENT_HEADER_SIZE)?;
+
+        Ok(address)
+    }
+
+    /// Writes the headers of the segment at `start` and the one after it, if the heap is in RAM.
+    fn write_segment_headers(&mut self, start: Word) -> Result<(), Trap> {
+        let memory = &self.os.memory;
+        if !memory.in_ram {
+            return Ok(());
         }
+        let next_start = memory.next_segment(start).map(|(next_start, _)| next_start);
+        for start in [Some(start), next_start].into_iter().flatten() {
+            let header = self.os.memory.header(start);
+            for (offset, value) in header.into_iter().enumerate() {
+                self.ram.write_at(start, offset as Word, value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the segment headers of the heap in RAM like the Jack OS does, so that programs and
+    /// the RAM view can inspect them, or only in the native OS.
+    pub fn set_heap_in_ram(&mut self, in_ram: bool) -> Result<(), Trap> {
+        self.os.memory.in_ram = in_ram;
+        let starts = self.os.memory.segments.keys().copied().collect::<Vec<_>>();
+        for start in starts {
+            self.write_segment_headers(start)?;
+        }
+
+        Ok(())
+    }
+
+    fn string_new(&mut self) -> Result<Word, Trap> {
+        let initial_capacity = self.ram.get(0, PushSegment::Argument, 0)?;
+        Ok(VMString::new(self, initial_capacity)?.address)
     }
 
-    fn string_length(&mut self) -> Word {
-        let address = self.ram.get(0, PushSegment::Argument, 0);
+    fn string_length(&mut self) -> Result<Word, Trap> {
+        let address = self.ram.get(0, PushSegment::Argument, 0)?;
         VMString { address }.length(self)
     }
 
-    fn string_char_at(&mut self) -> Word {
-        let address = self.ram.get(0, PushSegment::Argument, 0);
-        let index = self.ram.get(0, PushSegment::Argument, 1);
-        VMString { address }.char_at(self, index).unwrap()
+    fn string_char_at(&mut self) -> Result<Word, Trap> {
+        let address = self.ram.get(0, PushSegment::Argument, 0)?;
+        let index = self.ram.get(0, PushSegment::Argument, 1)?;
+        VMString { address }.char_at(self, index)
     }
 
-    fn string_set_char_at(&mut self) -> Word {
-        let address = self.ram.get(0, PushSegment::Argument, 0);
-        let index = self.ram.get(0, PushSegment::Argument, 1);
-        let new_value = self.ram.get(0, PushSegment::Argument, 2);
-        let result = VMString { address }.set_char_at(self, index, new_value);
-        if result.is_some() { 0 } else { panic!() }
+    fn string_set_char_at(&mut self) -> Result<Word, Trap> {
+        let address = self.ram.get(0, PushSegment::Argument, 0)?;
+        let index = self.ram.get(0, PushSegment::Argument, 1)?;
+        let new_value = self.ram.get(0, PushSegment::Argument, 2)?;
+        VMString { address }.set_char_at(self, index, new_value)?;
+
+        Ok(0)
     }
 
-    fn string_append_char(&mut self) -> Word {
-        let address = self.ram.get(0, PushSegment::Argument, 0);
-        let new_char = self.ram.get(0, PushSegment::Argument, 1);
-        let result = VMString { address }.append_char(self, new_char);
+    fn string_append_char(&mut self) -> Result<Word, Trap> {
+        let address = self.ram.get(0, PushSegment::Argument, 0)?;
+        let new_char = self.ram.get(0, PushSegment::Argument, 1)?;
+        VMString { address }.append_char(self, new_char)?;
 
-        if result.is_some() { address } else { panic!() }
+        Ok(address)
     }
 
-    fn string_erase_last_char(&mut self) -> Word {
-        let address = self.ram.get(0, PushSegment::Argument, 0);
-        let result = VMString { address }.erase_last_char(self);
+    fn string_erase_last_char(&mut self) -> Result<Word, Trap> {
+        let address = self.ram.get(0, PushSegment::Argument, 0)?;
+        VMString { address }.erase_last_char(self)?;
 
-        if result.is_some() { 0 } else { panic!() }
+        Ok(0)
     }
 
-    fn string_int_value(&mut self) -> Word {
-        let address = self.ram.get(0, PushSegment::Argument, 0);
-        VMString { address }.int_value(self).unwrap()
+    fn string_int_value(&mut self) -> Result<Word, Trap> {
+        let address = self.ram.get(0, PushSegment::Argument, 0)?;
+        VMString { address }.int_value(self)
     }
 
-    fn string_set_int(&mut self) -> Word {
-        let address = self.ram.get(0, PushSegment::Argument, 0);
-        let value = self.ram.get(0, PushSegment::Argument, 1);
-        let result = VMString { address }.set_int(self, value);
+    fn string_set_int(&mut self) -> Result<Word, Trap> {
+        let address = self.ram.get(0, PushSegment::Argument, 0)?;
+        let value = self.ram.get(0, PushSegment::Argument, 1)?;
+        VMString { address }.set_int(self, value)?;
 
-        if result.is_some() { 0 } else { panic!() }
+        Ok(0)
     }
 
-    fn string_backspace(&mut self) -> Word {
-        129
+    fn string_backspace(&mut self) -> Result<Word, Trap> {
+        Ok(129)
     }
 
-    fn string_double_quote(&mut self) -> Word {
-        34
+    fn string_double_quote(&mut self) -> Result<Word, Trap> {
+        Ok(34)
     }
 
-    fn string_new_line(&mut self) -> Word {
-        128
+    fn string_new_line(&mut self) -> Result<Word, Trap> {
+        Ok(128)
     }
 
-    fn output_move_cursor(&mut self) -> Word {
-        let row = self.ram.get(0, PushSegment::Argument, 0);
-        let col = self.ram.get(0, PushSegment::Argument, 1);
+    fn output_move_cursor(&mut self) -> Result<Word, Trap> {
+        let row = self.ram.get(0, PushSegment::Argument, 0)?;
+        let col = self.ram.get(0, PushSegment::Argument, 1)?;
 
         if Output::move_cursor(self, row, col).is_some() {
-            0
+            Ok(0)
         } else {
-            panic!()
+            Err(Trap::InvalidArgument(20))
         }
     }
 
-    fn output_print_char(&mut self) -> Word {
-        let c = self.ram.get(0, PushSegment::Argument, 0);
+    fn output_print_char(&mut self) -> Result<Word, Trap> {
+        let c = self.ram.get(0, PushSegment::Argument, 0)?;
         Output::print_char(self, c);
 
-        0
+        Ok(0)
     }
 
-    fn output_print_string(&mut self) -> Word {
-        let address = self.ram.get(0, PushSegment::Argument, 0);
+    fn output_print_string(&mut self) -> Result<Word, Trap> {
+        let address = self.ram.get(0, PushSegment::Argument, 0)?;
         let s = VMString { address };
-        Output::print_string(self, s);
+        Output::print_string(self, s)?;
 
-        0
+        Ok(0)
     }
 
-    fn output_print_int(&mut self) -> Word {
-        let value = self.ram.get(0, PushSegment::Argument, 0);
+    fn output_print_int(&mut self) -> Result<Word, Trap> {
+        let value = self.ram.get(0, PushSegment::Argument, 0)?;
         Output::print_int(self, value);
 
-        0
+        Ok(0)
     }
 
-    fn output_println(&mut self) -> Word {
+    fn output_println(&mut self) -> Result<Word, Trap> {
         self.os.output.println();
 
-        0
+        Ok(0)
     }
 
-    fn output_backspace(&mut self) -> Word {
+    fn output_backspace(&mut self) -> Result<Word, Trap> {
         Output::backspace(self);
 
-        0
+        Ok(0)
     }
 }
 
@@ -402,7 +871,7 @@ impl Output {
     }
 
     fn move_cursor(run_state: &mut RunState, row: Word, col: Word) -> Option<()> {
-        if !(0..=22).contains(&row) || !(0..63).contains(&col) {
+        if !(0..=22).contains(&row) || !(0..=63).contains(&col) {
             return None;
         }
 
@@ -431,11 +900,11 @@ impl Output {
     }
 
     fn print_char(run_state: &mut RunState, c: Word) {
-        if c == run_state.string_new_line() {
+        if c == 128 {
             return run_state.os.output.println();
         }
 
-        if c == run_state.string_backspace() {
+        if c == 129 {
             return Self::backspace(run_state);
         }
 
@@ -449,11 +918,13 @@ impl Output {
         };
     }
 
-    fn print_string(run_state: &mut RunState, s: VMString) {
-        for i in 0..s.length(run_state) {
-            let c = s.char_at(run_state, i).unwrap();
+    fn print_string(run_state: &mut RunState, s: VMString) -> Result<(), Trap> {
+        for i in 0..s.length(run_state)? {
+            let c = s.char_at(run_state, i)?;
             Self::print_char(run_state, c);
         }
+
+        Ok(())
     }
 
     fn print_int(run_state: &mut RunState, value: Word) {
@@ -480,82 +951,165 @@ impl Output {
     }
 }
 
-struct VMString {
-    address: Word,
+/// Like the Jack OS, reads digits up to the first non-digit character.
+fn int_value(chars: &[Word]) -> Word {
+    let is_negative = chars.first() == Some(&(b'-' as Word));
+
+    let mut value: Word = 0;
+    for &c in &chars[is_negative as usize..] {
+        let Some(digit) = (c as u8 as char)
+            .to_digit(10)
+            .filter(|_| (0..128).contains(&c))
+        else {
+            break;
+        };
+        value = value.wrapping_mul(10).wrapping_add(digit as Word);
+    }
+
+    if is_negative {
+        value.wrapping_neg()
+    } else {
+        value
+    }
 }
 
-impl VMString {
-    fn new(run_state: &mut RunState, capacity: Word) -> Option<Self> {
-        let address = run_state.os.memory.alloc(2 + capacity)?;
+#[derive(Clone, Default)]
+struct Keyboard {
+    /// The key a read is waiting to be released, or 0.
+    key: Word,
+    cursor_shown: bool,
+    /// The characters typed so far, if `readLine` or `readInt` is in progress.
+    line: Option<Vec<Word>>,
+}
 
-        let instance = Self { address };
+impl Keyboard {
+    const NEW_LINE: Word = 128;
+    const BACKSPACE: Word = 129;
+    /// The Jack OS shows a block as the cursor, which is what unknown characters look like.
+    const CURSOR: Word = 0;
+
+    /// Waits for a key to be pressed and released, showing the cursor meanwhile, and then echoes
+    /// it and returns it.
+    fn read_char(run_state: &mut RunState) -> Option<Word> {
+        if !run_state.os.keyboard.cursor_shown {
+            Output::draw_char(run_state, Self::CURSOR);
+            run_state.os.keyboard.cursor_shown = true;
+        }
+        let key = run_state.ram[RAM::KBD];
+        let keyboard = &mut run_state.os.keyboard;
+        if keyboard.key == 0 {
+            keyboard.key = key;
+            return None;
+        }
+        if key != 0 {
+            return None;
+        }
 
-        *instance.length_mut(run_state) = 0;
-        *instance.capacity_mut(run_state) = capacity;
+        let c = std::mem::take(&mut keyboard.key);
+        keyboard.cursor_shown = false;
+        Output::draw_char(run_state, b' ' as Word);
+        Output::print_char(run_state, c);
 
-        Some(instance)
+        Some(c)
     }
 
-    fn char_at(&self, run_state: &RunState, index: Word) -> Option<Word> {
-        if self.length(run_state) <= index {
-            return None;
+    /// Prints the message given as the first argument, then reads characters until a new line,
+    /// handling backspaces.
+    fn read_line(run_state: &mut RunState) -> Result<Option<Vec<Word>>, Trap> {
+        if run_state.os.keyboard.line.is_none() {
+            let address = run_state.ram.get(0, PushSegment::Argument, 0)?;
+            Output::print_string(run_state, VMString { address })?;
+            run_state.os.keyboard.line = Some(vec![]);
         }
+        let Some(c) = Self::read_char(run_state) else {
+            return Ok(None);
+        };
 
-        Some(run_state.ram[self.address + 2 + index])
+        let keyboard = &mut run_state.os.keyboard;
+        let line = keyboard.line.as_mut().unwrap();
+        match c {
+            Self::NEW_LINE => return Ok(keyboard.line.take()),
+            Self::BACKSPACE => {
+                line.pop();
+            }
+            c => line.push(c),
+        }
+
+        Ok(None)
     }
+}
 
-    fn set_char_at(&self, run_state: &mut RunState, index: Word, new_value: Word) -> Option<()> {
-        if self.length(run_state) <= index {
-            return None;
+struct VMString {
+    address: Word,
+}
+
+impl VMString {
+    fn new(run_state: &mut RunState, capacity: Word) -> Result<Self, Trap> {
+        if capacity < 0 {
+            return Err(Trap::InvalidArgument(14));
         }
+        let size = capacity + 2;
+        let address = run_state.heap_alloc(size)?;
 
-        run_state.ram[self.address + 2 + index] = new_value;
+        let instance = Self { address };
 
-        Some(())
+        instance.set_length(run_state, 0)?;
+        run_state.ram.write_at(address, 1, capacity)?;
+
+        Ok(instance)
     }
 
-    fn append_char(&self, run_state: &mut RunState, new_char: Word) -> Option<()> {
-        let old_length = self.length(run_state);
-        if old_length >= self.capacity(run_state) {
-            return None;
+    fn char_at(&self, run_state: &RunState, index: Word) -> Result<Word, Trap> {
+        if !(0..self.length(run_state)?).contains(&index) {
+            return Err(Trap::InvalidArgument(15));
         }
 
-        *self.length_mut(run_state) += 1;
-        run_state.ram[self.address + 2 + old_length] = new_char;
-
-        Some(())
+        run_state.ram.read_at(self.address, 2 + index)
     }
 
-    fn erase_last_char(&self, run_state: &mut RunState) -> Option<()> {
-        if self.length(run_state) <= 0 {
-            return None;
+    fn set_char_at(
+        &self,
+        run_state: &mut RunState,
+        index: Word,
+        new_value: Word,
+    ) -> Result<(), Trap> {
+        if !(0..self.length(run_state)?).contains(&index) {
+            return Err(Trap::InvalidArgument(16));
         }
 
-        *self.length_mut(run_state) -= 1;
-        Some(())
+        run_state.ram.write_at(self.address, 2 + index, new_value)
     }
 
-    fn int_value(&self, run_state: &RunState) -> Option<Word> {
-        if self.length(run_state) <= 0 {
-            return None;
+    fn append_char(&self, run_state: &mut RunState, new_char: Word) -> Result<(), Trap> {
+        let old_length = self.length(run_state)?;
+        if old_length >= self.capacity(run_state)? {
+            return Err(Trap::InvalidArgument(17));
         }
 
-        let mut start = self.address + 2;
-        let is_negative = run_state.ram[start] == b'-' as Word;
-        if is_negative {
-            start += 1;
+        self.set_length(run_state, old_length + 1)?;
+        run_state
+            .ram
+            .write_at(self.address, 2 + old_length, new_char)
+    }
+
+    fn erase_last_char(&self, run_state: &mut RunState) -> Result<(), Trap> {
+        let length = self.length(run_state)?;
+        if length <= 0 {
+            return Err(Trap::InvalidArgument(18));
         }
-        let value = run_state.ram.contents
-            [start as usize..(self.address + 2 + self.length(run_state)) as usize]
-            .iter()
-            .try_fold(0, |acc, &i| {
-                (i as u8 as char).to_digit(10).map(|d| acc * 10 + d as i64)
-            });
 
-        value.map(|v| if is_negative { -v } else { v } as Word)
+        self.set_length(run_state, length - 1)
+    }
+
+    fn int_value(&self, run_state: &RunState) -> Result<Word, Trap> {
+        let chars = (0..self.length(run_state)?)
+            .map(|index| run_state.ram.read_at(self.address, 2 + index))
+            .collect::<Result<Vec<_>, Trap>>()?;
+
+        Ok(int_value(&chars))
     }
 
-    fn set_int(&self, run_state: &mut RunState, value: Word) -> Option<()> {
+    fn set_int(&self, run_state: &mut RunState, value: Word) -> Result<(), Trap> {
         let mut buffer = [0; 11];
         let mut index = 0;
         let mut remainder = (value as i64).abs();
@@ -573,100 +1127,120 @@ impl VMString {
             index += 1;
         }
 
-        if index > self.capacity(run_state) as usize {
-            println!("{} {}", index, self.capacity(run_state) as usize);
-            return None;
+        if index > self.capacity(run_state)? as usize {
+            return Err(Trap::InvalidArgument(19));
         }
 
         for i in 0..index {
-            run_state.ram[self.address + 2 + i as Word] = buffer[index - i - 1];
+            run_state
+                .ram
+                .write_at(self.address, 2 + i as Word, buffer[index - i - 1])?;
         }
-        *self.length_mut(run_state) = index as Word;
-
-        Some(())
+        self.set_length(run_state, index as Word)
     }
 
-    fn length(&self, run_state: &RunState) -> Word {
-        run_state.ram[self.address]
+    fn length(&self, run_state: &RunState) -> Result<Word, Trap> {
+        run_state.ram.read_at(self.address, 0)
     }
 
-    fn length_mut<'a>(&self, run_state: &'a mut RunState) -> &'a mut Word {
-        &mut run_state.ram[self.address]
+    fn set_length(&self, run_state: &mut RunState, length: Word) -> Result<(), Trap> {
+        run_state.ram.write_at(self.address, 0, length)
     }
 
-    fn capacity(&self, run_state: &RunState) -> Word {
-        run_state.ram[self.address + 1]
-    }
-
-    fn capacity_mut<'a>(&self, run_state: &'a mut RunState) -> &'a mut Word {
-        &mut run_state.ram[self.address + 1]
+    fn capacity(&self, run_state: &RunState) -> Result<Word, Trap> {
+        run_state.ram.read_at(self.address, 1)
     }
 }
 
 #[derive(Clone)]
-struct Memory {
-    hole_starts: HashMap<Word, Word>,
-    hole_ends: HashMap<Word, Word>,
-    allocs: HashMap<Word, Word>,
+struct Screen {
+    color: bool,
 }
 
+/// Like the reference Jack OS, the heap is a chain of segments in address order, each starting
+/// with a header holding how many words are free after it, 0 if it's allocated, and where the next
+/// segment starts. Allocation is first fit, splitting the segment if the rest can hold another
+/// header, and freeing merges with the following segment but not the preceding one.
 #[derive(Clone)]
-struct Screen {
-    color: bool,
+struct Memory {
+    /// The free words of each segment by its start.
+    segments: BTreeMap<Word, Word>,
+    /// The size each live block was allocated with, by its address.
+    allocs: BTreeMap<Word, Word>,
+    end: Word,
+    /// Whether the segment headers are written into the RAM, as the Jack OS does.
+    in_ram: bool,
+    /// With the sanitizer on, the size of each freed block by its address, and the command which
+    /// freed it. Blocks are forgotten once they're allocated over.
+    freed: Option<BTreeMap<Word, (Word, usize)>>,
 }
 
+const SEGMENT_HEADER_SIZE: Word = 2;
+
 impl Memory {
     fn new(start_address: Word, size: Word) -> Self {
         Memory {
-            hole_starts: HashMap::from_iter([(start_address, size)]),
-            hole_ends: HashMap::from_iter([(start_address + size, size)]),
-            allocs: HashMap::new(),
+            segments: BTreeMap::from([(start_address, size - SEGMENT_HEADER_SIZE)]),
+            allocs: BTreeMap::new(),
+            end: start_address + size,
+            in_ram: false,
+            freed: None,
         }
     }
 
     fn alloc(&mut self, size: Word) -> Option<Word> {
-        let (&hole_start, &hole_size) = self
-            .hole_starts
-            .iter()
-            .find(|&(_, hole_size)| *hole_size >= size)?;
-
-        self.hole_starts.remove(&hole_start);
-        self.hole_ends.remove(&(hole_start + hole_size));
-        self.allocs.insert(hole_start, size);
-
-        if hole_size != size {
-            self.hole_starts.insert(hole_start + size, hole_size - size);
-            self.hole_ends
-                .insert(hole_start + hole_size, hole_size - size);
+        let (&start, &free) = self.segments.iter().find(|&(_, &free)| free >= size)?;
+        if free > size + SEGMENT_HEADER_SIZE {
+            self.segments.insert(
+                start + SEGMENT_HEADER_SIZE + size,
+                free - size - SEGMENT_HEADER_SIZE,
+            );
         }
+        self.segments.insert(start, 0);
 
-        Some(hole_start)
+        let address = start + SEGMENT_HEADER_SIZE;
+        self.allocs.insert(address, size);
+        if let Some(freed) = &mut self.freed {
+            freed.retain(|&freed_address, &mut (freed_size, _)| {
+                freed_address + freed_size <= start || freed_address >= address + size
+            });
+        }
+        Some(address)
     }
 
     fn dealloc(&mut self, address: Word) -> bool {
-        let Some(size) = self.allocs.remove(&address) else {
+        if self.allocs.remove(&address).is_none() {
             return false;
-        };
-
-        let mut hole_start = address;
-        let mut hole_size = size;
-        if let Some(preceding_size) = self.hole_ends.remove(&address) {
-            hole_start -= preceding_size;
-            hole_size += preceding_size;
-
-            self.hole_starts.remove(&hole_start);
         }
 
-        if let Some(following_size) = self.hole_starts.remove(&(address + size)) {
-            hole_size += following_size;
+        let start = address - SEGMENT_HEADER_SIZE;
+        let free = match self.next_segment(start) {
+            Some((next_start, 0)) => next_start - address,
+            Some((next_start, next_free)) => {
+                self.segments.remove(&next_start);
+                next_start - start + next_free
+            }
+            None => self.end - address,
+        };
+        self.segments.insert(start, free);
 
-            self.hole_ends.remove(&(hole_start + hole_size));
-        }
+        true
+    }
 
-        self.hole_starts.insert(hole_start, hole_size);
-        self.hole_ends.insert(hole_start + hole_size, hole_size);
+    fn next_segment(&self, start: Word) -> Option<(Word, Word)> {
+        self.segments
+            .range(start + 1..)
+            .next()
+            .map(|(&start, &free)| (start, free))
+    }
 
-        true
+    /// Like the Jack OS, the last segment points just past its header.
+    fn header(&self, start: Word) -> [Word; 2] {
+        let next_start = self
+            .next_segment(start)
+            .map_or(start + SEGMENT_HEADER_SIZE, |(next_start, _)| next_start);
+
+        [self.segments[&start], next_start]
     }
 }
 
@@ -685,7 +1259,7 @@ mod tests {
                 os: Default::default(),
                 call_stack: vec![],
                 breakpoints: vec![],
-                func_stats: HashMap::new(),
+                error: None,
             };
 
             instance.ram[Register::ARG] = 100;
@@ -697,44 +1271,52 @@ mod tests {
     #[test]
     fn test_string() {
         let mut run_state = RunState::test_instance();
-        run_state.ram.set(0, PopSegment::Argument, 0, 11);
-        let s = run_state.string_new();
+        run_state.ram.set(0, PopSegment::Argument, 0, 11).unwrap();
+        let s = run_state.string_new().unwrap();
         assert!(s.is_positive());
 
-        run_state.ram.set(0, PopSegment::Argument, 0, s);
-        assert_eq!(run_state.string_length(), 0);
+        run_state.ram.set(0, PopSegment::Argument, 0, s).unwrap();
+        assert_eq!(run_state.string_length(), Ok(0));
 
         run_state
             .ram
-            .set(0, PopSegment::Argument, 1, '5' as u8 as Word);
-        assert_eq!(run_state.string_append_char(), s);
-        assert_eq!(run_state.string_length(), 1);
+            .set(0, PopSegment::Argument, 1, '5' as u8 as Word)
+            .unwrap();
+        assert_eq!(run_state.string_append_char(), Ok(s));
+        assert_eq!(run_state.string_length(), Ok(1));
 
-        run_state.ram.set(0, PopSegment::Argument, 1, 0);
-        assert_eq!(run_state.string_char_at(), '5' as u8 as Word);
-        assert_eq!(run_state.string_int_value(), 5);
+        run_state.ram.set(0, PopSegment::Argument, 1, 0).unwrap();
+        assert_eq!(run_state.string_char_at(), Ok('5' as u8 as Word));
+        assert_eq!(run_state.string_int_value(), Ok(5));
 
         run_state
             .ram
-            .set(0, PopSegment::Argument, 2, '9' as u8 as Word);
-        assert_eq!(run_state.string_set_char_at(), 0);
-        assert_eq!(run_state.string_char_at(), '9' as u8 as Word);
-        assert_eq!(run_state.string_int_value(), 9);
+            .set(0, PopSegment::Argument, 2, '9' as u8 as Word)
+            .unwrap();
+        assert_eq!(run_state.string_set_char_at(), Ok(0));
+        assert_eq!(run_state.string_char_at(), Ok('9' as u8 as Word));
+        assert_eq!(run_state.string_int_value(), Ok(9));
 
-        run_state.ram.set(0, PopSegment::Argument, 1, Word::MAX);
-        assert_eq!(run_state.string_set_int(), 0);
+        run_state
+            .ram
+            .set(0, PopSegment::Argument, 1, Word::MAX)
+            .unwrap();
+        assert_eq!(run_state.string_set_int(), Ok(0));
         assert_eq!(
             run_state.string_length(),
-            Word::MAX.to_string().len() as Word
+            Ok(Word::MAX.to_string().len() as Word)
         );
-        assert_eq!(run_state.string_int_value(), Word::MAX);
+        assert_eq!(run_state.string_int_value(), Ok(Word::MAX));
 
-        run_state.ram.set(0, PopSegment::Argument, 1, Word::MIN);
-        assert_eq!(run_state.string_set_int(), 0);
+        run_state
+            .ram
+            .set(0, PopSegment::Argument, 1, Word::MIN)
+            .unwrap();
+        assert_eq!(run_state.string_set_int(), Ok(0));
         assert_eq!(
             run_state.string_length(),
-            Word::MIN.to_string().len() as Word
+            Ok(Word::MIN.to_string().len() as Word)
         );
-        assert_eq!(run_state.string_int_value(), Word::MIN);
+        assert_eq!(run_state.string_int_value(), Ok(Word::MIN));
     }
 }
@@ -4,12 +4,15 @@ use nom::{
     AsChar, Compare, FindToken, InputIter, InputLength, InputTake, InputTakeAtPosition, Parser,
     Slice,
     branch::alt,
-    bytes::complete::tag,
-    character::complete::{self, line_ending, not_line_ending, space0},
-    combinator::{all_consuming, map, opt, rest, value},
+    bytes::complete::{tag, take_until},
+    character::complete::{
+        self, alpha1, alphanumeric1, char, line_ending, multispace1, not_line_ending, satisfy,
+        space0,
+    },
+    combinator::{all_consuming, map, not, opt, recognize, rest, value},
     error::{ParseError, VerboseError},
-    multi::separated_list0,
-    sequence::{delimited, preceded, terminated},
+    multi::{many0_count, separated_list0},
+    sequence::{delimited, pair, preceded, terminated},
 };
 
 pub type IResult<I, O> = nom::IResult<I, O, VerboseError<I>>;
@@ -95,3 +98,41 @@ where
         |v| v.into_iter().flatten().collect::<Vec<_>>(),
     ))
 }
+
+pub fn comment(input: &str) -> IResult<&str, &str> {
+    alt((
+        preceded(tag("//"), not_line_ending),
+        delimited(tag("/*"), take_until("*/"), tag("*/")),
+    ))(input)
+}
+
+/// Whitespace and comments, which may separate any two tokens.
+pub fn skip(input: &str) -> IResult<&str, ()> {
+    value((), many0_count(alt((multispace1, comment))))(input)
+}
+
+pub fn token<'a, O>(
+    parser: impl FnMut(&'a str) -> IResult<&'a str, O>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    preceded(skip, parser)
+}
+
+/// A letter or underscore followed by letters, digits and underscores.
+pub fn word(input: &str) -> IResult<&str, &str> {
+    recognize(pair(
+        alt((alpha1, tag("_"))),
+        many0_count(alt((alphanumeric1, tag("_")))),
+    ))(input)
+}
+
+/// A keyword which isn't the start of a longer word, so that `tick` doesn't match `ticktock`.
+pub fn keyword<'a>(keyword: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    token(terminated(
+        tag(keyword),
+        not(satisfy(|c| c.is_alphanumeric() || c == '_')),
+    ))
+}
+
+pub fn symbol<'a>(c: char) -> impl FnMut(&'a str) -> IResult<&'a str, char> {
+    token(char(c))
+}
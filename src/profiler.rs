@@ -0,0 +1,301 @@
+use std::fmt::Write;
+
+use hashbrown::HashMap;
+
+use crate::vm::{Program, RunState, VMCommand};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub calls: u64,
+    /// Commands executed in the function itself.
+    pub exclusive: u64,
+    /// Commands executed in the function and everything it called. Recursive calls are only
+    /// counted once.
+    pub inclusive: u64,
+}
+
+impl FunctionProfile {
+    pub fn per_call(&self) -> f64 {
+        self.inclusive as f64 / self.calls.max(1) as f64
+    }
+}
+
+#[derive(Clone, Debug)]
+struct StackNode {
+    function_id: usize,
+    parent: usize,
+    count: u64,
+}
+
+const ROOT: usize = 0;
+
+/// Attributes the commands executed by the VM to the functions running them. Functions are
+/// identified by their index in the program, and the native OS functions are numbered after them.
+/// Each of those runs in a single command.
+///
+/// The call stacks are kept as a tree, so that attributing a command is a single increment.
+#[derive(Clone, Debug)]
+pub struct Profiler {
+    functions: Vec<FunctionProfile>,
+    os_function_ids: HashMap<String, usize>,
+    /// How many times each function is on the stack, and the total when it was first entered.
+    active: Vec<(u32, u64)>,
+    nodes: Vec<StackNode>,
+    children: HashMap<(usize, usize), usize>,
+    current_node: usize,
+    total: u64,
+    depth: usize,
+    max_depth: usize,
+}
+
+impl Profiler {
+    /// Starts with the functions which are already on the call stack.
+    pub fn new(program: &Program, run_state: &RunState) -> Self {
+        let functions = program
+            .function_metadata
+            .iter()
+            .map(|metadata| FunctionProfile {
+                name: metadata.name.clone(),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let mut profiler = Self {
+            active: vec![(0, 0); functions.len()],
+            functions,
+            os_function_ids: HashMap::new(),
+            nodes: vec![StackNode {
+                function_id: usize::MAX,
+                parent: ROOT,
+                count: 0,
+            }],
+            children: HashMap::new(),
+            current_node: ROOT,
+            total: 0,
+            depth: 0,
+            max_depth: 0,
+        };
+        for frame in &run_state.call_stack {
+            profiler.enter(frame.function_index);
+        }
+
+        profiler
+    }
+
+    /// Called after `command` was executed, with the depth of the call stack before it.
+    pub fn record(&mut self, command: &VMCommand, previous_depth: usize, run_state: &RunState) {
+        let depth = run_state.call_stack.len();
+        if depth > previous_depth {
+            // The call itself is attributed to the caller.
+            self.count();
+            self.enter(run_state.call_stack.last().unwrap().function_index);
+        } else if depth < previous_depth {
+            self.count();
+            self.exit();
+        } else if let VMCommand::Call { function_name, .. } = command {
+            let id = self.os_function_id(function_name);
+            self.enter(id);
+            self.count();
+            self.exit();
+        } else {
+            self.count();
+        }
+    }
+
+    /// Called after a step in which the OS function `function_name` kept waiting, which isn't
+    /// counted as another call.
+    pub fn record_waiting(&mut self, function_name: &str) {
+        let id = self.os_function_id(function_name);
+        self.enter(id);
+        self.functions[id].calls -= 1;
+        self.count();
+        self.exit();
+    }
+
+    fn os_function_id(&mut self, function_name: &str) -> usize {
+        if let Some(&id) = self.os_function_ids.get(function_name) {
+            return id;
+        }
+
+        let id = self.functions.len();
+        self.functions.push(FunctionProfile {
+            name: function_name.to_owned(),
+            ..Default::default()
+        });
+        self.active.push((0, 0));
+        self.os_function_ids.insert(function_name.to_owned(), id);
+
+        id
+    }
+
+    fn count(&mut self) {
+        self.total += 1;
+        self.nodes[self.current_node].count += 1;
+        let function_id = self.nodes[self.current_node].function_id;
+        if let Some(function) = self.functions.get_mut(function_id) {
+            function.exclusive += 1;
+        }
+    }
+
+    fn enter(&mut self, function_id: usize) {
+        self.functions[function_id].calls += 1;
+        let (active, entered_at) = &mut self.active[function_id];
+        if *active == 0 {
+            *entered_at = self.total;
+        }
+        *active += 1;
+
+        let parent = self.current_node;
+        let next_node = self.nodes.len();
+        self.current_node = *self
+            .children
+            .entry((parent, function_id))
+            .or_insert(next_node);
+        if self.current_node == next_node {
+            self.nodes.push(StackNode {
+                function_id,
+                parent,
+                count: 0,
+            });
+        }
+        self.depth += 1;
+        self.max_depth = self.max_depth.max(self.depth);
+    }
+
+    fn exit(&mut self) {
+        let node = &self.nodes[self.current_node];
+        let function_id = node.function_id;
+        self.current_node = node.parent;
+        self.depth = self.depth.saturating_sub(1);
+
+        let Some((active, entered_at)) = self.active.get_mut(function_id) else {
+            return;
+        };
+        *active -= 1;
+        if *active == 0 {
+            self.functions[function_id].inclusive += self.total - *entered_at;
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// The functions which were called, sorted by the commands they executed themselves.
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let mut functions = self
+            .functions
+            .iter()
+            .zip(&self.active)
+            .filter(|(function, _)| function.calls > 0)
+            .map(|(function, &(active, entered_at))| {
+                let mut function = function.clone();
+                if active > 0 {
+                    function.inclusive += self.total - entered_at;
+                }
+                function
+            })
+            .collect::<Vec<_>>();
+        functions.sort_by(|a, b| b.exclusive.cmp(&a.exclusive).then(a.name.cmp(&b.name)));
+
+        functions
+    }
+
+    pub fn report(&self) -> String {
+        let mut report = format!(
+            "{} commands, max call depth {}\n{:<40} {:>10} {:>12} {:>12} {:>12}\n",
+            self.total, self.max_depth, "function", "calls", "exclusive", "inclusive", "per call"
+        );
+        for function in self.functions() {
+            writeln!(
+                report,
+                "{:<40} {:>10} {:>12} {:>12} {:>12.1}",
+                function.name,
+                function.calls,
+                function.exclusive,
+                function.inclusive,
+                function.per_call()
+            )
+            .unwrap();
+        }
+
+        report
+    }
+
+    /// One `caller;callee count` line per call stack, as expected by flamegraph tools.
+    pub fn folded_stacks(&self) -> String {
+        let mut lines = vec![];
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.count == 0 || index == ROOT {
+                continue;
+            }
+
+            let mut names = vec![];
+            let mut current = index;
+            while current != ROOT {
+                names.push(
+                    self.functions[self.nodes[current].function_id]
+                        .name
+                        .as_str(),
+                );
+                current = self.nodes[current].parent;
+            }
+            names.reverse();
+            lines.push(format!("{} {}", names.join(";"), node.count));
+        }
+        lines.sort();
+
+        lines.into_iter().map(|line| line + "\n").collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{StopReason, VM};
+
+    #[test]
+    fn test_profiler() {
+        let mut vm = VM::from_file_contents(vec![
+            (
+                "Sys.vm".to_owned(),
+                "function Sys.init 0\npush constant 2\ncall Main.square 1\npop temp 0\npush constant 3\ncall Main.square 1\npop temp 0\nlabel END\ngoto END"
+                    .to_owned(),
+            ),
+            (
+                "Main.vm".to_owned(),
+                "function Main.square 0\npush argument 0\npush argument 0\ncall Math.multiply 2\nreturn"
+                    .to_owned(),
+            ),
+        ])
+        .unwrap();
+        vm.set_profiling(true);
+        assert_eq!(vm.run(100), StopReason::Halted);
+
+        let profiler = vm.profiler().unwrap();
+        assert_eq!((profiler.total(), profiler.max_depth()), (18, 3));
+        let function = |name: &str, calls, exclusive, inclusive| FunctionProfile {
+            name: name.to_owned(),
+            calls,
+            exclusive,
+            inclusive,
+        };
+        assert_eq!(
+            profiler.functions(),
+            vec![
+                function("Main.square", 2, 8, 10),
+                function("Sys.init", 1, 8, 18),
+                function("Math.multiply", 2, 2, 2),
+            ]
+        );
+        assert_eq!(profiler.functions()[0].per_call(), 5.0);
+        assert_eq!(
+            profiler.folded_stacks(),
+            "Sys.init 8\nSys.init;Main.square 8\nSys.init;Main.square;Math.multiply 2\n"
+        );
+    }
+}
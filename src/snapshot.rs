@@ -0,0 +1,213 @@
+use std::fmt::Write;
+
+use crate::hardware::Word;
+
+pub const VERSION: u32 = 1;
+const MAGIC: &str = "n2t-snapshot";
+
+/// A machine state which can be written to a file and loaded back. The file starts with a
+/// `n2t-snapshot <version> <kind>` header, followed by a `key value...` line per field.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub kind: String,
+    fields: Vec<(String, Vec<i64>)>,
+}
+
+impl Snapshot {
+    pub fn new(kind: &str) -> Self {
+        Self {
+            kind: kind.to_owned(),
+            fields: vec![],
+        }
+    }
+
+    pub fn push(&mut self, key: &str, values: impl IntoIterator<Item = i64>) {
+        self.fields
+            .push((key.to_owned(), values.into_iter().collect()));
+    }
+
+    pub fn get(&self, key: &str) -> Result<&[i64], String> {
+        self.fields
+            .iter()
+            .find(|(field_key, _)| field_key == key)
+            .map(|(_, values)| values.as_slice())
+            .ok_or_else(|| format!("snapshot is missing {key}"))
+    }
+
+    pub fn get_one(&self, key: &str) -> Result<i64, String> {
+        match self.get(key)? {
+            [value] => Ok(*value),
+            values => Err(format!(
+                "expected one value for {key}, got {}",
+                values.len()
+            )),
+        }
+    }
+
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a [i64]> {
+        self.fields
+            .iter()
+            .filter(move |(field_key, _)| field_key == key)
+            .map(|(_, values)| values.as_slice())
+    }
+
+    pub fn expect_kind(&self, kind: &str) -> Result<(), String> {
+        if self.kind != kind {
+            return Err(format!(
+                "expected a {kind} snapshot, got a {} one",
+                self.kind
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Stores the runs of non-zero words as `key start value...` lines, since most of the memory
+    /// is usually empty.
+    pub fn push_words(&mut self, key: &str, words: &[Word]) {
+        let mut start = 0;
+        while start < words.len() {
+            if words[start] == 0 {
+                start += 1;
+                continue;
+            }
+            let end = words[start..]
+                .iter()
+                .position(|&word| word == 0)
+                .map_or(words.len(), |length| start + length);
+            self.push(
+                key,
+                std::iter::once(start as i64).chain(words[start..end].iter().map(|&w| w as i64)),
+            );
+            start = end;
+        }
+    }
+
+    /// Reads words stored by `push_words`, leaving the rest of `words` zeroed.
+    pub fn get_words(&self, key: &str, words: &mut [Word]) -> Result<(), String> {
+        words.fill(0);
+        for values in self.get_all(key) {
+            let Some((&start, values)) = values.split_first() else {
+                return Err(format!("{key} is missing a start address"));
+            };
+            let range = usize::try_from(start)
+                .ok()
+                .and_then(|start| words.get_mut(start..start + values.len()))
+                .ok_or_else(|| format!("{key} at {start} is out of range"))?;
+            for (word, &value) in range.iter_mut().zip(values) {
+                *word = to_word(value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut lines = contents.lines().enumerate();
+        let header = lines
+            .next()
+            .map(|(_, line)| line.split_whitespace().collect::<Vec<_>>())
+            .unwrap_or_default();
+        let [MAGIC, version, kind] = header[..] else {
+            return Err("not a snapshot file".to_owned());
+        };
+        match version.parse::<u32>() {
+            Ok(version) if version <= VERSION => {}
+            _ => return Err(format!("unsupported snapshot version {version}")),
+        }
+
+        let mut snapshot = Self::new(kind);
+        for (index, line) in lines {
+            let mut words = line.split_whitespace();
+            let Some(key) = words.next() else {
+                continue;
+            };
+            let values = words
+                .map(|word| word.parse())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("line {}: {e}", index + 1))?;
+            snapshot.fields.push((key.to_owned(), values));
+        }
+
+        Ok(snapshot)
+    }
+}
+
+impl std::fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{MAGIC} {VERSION} {}", self.kind)?;
+        for (key, values) in &self.fields {
+            let mut line = key.clone();
+            for value in values {
+                write!(line, " {value}")?;
+            }
+            writeln!(f, "{line}")?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn to_word(value: i64) -> Result<Word, String> {
+    Word::try_from(value).map_err(|_| format!("{value} doesn't fit in a word"))
+}
+
+pub fn to_index(value: i64, length: usize) -> Result<usize, String> {
+    usize::try_from(value)
+        .ok()
+        .filter(|&index| index < length)
+        .ok_or_else(|| format!("{value} is out of range"))
+}
+
+/// An FNV-1a hash, used to check that a snapshot is loaded into the program it was taken from.
+pub fn fingerprint<'a>(parts: impl IntoIterator<Item = &'a str>) -> i64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for byte in part.bytes().chain([0]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    hash as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut snapshot = Snapshot::new("test");
+        snapshot.push("pc", [12]);
+        snapshot.push("empty", []);
+        snapshot.push_words("ram", &[0, 1, 2, 0, 0, -3]);
+
+        let text = snapshot.to_string();
+        assert_eq!(
+            text,
+            "n2t-snapshot 1 test\npc 12\nempty\nram 1 1 2\nram 5 -3\n"
+        );
+
+        let parsed = Snapshot::parse(&text).unwrap();
+        assert_eq!(parsed, snapshot);
+        assert_eq!(parsed.get_one("pc"), Ok(12));
+        let mut words = [7; 6];
+        parsed.get_words("ram", &mut words).unwrap();
+        assert_eq!(words, [0, 1, 2, 0, 0, -3]);
+        assert!(parsed.get_words("ram", &mut [0; 4]).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Snapshot::parse("").is_err());
+        assert!(Snapshot::parse("n2t-snapshot 2 vm").is_err());
+        assert!(Snapshot::parse("n2t-snapshot 1 vm\npc x").is_err());
+        assert_eq!(
+            Snapshot::parse("n2t-snapshot 1 vm")
+                .unwrap()
+                .expect_kind("hardware"),
+            Err("expected a hardware snapshot, got a vm one".to_owned())
+        );
+    }
+}
@@ -0,0 +1,972 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use hashbrown::HashMap;
+
+use crate::{
+    hardware::{AnyHardware, Word},
+    hardware_parse::{assemble_hack_file, parse_binary_file},
+    test_script_parse::parse_test_script,
+    vm::{StopReason, VM},
+    vm_parse::errors_to_string,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScriptCommand {
+    Load(Option<String>),
+    OutputFile(String),
+    CompareTo(String),
+    OutputList(Vec<OutputColumn>),
+    Set {
+        variable: Variable,
+        value: Word,
+    },
+    Output,
+    Echo(String),
+    ClearEcho,
+    Tick,
+    Tock,
+    TickTock,
+    VmStep,
+    Repeat {
+        /// `None` repeats forever.
+        count: Option<u64>,
+        commands: Vec<ScriptCommand>,
+    },
+    While {
+        condition: Condition,
+        commands: Vec<ScriptCommand>,
+    },
+}
+
+/// A simulator variable such as `D`, `RAM[16]` or `local[2]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Variable {
+    pub name: String,
+    pub index: Option<Word>,
+}
+
+impl std::fmt::Display for Variable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.index {
+            Some(index) => write!(f, "{}[{index}]", self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FormatKind {
+    Decimal,
+    Hex,
+    Binary,
+    String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputColumn {
+    pub variable: Variable,
+    pub kind: FormatKind,
+    pub pad_left: usize,
+    pub length: usize,
+    pub pad_right: usize,
+}
+
+impl OutputColumn {
+    fn width(&self) -> usize {
+        self.pad_left + self.length + self.pad_right
+    }
+
+    fn header(&self) -> String {
+        let mut name = self.variable.to_string();
+        name.truncate(self.width());
+        let left = (self.width() - name.len()) / 2;
+
+        format!(
+            "{}{name}{}",
+            " ".repeat(left),
+            " ".repeat(self.width() - left - name.len())
+        )
+    }
+
+    fn format(&self, value: Word) -> String {
+        let text = match self.kind {
+            FormatKind::Decimal | FormatKind::String => value.to_string(),
+            FormatKind::Hex => format!("{:04X}", value as u16),
+            FormatKind::Binary => format!("{:016b}", value as u16),
+        };
+
+        format!(
+            "{}{text:>length$}{}",
+            " ".repeat(self.pad_left),
+            " ".repeat(self.pad_right),
+            length = self.length
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompareOp {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub variable: Variable,
+    pub op: CompareOp,
+    pub value: Word,
+}
+
+/// The first line where the output differs from the compare file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    /// 1-based, counting the header line.
+    pub line: usize,
+    pub column: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Comparison failure at line {}, column {}: expected \"{}\", got \"{}\"",
+            self.line, self.column, self.expected, self.actual
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TestReport {
+    pub output_file: Option<String>,
+    pub output: String,
+    pub mismatch: Option<Mismatch>,
+    pub echo: Option<String>,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> bool {
+        self.mismatch.is_none()
+    }
+}
+
+/// The files a script may refer to, relative to the script.
+pub trait ScriptFiles {
+    fn read(&mut self, name: &str) -> Result<String, String>;
+    /// Names of all files with the given extension, for commands that load a whole directory.
+    fn list(&mut self, extension: &str) -> Result<Vec<String>, String>;
+}
+
+impl ScriptFiles for HashMap<String, String> {
+    fn read(&mut self, name: &str) -> Result<String, String> {
+        self.get(name)
+            .cloned()
+            .ok_or_else(|| format!("file not found: {name}"))
+    }
+
+    fn list(&mut self, extension: &str) -> Result<Vec<String>, String> {
+        let mut names: Vec<_> = self
+            .keys()
+            .filter(|name| name.ends_with(&format!(".{extension}")))
+            .cloned()
+            .collect();
+        names.sort();
+
+        Ok(names)
+    }
+}
+
+pub struct DirectoryFiles(pub PathBuf);
+
+impl ScriptFiles for DirectoryFiles {
+    fn read(&mut self, name: &str) -> Result<String, String> {
+        let path = self.0.join(name);
+        fs::read_to_string(&path).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    fn list(&mut self, extension: &str) -> Result<Vec<String>, String> {
+        let mut names = fs::read_dir(&self.0)
+            .map_err(|e| format!("{}: {e}", self.0.display()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|e| e == extension))
+            .map(|path| path.file_name().unwrap().to_str().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+
+        Ok(names)
+    }
+}
+
+/// A simulator that can be driven by a test script.
+pub trait TestTarget {
+    fn load(&mut self, file_name: Option<&str>, files: &mut dyn ScriptFiles) -> Result<(), String>;
+    fn get(&mut self, variable: &Variable) -> Result<Word, String>;
+    fn set(&mut self, variable: &Variable, value: Word) -> Result<(), String>;
+    /// Executes a clock command: `tick`, `tock`, `ticktock` or `vmstep`.
+    fn clock(&mut self, command: &ScriptCommand) -> Result<(), String>;
+}
+
+struct ScriptRunner<'a, T: TestTarget + ?Sized> {
+    target: &'a mut T,
+    files: &'a mut dyn ScriptFiles,
+    output_list: Vec<OutputColumn>,
+    compare_lines: Option<Vec<String>>,
+    line_count: usize,
+    report: TestReport,
+}
+
+impl<T: TestTarget + ?Sized> ScriptRunner<'_, T> {
+    fn write_line(&mut self, line: String) {
+        self.line_count += 1;
+        if let Some(compare_lines) = &self.compare_lines
+            && self.report.mismatch.is_none()
+        {
+            let expected = compare_lines
+                .get(self.line_count - 1)
+                .map_or("", String::as_str);
+            self.report.mismatch = compare_line(
+                self.line_count,
+                &self.output_list,
+                expected.trim_end(),
+                &line,
+            );
+        }
+        self.report.output.push_str(&line);
+        self.report.output.push('\n');
+    }
+
+    fn run(&mut self, commands: &[ScriptCommand]) -> Result<(), String> {
+        for command in commands {
+            if self.report.mismatch.is_some() {
+                break;
+            }
+            self.run_command(command)?;
+        }
+
+        Ok(())
+    }
+
+    fn run_command(&mut self, command: &ScriptCommand) -> Result<(), String> {
+        match command {
+            ScriptCommand::Load(file_name) => {
+                self.target.load(file_name.as_deref(), self.files)?;
+            }
+            ScriptCommand::OutputFile(file_name) => {
+                self.report.output_file = Some(file_name.clone());
+            }
+            ScriptCommand::CompareTo(file_name) => {
+                let contents = self.files.read(file_name)?;
+                self.compare_lines = Some(contents.lines().map(str::to_owned).collect());
+            }
+            ScriptCommand::OutputList(columns) => {
+                self.output_list = columns.clone();
+                let header = columns.iter().map(OutputColumn::header).collect::<Vec<_>>();
+                self.write_line(format!("|{}|", header.join("|")));
+            }
+            ScriptCommand::Set { variable, value } => self.target.set(variable, *value)?,
+            ScriptCommand::Output => {
+                let values = self
+                    .output_list
+                    .clone()
+                    .iter()
+                    .map(|column| Ok(column.format(self.target.get(&column.variable)?)))
+                    .collect::<Result<Vec<_>, String>>()?;
+                self.write_line(format!("|{}|", values.join("|")));
+            }
+            ScriptCommand::Echo(text) => self.report.echo = Some(text.clone()),
+            ScriptCommand::ClearEcho => self.report.echo = None,
+            ScriptCommand::Tick
+            | ScriptCommand::Tock
+            | ScriptCommand::TickTock
+            | ScriptCommand::VmStep => self.target.clock(command)?,
+            ScriptCommand::Repeat {
+                count: Some(count),
+                commands,
+            } => {
+                for _ in 0..*count {
+                    if self.report.mismatch.is_some() {
+                        break;
+                    }
+                    self.run(commands)?;
+                }
+            }
+            ScriptCommand::Repeat {
+                count: None,
+                commands,
+            } => {
+                while self.report.mismatch.is_none() {
+                    self.run(commands)?;
+                }
+            }
+            ScriptCommand::While {
+                condition,
+                commands,
+            } => {
+                while self.report.mismatch.is_none() && self.evaluate(condition)? {
+                    self.run(commands)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn evaluate(&mut self, condition: &Condition) -> Result<bool, String> {
+        let value = self.target.get(&condition.variable)?;
+
+        Ok(match condition.op {
+            CompareOp::Equal => value == condition.value,
+            CompareOp::NotEqual => value != condition.value,
+            CompareOp::Less => value < condition.value,
+            CompareOp::Greater => value > condition.value,
+            CompareOp::LessOrEqual => value <= condition.value,
+            CompareOp::GreaterOrEqual => value >= condition.value,
+        })
+    }
+}
+
+fn compare_line(
+    line_number: usize,
+    output_list: &[OutputColumn],
+    expected: &str,
+    actual: &str,
+) -> Option<Mismatch> {
+    let expected_cells = expected.split('|').collect::<Vec<_>>();
+    let actual_cells = actual.split('|').collect::<Vec<_>>();
+    for i in 0..expected_cells.len().max(actual_cells.len()) {
+        let expected_cell = expected_cells.get(i).map_or("", |c| c.trim());
+        let actual_cell = actual_cells.get(i).map_or("", |c| c.trim());
+        // Cells made of `*` in the compare file match anything.
+        let is_wildcard = !expected_cell.is_empty() && expected_cell.chars().all(|c| c == '*');
+        if expected_cell != actual_cell && !is_wildcard {
+            let column = i
+                .checked_sub(1)
+                .and_then(|column| output_list.get(column))
+                .map_or_else(|| format!("{i}"), |column| column.variable.to_string());
+
+            return Some(Mismatch {
+                line: line_number,
+                column,
+                expected: expected.to_owned(),
+                actual: actual.to_owned(),
+            });
+        }
+    }
+
+    None
+}
+
+/// Runs a test script against `target`, stopping at the first line that differs from the
+/// compare file.
+pub fn run_test_script<T: TestTarget + ?Sized>(
+    target: &mut T,
+    script: &str,
+    files: &mut dyn ScriptFiles,
+) -> Result<TestReport, String> {
+    let commands = parse_test_script(script)?;
+    let mut runner = ScriptRunner {
+        target,
+        files,
+        output_list: vec![],
+        compare_lines: None,
+        line_count: 0,
+        report: TestReport::default(),
+    };
+    runner.run(&commands)?;
+
+    Ok(runner.report)
+}
+
+/// Runs the script at `path`, resolving file names relative to it and writing the output file.
+pub fn run_test_file<T: TestTarget + ?Sized>(
+    target: &mut T,
+    path: &Path,
+) -> Result<TestReport, String> {
+    let script = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new(".")).to_owned();
+    let report = run_test_script(target, &script, &mut DirectoryFiles(dir.clone()))?;
+    if let Some(output_file) = &report.output_file {
+        let output_path = dir.join(output_file);
+        fs::write(&output_path, &report.output)
+            .map_err(|e| format!("{}: {e}", output_path.display()))?;
+    }
+
+    Ok(report)
+}
+
+/// Drives an `AnyHardware` through CPU emulator scripts.
+pub struct CpuTestTarget<'a, H: AnyHardware> {
+    pub hardware: &'a mut H,
+    pub time: Word,
+}
+
+impl<'a, H: AnyHardware> CpuTestTarget<'a, H> {
+    pub fn new(hardware: &'a mut H) -> Self {
+        Self { hardware, time: 0 }
+    }
+}
+
+impl<H: AnyHardware> TestTarget for CpuTestTarget<'_, H> {
+    fn load(&mut self, file_name: Option<&str>, files: &mut dyn ScriptFiles) -> Result<(), String> {
+        let file_name = file_name.ok_or("load requires a program file")?;
+        let contents = files.read(file_name)?;
+        let instructions = if file_name.to_lowercase().ends_with(".hack") {
+            parse_binary_file(&contents)
+        } else {
+            assemble_hack_file(&contents)
+        }
+        .map_err(|e| format!("{file_name}: {e}"))?;
+        self.hardware.load_program(&instructions);
+        self.hardware.reset();
+        self.time = 0;
+
+        Ok(())
+    }
+
+    fn get(&mut self, variable: &Variable) -> Result<Word, String> {
+        match (variable.name.as_str(), variable.index) {
+            ("A", None) => Ok(self.hardware.a()),
+            ("D", None) => Ok(self.hardware.d()),
+            ("PC", None) => Ok(self.hardware.pc()),
+            ("time", None) => Ok(self.time),
+            ("RAM", Some(address)) => Ok(self.hardware.get_ram_value(address)),
+            _ => Err(format!("unknown variable {variable}")),
+        }
+    }
+
+    fn set(&mut self, variable: &Variable, value: Word) -> Result<(), String> {
+        match (variable.name.as_str(), variable.index) {
+            ("A", None) => *self.hardware.a_mut() = value,
+            ("D", None) => *self.hardware.d_mut() = value,
+            ("PC", None) => self.hardware.set_pc(value),
+            ("RAM", Some(address)) => self.hardware.set_ram_value(address, value),
+            _ => return Err(format!("cannot set {variable}")),
+        }
+
+        Ok(())
+    }
+
+    fn clock(&mut self, command: &ScriptCommand) -> Result<(), String> {
+        match command {
+            // The CPU's registers only change at the end of a cycle, so a tick alone shows nothing
+            // and the tock executes the instruction.
+            ScriptCommand::Tick => Ok(()),
+            ScriptCommand::TickTock | ScriptCommand::Tock => {
+                self.hardware.run(1);
+                self.time += 1;
+                Ok(())
+            }
+            _ => Err(format!("unsupported command {command:?}")),
+        }
+    }
+}
+
+pub fn run_cpu_test_file(
+    hardware: &mut impl AnyHardware,
+    path: &Path,
+) -> Result<TestReport, String> {
+    run_test_file(&mut CpuTestTarget::new(hardware), path)
+}
+
+/// Drives a `VM` through VM emulator scripts. The VM is created by the script's `load`.
+#[derive(Default)]
+pub struct VmTestTarget {
+    pub vm: Option<VM>,
+}
+
+impl VmTestTarget {
+    fn vm(&mut self) -> Result<&mut VM, String> {
+        self.vm
+            .as_mut()
+            .ok_or_else(|| "no program loaded".to_owned())
+    }
+
+    fn address(&mut self, variable: &Variable) -> Result<Word, String> {
+        let vm = self.vm()?;
+        let pointer = match variable.name.as_str() {
+            "sp" => Some(0),
+            "local" => Some(1),
+            "argument" => Some(2),
+            "this" => Some(3),
+            "that" => Some(4),
+            _ => None,
+        };
+
+        match (variable.name.as_str(), pointer, variable.index) {
+            (_, Some(pointer), None) => Ok(pointer),
+            ("sp", _, Some(_)) => Err(format!("unknown variable {variable}")),
+            (_, Some(pointer), Some(index)) => Ok(vm.get_ram_value(pointer) + index),
+            ("RAM", _, Some(index)) => Ok(index),
+            ("temp", _, Some(index)) => Ok(5 + index),
+            ("pointer", _, Some(index)) => Ok(3 + index),
+            ("static", _, Some(index)) => {
+                let file = &vm.program.files[vm.current_file_index()];
+                Ok(*file.static_segment.start() + index)
+            }
+            _ => Err(format!("unknown variable {variable}")),
+        }
+    }
+}
+
+impl TestTarget for VmTestTarget {
+    fn load(&mut self, file_name: Option<&str>, files: &mut dyn ScriptFiles) -> Result<(), String> {
+        let file_names = match file_name {
+            Some(file_name) => vec![file_name.to_owned()],
+            None => files.list("vm")?,
+        };
+        let file_contents = file_names
+            .into_iter()
+            .map(|file_name| Ok((file_name.clone(), files.read(&file_name)?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        let vm =
+            VM::from_file_contents(file_contents).map_err(|errors| errors_to_string(&errors))?;
+        self.vm = Some(vm);
+
+        Ok(())
+    }
+
+    fn get(&mut self, variable: &Variable) -> Result<Word, String> {
+        let address = self.address(variable)?;
+
+        Ok(self.vm()?.get_ram_value(address))
+    }
+
+    fn set(&mut self, variable: &Variable, value: Word) -> Result<(), String> {
+        let address = self.address(variable)?;
+        self.vm()?.set_ram_value(address, value);
+
+        Ok(())
+    }
+
+    fn clock(&mut self, command: &ScriptCommand) -> Result<(), String> {
+        match command {
+            ScriptCommand::VmStep => {
+                let vm = self.vm()?;
+                match vm.step() {
+                    StopReason::Error => Err(vm.error().unwrap().to_string()),
+                    _ => Ok(()),
+                }
+            }
+            _ => Err(format!("unsupported command {command:?}")),
+        }
+    }
+}
+
+pub fn run_vm_test_file(path: &Path) -> Result<TestReport, String> {
+    run_test_file(&mut VmTestTarget::default(), path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::Hardware;
+    use crate::wasm_hardware::WasmHardware;
+
+    const MULT_ASM: &str = r#"
+        @R2
+        M=0
+        (LOOP)
+        @R1
+        D=M
+        @END
+        D;JLE
+        @R0
+        D=M
+        @R2
+        M=D+M
+        @R1
+        M=M-1
+        @LOOP
+        0;JMP
+        (END)
+        @END
+        0;JMP
+        "#;
+
+    /// The course's project 4 script, unmodified.
+    const MULT_TST: &str = r#"// This file is part of www.nand2tetris.org
+// and the book "The Elements of Computing Systems"
+// by Nisan and Schocken, MIT Press.
+// File name: projects/04/mult/Mult.tst
+
+load Mult.asm,
+output-file Mult.out,
+compare-to Mult.cmp,
+output-list RAM[0]%D2.6.2 RAM[1]%D2.6.2 RAM[2]%D2.6.2;
+
+set RAM[0] 0,   // Set test arguments
+set RAM[1] 0,
+set RAM[2] -1;  // Ensure that program initialized product to 0
+repeat 20 {
+  ticktock;
+}
+set RAM[0] 0,   // Restore arguments in case program used them as loop counter
+set RAM[1] 0,
+output;
+
+set PC 0,
+set RAM[0] 1,   // Set test arguments
+set RAM[1] 0,
+set RAM[2] -1;  // Ensure that program initialized product to 0
+repeat 50 {
+  ticktock;
+}
+set RAM[0] 1,   // Restore arguments in case program used them as loop counter
+set RAM[1] 0,
+output;
+
+set PC 0,
+set RAM[0] 0,   // Set test arguments
+set RAM[1] 2,
+set RAM[2] -1;  // Ensure that program initialized product to 0
+repeat 80 {
+  ticktock;
+}
+set RAM[0] 0,   // Restore arguments in case program used them as loop counter
+set RAM[1] 2,
+output;
+
+set PC 0,
+set RAM[0] 3,   // Set test arguments
+set RAM[1] 1,
+set RAM[2] -1;  // Ensure that program initialized product to 0
+repeat 120 {
+  ticktock;
+}
+set RAM[0] 3,   // Restore arguments in case program used them as loop counter
+set RAM[1] 1,
+output;
+
+set PC 0,
+set RAM[0] 2,   // Set test arguments
+set RAM[1] 4,
+set RAM[2] -1;  // Ensure that program initialized product to 0
+repeat 150 {
+  ticktock;
+}
+set RAM[0] 2,   // Restore arguments in case program used them as loop counter
+set RAM[1] 4,
+output;
+
+set PC 0,
+set RAM[0] 6,   // Set test arguments
+set RAM[1] 7,
+set RAM[2] -1;  // Ensure that program initialized product to 0
+repeat 210 {
+  ticktock;
+}
+set RAM[0] 6,   // Restore arguments in case program used them as loop counter
+set RAM[1] 7,
+output;
+"#;
+
+    fn files(cmp: &str) -> HashMap<String, String> {
+        HashMap::from([
+            ("Mult.asm".to_owned(), MULT_ASM.to_owned()),
+            ("Mult.cmp".to_owned(), cmp.to_owned()),
+        ])
+    }
+
+    const MULT_CMP: &str = "|  RAM[0]  |  RAM[1]  |  RAM[2]  |
+|       0  |       0  |       0  |
+|       1  |       0  |       0  |
+|       0  |       2  |       0  |
+|       3  |       1  |       3  |
+|       2  |       4  |       8  |
+|       6  |       7  |      42  |
+";
+
+    #[test]
+    fn test_cpu_script() {
+        let mut hardware = Hardware::default();
+        let report = run_test_script(
+            &mut CpuTestTarget::new(&mut hardware),
+            MULT_TST,
+            &mut files(MULT_CMP),
+        )
+        .unwrap();
+
+        assert_eq!(report.output, MULT_CMP);
+        assert_eq!(report.output_file.as_deref(), Some("Mult.out"));
+        assert!(report.passed());
+    }
+
+    #[test]
+    fn test_wasm_cpu_script() {
+        let mut hardware = WasmHardware::from_instructions(&[]);
+        let report = run_test_script(
+            &mut CpuTestTarget::new(&mut hardware),
+            MULT_TST,
+            &mut files(MULT_CMP),
+        )
+        .unwrap();
+
+        assert!(report.passed());
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let cmp = MULT_CMP.replace("42", "41");
+        let mut hardware = Hardware::default();
+        let report = run_test_script(
+            &mut CpuTestTarget::new(&mut hardware),
+            MULT_TST,
+            &mut files(&cmp),
+        )
+        .unwrap();
+
+        assert_eq!(
+            report.mismatch,
+            Some(Mismatch {
+                line: 7,
+                column: "RAM[2]".to_owned(),
+                expected: "|       6  |       7  |      41  |".to_owned(),
+                actual: "|       6  |       7  |      42  |".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_unsupported_variable() {
+        let mut hardware = Hardware::default();
+        let result = run_test_script(
+            &mut CpuTestTarget::new(&mut hardware),
+            "load Mult.asm, set time 5;",
+            &mut files(MULT_CMP),
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_tick_tock() {
+        let script = "
+            load Mult.asm,
+            output-list time%D1.4.1 PC%D1.4.1 RAM[2]%D1.4.1;
+            set RAM[2] -1,
+            tick, output;
+            tock, output;
+            set PC 0,
+            tick, tock, output;
+            ";
+        let mut hardware = Hardware::default();
+        let report = run_test_script(
+            &mut CpuTestTarget::new(&mut hardware),
+            script,
+            &mut files(""),
+        )
+        .unwrap();
+
+        assert_eq!(
+            report.output,
+            "| time |  PC  |RAM[2]|
+|    0 |    0 |   -1 |
+|    1 |    1 |   -1 |
+|    2 |    1 |   -1 |
+"
+        );
+    }
+
+    #[test]
+    fn test_formats() {
+        let column = |kind| OutputColumn {
+            variable: Variable {
+                name: "D".to_owned(),
+                index: None,
+            },
+            kind,
+            pad_left: 1,
+            length: 16,
+            pad_right: 1,
+        };
+
+        assert_eq!(column(FormatKind::Hex).format(-2), "             FFFE ");
+        assert_eq!(column(FormatKind::Binary).format(5), " 0000000000000101 ");
+        assert_eq!(column(FormatKind::Decimal).header(), "        D         ");
+    }
+
+    const BASIC_TEST_VM: &str = r#"
+        // Executes pop and push commands outside any function.
+        push constant 10
+        pop local 0
+        push constant 21
+        push constant 22
+        pop argument 2
+        pop argument 1
+        push constant 36
+        pop this 6
+        push constant 510
+        pop temp 6
+        push local 0
+        push argument 1
+        add
+        push this 6
+        sub
+        push temp 6
+        add
+        "#;
+
+    const BASIC_TEST_TST: &str = r#"
+        load BasicTest.vm,
+        output-file BasicTest.out,
+        compare-to BasicTest.cmp,
+        output-list RAM[256]%D1.6.1 RAM[300]%D1.6.1 RAM[401]%D1.6.1
+                    RAM[402]%D1.6.1 RAM[3006]%D1.6.1 RAM[11]%D1.6.1;
+
+        set sp 256,
+        set local 300,
+        set argument 400,
+        set this 3000,
+        set that 3010;
+
+        repeat 17 {
+          vmstep;
+        }
+
+        output;
+        "#;
+
+    const BASIC_TEST_CMP: &str = "|RAM[256]|RAM[300]|RAM[401]|RAM[402]|RAM[3006|RAM[11] |
+|    505 |     10 |     21 |     22 |     36 |    510 |
+";
+
+    #[test]
+    fn test_vm_script() {
+        let mut files = HashMap::from([
+            ("BasicTest.vm".to_owned(), BASIC_TEST_VM.to_owned()),
+            ("BasicTest.cmp".to_owned(), BASIC_TEST_CMP.to_owned()),
+        ]);
+        let report =
+            run_test_script(&mut VmTestTarget::default(), BASIC_TEST_TST, &mut files).unwrap();
+
+        assert_eq!(report.output, BASIC_TEST_CMP);
+        assert!(report.passed());
+    }
+
+    const SIMPLE_FUNCTION_VM: &str = "
+        function SimpleFunction.test 2
+        push local 0
+        push local 1
+        add
+        not
+        push argument 0
+        add
+        push argument 1
+        sub
+        return
+        ";
+
+    const SIMPLE_FUNCTION_TST: &str = r#"
+        load SimpleFunction.vm,
+        output-file SimpleFunction.out,
+        compare-to SimpleFunction.cmp,
+        output-list RAM[0]%D1.6.1 RAM[1]%D1.6.1 RAM[2]%D1.6.1
+                    RAM[3]%D1.6.1 RAM[4]%D1.6.1 RAM[310]%D1.6.1;
+
+        set sp 317,
+        set local 317,
+        set argument 310,
+        set this 3000,
+        set that 4000,
+        set argument[0] 1234,
+        set argument[1] 37,
+        set argument[2] 9,
+        set argument[3] 305,
+        set argument[4] 300,
+        set argument[5] 3010,
+        set argument[6] 4010;
+
+        repeat 10 {
+          vmstep;
+        }
+
+        output;
+        "#;
+
+    const SIMPLE_FUNCTION_CMP: &str = "| RAM[0] | RAM[1] | RAM[2] | RAM[3] | RAM[4] |RAM[310]|
+|    311 |    305 |    300 |   3010 |   4010 |   1196 |
+";
+
+    /// Steps a function through its `return` without a `call` having set up its frame.
+    #[test]
+    fn test_vm_script_return() {
+        let mut files = HashMap::from([
+            (
+                "SimpleFunction.vm".to_owned(),
+                SIMPLE_FUNCTION_VM.to_owned(),
+            ),
+            (
+                "SimpleFunction.cmp".to_owned(),
+                SIMPLE_FUNCTION_CMP.to_owned(),
+            ),
+        ]);
+        let report = run_test_script(
+            &mut VmTestTarget::default(),
+            SIMPLE_FUNCTION_TST,
+            &mut files,
+        )
+        .unwrap();
+
+        assert_eq!(report.output, SIMPLE_FUNCTION_CMP);
+        assert!(report.passed());
+    }
+
+    #[test]
+    fn test_vm_script_load_all() {
+        let sys = r#"
+            function Sys.init 0
+            push constant 4
+            call Main.fibonacci 1
+            label WHILE
+            goto WHILE
+            "#;
+        let main = r#"
+            function Main.fibonacci 0
+            push argument 0
+            push constant 2
+            lt
+            if-goto IF_TRUE
+            push argument 0
+            push constant 2
+            sub
+            call Main.fibonacci 1
+            push argument 0
+            push constant 1
+            sub
+            call Main.fibonacci 1
+            add
+            return
+            label IF_TRUE
+            push argument 0
+            return
+            "#;
+        let tst = r#"
+            load,
+            compare-to FibonacciElement.cmp,
+            output-list RAM[0]%D1.6.1 RAM[261]%D1.6.1 local%D1.6.1;
+
+            set sp 261;
+            repeat 110 {
+              vmstep;
+            }
+            output;
+            "#;
+        let cmp = "|  RAM[0]  | RAM[261] |  local   |\n|    262 |      3 |      0 |\n";
+        let mut files = HashMap::from([
+            ("Sys.vm".to_owned(), sys.to_owned()),
+            ("Main.vm".to_owned(), main.to_owned()),
+            ("FibonacciElement.cmp".to_owned(), cmp.to_owned()),
+        ]);
+        let report = run_test_script(&mut VmTestTarget::default(), tst, &mut files).unwrap();
+
+        assert_eq!(report.mismatch, None);
+    }
+}
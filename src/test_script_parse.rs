@@ -0,0 +1,282 @@
+use crate::{
+    hardware::Word,
+    parse_utils::{IResult, is_not0, keyword, skip, symbol, token},
+    test_script::*,
+};
+
+use nom::{
+    Finish,
+    branch::alt,
+    bytes::complete::{tag, take_while1},
+    character::complete::{self, char, digit1, hex_digit1, one_of},
+    combinator::{all_consuming, cut, map, map_res, opt, recognize, value},
+    error::convert_error,
+    multi::{many0, many1},
+    sequence::{delimited, pair, preceded, terminated, tuple},
+};
+
+fn file_name(input: &str) -> IResult<&str, String> {
+    map(
+        token(take_while1(|c: char| {
+            c.is_alphanumeric() || "-_./\\".contains(c)
+        })),
+        str::to_owned,
+    )(input)
+}
+
+fn variable(input: &str) -> IResult<&str, Variable> {
+    map(
+        pair(
+            token(take_while1(|c: char| c.is_alphanumeric() || c == '_')),
+            opt(delimited(char('['), cut(complete::i32), cut(char(']')))),
+        ),
+        |(name, index)| Variable {
+            name: name.to_owned(),
+            index: index.map(|i| i as Word),
+        },
+    )(input)
+}
+
+fn number(input: &str) -> IResult<&str, Word> {
+    token(alt((
+        preceded(
+            tag("%X"),
+            cut(map_res(hex_digit1, |s| {
+                i64::from_str_radix(s, 16).map(|v| v as Word)
+            })),
+        ),
+        preceded(
+            tag("%B"),
+            cut(map_res(take_while1(|c| c == '0' || c == '1'), |s| {
+                i64::from_str_radix(s, 2).map(|v| v as Word)
+            })),
+        ),
+        preceded(
+            opt(tag("%D")),
+            map_res(recognize(pair(opt(char('-')), digit1)), |s: &str| {
+                s.parse::<i64>().map(|v| v as Word)
+            }),
+        ),
+    )))(input)
+}
+
+fn output_column(input: &str) -> IResult<&str, OutputColumn> {
+    map(
+        pair(
+            variable,
+            opt(preceded(
+                char('%'),
+                cut(tuple((
+                    one_of("DXBS"),
+                    complete::u8,
+                    preceded(char('.'), complete::u8),
+                    preceded(char('.'), complete::u8),
+                ))),
+            )),
+        ),
+        |(variable, format)| {
+            let (kind, pad_left, length, pad_right) = format.unwrap_or(('D', 1, 6, 1));
+            OutputColumn {
+                variable,
+                kind: match kind {
+                    'X' => FormatKind::Hex,
+                    'B' => FormatKind::Binary,
+                    'S' => FormatKind::String,
+                    _ => FormatKind::Decimal,
+                },
+                pad_left: pad_left as usize,
+                length: length as usize,
+                pad_right: pad_right as usize,
+            }
+        },
+    )(input)
+}
+
+fn compare_op(input: &str) -> IResult<&str, CompareOp> {
+    token(alt((
+        value(CompareOp::NotEqual, tag("<>")),
+        value(CompareOp::LessOrEqual, tag("<=")),
+        value(CompareOp::GreaterOrEqual, tag(">=")),
+        value(CompareOp::Equal, tag("=")),
+        value(CompareOp::Less, tag("<")),
+        value(CompareOp::Greater, tag(">")),
+    )))(input)
+}
+
+fn block(input: &str) -> IResult<&str, Vec<ScriptCommand>> {
+    delimited(symbol('{'), many0(command), cut(symbol('}')))(input)
+}
+
+fn simple_command(input: &str) -> IResult<&str, ScriptCommand> {
+    alt((
+        map(
+            preceded(keyword("load"), opt(file_name)),
+            ScriptCommand::Load,
+        ),
+        map(
+            preceded(keyword("output-file"), cut(file_name)),
+            ScriptCommand::OutputFile,
+        ),
+        map(
+            preceded(keyword("compare-to"), cut(file_name)),
+            ScriptCommand::CompareTo,
+        ),
+        map(
+            preceded(keyword("output-list"), cut(many1(output_column))),
+            ScriptCommand::OutputList,
+        ),
+        map(
+            preceded(keyword("set"), cut(pair(variable, number))),
+            |(variable, value)| ScriptCommand::Set { variable, value },
+        ),
+        map(
+            preceded(
+                keyword("echo"),
+                cut(token(delimited(char('"'), is_not0("\"\r\n"), char('"')))),
+            ),
+            |s: &str| ScriptCommand::Echo(s.to_owned()),
+        ),
+        value(ScriptCommand::ClearEcho, keyword("clear-echo")),
+        value(ScriptCommand::Output, keyword("output")),
+        value(ScriptCommand::TickTock, keyword("ticktock")),
+        value(ScriptCommand::Tick, keyword("tick")),
+        value(ScriptCommand::Tock, keyword("tock")),
+        value(ScriptCommand::VmStep, keyword("vmstep")),
+    ))(input)
+}
+
+fn command(input: &str) -> IResult<&str, ScriptCommand> {
+    alt((
+        map(
+            preceded(
+                keyword("repeat"),
+                cut(pair(opt(token(complete::u64)), block)),
+            ),
+            |(count, commands)| ScriptCommand::Repeat { count, commands },
+        ),
+        map(
+            preceded(
+                keyword("while"),
+                cut(pair(tuple((variable, compare_op, number)), block)),
+            ),
+            |((variable, op, value), commands)| ScriptCommand::While {
+                condition: Condition {
+                    variable,
+                    op,
+                    value,
+                },
+                commands,
+            },
+        ),
+        terminated(simple_command, cut(token(one_of(",;!")))),
+    ))(input)
+}
+
+pub fn parse_test_script(input: &str) -> Result<Vec<ScriptCommand>, String> {
+    all_consuming(terminated(many0(command), skip))(input)
+        .finish()
+        .map(|(_, commands)| commands)
+        .map_err(|e| convert_error(input, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        let script = r#"
+        // Mult
+        load Mult.asm,
+        output-file Mult.out,
+        compare-to Mult.cmp,
+        output-list RAM[0]%D2.6.2 RAM[1]%X1.4.1 D;
+
+        set RAM[0] %X1F,   // Set test arguments
+        set RAM[1] -3;
+        repeat 20 {
+            ticktock;
+        }
+        while RAM[1] <> 0 {
+            ticktock;
+        }
+        output;
+        "#;
+
+        assert_eq!(
+            parse_test_script(script),
+            Ok(vec![
+                ScriptCommand::Load(Some("Mult.asm".to_owned())),
+                ScriptCommand::OutputFile("Mult.out".to_owned()),
+                ScriptCommand::CompareTo("Mult.cmp".to_owned()),
+                ScriptCommand::OutputList(vec![
+                    OutputColumn {
+                        variable: Variable {
+                            name: "RAM".to_owned(),
+                            index: Some(0)
+                        },
+                        kind: FormatKind::Decimal,
+                        pad_left: 2,
+                        length: 6,
+                        pad_right: 2,
+                    },
+                    OutputColumn {
+                        variable: Variable {
+                            name: "RAM".to_owned(),
+                            index: Some(1)
+                        },
+                        kind: FormatKind::Hex,
+                        pad_left: 1,
+                        length: 4,
+                        pad_right: 1,
+                    },
+                    OutputColumn {
+                        variable: Variable {
+                            name: "D".to_owned(),
+                            index: None
+                        },
+                        kind: FormatKind::Decimal,
+                        pad_left: 1,
+                        length: 6,
+                        pad_right: 1,
+                    },
+                ]),
+                ScriptCommand::Set {
+                    variable: Variable {
+                        name: "RAM".to_owned(),
+                        index: Some(0)
+                    },
+                    value: 31
+                },
+                ScriptCommand::Set {
+                    variable: Variable {
+                        name: "RAM".to_owned(),
+                        index: Some(1)
+                    },
+                    value: -3
+                },
+                ScriptCommand::Repeat {
+                    count: Some(20),
+                    commands: vec![ScriptCommand::TickTock]
+                },
+                ScriptCommand::While {
+                    condition: Condition {
+                        variable: Variable {
+                            name: "RAM".to_owned(),
+                            index: Some(1)
+                        },
+                        op: CompareOp::NotEqual,
+                        value: 0
+                    },
+                    commands: vec![ScriptCommand::TickTock]
+                },
+                ScriptCommand::Output,
+            ])
+        );
+    }
+
+    #[test]
+    fn test_missing_terminator() {
+        assert!(parse_test_script("load Mult.asm\noutput;").is_err());
+    }
+}
@@ -6,9 +6,16 @@ use std::{
 };
 
 use crate::{
-    hardware::{RAM, Word},
-    os::OS,
-    vm_parse::parse_commands,
+    condition::{self, Condition, Variable},
+    condition_parse::parse_condition,
+    coverage::Coverage,
+    hardware::{MEM_SIZE, RAM, Word},
+    history::History,
+    os::{self, ClassPolicy, OS, OsCall, OsPolicy},
+    profiler::Profiler,
+    snapshot::{Snapshot, fingerprint, to_index},
+    vm_parse::{VMParseError, errors_to_string, parse_files},
+    watchpoint::{self, WatchHit, Watchpoint},
 };
 
 impl Index<Register> for RAM {
@@ -25,83 +32,92 @@ impl IndexMut<Register> for RAM {
     }
 }
 
+const STACK_START: Word = 256;
+
 impl Default for RAM {
     fn default() -> Self {
-        let mut instance = Self {
-            contents: Box::new([0; 32 * 1024]),
-        };
-        instance[Register::SP] = 256;
+        let mut instance = Self::zeroed();
+        instance[Register::SP] = STACK_START;
 
         instance
     }
 }
 
 impl RAM {
-    fn new() -> Self {
-        let mut instance = Self {
-            contents: Box::new([0; 32 * 1024]),
-        };
-        instance[Register::SP] = 256;
+    /// Adds `offset` to `base`, trapping if the result isn't a RAM address.
+    fn address(base: Word, offset: Word) -> Result<Word, Trap> {
+        let address = base as i64 + offset as i64;
+        if (0..MEM_SIZE as i64).contains(&address) {
+            Ok(address as Word)
+        } else {
+            Err(Trap::AddressOutOfRange(address))
+        }
+    }
 
-        instance
+    pub fn read_at(&self, base: Word, offset: Word) -> Result<Word, Trap> {
+        Ok(self[Self::address(base, offset)?])
     }
 
-    pub fn push(&mut self, value: Word) {
+    pub fn write_at(&mut self, base: Word, offset: Word, value: Word) -> Result<(), Trap> {
+        self[Self::address(base, offset)?] = value;
+
+        Ok(())
+    }
+
+    pub fn push(&mut self, value: Word) -> Result<(), Trap> {
         let sp = self[Register::SP];
-        self[sp] = value;
-        self[Register::SP] += 1;
+        self.write_at(sp, 0, value)?;
+        self[Register::SP] = sp.wrapping_add(1);
+
+        Ok(())
     }
 
-    fn pop(&mut self) -> Word {
+    fn pop(&mut self) -> Result<Word, Trap> {
+        let value = self.read_at(self[Register::SP], -1)?;
         self[Register::SP] -= 1;
-        self[self[Register::SP]]
+
+        Ok(value)
     }
 
-    fn stack_top(&mut self) -> &mut Word {
-        let sp = self[Register::SP];
-        &mut self[sp - 1]
+    fn stack_top(&mut self) -> Result<&mut Word, Trap> {
+        let address = Self::address(self[Register::SP], -1)?;
+
+        Ok(&mut self[address])
     }
 
-    pub fn set(&mut self, static_segment: Word, segment: PopSegment, offset: Word, value: Word) {
+    fn segment_base(&self, static_segment: Word, segment: PushSegment) -> Word {
         match segment {
-            PopSegment::Static => {
-                self[static_segment + offset] = value;
-            }
-            PopSegment::Local => {
-                let lcl = self[Register::LCL];
-                self[lcl + offset] = value;
-            }
-            PopSegment::Argument => {
-                let arg = self[Register::ARG];
-                self[arg + offset] = value;
-            }
-            PopSegment::This => {
-                let this = self[Register::THIS];
-                self[this + offset] = value;
-            }
-            PopSegment::That => {
-                let that = self[Register::THAT];
-                self[that + offset] = value;
-            }
-            PopSegment::Temp => {
-                self[Register::TEMP(offset)] = value;
-            }
-            PopSegment::Pointer => {
-                self[Register::THIS.address() + offset] = value;
-            }
+            PushSegment::Constant => 0,
+            PushSegment::Static => static_segment,
+            PushSegment::Local => self[Register::LCL],
+            PushSegment::Argument => self[Register::ARG],
+            PushSegment::This => self[Register::THIS],
+            PushSegment::That => self[Register::THAT],
+            PushSegment::Temp => Register::TEMP(0).address(),
+            PushSegment::Pointer => Register::THIS.address(),
         }
     }
 
-    pub fn get(&self, static_segment: Word, segment: PushSegment, offset: Word) -> Word {
+    pub fn set(
+        &mut self,
+        static_segment: Word,
+        segment: PopSegment,
+        offset: Word,
+        value: Word,
+    ) -> Result<(), Trap> {
+        let base = self.segment_base(static_segment, segment.into());
+        self.write_at(base, offset, value)
+    }
+
+    pub fn get(
+        &self,
+        static_segment: Word,
+        segment: PushSegment,
+        offset: Word,
+    ) -> Result<Word, Trap> {
         match segment {
-            PushSegment::Constant => offset,
-            PushSegment::Static => self[static_segment + offset],
-            PushSegment::Local => self[self[Register::LCL] + offset],
-            PushSegment::Argument => self[self[Register::ARG] + offset],
-            PushSegment::This => self[self[Register::THIS] + offset],
-            PushSegment::That => self[self[Register::THAT] + offset],
-            PushSegment::Temp => self[Register::TEMP(offset)],
-            PushSegment::Pointer => self[Register::THIS.address() + offset],
+            PushSegment::Constant => Ok(offset),
+            _ => self.read_at(self.segment_base(static_segment, segment), offset),
         }
     }
 }
@@ -113,6 +129,70 @@ pub struct Program {
     pub function_metadata: Vec<FunctionMetadata>,
     pub file_name_to_index: HashMap<String, usize>,
     pub files: Vec<File>,
+    pub os_policy: OsPolicy,
+}
+
+impl Program {
+    /// Whether a call to `function_name` runs natively rather than in the program, according to
+    /// the policy of its class.
+    pub fn is_native(&self, function_name: &str) -> bool {
+        let class_name = function_name
+            .split_once('.')
+            .map_or(function_name, |(class_name, _)| class_name);
+        match self.os_policy.class(class_name) {
+            ClassPolicy::Native => true,
+            ClassPolicy::Program => false,
+            ClassPolicy::PreferProgram => !self.function_name_to_index.contains_key(function_name),
+        }
+    }
+
+    /// The functions of `class_name` which the program defines.
+    pub fn class_functions(&self, class_name: &str) -> impl Iterator<Item = &str> {
+        self.function_metadata
+            .iter()
+            .map(|metadata| metadata.name.as_str())
+            .filter(move |name| {
+                name.split_once('.')
+                    .is_some_and(|(class, _)| class == class_name)
+            })
+    }
+
+    pub fn file_index_of_command(&self, command_index: usize) -> usize {
+        self.files
+            .partition_point(|file| file.starting_command_index <= command_index)
+            .saturating_sub(1)
+    }
+
+    pub fn function_index_of_command(&self, command_index: usize) -> usize {
+        self.function_metadata
+            .partition_point(|function| function.command_index <= command_index)
+            .saturating_sub(1)
+    }
+
+    pub fn source_line(&self, command_index: usize) -> Option<(&File, &SourceLine)> {
+        let file = self.files.get(self.file_index_of_command(command_index))?;
+
+        Some((file, file.source_line(command_index)?))
+    }
+
+    pub fn command_index_at_line(&self, file_name: &str, line_number: usize) -> Option<usize> {
+        self.files[*self.file_name_to_index.get(file_name)?].command_index_at_line(line_number)
+    }
+
+    pub fn fingerprint(&self) -> i64 {
+        let commands = self
+            .all_commands
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
+        fingerprint(
+            self.files
+                .iter()
+                .map(|file| file.name.as_str())
+                .chain(commands.iter().map(String::as_str)),
+        )
+    }
 }
 
 #[derive(Clone)]
@@ -123,57 +203,70 @@ pub struct RunState {
     pub os: OS,
     pub call_stack: Vec<Frame>,
     pub breakpoints: Vec<Breakpoint>,
-    pub func_stats: HashMap<String, u64>,
+    pub error: Option<VMError>,
 }
 
 #[derive(Clone)]
 pub struct VM {
     pub run_state: RunState,
     pub program: Program,
+    history: Option<History<VMStep>>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    watchpoints: Vec<Watchpoint>,
+    /// How many steps each conditional breakpoint was counted on, indexed like the breakpoints.
+    breakpoint_hits: Vec<u64>,
 }
 
 impl VM {
-    pub fn from_paths(paths: &[PathBuf]) -> Self {
+    pub fn from_paths(paths: &[PathBuf]) -> Result<Self, String> {
         let files = paths
             .iter()
             .map(|path| {
-                (
+                Ok((
                     path.file_name().unwrap().to_str().unwrap().to_owned(),
-                    fs::read_to_string(path).unwrap(),
-                )
+                    fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?,
+                ))
             })
-            .collect();
+            .collect::<Result<Vec<_>, String>>()?;
 
-        Self::from_file_contents(files)
+        Self::from_file_contents(files).map_err(|errors| errors_to_string(&errors))
     }
 
-    pub fn from_file_contents(file_contents: Vec<(String, String)>) -> Self {
-        Self::from_all_file_commands(
-            file_contents
+    pub fn from_file_contents(
+        file_contents: Vec<(String, String)>,
+    ) -> Result<Self, Vec<VMParseError>> {
+        Ok(Self::from_all_file_sources(parse_files(file_contents)?))
+    }
+
+    /// Commands that weren't parsed from a file, e.g. ones produced by the Jack compiler, are
+    /// numbered as if they were written one per line.
+    pub fn from_all_file_commands(all_file_commands: Vec<(String, Vec<VMCommand>)>) -> Self {
+        Self::from_all_file_sources(
+            all_file_commands
                 .into_iter()
-                .map(|(name, contents)| {
-                    (
-                        name.rsplit_once('.').unwrap().0.to_owned(),
-                        parse_commands(&contents).unwrap().1,
-                    )
+                .map(|(name, commands)| {
+                    let source_lines = (1..=commands.len()).map(SourceLine::new).collect();
+                    (name, commands, source_lines)
                 })
                 .collect(),
         )
     }
 
-    pub fn from_all_file_commands(all_file_commands: Vec<(String, Vec<VMCommand>)>) -> Self {
+    pub fn from_all_file_sources(all_file_sources: Vec<FileSource>) -> Self {
         let mut all_commands = vec![];
         let mut file_name_to_index = HashMap::new();
         let mut files = vec![];
         let mut next_static_index = 16;
         let mut function_name_to_index = HashMap::new();
         let mut function_metadata = vec![];
-        for (name, file_commands) in all_file_commands.into_iter() {
+        for (name, file_commands, source_lines) in all_file_sources.into_iter() {
             let file_index = files.len();
             let file = File::new(
                 &name,
                 file_index,
                 &file_commands,
+                source_lines,
                 all_commands.len(),
                 next_static_index,
                 &mut function_name_to_index,
@@ -191,6 +284,7 @@ impl VM {
             function_metadata,
             file_name_to_index,
             files: files.into_iter().collect(),
+            os_policy: OsPolicy::default(),
         };
 
         Self::new(program)
@@ -205,241 +299,1105 @@ impl VM {
             run_state: RunState {
                 current_file_index,
                 current_command_index,
-                ram: RAM::new(),
+                ram: RAM::default(),
                 os: Default::default(),
                 call_stack: vec![Frame { function_index }],
                 breakpoints: vec![],
-                func_stats: HashMap::new(),
+                error: None,
             },
+            history: None,
+            profiler: None,
+            coverage: None,
+            watchpoints: vec![],
+            breakpoint_hits: vec![],
         }
     }
 
     pub fn reset(&mut self) {
-        for (key, value) in self.run_state.func_stats.iter() {
-            println!("Function {} was called {} times", key, value);
-        }
+        let breakpoints = std::mem::take(&mut self.run_state.breakpoints);
+        let watchpoints = std::mem::take(&mut self.watchpoints);
+        let capacity = self.history.as_ref().map(History::capacity);
+        let profiling = self.profiler.is_some();
+        let covering = self.coverage.is_some();
+        let sanitizing = self.is_sanitizing();
+        let heap_in_ram = self.is_heap_in_ram();
         *self = VM::new(self.program.clone());
+        self.set_sanitizing(sanitizing);
+        self.run_state
+            .set_heap_in_ram(heap_in_ram)
+            .expect("a new heap is inside the RAM");
+        self.run_state.breakpoints = breakpoints;
+        self.watchpoints = watchpoints;
+        self.set_recording(capacity);
+        self.set_profiling(profiling);
+        self.set_coverage(covering);
+    }
+
+    /// Starts catching double frees and accesses to freed or unallocated heap memory, or stops.
+    /// Only blocks freed while it's on are known to be freed.
+    pub fn set_sanitizing(&mut self, sanitizing: bool) {
+        self.run_state.os.set_sanitizing(sanitizing);
+    }
+
+    pub fn is_sanitizing(&self) -> bool {
+        self.run_state.os.is_sanitizing()
+    }
+
+    /// Starts or stops keeping the native heap's segment headers in RAM, like the Jack OS. The
+    /// headers are written straight away, so the recorded history starts over.
+    pub fn set_heap_in_ram(&mut self, in_ram: bool) -> Result<(), Trap> {
+        self.run_state.set_heap_in_ram(in_ram)?;
+        self.set_recording(self.history.as_ref().map(History::capacity));
+
+        Ok(())
+    }
+
+    pub fn is_heap_in_ram(&self) -> bool {
+        self.run_state.os.is_heap_in_ram()
+    }
+
+    /// Takes effect from the next call.
+    pub fn set_os_policy(&mut self, os_policy: OsPolicy) {
+        self.program.os_policy = os_policy;
+    }
+
+    /// Starts profiling from the current state, or stops it.
+    pub fn set_profiling(&mut self, profiling: bool) {
+        self.profiler = profiling.then(|| Profiler::new(&self.program, &self.run_state));
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Starts counting how many times each command is executed, or stops it.
+    pub fn set_coverage(&mut self, covering: bool) {
+        self.coverage = covering.then(|| Coverage::new(self.program.all_commands.len()));
+    }
+
+    /// Hit counts indexed like `program.all_commands`. Running backwards doesn't take them away.
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn save_snapshot(&self) -> Snapshot {
+        let run_state = &self.run_state;
+        let mut snapshot = Snapshot::new("vm");
+        snapshot.push("program", [self.program.fingerprint()]);
+        snapshot.push("file", [run_state.current_file_index as i64]);
+        snapshot.push("command", [run_state.current_command_index as i64]);
+        snapshot.push(
+            "call_stack",
+            run_state
+                .call_stack
+                .iter()
+                .map(|frame| frame.function_index as i64),
+        );
+        run_state.os.save(&mut snapshot);
+        snapshot.push_words("ram", &run_state.ram.contents[..]);
+
+        snapshot
+    }
+
+    /// Breakpoints are kept, but the recorded history starts over.
+    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        snapshot.expect_kind("vm")?;
+        if snapshot.get_one("program")? != self.program.fingerprint() {
+            return Err("snapshot was taken from a different program".to_owned());
+        }
+        let function_count = self.program.function_metadata.len();
+        let call_stack = snapshot
+            .get("call_stack")?
+            .iter()
+            .map(|&index| {
+                Ok(Frame {
+                    function_index: to_index(index, function_count)?,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if call_stack.is_empty() {
+            return Err("snapshot has an empty call stack".to_owned());
+        }
+        let mut ram = RAM::zeroed();
+        snapshot.get_words("ram", &mut ram.contents[..])?;
+
+        let run_state = &mut self.run_state;
+        run_state.current_file_index =
+            to_index(snapshot.get_one("file")?, self.program.files.len())?;
+        run_state.current_command_index = to_index(
+            snapshot.get_one("command")?,
+            self.program.all_commands.len() + 1,
+        )?;
+        run_state.call_stack = call_stack;
+        let sanitizing = run_state.os.is_sanitizing();
+        run_state.os = OS::restore(snapshot)?;
+        run_state.os.set_sanitizing(sanitizing);
+        run_state.ram = ram;
+        run_state.error = None;
+        self.set_recording(self.history.as_ref().map(History::capacity));
+        self.set_profiling(self.profiler.is_some());
+
+        Ok(())
     }
 
-    pub fn step(&mut self) {
+    /// Starts recording up to `capacity` steps, or stops recording if it's `None`.
+    pub fn set_recording(&mut self, capacity: Option<usize>) {
+        self.history = capacity.map(History::new);
+        self.update_logging();
+    }
+
+    /// The RAM logs writes for the history and the watchpoints, and reads only for the latter.
+    fn update_logging(&mut self) {
+        let (writes, reads) = watchpoint::logging_needed(&self.watchpoints);
+        self.run_state
+            .ram
+            .set_journaling(self.history.is_some() || writes);
+        self.run_state.ram.set_logging_reads(reads);
+    }
+
+    /// The position in the recorded timeline and its length, if recording.
+    pub fn timeline(&self) -> Option<(usize, usize)> {
+        let history = self.history.as_ref()?;
+
+        Some((history.position(), history.len()))
+    }
+
+    /// Runs a single command, stepping into calls.
+    pub fn step(&mut self) -> StopReason {
         self.run(1)
     }
 
-    pub fn run(&mut self, num_steps: u64) {
-        let files = &self.program.files;
+    /// Breakpoints are checked after every command, so a run stops right after the command which
+    /// made one of them true. One that is already true when the run starts has to become false
+    /// before it can stop it again.
+    pub fn run(&mut self, num_steps: u64) -> StopReason {
+        self.run_until(num_steps, |_| false)
+    }
+
+    /// Runs a `call` until it returns, or a single command if it isn't one.
+    pub fn step_over(&mut self, num_steps: u64) -> StopReason {
+        self.run_to(self.step_over_target(), num_steps)
+    }
+
+    /// Runs until the current function returns.
+    pub fn step_out(&mut self, num_steps: u64) -> StopReason {
+        self.run_to(self.step_out_target(), num_steps)
+    }
+
+    pub fn step_over_target(&self) -> RunTarget {
+        RunTarget::Depth(self.run_state.call_stack.len())
+    }
+
+    pub fn step_out_target(&self) -> RunTarget {
+        RunTarget::Depth(self.run_state.call_stack.len().saturating_sub(1))
+    }
+
+    /// Runs at least one command, stopping with `StopReason::TargetReached` once `target` is.
+    pub fn run_to(&mut self, target: RunTarget, num_steps: u64) -> StopReason {
+        self.run_until(num_steps, |run_state| match target {
+            RunTarget::Depth(depth) => run_state.call_stack.len() <= depth,
+            RunTarget::Command(command_index) => run_state.current_command_index == command_index,
+        })
+    }
+
+    fn run_until(
+        &mut self,
+        num_steps: u64,
+        mut is_done: impl FnMut(&RunState) -> bool,
+    ) -> StopReason {
+        self.breakpoint_hits
+            .resize(self.run_state.breakpoints.len(), 0);
+        let mut breakpoints =
+            BreakpointTracker::new(&self.program, &self.run_state, &self.breakpoint_hits);
+        let program = &self.program;
         let run_state = &mut self.run_state;
+        if run_state.error.is_some() {
+            return StopReason::Error;
+        }
 
-        let mut static_segment = *files[run_state.current_file_index].static_segment.start();
+        let mut static_segment = *program.files[run_state.current_file_index]
+            .static_segment
+            .start();
         for _ in 0..num_steps {
-            match &self.program.all_commands[run_state.current_command_index] {
-                VMCommand::Add => {
-                    let y = run_state.ram.pop();
-                    *run_state.ram.stack_top() = run_state.ram.stack_top().wrapping_add(y);
-                    run_state.current_command_index += 1;
-                }
-                VMCommand::Push { segment, offset } => {
-                    let value = run_state.ram.get(static_segment, *segment, *offset);
-                    run_state.ram.push(value);
-                    run_state.current_command_index += 1;
-                }
-                VMCommand::Pop { segment, offset } => {
-                    let value = run_state.ram.pop();
-                    run_state.ram.set(static_segment, *segment, *offset, value);
-                    run_state.current_command_index += 1;
-                }
-                VMCommand::Sub => {
-                    let y = run_state.ram.pop();
-                    *run_state.ram.stack_top() = run_state.ram.stack_top().wrapping_sub(y);
-                    run_state.current_command_index += 1;
-                }
-                VMCommand::Neg => {
-                    let y = run_state.ram.stack_top();
-                    *y = y.wrapping_neg();
-                    run_state.current_command_index += 1;
-                }
-                VMCommand::Eq => {
-                    let y = run_state.ram.pop();
-                    let x = run_state.ram.stack_top();
-                    *x = -((*x == y) as Word);
-                    run_state.current_command_index += 1;
-                }
-                VMCommand::Gt => {
-                    let y = run_state.ram.pop();
-                    let x = run_state.ram.stack_top();
-                    *x = -((*x > y) as Word);
-                    run_state.current_command_index += 1;
-                }
-                VMCommand::Lt => {
-                    let y = run_state.ram.pop();
-                    let x = run_state.ram.stack_top();
-                    *x = -((*x < y) as Word);
-                    run_state.current_command_index += 1;
-                }
-                VMCommand::And => {
-                    let y = run_state.ram.pop();
-                    *run_state.ram.stack_top() &= y;
-                    run_state.current_command_index += 1;
+            let step = self
+                .history
+                .is_some()
+                .then(|| VMStep::new(program, run_state));
+            let command_index = run_state.current_command_index;
+            let depth = run_state.call_stack.len();
+            if let Some(coverage) = &mut self.coverage {
+                coverage.hit(command_index);
+            }
+            // Only the accesses made by this command are checked against the watchpoints.
+            run_state.ram.take_journal();
+            run_state.ram.take_reads();
+            let result = Self::execute(program, run_state, &mut static_segment);
+            let writes = run_state.ram.take_journal();
+            let reads = run_state.ram.take_reads();
+            let watch_hit = watchpoint::check(
+                &self.watchpoints,
+                &run_state.ram,
+                &writes,
+                &reads,
+                command_index,
+            );
+            if let Some(profiler) = &mut self.profiler
+                && result == Ok(false)
+            {
+                let command = &program.all_commands[command_index];
+                match command {
+                    // A blocked OS call stays on the same command.
+                    VMCommand::Call { function_name, .. }
+                        if run_state.current_command_index == command_index
+                            && run_state.call_stack.len() == depth =>
+                    {
+                        profiler.record_waiting(function_name)
+                    }
+                    _ => profiler.record(command, depth, run_state),
                 }
-                VMCommand::Or => {
-                    let y = run_state.ram.pop();
-                    *run_state.ram.stack_top() |= y;
-                    run_state.current_command_index += 1;
+            }
+            if let Some(history) = &mut self.history
+                && let Some(step) = step
+                && result != Ok(true)
+            {
+                history.push(VMStep { writes, ..step });
+            }
+            match result {
+                Ok(false) => {}
+                Ok(true) => return StopReason::Halted,
+                Err(trap) => {
+                    run_state.error = Some(VMError::new(program, run_state, trap));
+                    return StopReason::Error;
                 }
-                VMCommand::Not => {
-                    *run_state.ram.stack_top() ^= -1;
-                    run_state.current_command_index += 1;
+            }
+
+            if let Some(watch_hit) = watch_hit {
+                return StopReason::Watchpoint(watch_hit);
+            }
+            if let Some(index) =
+                breakpoints.hit(program, run_state, &mut self.breakpoint_hits, true)
+            {
+                return StopReason::Breakpoint(index);
+            }
+            if is_done(run_state) {
+                return StopReason::TargetReached;
+            }
+        }
+
+        StopReason::StepsExhausted
+    }
+
+    /// Undoes up to `num_steps` recorded steps, stopping at breakpoints the same way `run` does.
+    /// The hit counts of conditional breakpoints aren't taken back.
+    pub fn run_back(&mut self, num_steps: u64) -> StopReason {
+        self.breakpoint_hits
+            .resize(self.run_state.breakpoints.len(), 0);
+        let mut breakpoints =
+            BreakpointTracker::new(&self.program, &self.run_state, &self.breakpoint_hits);
+        let run_state = &mut self.run_state;
+        // Writes made outside of a step, e.g. by the keyboard, aren't undone.
+        run_state.ram.take_journal();
+        for _ in 0..num_steps {
+            let Some(step) = self.history.as_mut().and_then(History::pop) else {
+                return StopReason::StartOfHistory;
+            };
+            step.undo(run_state);
+
+            if let Some(index) =
+                breakpoints.hit(&self.program, run_state, &mut self.breakpoint_hits, false)
+            {
+                return StopReason::Breakpoint(index);
+            }
+        }
+
+        StopReason::StepsExhausted
+    }
+
+    /// Executes the current command, returning whether the program has halted. A command which
+    /// traps leaves `current_command_index` pointing at it.
+    fn execute(
+        program: &Program,
+        run_state: &mut RunState,
+        static_segment: &mut Word,
+    ) -> Result<bool, Trap> {
+        let Some(command) = program.all_commands.get(run_state.current_command_index) else {
+            return Ok(true);
+        };
+        match command {
+            VMCommand::Add => {
+                let y = run_state.ram.pop()?;
+                let x = run_state.ram.stack_top()?;
+                *x = x.wrapping_add(y);
+                run_state.current_command_index += 1;
+            }
+            VMCommand::Push { segment, offset } => {
+                Self::check_heap_access(program, run_state, *segment, *offset)?;
+                let value = run_state.ram.get(*static_segment, *segment, *offset)?;
+                run_state.ram.push(value)?;
+                run_state.current_command_index += 1;
+            }
+            VMCommand::Pop { segment, offset } => {
+                Self::check_heap_access(program, run_state, (*segment).into(), *offset)?;
+                let value = run_state.ram.pop()?;
+                run_state
+                    .ram
+                    .set(*static_segment, *segment, *offset, value)?;
+                run_state.current_command_index += 1;
+            }
+            VMCommand::Sub => {
+                let y = run_state.ram.pop()?;
+                let x = run_state.ram.stack_top()?;
+                *x = x.wrapping_sub(y);
+                run_state.current_command_index += 1;
+            }
+            VMCommand::Neg => {
+                let y = run_state.ram.stack_top()?;
+                *y = y.wrapping_neg();
+                run_state.current_command_index += 1;
+            }
+            VMCommand::Eq => {
+                let y = run_state.ram.pop()?;
+                let x = run_state.ram.stack_top()?;
+                *x = -((*x == y) as Word);
+                run_state.current_command_index += 1;
+            }
+            VMCommand::Gt => {
+                let y = run_state.ram.pop()?;
+                let x = run_state.ram.stack_top()?;
+                *x = -((*x > y) as Word);
+                run_state.current_command_index += 1;
+            }
+            VMCommand::Lt => {
+                let y = run_state.ram.pop()?;
+                let x = run_state.ram.stack_top()?;
+                *x = -((*x < y) as Word);
+                run_state.current_command_index += 1;
+            }
+            VMCommand::And => {
+                let y = run_state.ram.pop()?;
+                *run_state.ram.stack_top()? &= y;
+                run_state.current_command_index += 1;
+            }
+            VMCommand::Or => {
+                let y = run_state.ram.pop()?;
+                *run_state.ram.stack_top()? |= y;
+                run_state.current_command_index += 1;
+            }
+            VMCommand::Not => {
+                *run_state.ram.stack_top()? ^= -1;
+                run_state.current_command_index += 1;
+            }
+            VMCommand::Label { name: _ } => {
+                run_state.current_command_index += 1;
+            }
+            VMCommand::Goto { label_name } => {
+                let goto_index = run_state.current_command_index;
+                run_state.current_command_index =
+                    Self::label_command_index(program, &run_state.call_stack, label_name)?;
+                // A goto to its own label, as in `label END; goto END`, loops forever.
+                if (goto_index.saturating_sub(1)..=goto_index)
+                    .contains(&run_state.current_command_index)
+                {
+                    return Ok(true);
                 }
-                VMCommand::Label { name: _ } => {
+            }
+            VMCommand::IfGoto { label_name } => {
+                let target = Self::label_command_index(program, &run_state.call_stack, label_name)?;
+                let value = run_state.ram.pop()?;
+                if value != 0 {
+                    run_state.current_command_index = target;
+                } else {
                     run_state.current_command_index += 1;
                 }
-                VMCommand::Goto { label_name } => {
-                    Self::goto(
-                        &mut run_state.current_command_index,
-                        &self.program.function_metadata
-                            [run_state.call_stack.last().unwrap().function_index],
-                        label_name,
-                    );
+            }
+            VMCommand::Function {
+                name: _,
+                local_var_count,
+            } => {
+                for _ in 0..*local_var_count {
+                    run_state.ram.push(0)?;
                 }
-                VMCommand::IfGoto { label_name } => {
-                    let value = run_state.ram.pop();
-                    if value != 0 {
-                        Self::goto(
-                            &mut run_state.current_command_index,
-                            &self.program.function_metadata
-                                [run_state.call_stack.last().unwrap().function_index],
-                            label_name,
-                        );
-                    } else {
-                        run_state.current_command_index += 1;
-                    }
+                run_state.current_command_index += 1;
+            }
+            VMCommand::Call {
+                function_name,
+                argument_count,
+            } => {
+                let [stack_pointer, caller_local, caller_argument] =
+                    [Register::SP, Register::LCL, Register::ARG].map(|r| run_state.ram[r]);
+                let argument_segment = stack_pointer.wrapping_sub(*argument_count);
+                run_state
+                    .ram
+                    .push((run_state.current_command_index + 1) as Word)?;
+                for i in 1..=4 {
+                    let value = run_state.ram[i];
+                    run_state.ram.push(value)?;
                 }
-                VMCommand::Function {
-                    name: _,
-                    local_var_count,
-                } => {
-                    for _ in 0..*local_var_count {
-                        run_state.ram.push(0);
+
+                let local_segment = run_state.ram[Register::SP];
+                run_state.ram[Register::LCL] = local_segment;
+                run_state.ram[Register::ARG] = argument_segment;
+                let os_call = if program.is_native(function_name) {
+                    run_state.call_os(function_name)?
+                } else {
+                    OsCall::NotOs
+                };
+                match os_call {
+                    OsCall::Returned => {
+                        let return_value = run_state.ram.pop()?;
+                        Self::return_to_caller(program, run_state, *static_segment, return_value)?;
                     }
-                    run_state.current_command_index += 1;
-                }
-                VMCommand::Call {
-                    function_name,
-                    argument_count,
-                } => {
-                    run_state
-                        .func_stats
-                        .entry(function_name.clone())
-                        .and_modify(|c| *c += 1)
-                        .or_insert(1);
-                    let argument_segment = run_state.ram[Register::SP] - argument_count;
-                    run_state
-                        .ram
-                        .push((run_state.current_command_index + 1) as Word);
-                    for i in 1..=4 {
-                        let value = run_state.ram[i];
-                        run_state.ram.push(value);
+                    // Undo the call so that it runs again on the next step.
+                    call @ (OsCall::Blocked | OsCall::Halted) => {
+                        run_state.ram[Register::SP] = stack_pointer;
+                        run_state.ram[Register::LCL] = caller_local;
+                        run_state.ram[Register::ARG] = caller_argument;
+                        return Ok(call == OsCall::Halted);
                     }
-
-                    let local_segment = run_state.ram[Register::SP];
-                    run_state.ram[Register::LCL] = local_segment;
-                    run_state.ram[Register::ARG] = argument_segment;
-                    if run_state.call_os(function_name) {
-                        let frame = run_state.ram[Register::LCL];
-                        run_state.current_command_index = run_state.ram[frame - 5] as usize;
-                        let return_value = run_state.ram.pop();
-                        run_state
-                            .ram
-                            .set(static_segment, PopSegment::Argument, 0, return_value);
-                        run_state.ram[Register::SP] = run_state.ram[Register::ARG] + 1;
-                        for i in 1..=4 {
-                            run_state.ram[i] = run_state.ram[frame - 5 + i];
-                        }
-                    } else {
-                        let function_index = self.program.function_name_to_index[function_name];
-                        let function_metadata = &self.program.function_metadata[function_index];
+                    OsCall::NotOs => {
+                        let function_index = *program
+                            .function_name_to_index
+                            .get(function_name)
+                            .ok_or_else(|| Trap::UnknownFunction(function_name.clone()))?;
+                        let function_metadata = &program.function_metadata[function_index];
                         let file_index = function_metadata.file_index;
 
                         run_state.current_command_index = function_metadata.command_index;
                         run_state.current_file_index = file_index;
-                        static_segment = *files[file_index].static_segment.start();
+                        *static_segment = *program.files[file_index].static_segment.start();
 
                         run_state.call_stack.push(Frame { function_index });
                     }
                 }
-                VMCommand::Return => {
-                    let frame = run_state.ram[Register::LCL];
-                    run_state.current_command_index = run_state.ram[frame - 5] as usize;
-                    let return_value = run_state.ram.pop();
-                    run_state
-                        .ram
-                        .set(static_segment, PopSegment::Argument, 0, return_value);
-                    run_state.ram[Register::SP] = run_state.ram[Register::ARG] + 1;
-                    for i in 1..=4 {
-                        run_state.ram[i] = run_state.ram[frame - 5 + i];
-                    }
+            }
+            VMCommand::Return => {
+                // A function run on its own, as in the course's test scripts, still returns
+                // through the frame saved below LCL, as long as there's room for one on the stack.
+                let is_outermost = run_state.call_stack.len() <= 1;
+                if is_outermost && run_state.ram[Register::LCL] < STACK_START + 5 {
+                    return Err(Trap::ReturnWithoutCall);
+                }
+                let return_value = run_state.ram.pop()?;
+                Self::return_to_caller(program, run_state, *static_segment, return_value)?;
+                if is_outermost {
+                    let function_index =
+                        program.function_index_of_command(run_state.current_command_index);
+                    run_state.call_stack[0] = Frame { function_index };
+                } else {
                     run_state.call_stack.pop();
+                }
+
+                let last_frame = run_state.call_stack.last().unwrap();
+                let file_index = program.function_metadata[last_frame.function_index].file_index;
+                run_state.current_file_index = file_index;
+                *static_segment = *program.files[file_index].static_segment.start();
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// With the sanitizer on, accesses through `this` and `that` have to be inside live blocks of
+    /// the native heap. Programs which provide their own Memory class aren't checked.
+    fn check_heap_access(
+        program: &Program,
+        run_state: &RunState,
+        segment: PushSegment,
+        offset: Word,
+    ) -> Result<(), Trap> {
+        let pointer = match segment {
+            PushSegment::This => Register::THIS,
+            PushSegment::That => Register::THAT,
+            _ => return Ok(()),
+        };
+        if !run_state.os.is_sanitizing() || !program.is_native("Memory.alloc") {
+            return Ok(());
+        }
+
+        run_state
+            .os
+            .check_heap_access(run_state.ram[pointer].wrapping_add(offset))
+    }
+
+    /// Restores the caller's frame, which starts 5 words below LCL, and leaves the return value
+    /// in place of the arguments.
+    fn return_to_caller(
+        program: &Program,
+        run_state: &mut RunState,
+        static_segment: Word,
+        return_value: Word,
+    ) -> Result<(), Trap> {
+        let frame = run_state.ram[Register::LCL];
+        let return_address = run_state.ram.read_at(frame, -5)?;
+        if !(0..=program.all_commands.len() as i64).contains(&(return_address as i64)) {
+            return Err(Trap::InvalidReturnAddress(return_address));
+        }
+        let mut saved_registers = [0; 4];
+        for (i, register) in saved_registers.iter_mut().enumerate() {
+            *register = run_state.ram.read_at(frame, i as Word - 4)?;
+        }
+
+        run_state
+            .ram
+            .set(static_segment, PopSegment::Argument, 0, return_value)?;
+        run_state.ram[Register::SP] = run_state.ram[Register::ARG].wrapping_add(1);
+        for (i, register) in saved_registers.into_iter().enumerate() {
+            run_state.ram[i as Word + 1] = register;
+        }
+        run_state.current_command_index = return_address as usize;
+
+        Ok(())
+    }
+
+    fn label_command_index(
+        program: &Program,
+        call_stack: &[Frame],
+        label_name: &str,
+    ) -> Result<usize, Trap> {
+        let function_metadata =
+            &program.function_metadata[call_stack.last().unwrap().function_index];
+        function_metadata
+            .label_command_index(label_name)
+            .ok_or_else(|| Trap::UnknownLabel(label_name.to_owned()))
+    }
+
+    pub fn error(&self) -> Option<&VMError> {
+        self.run_state.error.as_ref()
+    }
+
+    /// Walks the frames saved on the stack by each call, starting from the innermost function.
+    /// Outer frames report the command index of the call they're waiting on.
+    pub fn backtrace(&self) -> Vec<StackFrame> {
+        let ram = &self.run_state.ram;
+        let mut command_index = self.run_state.current_command_index;
+        let mut lcl = ram[Register::LCL];
+        let mut arg = ram[Register::ARG];
+        let mut frames = vec![];
+        for frame in self.run_state.call_stack.iter().rev() {
+            let function_metadata = &self.program.function_metadata[frame.function_index];
+            let values = |base: Word, count: Word| {
+                (0..count)
+                    .map_while(|offset| ram.read_at(base, offset).ok())
+                    .collect()
+            };
+            frames.push(StackFrame {
+                function_name: function_metadata.name.clone(),
+                file_name: self.program.files[function_metadata.file_index]
+                    .name
+                    .clone(),
+                command_index,
+                arg,
+                lcl,
+                arguments: values(arg, function_metadata.argument_count),
+                locals: values(lcl, function_metadata.local_var_count),
+            });
+
+            let (Ok(return_address), Ok(caller_lcl), Ok(caller_arg)) = (
+                ram.read_at(lcl, -5),
+                ram.read_at(lcl, -4),
+                ram.read_at(lcl, -3),
+            ) else {
+                break;
+            };
+            command_index = (return_address as usize).saturating_sub(1);
+            lcl = caller_lcl;
+            arg = caller_arg;
+        }
+
+        frames
+    }
+
+    pub fn get_breakpoints(&self) -> &Vec<Breakpoint> {
+        &self.run_state.breakpoints
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: &Breakpoint) {
+        self.run_state.breakpoints.push(breakpoint.clone())
+    }
+
+    pub fn remove_breakpoint(&mut self, index: usize) {
+        self.run_state.breakpoints.remove(index);
+        if index < self.breakpoint_hits.len() {
+            self.breakpoint_hits.remove(index);
+        }
+    }
+
+    /// Adds a breakpoint which stops a run when `condition` becomes met, if the condition only
+    /// uses variables the VM has.
+    pub fn add_condition(&mut self, condition: Condition) -> Result<(), String> {
+        self.evaluate_condition(&condition)?;
+        self.add_breakpoint(&Breakpoint::Condition(condition));
+
+        Ok(())
+    }
+
+    pub fn evaluate_condition(&self, condition: &Condition) -> Result<bool, String> {
+        condition.evaluate(
+            &ConditionContext {
+                program: &self.program,
+                run_state: &self.run_state,
+            },
+            Some(0),
+        )
+    }
+
+    /// How many steps the conditional breakpoint at `index` was counted on, see
+    /// `Condition::check`.
+    pub fn breakpoint_hits(&self, index: usize) -> u64 {
+        self.breakpoint_hits.get(index).copied().unwrap_or(0)
+    }
+
+    pub fn get_watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: &Watchpoint) {
+        self.watchpoints.push(watchpoint.clone());
+        self.update_logging();
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) {
+        self.watchpoints.remove(index);
+        self.update_logging();
+    }
+
+    pub fn is_ready(&self) -> bool {
+        true
+    }
+
+    pub fn copy_ram(&self) -> RAM {
+        self.run_state.ram.clone()
+    }
+
+    pub fn set_ram_value(&mut self, address: i16, value: i16) {
+        self.run_state.ram[address as Word] = value as Word;
+    }
+
+    pub fn get_ram_value(&self, address: i16) -> i16 {
+        self.run_state.ram[address as Word] as i16
+    }
+
+    pub fn current_file_index(&self) -> usize {
+        self.run_state.current_file_index
+    }
+
+    pub fn current_command_index(&self) -> usize {
+        self.run_state.current_command_index
+    }
+
+    pub fn current_file_name(&self) -> &str {
+        let index = self.current_file_index();
+
+        &self.program.files[index].name
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    StepsExhausted,
+    /// The index of the breakpoint in `RunState::breakpoints`.
+    Breakpoint(usize),
+    Halted,
+    /// The program trapped, see `RunState::error`.
+    Error,
+    /// There were no more recorded steps to undo.
+    StartOfHistory,
+    /// A step over, step out or run to cursor got where it was going.
+    TargetReached,
+    Watchpoint(WatchHit),
+}
 
-                    let last_frame = run_state.call_stack.last().unwrap();
-                    let file_index =
-                        self.program.function_metadata[last_frame.function_index].file_index;
-                    run_state.current_file_index = file_index;
-                    static_segment = *files[file_index].static_segment.start();
+/// Where `VM::run_to` stops.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunTarget {
+    /// Once the call stack is at most this deep.
+    Depth(usize),
+    /// Right before the command at this index in `Program::all_commands` runs.
+    Command(usize),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trap {
+    SysError(Word),
+    UnknownLabel(String),
+    UnknownFunction(String),
+    ReturnWithoutCall,
+    InvalidReturnAddress(Word),
+    AddressOutOfRange(i64),
+    DivisionByZero,
+    OutOfMemory(Word),
+    InvalidDealloc(Word),
+    /// Freeing a block again, caught by the sanitizer along with the command which freed it.
+    DoubleFree {
+        address: Word,
+        freed_at: usize,
+    },
+    /// Accessing a freed block through `this` or `that`, caught by the sanitizer.
+    UseAfterFree {
+        address: Word,
+        block: Word,
+        freed_at: usize,
+    },
+    /// Accessing the heap outside any block through `this` or `that`, caught by the sanitizer.
+    InvalidHeapAccess(Word),
+    /// An OS function was called with arguments it can't handle, with the Jack OS error code.
+    InvalidArgument(Word),
+}
+
+impl Trap {
+    /// The Jack OS error code for traps which the Jack OS reports with `Sys.error`.
+    pub fn os_error_code(&self) -> Option<Word> {
+        match *self {
+            Trap::SysError(code) | Trap::InvalidArgument(code) => Some(code),
+            Trap::DivisionByZero => Some(3),
+            Trap::OutOfMemory(_) => Some(6),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Trap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Trap::SysError(code) => {
+                write!(f, "Sys.error called with code {code}")?;
+                match os::error_message(*code) {
+                    Some(message) => write!(f, " ({message})"),
+                    None => Ok(()),
+                }
+            }
+            Trap::UnknownLabel(label_name) => write!(f, "unknown label {label_name}"),
+            Trap::UnknownFunction(function_name) => {
+                write!(f, "call to unknown function {function_name}")
+            }
+            Trap::ReturnWithoutCall => {
+                write!(
+                    f,
+                    "return from the outermost function without a caller's frame"
+                )
+            }
+            Trap::InvalidReturnAddress(address) => write!(f, "invalid return address {address}"),
+            Trap::AddressOutOfRange(address) => write!(f, "address {address} is out of range"),
+            Trap::DivisionByZero => write!(f, "division by zero"),
+            Trap::OutOfMemory(size) => write!(f, "out of heap memory allocating {size} words"),
+            Trap::InvalidDealloc(address) => {
+                write!(f, "deallocating {address}, which isn't an allocated block")
+            }
+            Trap::DoubleFree { address, .. } => {
+                write!(f, "freeing {address}, which was already freed")
+            }
+            Trap::UseAfterFree { address, block, .. } => {
+                write!(f, "accessing {address} in block {block}, which was freed")
+            }
+            Trap::InvalidHeapAccess(address) => {
+                write!(f, "accessing {address}, which isn't in an allocated block")
+            }
+            Trap::InvalidArgument(code) => match os::error_message(*code) {
+                Some(message) => write!(f, "{message} (error code {code})"),
+                None => write!(f, "invalid argument (error code {code})"),
+            },
+        }
+    }
+}
+
+/// A trap along with where it happened. The call stack lists function names from the outermost
+/// call, so its last entry is the function which trapped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VMError {
+    pub trap: Trap,
+    pub command_index: usize,
+    pub file_name: String,
+    pub line_number: usize,
+    pub call_stack: Vec<String>,
+    /// The file and line which freed the block, for the sanitizer's traps.
+    pub freed_at: Option<(String, usize)>,
+}
+
+impl VMError {
+    fn new(program: &Program, run_state: &RunState, trap: Trap) -> Self {
+        let command_index = run_state.current_command_index;
+        let (file_name, line_number) = program
+            .source_line(command_index)
+            .map_or((String::new(), 0), |(file, source_line)| {
+                (file.name.clone(), source_line.line_number)
+            });
+        let call_stack = run_state
+            .call_stack
+            .iter()
+            .map(|frame| program.function_metadata[frame.function_index].name.clone())
+            .collect();
+        let freed_at = match trap {
+            Trap::DoubleFree { freed_at, .. } | Trap::UseAfterFree { freed_at, .. } => program
+                .source_line(freed_at)
+                .map(|(file, source_line)| (file.name.clone(), source_line.line_number)),
+            _ => None,
+        };
+
+        Self {
+            trap,
+            command_index,
+            file_name,
+            line_number,
+            call_stack,
+            freed_at,
+        }
+    }
+
+    pub fn function_name(&self) -> &str {
+        self.call_stack.last().map_or("", String::as_str)
+    }
+}
+
+impl std::fmt::Display for VMError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}, line {}: {} in {}",
+            self.file_name,
+            self.line_number,
+            self.trap,
+            self.function_name()
+        )?;
+        if let Some((file_name, line_number)) = &self.freed_at {
+            write!(f, " (freed at {file_name}, line {line_number})")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A breakpoint resolved against the program, so it can be checked cheaply after every command.
+enum BreakpointCondition {
+    CommandIndex(usize),
+    FunctionIndex(usize),
+    Ram {
+        address: Word,
+        value: Word,
+    },
+    Segment {
+        pointer: Word,
+        offset: Word,
+        value: Word,
+    },
+    Expression(Condition),
+    Never,
+}
+
+impl BreakpointCondition {
+    fn new(program: &Program, breakpoint: &Breakpoint) -> Self {
+        let segment = |pointer: Register, offset: Word, value: Word| Self::Segment {
+            pointer: pointer.address(),
+            offset,
+            value,
+        };
+        let register = |register: Register, value: Word| Self::Ram {
+            address: register.address(),
+            value,
+        };
+        match breakpoint {
+            Breakpoint::SP(value) => register(Register::SP, *value),
+            Breakpoint::CurrentFunction(function_name) => program
+                .function_name_to_index
+                .get(function_name)
+                .map_or(Self::Never, |index| Self::FunctionIndex(*index)),
+            Breakpoint::Line {
+                file_name,
+                line_number,
+            } => program
+                .command_index_at_line(file_name, *line_number as usize)
+                .map_or(Self::Never, Self::CommandIndex),
+            Breakpoint::RAM { address, value } => Self::Ram {
+                address: *address,
+                value: *value,
+            },
+            Breakpoint::LCL(value) => register(Register::LCL, *value),
+            Breakpoint::Local { offset, value } => segment(Register::LCL, *offset, *value),
+            Breakpoint::ARG(value) => register(Register::ARG, *value),
+            Breakpoint::Argument { offset, value } => segment(Register::ARG, *offset, *value),
+            Breakpoint::This(value) => register(Register::THIS, *value),
+            Breakpoint::ThisPointer { offset, value } => segment(Register::THIS, *offset, *value),
+            Breakpoint::That(value) => register(Register::THAT, *value),
+            Breakpoint::ThatPointer { offset, value } => segment(Register::THAT, *offset, *value),
+            Breakpoint::Temp { offset, value } => register(Register::TEMP(*offset), *value),
+            Breakpoint::Condition(condition) => Self::Expression(condition.clone()),
+        }
+    }
+
+    /// Conditions are only counted towards their hits if `hits` is mutable.
+    fn is_met(&self, program: &Program, run_state: &RunState, hits: Hits<'_>) -> bool {
+        match *self {
+            Self::CommandIndex(index) => run_state.current_command_index == index,
+            Self::FunctionIndex(index) => run_state
+                .call_stack
+                .last()
+                .is_some_and(|frame| frame.function_index == index),
+            Self::Ram { address, value } => run_state.ram[address] == value,
+            Self::Segment {
+                pointer,
+                offset,
+                value,
+            } => {
+                let address = run_state.ram[pointer].wrapping_add(offset);
+                run_state.ram.contents.get(address as usize) == Some(&value)
+            }
+            Self::Expression(ref condition) => {
+                let context = ConditionContext { program, run_state };
+                match hits {
+                    Hits::Count(hits) => condition.check(&context, hits),
+                    Hits::Keep(hits) => condition.evaluate(&context, Some(hits)) == Ok(true),
                 }
             }
+            Self::Never => false,
         }
     }
+}
 
-    fn goto(
-        current_command_index: &mut usize,
-        function_metadata: &FunctionMetadata,
-        label_name: &str,
-    ) {
-        *current_command_index = function_metadata.label_name_to_command_index[label_name]
-    }
+enum Hits<'a> {
+    Count(&'a mut u64),
+    Keep(u64),
+}
 
-    pub fn get_breakpoints(&self) -> &Vec<Breakpoint> {
-        &self.run_state.breakpoints
-    }
+/// The state conditional breakpoints are evaluated against. The VM has no CPU registers.
+struct ConditionContext<'a> {
+    program: &'a Program,
+    run_state: &'a RunState,
+}
 
-    pub fn add_breakpoint(&mut self, breakpoint: &Breakpoint) {
-        self.run_state.breakpoints.push(breakpoint.clone())
+impl condition::Context for ConditionContext<'_> {
+    fn register(&self, _variable: Variable) -> Option<Word> {
+        None
     }
 
-    pub fn remove_breakpoint(&mut self, index: usize) {
-        self.run_state.breakpoints.remove(index);
+    fn ram(&self) -> &RAM {
+        &self.run_state.ram
     }
 
-    pub fn is_ready(&self) -> bool {
-        true
-    }
+    fn function_name(&self) -> Option<&str> {
+        let frame = self.run_state.call_stack.last()?;
 
-    pub fn copy_ram(&self) -> RAM {
-        self.run_state.ram.clone()
+        Some(&self.program.function_metadata[frame.function_index].name)
     }
+}
 
-    pub fn set_ram_value(&mut self, address: i16, value: i16) {
-        self.run_state.ram[address as Word] = value as Word;
-    }
+/// Tracks which breakpoints are met, since a run only stops when one of them becomes met.
+struct BreakpointTracker {
+    conditions: Vec<BreakpointCondition>,
+    were_met: Vec<bool>,
+}
 
-    pub fn get_ram_value(&self, address: i16) -> i16 {
-        self.run_state.ram[address as Word] as i16
-    }
+impl BreakpointTracker {
+    /// `hits` has to have an element for every breakpoint.
+    fn new(program: &Program, run_state: &RunState, hits: &[u64]) -> Self {
+        let conditions = run_state
+            .breakpoints
+            .iter()
+            .map(|breakpoint| BreakpointCondition::new(program, breakpoint))
+            .collect::<Vec<_>>();
+        let were_met = conditions
+            .iter()
+            .zip(hits)
+            .map(|(condition, &hits)| condition.is_met(program, run_state, Hits::Keep(hits)))
+            .collect();
 
-    pub fn current_file_index(&self) -> usize {
-        self.run_state.current_file_index
+        Self {
+            conditions,
+            were_met,
+        }
     }
 
-    pub fn current_command_index(&self) -> usize {
-        self.run_state.current_command_index
+    /// Returns the index of the first breakpoint which became met since the last check. The step
+    /// is counted towards the hits of conditional breakpoints if `counting`.
+    fn hit(
+        &mut self,
+        program: &Program,
+        run_state: &RunState,
+        hits: &mut [u64],
+        counting: bool,
+    ) -> Option<usize> {
+        let mut hit = None;
+        for (index, ((condition, was_met), hits)) in self
+            .conditions
+            .iter()
+            .zip(&mut self.were_met)
+            .zip(hits)
+            .enumerate()
+        {
+            let hits = if counting {
+                Hits::Count(hits)
+            } else {
+                Hits::Keep(*hits)
+            };
+            let is_met = condition.is_met(program, run_state, hits);
+            if is_met && !*was_met && hit.is_none() {
+                hit = Some(index);
+            }
+            *was_met = is_met;
+        }
+
+        hit
     }
+}
 
-    pub fn current_file_name(&self) -> &str {
-        let index = self.current_file_index();
+/// What a step overwrote, so that it can be undone. The OS state is only saved for calls to OS
+/// functions, since nothing else changes it.
+#[derive(Clone)]
+struct VMStep {
+    current_file_index: usize,
+    current_command_index: usize,
+    call_stack_len: usize,
+    returned_from: Option<Frame>,
+    os: Option<OS>,
+    writes: Vec<(Word, Word)>,
+}
 
-        &self.program.files[index].name
+impl VMStep {
+    fn new(program: &Program, run_state: &RunState) -> Self {
+        let command = program.all_commands.get(run_state.current_command_index);
+        Self {
+            current_file_index: run_state.current_file_index,
+            current_command_index: run_state.current_command_index,
+            call_stack_len: run_state.call_stack.len(),
+            returned_from: match command {
+                Some(VMCommand::Return) => run_state.call_stack.last().cloned(),
+                _ => None,
+            },
+            os: match command {
+                Some(VMCommand::Call { function_name, .. }) if program.is_native(function_name) => {
+                    Some(run_state.os.clone())
+                }
+                _ => None,
+            },
+            writes: vec![],
+        }
+    }
+
+    /// A step can't start after a trap, so undoing one always clears the error.
+    fn undo(self, run_state: &mut RunState) {
+        run_state.ram.undo(&self.writes);
+        run_state.current_file_index = self.current_file_index;
+        run_state.current_command_index = self.current_command_index;
+        // Returning from the outermost function replaces its frame rather than popping it.
+        let kept_frames = self.call_stack_len - usize::from(self.returned_from.is_some());
+        run_state.call_stack.truncate(kept_frames);
+        run_state.call_stack.extend(self.returned_from);
+        if let Some(os) = self.os {
+            run_state.os = os;
+        }
+        run_state.error = None;
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackFrame {
+    pub function_name: String,
+    pub file_name: String,
+    pub command_index: usize,
+    pub arg: Word,
+    pub lcl: Word,
+    pub arguments: Vec<Word>,
+    pub locals: Vec<Word>,
+}
+
 #[derive(Clone)]
 pub struct Frame {
     pub function_index: usize,
@@ -447,6 +1405,7 @@ pub struct Frame {
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct FunctionMetadata {
+    pub name: String,
     pub argument_count: Word,
     pub local_var_count: Word,
     pub command_index: usize,
@@ -454,19 +1413,47 @@ pub struct FunctionMetadata {
     label_name_to_command_index: HashMap<String, usize>,
 }
 
+impl FunctionMetadata {
+    pub fn label_command_index(&self, label_name: &str) -> Option<usize> {
+        self.label_name_to_command_index.get(label_name).copied()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct File {
     pub name: String,
     pub starting_command_index: usize,
     command_count: usize,
     pub static_segment: RangeInclusive<Word>,
+    source_lines: Vec<SourceLine>,
+}
+
+/// A file's name with its commands and the line each of them was written on.
+pub type FileSource = (String, Vec<VMCommand>, Vec<SourceLine>);
+
+/// Where a command was written in its file, counting lines from 1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLine {
+    pub line_number: usize,
+    pub comment: Option<String>,
+}
+
+impl SourceLine {
+    pub fn new(line_number: usize) -> Self {
+        Self {
+            line_number,
+            comment: None,
+        }
+    }
 }
 
 impl File {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         name: &str,
         file_index: usize,
         commands: &[VMCommand],
+        source_lines: Vec<SourceLine>,
         starting_command_index: usize,
         static_segment_start: Word,
         function_name_to_index: &mut HashMap<String, usize>,
@@ -476,9 +1463,7 @@ impl File {
         for (i, command) in commands.iter().enumerate() {
             match command {
                 VMCommand::Label { name } => {
-                    function_metadata
-                        .last_mut()
-                        .unwrap()
+                    Self::current_function(function_metadata, file_index, starting_command_index)
                         .label_name_to_command_index
                         .insert(name.clone(), starting_command_index + i);
                 }
@@ -488,6 +1473,7 @@ impl File {
                 } => {
                     function_name_to_index.insert(name.clone(), function_metadata.len());
                     function_metadata.push(FunctionMetadata {
+                        name: name.clone(),
                         argument_count: 0,
                         local_var_count: *local_var_count,
                         command_index: starting_command_index + i,
@@ -503,8 +1489,12 @@ impl File {
                     segment: PushSegment::Argument,
                     offset,
                 } => {
-                    let metadata = function_metadata.last_mut().unwrap();
-                    metadata.argument_count = Word::max(metadata.argument_count, *offset);
+                    let metadata = Self::current_function(
+                        function_metadata,
+                        file_index,
+                        starting_command_index,
+                    );
+                    metadata.argument_count = Word::max(metadata.argument_count, *offset + 1);
                 }
                 VMCommand::Push {
                     segment: PushSegment::Static,
@@ -525,12 +1515,50 @@ impl File {
             starting_command_index,
             static_segment: static_segment_start..=max_static_index,
             command_count: commands.len(),
+            source_lines,
+        }
+    }
+
+    fn current_function(
+        function_metadata: &mut Vec<FunctionMetadata>,
+        file_index: usize,
+        starting_command_index: usize,
+    ) -> &mut FunctionMetadata {
+        // Programs may start with commands outside any function, as in the course's early VM
+        // tests; treat them as an unnamed function.
+        if function_metadata.is_empty() {
+            function_metadata.push(FunctionMetadata {
+                name: String::new(),
+                argument_count: 0,
+                local_var_count: 0,
+                command_index: starting_command_index,
+                file_index,
+                label_name_to_command_index: HashMap::new(),
+            });
         }
+
+        function_metadata.last_mut().unwrap()
     }
 
     pub fn commands<'a>(&self, all_commands: &'a [VMCommand]) -> &'a [VMCommand] {
         &all_commands[self.starting_command_index..self.starting_command_index + self.command_count]
     }
+
+    pub fn source_lines(&self) -> &[SourceLine] {
+        &self.source_lines
+    }
+
+    pub fn source_line(&self, command_index: usize) -> Option<&SourceLine> {
+        self.source_lines
+            .get(command_index.checked_sub(self.starting_command_index)?)
+    }
+
+    pub fn command_index_at_line(&self, line_number: usize) -> Option<usize> {
+        self.source_lines
+            .binary_search_by_key(&line_number, |source_line| source_line.line_number)
+            .ok()
+            .map(|index| self.starting_command_index + index)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -679,6 +1707,20 @@ impl std::fmt::Display for PopSegment {
     }
 }
 
+impl From<PopSegment> for PushSegment {
+    fn from(segment: PopSegment) -> Self {
+        match segment {
+            PopSegment::Static => PushSegment::Static,
+            PopSegment::Local => PushSegment::Local,
+            PopSegment::Argument => PushSegment::Argument,
+            PopSegment::This => PushSegment::This,
+            PopSegment::That => PushSegment::That,
+            PopSegment::Temp => PushSegment::Temp,
+            PopSegment::Pointer => PushSegment::Pointer,
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum Breakpoint {
     SP(Word),
@@ -715,6 +1757,7 @@ pub enum Breakpoint {
         offset: Word,
         value: Word,
     },
+    Condition(Condition),
 }
 
 impl Breakpoint {
@@ -734,8 +1777,9 @@ impl Breakpoint {
             Breakpoint::This(_) => "THIS".to_owned(),
             Breakpoint::ThisPointer { offset, .. } => format!("THIS[{offset}]"),
             Breakpoint::That(_) => "THAT".to_owned(),
-            Breakpoint::ThatPointer { offset, .. } => format!("THIS[{offset}]"),
+            Breakpoint::ThatPointer { offset, .. } => format!("THAT[{offset}]"),
             Breakpoint::Temp { offset, .. } => format!("TEMP[{offset}]"),
+            Breakpoint::Condition(_) => "Condition".to_owned(),
         }
     }
 
@@ -757,6 +1801,7 @@ impl Breakpoint {
                 line_number,
             } => format!("{file_name}:{line_number}"),
             Breakpoint::CurrentFunction(function_name) => function_name.clone(),
+            Breakpoint::Condition(condition) => condition.to_string(),
         }
     }
 
@@ -775,6 +1820,7 @@ impl Breakpoint {
             Breakpoint::That(_) => None,
             Breakpoint::ThatPointer { offset, .. } => Some(*offset),
             Breakpoint::Temp { offset, .. } => Some(*offset),
+            Breakpoint::Condition(_) => None,
         }
     }
 
@@ -808,7 +1854,22 @@ impl Breakpoint {
                 }
             }
             Breakpoint::CurrentFunction(value) => *value = new_value,
-            _ => panic!("Tried to change value of a non-value breakpoint"),
+            Breakpoint::Line {
+                file_name,
+                line_number,
+            } => {
+                if let Some((new_file_name, new_line_number)) = new_value.rsplit_once(':')
+                    && let Ok(new_line_number) = new_line_number.parse::<Word>()
+                {
+                    new_file_name.clone_into(file_name);
+                    *line_number = new_line_number;
+                }
+            }
+            Breakpoint::Condition(condition) => {
+                if let Ok(new_condition) = parse_condition(&new_value) {
+                    *condition = new_condition;
+                }
+            }
         }
     }
 }
@@ -836,27 +1897,34 @@ impl Breakpoint {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::os::HeapBlock;
 
     impl VM {
         fn test_get(&self, segment: PushSegment, offset: Word) -> Word {
-            self.run_state.ram.get(
-                *self.program.files[self.run_state.current_file_index]
-                    .static_segment
-                    .start(),
-                segment,
-                offset,
-            )
+            self.run_state
+                .ram
+                .get(
+                    *self.program.files[self.run_state.current_file_index]
+                        .static_segment
+                        .start(),
+                    segment,
+                    offset,
+                )
+                .unwrap()
         }
 
         fn test_set(&mut self, segment: PopSegment, offset: Word, value: Word) {
-            self.run_state.ram.set(
-                *self.program.files[self.run_state.current_file_index]
-                    .static_segment
-                    .start(),
-                segment,
-                offset,
-                value,
-            )
+            self.run_state
+                .ram
+                .set(
+                    *self.program.files[self.run_state.current_file_index]
+                        .static_segment
+                        .start(),
+                    segment,
+                    offset,
+                    value,
+                )
+                .unwrap()
         }
 
         fn test_instance() -> VM {
@@ -1030,7 +2098,7 @@ mod tests {
         vm.step();
         vm.step();
 
-        assert_eq!(*vm.run_state.ram.stack_top(), 1337 + 2337);
+        assert_eq!(*vm.run_state.ram.stack_top().unwrap(), 1337 + 2337);
     }
 
     #[test]
@@ -1056,7 +2124,7 @@ mod tests {
         vm.step();
         vm.step();
 
-        assert_eq!(*vm.run_state.ram.stack_top(), 2337 - 1337);
+        assert_eq!(*vm.run_state.ram.stack_top().unwrap(), 2337 - 1337);
     }
 
     #[test]
@@ -1077,7 +2145,7 @@ mod tests {
         vm.step();
         vm.step();
 
-        assert_eq!(*vm.run_state.ram.stack_top(), -1337);
+        assert_eq!(*vm.run_state.ram.stack_top().unwrap(), -1337);
     }
 
     #[test]
@@ -1112,13 +2180,13 @@ mod tests {
         vm.step();
         vm.step();
 
-        assert_eq!(*vm.run_state.ram.stack_top(), 0);
+        assert_eq!(*vm.run_state.ram.stack_top().unwrap(), 0);
 
         vm.step();
         vm.step();
         vm.step();
 
-        assert_eq!(*vm.run_state.ram.stack_top(), -1);
+        assert_eq!(*vm.run_state.ram.stack_top().unwrap(), -1);
     }
 
     #[test]
@@ -1153,13 +2221,13 @@ mod tests {
         vm.step();
         vm.step();
 
-        assert_eq!(*vm.run_state.ram.stack_top(), 0);
+        assert_eq!(*vm.run_state.ram.stack_top().unwrap(), 0);
 
         vm.step();
         vm.step();
         vm.step();
 
-        assert_eq!(*vm.run_state.ram.stack_top(), -1);
+        assert_eq!(*vm.run_state.ram.stack_top().unwrap(), -1);
     }
 
     #[test]
@@ -1194,13 +2262,13 @@ mod tests {
         vm.step();
         vm.step();
 
-        assert_eq!(*vm.run_state.ram.stack_top(), 0);
+        assert_eq!(*vm.run_state.ram.stack_top().unwrap(), 0);
 
         vm.step();
         vm.step();
         vm.step();
 
-        assert_eq!(*vm.run_state.ram.stack_top(), -1);
+        assert_eq!(*vm.run_state.ram.stack_top().unwrap(), -1);
     }
 
     #[test]
@@ -1226,7 +2294,7 @@ mod tests {
         vm.step();
         vm.step();
 
-        assert_eq!(*vm.run_state.ram.stack_top(), 1337 & 2337);
+        assert_eq!(*vm.run_state.ram.stack_top().unwrap(), 1337 & 2337);
     }
 
     #[test]
@@ -1252,7 +2320,7 @@ mod tests {
         vm.step();
         vm.step();
 
-        assert_eq!(*vm.run_state.ram.stack_top(), 1337 | 2337);
+        assert_eq!(*vm.run_state.ram.stack_top().unwrap(), 1337 | 2337);
     }
 
     #[test]
@@ -1273,7 +2341,7 @@ mod tests {
         vm.step();
         vm.step();
 
-        assert_eq!(*vm.run_state.ram.stack_top(), -1338);
+        assert_eq!(*vm.run_state.ram.stack_top().unwrap(), -1338);
     }
 
     #[test]
@@ -1374,6 +2442,32 @@ mod tests {
         assert_eq!(vm.run_state.current_command_index, 6);
     }
 
+    #[test]
+    fn test_top_level_commands() {
+        let all_file_commands = vec![(
+            "BasicLoop".to_owned(),
+            vec![
+                VMCommand::Label {
+                    name: "foo".to_owned(),
+                },
+                VMCommand::Push {
+                    segment: PushSegment::Argument,
+                    offset: 0,
+                },
+                VMCommand::Goto {
+                    label_name: "foo".to_owned(),
+                },
+            ],
+        )];
+
+        let mut vm = VM::from_all_file_commands(all_file_commands);
+        vm.step();
+        vm.step();
+        vm.step();
+
+        assert_eq!(vm.run_state.current_command_index, 0);
+    }
+
     #[test]
     fn test_call_return() {
         let all_file_commands = vec![(
@@ -1415,6 +2509,687 @@ mod tests {
         vm.step();
 
         assert_eq!(vm.run_state.current_command_index, 3);
-        assert_eq!(*vm.run_state.ram.stack_top(), 2337);
+        assert_eq!(*vm.run_state.ram.stack_top().unwrap(), 2337);
+    }
+
+    #[test]
+    fn test_source_lines() {
+        let vm = VM::from_file_contents(vec![
+            (
+                "Main.vm".to_owned(),
+                "function Main.main 0\n\n// one\npush constant 1 // one\nreturn".to_owned(),
+            ),
+            (
+                "Sys.vm".to_owned(),
+                "function Sys.init 0\ncall Main.main 0\nlabel LOOP\ngoto LOOP".to_owned(),
+            ),
+        ])
+        .unwrap();
+        let program = &vm.program;
+
+        assert_eq!(program.command_index_at_line("Main", 4), Some(1));
+        assert_eq!(program.command_index_at_line("Main", 3), None);
+        assert_eq!(program.command_index_at_line("Sys", 2), Some(4));
+        assert_eq!(program.command_index_at_line("Foo", 1), None);
+
+        let (file, source_line) = program.source_line(1).unwrap();
+        assert_eq!(file.name, "Main");
+        assert_eq!(source_line.line_number, 4);
+        assert_eq!(source_line.comment.as_deref(), Some("one"));
+        let (file, source_line) = program.source_line(5).unwrap();
+        assert_eq!(file.name, "Sys");
+        assert_eq!(source_line, &SourceLine::new(3));
+    }
+
+    #[test]
+    fn test_line_breakpoint() {
+        let mut vm = VM::from_file_contents(vec![
+            (
+                "Main.vm".to_owned(),
+                "function Main.main 0\npush constant 1\n\npush constant 2\nadd\nreturn".to_owned(),
+            ),
+            (
+                "Sys.vm".to_owned(),
+                "function Sys.init 0\ncall Main.main 0\nlabel LOOP\ngoto LOOP".to_owned(),
+            ),
+        ])
+        .unwrap();
+        vm.add_breakpoint(&Breakpoint::Line {
+            file_name: "Main".to_owned(),
+            line_number: 4,
+        });
+
+        assert_eq!(vm.run(100), StopReason::Breakpoint(0));
+        assert_eq!(vm.current_command_index(), 2);
+        assert_eq!(*vm.run_state.ram.stack_top().unwrap(), 1);
+
+        assert_eq!(vm.run(100), StopReason::Halted);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let program = vec![(
+            "Sys.vm".to_owned(),
+            "function Sys.init 1
+            push constant 7
+            pop local 0
+            push constant 3000
+            pop pointer 1
+            push constant 42
+            pop that 2
+            call Sys.foo 0
+            pop temp 1
+            push constant 1
+            pop temp 1
+            label END
+            goto END
+            function Sys.foo 0
+            push constant 9
+            return"
+                .to_owned(),
+        )];
+        let mut vm = VM::from_file_contents(program).unwrap();
+        for breakpoint in [
+            Breakpoint::Local {
+                offset: 0,
+                value: 7,
+            },
+            Breakpoint::That(3000),
+            Breakpoint::ThatPointer {
+                offset: 2,
+                value: 42,
+            },
+            Breakpoint::CurrentFunction("Sys.foo".to_owned()),
+            Breakpoint::Temp {
+                offset: 1,
+                value: 9,
+            },
+            Breakpoint::RAM {
+                address: 6,
+                value: 1,
+            },
+        ] {
+            vm.add_breakpoint(&breakpoint);
+        }
+
+        assert_eq!(vm.run(1000), StopReason::Breakpoint(0));
+        assert_eq!(vm.current_command_index(), 3);
+        vm.remove_breakpoint(0);
+        assert_eq!(vm.run(1000), StopReason::Breakpoint(0));
+        assert_eq!(vm.current_command_index(), 5);
+        vm.remove_breakpoint(0);
+        assert_eq!(vm.run(1000), StopReason::Breakpoint(0));
+        assert_eq!(vm.current_command_index(), 7);
+        vm.remove_breakpoint(0);
+        assert_eq!(vm.run(1000), StopReason::Breakpoint(0));
+        assert_eq!(vm.current_command_index(), 13);
+        vm.remove_breakpoint(0);
+        assert_eq!(vm.run(1000), StopReason::Breakpoint(0));
+        assert_eq!(vm.current_command_index(), 9);
+        assert_eq!(vm.run(1000), StopReason::Breakpoint(1));
+        assert_eq!(vm.current_command_index(), 11);
+        assert_eq!(vm.run(1000), StopReason::Halted);
+        assert_eq!(vm.run(1), StopReason::StepsExhausted);
+    }
+
+    #[test]
+    fn test_outermost_return() {
+        // Like the course's SimpleFunction test, with the caller's frame set up by hand.
+        let run = |return_address: Word| {
+            let mut vm = VM::from_file_contents(vec![(
+                "Main.vm".to_owned(),
+                "function Main.five 0\npush constant 5\nreturn".to_owned(),
+            )])
+            .unwrap();
+            vm.set_ram_value(0, 300);
+            vm.set_ram_value(1, 300);
+            vm.set_ram_value(2, 290);
+            vm.set_ram_value(295, return_address);
+            vm.run(3);
+            vm
+        };
+
+        let vm = run(1);
+        assert_eq!(vm.error(), None);
+        assert_eq!(vm.current_command_index(), 1);
+        assert_eq!((vm.get_ram_value(0), vm.get_ram_value(290)), (291, 5));
+        assert_eq!(vm.backtrace()[0].function_name, "Main.five");
+
+        let vm = run(99);
+        assert_eq!(vm.error().unwrap().trap, Trap::InvalidReturnAddress(99));
+    }
+
+    #[test]
+    fn test_traps() {
+        let trap = |sys: &str| {
+            let mut vm = VM::from_file_contents(vec![
+                ("Sys.vm".to_owned(), sys.to_owned()),
+                (
+                    "Main.vm".to_owned(),
+                    "function Main.main 0\npush argument 0\npush argument 1\ncall Math.divide 2\nreturn"
+                        .to_owned(),
+                ),
+            ])
+            .unwrap();
+            assert_eq!(vm.run(1000), StopReason::Error);
+            assert_eq!(vm.run(1000), StopReason::Error);
+            vm.error().unwrap().clone()
+        };
+
+        let error = trap("function Sys.init 0\npush constant 1\npush constant 0\ncall Main.main 2");
+        assert_eq!(error.trap, Trap::DivisionByZero);
+        assert_eq!(error.command_index, 7);
+        assert_eq!((error.file_name.as_str(), error.line_number), ("Main", 4));
+        assert_eq!(error.call_stack, vec!["Sys.init", "Main.main"]);
+        assert_eq!(
+            error.to_string(),
+            "Main, line 4: division by zero in Main.main"
+        );
+
+        let error = trap("function Sys.init 0\npush constant 3\ncall Sys.error 1");
+        assert_eq!(error.trap, Trap::SysError(3));
+        assert_eq!(error.command_index, 2);
+        assert_eq!(
+            error.trap.to_string(),
+            "Sys.error called with code 3 (Math.divide: division by zero)"
+        );
+
+        let error = trap("function Sys.init 0\npush constant 0\ncall Array.new 1");
+        assert_eq!(error.trap, Trap::InvalidArgument(2));
+        assert_eq!(
+            error.trap.to_string(),
+            "Array.new: array size must be positive (error code 2)"
+        );
+        assert_eq!(Trap::DivisionByZero.os_error_code(), Some(3));
+
+        let error = trap("function Sys.init 0\ncall Sys.missing 0");
+        assert_eq!(error.trap, Trap::UnknownFunction("Sys.missing".to_owned()));
+
+        let error = trap("function Sys.init 0\npush constant 0\nreturn");
+        assert_eq!(error.trap, Trap::ReturnWithoutCall);
+
+        let error = trap("function Sys.init 0\npush constant 32767\npop pointer 1\npush that 1");
+        assert_eq!(error.trap, Trap::AddressOutOfRange(32768));
+        assert_eq!(error.command_index, 3);
+    }
+
+    #[test]
+    fn test_blocking_os_calls() {
+        let mut vm = VM::from_file_contents(vec![(
+            "Sys.vm".to_owned(),
+            "function Sys.init 0
+call Keyboard.readChar 0
+pop static 0
+push constant 0
+call String.new 1
+call Keyboard.readInt 1
+pop static 1
+push constant 2
+call Sys.wait 1
+pop static 2
+call Sys.halt 0"
+                .to_owned(),
+        )])
+        .unwrap();
+        vm.set_profiling(true);
+        let type_key = |vm: &mut VM, key: Word| {
+            vm.set_ram_value(RAM::KBD, key);
+            assert_eq!(vm.run(5), StopReason::StepsExhausted);
+            vm.set_ram_value(RAM::KBD, 0);
+            assert_eq!(vm.run(1), StopReason::StepsExhausted);
+        };
+
+        assert_eq!(vm.run(10), StopReason::StepsExhausted);
+        let stack_pointer = vm.get_ram_value(0);
+        assert_eq!(vm.run(10), StopReason::StepsExhausted);
+        assert_eq!(vm.get_ram_value(0), stack_pointer);
+        type_key(&mut vm, b'A' as Word);
+        assert_eq!(vm.run(1), StopReason::StepsExhausted);
+        assert_eq!(vm.get_ram_value(16), b'A' as Word);
+
+        for key in [b'-', b'1', b'2']
+            .map(Word::from)
+            .into_iter()
+            .chain([129, b'5' as Word, 128])
+        {
+            type_key(&mut vm, key);
+        }
+        assert_eq!(vm.run(1), StopReason::StepsExhausted);
+        assert_eq!(vm.get_ram_value(17), -15);
+
+        assert_eq!(vm.run(1000), StopReason::StepsExhausted);
+        assert_eq!(vm.run(2000), StopReason::Halted);
+        assert_eq!(vm.get_ram_value(18), 0);
+        assert_eq!(vm.run(10), StopReason::Halted);
+
+        let functions = vm.profiler().unwrap().functions();
+        let read_char = functions
+            .iter()
+            .find(|function| function.name == "Keyboard.readChar")
+            .unwrap();
+        assert_eq!(read_char.calls, 1);
+    }
+
+    #[test]
+    fn test_os_policy() {
+        let run = |class_name: &str, policy: ClassPolicy| {
+            let mut vm = VM::from_file_contents(vec![
+                (
+                    "Sys.vm".to_owned(),
+                    "function Sys.init 0\npush constant 6\npush constant 7\ncall Math.multiply 2\npop static 0\npush constant 7\npush constant 2\ncall Math.divide 2\npop static 1\nlabel END\ngoto END"
+                        .to_owned(),
+                ),
+                (
+                    "Math.vm".to_owned(),
+                    "function Math.multiply 0\npush constant 1\nreturn".to_owned(),
+                ),
+            ])
+            .unwrap();
+            let mut os_policy = OsPolicy::default();
+            os_policy.set_class(class_name, policy).unwrap();
+            vm.set_os_policy(os_policy);
+            let stop_reason = vm.run(100);
+            (stop_reason, vm.get_ram_value(16), vm.get_ram_value(17))
+        };
+
+        // By default the program's Math.multiply replaces the native one, but not Math.divide.
+        assert_eq!(
+            run("Math", ClassPolicy::PreferProgram),
+            (StopReason::Halted, 1, 3)
+        );
+        assert_eq!(
+            run("Math", ClassPolicy::Native),
+            (StopReason::Halted, 42, 3)
+        );
+        assert_eq!(run("Math", ClassPolicy::Program), (StopReason::Error, 1, 0));
+        assert_eq!(run("Sys", ClassPolicy::Program), (StopReason::Halted, 1, 3));
+
+        assert!(
+            OsPolicy::default()
+                .set_class("Main", ClassPolicy::Native)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_heap_matches_jack_os() {
+        let sys = "function Sys.init 0
+call Memory.init 0
+pop temp 0
+push constant 5
+call Memory.alloc 1
+pop static 0
+push constant 3
+call Memory.alloc 1
+pop static 1
+push static 0
+call Memory.deAlloc 1
+pop temp 0
+push constant 2
+call Memory.alloc 1
+pop static 2
+push constant 10
+call Memory.alloc 1
+pop static 3
+push static 1
+call Memory.deAlloc 1
+pop temp 0
+push constant 4
+call Memory.alloc 1
+pop static 4
+label END
+goto END";
+        let run = |files: Vec<(String, String)>| {
+            let mut vm = VM::from_file_contents(files).unwrap();
+            vm.run_state.set_heap_in_ram(true).unwrap();
+            assert_eq!(vm.run(10000), StopReason::Halted);
+            vm
+        };
+        let native = run(vec![("Sys.vm".to_owned(), sys.to_owned())]);
+        let jack_os = run(vec![
+            ("Sys.vm".to_owned(), sys.to_owned()),
+            (
+                "Memory.vm".to_owned(),
+                include_str!("../VmTest/Memory.vm").to_owned(),
+            ),
+        ]);
+
+        let addresses = (16..=20)
+            .map(|address| native.get_ram_value(address))
+            .collect::<Vec<_>>();
+        assert_eq!(addresses, [2050, 2057, 2050, 2062, 2074]);
+        for address in (16..=20).chain(RAM::HEAP..RAM::HEAP + 40) {
+            assert_eq!(
+                native.get_ram_value(address),
+                jack_os.get_ram_value(address),
+                "RAM[{address}]"
+            );
+        }
+
+        let os = &native.run_state.os;
+        assert_eq!(
+            os.allocations(),
+            [(2050, 2), (2062, 10), (2074, 4)].map(|(address, size)| HeapBlock { address, size })
+        );
+        assert_eq!(
+            os.holes(),
+            [(2054, 1), (2057, 3), (2080, 14304)]
+                .map(|(address, size)| HeapBlock { address, size })
+        );
+        assert_eq!(
+            os.heap_report().lines().next(),
+            Some("3 allocations using 16 words, 3 holes with 14308 free words, 0.0% fragmented")
+        );
+    }
+
+    #[test]
+    fn test_sanitizer() {
+        let run = |sanitizing: bool, tail: &str| {
+            let sys = format!(
+                "function Sys.init 0
+push constant 3
+call Array.new 1
+pop static 0
+push static 0
+pop pointer 1
+push constant 7
+pop that 2
+push static 0
+call Array.dispose 1
+pop temp 0
+{tail}
+label END
+goto END"
+            );
+            let mut vm = VM::from_file_contents(vec![("Sys.vm".to_owned(), sys)]).unwrap();
+            vm.set_sanitizing(sanitizing);
+            vm.run(100);
+            vm.error().map(VMError::to_string)
+        };
+
+        let use_after_free = "push constant 9\npop that 0";
+        assert_eq!(run(false, use_after_free), None);
+        assert_eq!(
+            run(true, use_after_free).as_deref(),
+            Some(
+                "Sys, line 13: accessing 2050 in block 2050, which was freed in Sys.init \
+                 (freed at Sys, line 10)"
+            )
+        );
+        assert_eq!(
+            run(true, "push static 0\ncall Array.dispose 1").as_deref(),
+            Some(
+                "Sys, line 13: freeing 2050, which was already freed in Sys.init \
+                 (freed at Sys, line 10)"
+            )
+        );
+        assert_eq!(
+            run(
+                true,
+                "push constant 2\ncall Array.new 1\npop pointer 1\npush that 2"
+            )
+            .as_deref(),
+            Some("Sys, line 15: accessing 2052, which isn't in an allocated block in Sys.init")
+        );
+        assert_eq!(
+            run(true, "push constant 100\ncall Memory.deAlloc 1").as_deref(),
+            Some("Sys, line 13: deallocating 100, which isn't an allocated block in Sys.init")
+        );
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut vm = VM::from_file_contents(vec![
+            (
+                "Sys.vm".to_owned(),
+                "function Sys.init 0\npush constant 3\npush constant 4\ncall Main.add 2\nlabel END\ngoto END"
+                    .to_owned(),
+            ),
+            (
+                "Main.vm".to_owned(),
+                "function Main.add 1\npush argument 0\npush argument 1\nadd\npop local 0\npush local 0\nreturn"
+                    .to_owned(),
+            ),
+        ])
+        .unwrap();
+        vm.run(9);
+
+        let backtrace = vm.backtrace();
+        assert_eq!(backtrace.len(), 2);
+        assert_eq!(
+            backtrace[0],
+            StackFrame {
+                function_name: "Main.add".to_owned(),
+                file_name: "Main".to_owned(),
+                command_index: 11,
+                arg: 256,
+                lcl: 263,
+                arguments: vec![3, 4],
+                locals: vec![7],
+            }
+        );
+        assert_eq!(backtrace[1].function_name, "Sys.init");
+        assert_eq!(backtrace[1].file_name, "Sys");
+        assert_eq!(backtrace[1].command_index, 3);
+        assert!(backtrace[1].arguments.is_empty());
+    }
+
+    #[test]
+    fn test_run_back() {
+        let mut vm = VM::from_file_contents(vec![
+            (
+                "Sys.vm".to_owned(),
+                "function Sys.init 0\npush constant 3\ncall Memory.alloc 1\npop static 0\npush constant 3\npush constant 4\ncall Main.add 2\npop temp 0\nlabel END\ngoto END"
+                    .to_owned(),
+            ),
+            (
+                "Main.vm".to_owned(),
+                "function Main.add 0\npush argument 0\npush argument 1\nadd\nreturn".to_owned(),
+            ),
+        ])
+        .unwrap();
+        vm.set_recording(Some(100));
+        let snapshot = |vm: &VM| {
+            (
+                vm.copy_ram(),
+                vm.current_command_index(),
+                vm.run_state.call_stack.len(),
+            )
+        };
+
+        let mut snapshots = vec![];
+        while snapshots.len() < 14 {
+            snapshots.push(snapshot(&vm));
+            vm.step();
+        }
+        let end = snapshot(&vm);
+        assert_eq!(vm.timeline(), Some((14, 14)));
+
+        for expected in snapshots.iter().rev() {
+            assert_eq!(vm.run_back(1), StopReason::StepsExhausted);
+            assert_eq!(&snapshot(&vm), expected);
+        }
+        assert_eq!(vm.run_back(1), StopReason::StartOfHistory);
+        assert_eq!(vm.timeline(), Some((0, 14)));
+
+        vm.run(14);
+        assert_eq!(snapshot(&vm), end);
+        assert_eq!(vm.timeline(), Some((14, 14)));
+
+        vm.add_breakpoint(&Breakpoint::CurrentFunction("Main.add".to_owned()));
+        assert_eq!(vm.run_back(100), StopReason::Breakpoint(0));
+        assert_eq!(vm.current_command_index(), 14);
+    }
+
+    #[test]
+    fn test_run_back_native_os() {
+        let mut vm = VM::from_file_contents(vec![
+            (
+                "Sys.vm".to_owned(),
+                "function Sys.init 0\npush constant 3\ncall Memory.alloc 1\npop static 0\nlabel END\ngoto END"
+                    .to_owned(),
+            ),
+            (
+                "Memory.vm".to_owned(),
+                "function Memory.alloc 0\npush constant 1234\nreturn".to_owned(),
+            ),
+        ])
+        .unwrap();
+        let mut os_policy = OsPolicy::default();
+        os_policy.set_class("Memory", ClassPolicy::Native).unwrap();
+        vm.set_os_policy(os_policy);
+        vm.set_recording(Some(100));
+
+        vm.run(4);
+        let address = vm.get_ram_value(16);
+        assert_ne!(address, 1234);
+        // Undoing the native call has to free the block again, so that redoing it gets the same one.
+        assert_eq!(vm.run_back(2), StopReason::StepsExhausted);
+        vm.run(2);
+        assert_eq!(vm.get_ram_value(16), address);
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        let mut vm = VM::from_file_contents(vec![
+            (
+                "Sys.vm".to_owned(),
+                "function Sys.init 0\npush constant 2\ncall Main.square 1\npop temp 0\npush constant 3\ncall Main.square 1\npop temp 0\nlabel END\ngoto END"
+                    .to_owned(),
+            ),
+            (
+                "Main.vm".to_owned(),
+                "function Main.square 0\npush argument 0\npush argument 0\ncall Math.multiply 2\nreturn"
+                    .to_owned(),
+            ),
+        ])
+        .unwrap();
+
+        assert_eq!(vm.step_over(100), StopReason::TargetReached);
+        assert_eq!(vm.step_over(100), StopReason::TargetReached);
+        assert_eq!(vm.current_command_index(), 2);
+        assert_eq!(vm.step_over(100), StopReason::TargetReached);
+        assert_eq!(vm.current_command_index(), 3);
+        assert_eq!(vm.get_ram_value(256), 4);
+
+        assert_eq!(
+            vm.run_to(RunTarget::Command(11), 100),
+            StopReason::TargetReached
+        );
+        assert_eq!(vm.run_state.call_stack.len(), 2);
+        vm.step_over(100);
+        assert_eq!(vm.step_over(100), StopReason::TargetReached);
+        assert_eq!(vm.current_command_index(), 13);
+        assert_eq!(vm.step_out(100), StopReason::TargetReached);
+        assert_eq!(vm.current_command_index(), 6);
+        assert_eq!(vm.get_ram_value(256), 9);
+
+        assert_eq!(vm.step_out(10), StopReason::Halted);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut vm = VM::from_file_contents(vec![(
+            "Sys.vm".to_owned(),
+            "function Sys.init 0\npush constant 5\npop static 0\npush static 0\npop temp 0\npush constant 5\npop static 0\nlabel END\ngoto END"
+                .to_owned(),
+        )])
+        .unwrap();
+        vm.set_recording(Some(100));
+        let watchpoint = |s| Watchpoint::parse(s).unwrap();
+
+        vm.add_watchpoint(&watchpoint("16..17"));
+        assert_eq!(
+            vm.run(100),
+            StopReason::Watchpoint(WatchHit {
+                index: 0,
+                access: watchpoint::Access::Write,
+                address: 16,
+                old_value: 0,
+                new_value: 5,
+                location: 2,
+            })
+        );
+        vm.add_watchpoint(&watchpoint("16:read"));
+        let StopReason::Watchpoint(watch_hit) = vm.run(100) else {
+            panic!("expected a watchpoint to be hit");
+        };
+        assert_eq!(
+            (watch_hit.index, watch_hit.new_value, watch_hit.location),
+            (1, 5, 3)
+        );
+
+        vm.remove_watchpoint(0);
+        vm.remove_watchpoint(0);
+        vm.add_watchpoint(&watchpoint("16:change"));
+        assert_eq!(vm.run(100), StopReason::Halted);
+
+        assert_eq!(vm.run_back(100), StopReason::StartOfHistory);
+        assert_eq!(vm.get_ram_value(16), 0);
+    }
+
+    #[test]
+    fn test_conditional_breakpoints() {
+        let mut vm = VM::from_file_contents(vec![(
+            "Sys.vm".to_owned(),
+            "function Sys.init 0\npush constant 3\ncall Sys.double 1\npush constant 4\ncall Sys.double 1\nlabel END\ngoto END\nfunction Sys.double 0\npush argument 0\npush argument 0\nadd\nreturn"
+                .to_owned(),
+        )])
+        .unwrap();
+        let condition = |s| parse_condition(s).unwrap();
+
+        assert!(vm.add_condition(condition("D == 0")).is_err());
+        vm.add_condition(condition(
+            "function == \"Sys.double\" && RAM[SP - 1] == ARG[0] && hits == 3",
+        ))
+        .unwrap();
+        assert_eq!(vm.run(100), StopReason::Breakpoint(0));
+        assert_eq!(vm.run_state.current_command_index, 9);
+        assert_eq!(vm.get_ram_value(263), 4);
+        assert_eq!(vm.breakpoint_hits(0), 3);
+
+        vm.reset();
+        assert_eq!(vm.breakpoint_hits(0), 0);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let sources = || {
+            vec![
+                (
+                    "Sys.vm".to_owned(),
+                    "function Sys.init 0\npush constant 3\ncall Memory.alloc 1\npop static 0\npush constant 5\ncall Main.double 1\npop temp 0\nlabel END\ngoto END"
+                        .to_owned(),
+                ),
+                (
+                    "Main.vm".to_owned(),
+                    "function Main.double 0\npush argument 0\npush argument 0\nadd\nreturn"
+                        .to_owned(),
+                ),
+            ]
+        };
+        let mut vm = VM::from_file_contents(sources()).unwrap();
+        vm.run(7);
+        assert_eq!(vm.run_state.call_stack.len(), 2);
+
+        let snapshot = Snapshot::parse(&vm.save_snapshot().to_string()).unwrap();
+        let mut restored = VM::from_file_contents(sources()).unwrap();
+        restored.restore_snapshot(&snapshot).unwrap();
+        assert_eq!(restored.save_snapshot(), vm.save_snapshot());
+
+        vm.run(100);
+        restored.run(100);
+        assert_eq!(restored.copy_ram(), vm.copy_ram());
+        assert_eq!(restored.get_ram_value(5), 10);
+
+        let mut other = VM::from_file_contents(vec![(
+            "Sys.vm".to_owned(),
+            "function Sys.init 0\nlabel END\ngoto END".to_owned(),
+        )])
+        .unwrap();
+        assert_eq!(
+            other.restore_snapshot(&snapshot),
+            Err("snapshot was taken from a different program".to_owned())
+        );
     }
 }
@@ -8,7 +8,7 @@ use nom::{
     branch::alt,
     bytes::complete::tag,
     character::complete::{alphanumeric1, space1},
-    combinator::{map, recognize, value},
+    combinator::{all_consuming, map, recognize, value, verify},
     multi::many1_count,
     sequence::{pair, preceded, separated_pair},
 };
@@ -94,7 +94,11 @@ fn command_two_args<'a, A>(
 ) -> impl FnMut(&'a str) -> IResult<&'a str, (A, Word)> {
     preceded(
         pair(tag(keyword), space1),
-        separated_pair(arg1_parser, space1, ParsableWord::parse_word),
+        separated_pair(
+            arg1_parser,
+            space1,
+            verify(ParsableWord::parse_word, |w: &Word| *w >= 0),
+        ),
     )
 }
 
@@ -128,6 +132,139 @@ pub fn parse_commands(input: &str) -> IResult<&str, Vec<VMCommand>> {
     non_comment_lines(command)(input)
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VMParseError {
+    pub file_name: String,
+    pub line: usize,
+    pub text: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for VMParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}, line {}: {}: \"{}\"",
+            self.file_name, self.line, self.message, self.text
+        )
+    }
+}
+
+pub fn errors_to_string(errors: &[VMParseError]) -> String {
+    errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn diagnose_number(number: &str, name: &str) -> String {
+    match number.parse::<i64>() {
+        Ok(n) if n < 0 => format!("negative {name}"),
+        Ok(_) => format!("{name} out of range"),
+        Err(_) => format!("invalid {name}"),
+    }
+}
+
+/// Explains why a line isn't a valid command.
+fn diagnose(text: &str) -> String {
+    let tokens = text.split_whitespace().collect::<Vec<_>>();
+    match tokens.as_slice() {
+        ["pop", "constant", ..] => "cannot pop to constant".to_owned(),
+        ["push" | "pop", segment, ..] if push_segment(segment).is_err() => {
+            "unknown segment".to_owned()
+        }
+        ["push" | "pop", _, offset] => diagnose_number(offset, "offset"),
+        ["function" | "call", name, count] => {
+            if all_consuming(identifier)(name).is_err() {
+                "invalid function name".to_owned()
+            } else {
+                diagnose_number(count, "count")
+            }
+        }
+        ["label" | "goto" | "if-goto", name] if all_consuming(identifier)(name).is_err() => {
+            "invalid label name".to_owned()
+        }
+        ["push" | "pop" | "function" | "call", ..] => "expected two arguments".to_owned(),
+        ["label" | "goto" | "if-goto", ..] => "expected a label name".to_owned(),
+        [
+            "add" | "sub" | "neg" | "eq" | "gt" | "lt" | "and" | "or" | "not" | "return",
+            ..,
+        ] => "unexpected text after command".to_owned(),
+        _ => "unknown command".to_owned(),
+    }
+}
+
+/// Parses a single file, reporting every line which couldn't be parsed. Each command is paired
+/// with the line it was written on and the comment trailing it, if any.
+pub fn parse_file(
+    file_name: &str,
+    contents: &str,
+) -> Result<(Vec<VMCommand>, Vec<SourceLine>), Vec<VMParseError>> {
+    let mut commands = vec![];
+    let mut source_lines = vec![];
+    let mut errors = vec![];
+    for (line_index, line) in contents.lines().enumerate() {
+        let (text, comment) = match line.split_once("//") {
+            Some((text, comment)) => (text.trim(), Some(comment.trim())),
+            None => (line.trim(), None),
+        };
+        if text.is_empty() {
+            continue;
+        }
+
+        match all_consuming(command)(text) {
+            Ok((_, command)) => {
+                commands.push(command);
+                source_lines.push(SourceLine {
+                    line_number: line_index + 1,
+                    comment: comment
+                        .filter(|comment| !comment.is_empty())
+                        .map(str::to_owned),
+                });
+            }
+            Err(_) => errors.push(VMParseError {
+                file_name: file_name.to_owned(),
+                line: line_index + 1,
+                text: text.to_owned(),
+                message: diagnose(text),
+            }),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok((commands, source_lines))
+    } else {
+        Err(errors)
+    }
+}
+
+/// Parses `(file name, contents)` pairs into the per-file commands and source lines taken by
+/// `VM::from_all_file_sources`, collecting the errors from all files.
+pub fn parse_files(
+    file_contents: Vec<(String, String)>,
+) -> Result<Vec<FileSource>, Vec<VMParseError>> {
+    let mut all_file_sources = vec![];
+    let mut errors = vec![];
+    for (file_name, contents) in file_contents {
+        match parse_file(&file_name, &contents) {
+            Ok((commands, source_lines)) => {
+                let name = file_name
+                    .rsplit_once('.')
+                    .map_or(file_name.as_str(), |(name, _)| name);
+                all_file_sources.push((name.to_owned(), commands, source_lines));
+            }
+            Err(file_errors) => errors.extend(file_errors),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(all_file_sources)
+    } else {
+        Err(errors)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -306,4 +443,71 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn test_parse_errors() {
+        let files = vec![
+            (
+                "Main.vm".to_owned(),
+                "function Main.main 0\npush constant 1\npush segment 1\n\n  pop constant 0 // oops\nreturn".to_owned(),
+            ),
+            ("Sys.vm".to_owned(), "function Sys.init 0\npush local -1\nlabel\nmul".to_owned()),
+        ];
+
+        let error = |file_name: &str, line, text: &str, message: &str| VMParseError {
+            file_name: file_name.to_owned(),
+            line,
+            text: text.to_owned(),
+            message: message.to_owned(),
+        };
+        assert_eq!(
+            parse_files(files),
+            Err(vec![
+                error("Main.vm", 3, "push segment 1", "unknown segment"),
+                error("Main.vm", 5, "pop constant 0", "cannot pop to constant"),
+                error("Sys.vm", 2, "push local -1", "negative offset"),
+                error("Sys.vm", 3, "label", "expected a label name"),
+                error("Sys.vm", 4, "mul", "unknown command"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_files() {
+        let files = vec![
+            (
+                "Main.vm".to_owned(),
+                "// Main\npush constant 1 // one\n\nadd //".to_owned(),
+            ),
+            ("Sys".to_owned(), "return".to_owned()),
+        ];
+
+        assert_eq!(
+            parse_files(files),
+            Ok(vec![
+                (
+                    "Main".to_owned(),
+                    vec![
+                        VMCommand::Push {
+                            segment: PushSegment::Constant,
+                            offset: 1
+                        },
+                        VMCommand::Add
+                    ],
+                    vec![
+                        SourceLine {
+                            line_number: 2,
+                            comment: Some("one".to_owned())
+                        },
+                        SourceLine::new(4)
+                    ]
+                ),
+                (
+                    "Sys".to_owned(),
+                    vec![VMCommand::Return],
+                    vec![SourceLine::new(1)]
+                ),
+            ])
+        );
+    }
 }
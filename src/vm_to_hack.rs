@@ -0,0 +1,579 @@
+use hashbrown::HashSet;
+
+use crate::{
+    hardware::{Instruction, Word},
+    hardware_parse::{AssemblyInstruction, assemble, format_assembly, parse_instructions},
+    vm::{PopSegment, Program, PushSegment, VMCommand},
+};
+
+const PUSH_D: &str = "
+    @SP
+    M=M+1
+    A=M-1
+    M=D";
+
+const POP_D: &str = "
+    @SP
+    AM=M-1
+    D=M";
+
+struct Translator<'a> {
+    program: &'a Program,
+    instructions: Vec<AssemblyInstruction>,
+    function_name: &'a str,
+    labels: HashSet<&'a str>,
+    label_count: usize,
+}
+
+impl<'a> Translator<'a> {
+    fn emit(&mut self, code: &str) {
+        let (_, instructions) = parse_instructions(code).unwrap();
+        self.instructions.extend(instructions);
+    }
+
+    /// Generated labels use `$$`, which can't appear in a scoped user label since VM identifiers
+    /// don't contain `$`.
+    fn unique_label(&mut self, kind: &str) -> String {
+        self.label_count += 1;
+
+        format!("{}$${kind}.{}", self.function_name, self.label_count)
+    }
+
+    fn scoped_label(&self, label_name: &str) -> Result<String, String> {
+        if !self.labels.contains(label_name) {
+            return Err(format!(
+                "{}: undefined label {label_name}",
+                self.function_name
+            ));
+        }
+
+        Ok(format!("{}${label_name}", self.function_name))
+    }
+
+    /// Collects the labels that can be jumped to from the commands up to the next `function`.
+    fn enter_scope(&mut self, function_name: &'a str, commands: &'a [VMCommand]) {
+        self.function_name = function_name;
+        self.labels = commands
+            .iter()
+            .take_while(|command| !matches!(command, VMCommand::Function { .. }))
+            .filter_map(|command| match command {
+                VMCommand::Label { name } => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+    }
+
+    fn segment_pointer(segment: PushSegment) -> Option<&'static str> {
+        match segment {
+            PushSegment::Local => Some("LCL"),
+            PushSegment::Argument => Some("ARG"),
+            PushSegment::This => Some("THIS"),
+            PushSegment::That => Some("THAT"),
+            _ => None,
+        }
+    }
+
+    fn fixed_address(static_start: Word, segment: PushSegment, offset: Word) -> Word {
+        match segment {
+            PushSegment::Static => static_start + offset,
+            PushSegment::Temp => 5 + offset,
+            PushSegment::Pointer => 3 + offset,
+            _ => unreachable!(),
+        }
+    }
+
+    fn push(&mut self, static_start: Word, segment: PushSegment, offset: Word) {
+        if segment == PushSegment::Constant {
+            if offset >= 0 {
+                self.emit(&format!("@{offset}\nD=A"));
+            } else {
+                self.emit(&format!("@{}\nD=!A", !offset));
+            }
+        } else if let Some(pointer) = Self::segment_pointer(segment) {
+            self.emit(&format!(
+                "
+                @{offset}
+                D=A
+                @{pointer}
+                A=D+M
+                D=M"
+            ));
+        } else {
+            let address = Self::fixed_address(static_start, segment, offset);
+            self.emit(&format!("@{address}\nD=M"));
+        }
+        self.emit(PUSH_D);
+    }
+
+    fn pop(&mut self, static_start: Word, segment: PopSegment, offset: Word) {
+        let segment = match segment {
+            PopSegment::Static => PushSegment::Static,
+            PopSegment::Local => PushSegment::Local,
+            PopSegment::Argument => PushSegment::Argument,
+            PopSegment::This => PushSegment::This,
+            PopSegment::That => PushSegment::That,
+            PopSegment::Temp => PushSegment::Temp,
+            PopSegment::Pointer => PushSegment::Pointer,
+        };
+        if let Some(pointer) = Self::segment_pointer(segment) {
+            self.emit(&format!(
+                "
+                @{offset}
+                D=A
+                @{pointer}
+                D=D+M
+                @R13
+                M=D"
+            ));
+            self.emit(POP_D);
+            self.emit("@R13\nA=M\nM=D");
+        } else {
+            self.emit(POP_D);
+            let address = Self::fixed_address(static_start, segment, offset);
+            self.emit(&format!("@{address}\nM=D"));
+        }
+    }
+
+    fn binary(&mut self, operation: &str) {
+        self.emit(POP_D);
+        self.emit(&format!("A=A-1\n{operation}"));
+    }
+
+    fn unary(&mut self, operation: &str) {
+        self.emit(&format!("@SP\nA=M-1\n{operation}"));
+    }
+
+    /// Leaves D with the sign of x - y, where y is in D and x is below the top of the stack. When
+    /// x and y have different signs x - y can overflow, but then the sign of x alone decides.
+    fn ordered_difference(&mut self, label: &str) {
+        self.emit(&format!(
+            "
+            @R13
+            M=D
+            @SP
+            A=M-1
+            D=M
+            @{label}.neg
+            D;JLT
+            @R13
+            D=M
+            @{label}.sub
+            D;JGE
+            D=1
+            @{label}.set
+            0;JMP
+            ({label}.neg)
+            @R13
+            D=M
+            @{label}.sub
+            D;JLT
+            D=-1
+            @{label}.set
+            0;JMP
+            ({label}.sub)
+            @SP
+            A=M-1
+            D=M
+            @R13
+            D=D-M
+            ({label}.set)"
+        ));
+    }
+
+    fn compare(&mut self, jump: &str) {
+        let label = self.unique_label("cmp");
+        self.emit(POP_D);
+        if jump == "JEQ" {
+            self.emit("A=A-1\nD=M-D");
+        } else {
+            self.ordered_difference(&label);
+        }
+        self.emit(&format!(
+            "
+            @SP
+            A=M-1
+            M=-1
+            @{label}
+            D;{jump}
+            @SP
+            A=M-1
+            M=0
+            ({label})"
+        ));
+    }
+
+    fn call(&mut self, function_name: &str, argument_count: Word) -> Result<(), String> {
+        if !self
+            .program
+            .function_name_to_index
+            .contains_key(function_name)
+        {
+            return Err(format!(
+                "{}: call to undefined function {function_name}",
+                self.function_name
+            ));
+        }
+        let return_label = self.unique_label("ret");
+        self.emit(&format!("@{return_label}\nD=A"));
+        self.emit(PUSH_D);
+        for pointer in ["LCL", "ARG", "THIS", "THAT"] {
+            self.emit(&format!("@{pointer}\nD=M"));
+            self.emit(PUSH_D);
+        }
+        self.emit(&format!(
+            "
+            @SP
+            D=M
+            @{}
+            D=D-A
+            @ARG
+            M=D
+            @SP
+            D=M
+            @LCL
+            M=D
+            @{function_name}
+            0;JMP
+            ({return_label})",
+            argument_count + 5
+        ));
+
+        Ok(())
+    }
+
+    fn ret(&mut self) {
+        self.emit(
+            "
+            @LCL
+            D=M
+            @R13
+            M=D
+            @5
+            A=D-A
+            D=M
+            @R14
+            M=D",
+        );
+        self.emit(POP_D);
+        self.emit(
+            "
+            @ARG
+            A=M
+            M=D
+            @ARG
+            D=M+1
+            @SP
+            M=D",
+        );
+        for pointer in ["THAT", "THIS", "ARG", "LCL"] {
+            self.emit(&format!("@R13\nAM=M-1\nD=M\n@{pointer}\nM=D"));
+        }
+        self.emit("@R14\nA=M\n0;JMP");
+    }
+
+    fn command(&mut self, static_start: Word, command: &VMCommand) -> Result<(), String> {
+        match command {
+            VMCommand::Add => self.binary("M=D+M"),
+            VMCommand::Sub => self.binary("M=M-D"),
+            VMCommand::And => self.binary("M=D&M"),
+            VMCommand::Or => self.binary("M=D|M"),
+            VMCommand::Neg => self.unary("M=-M"),
+            VMCommand::Not => self.unary("M=!M"),
+            VMCommand::Eq => self.compare("JEQ"),
+            VMCommand::Gt => self.compare("JGT"),
+            VMCommand::Lt => self.compare("JLT"),
+            VMCommand::Push { segment, offset } => self.push(static_start, *segment, *offset),
+            VMCommand::Pop { segment, offset } => self.pop(static_start, *segment, *offset),
+            VMCommand::Label { name } => {
+                let label = self.scoped_label(name)?;
+                self.instructions.push(AssemblyInstruction::Label(label));
+            }
+            VMCommand::Goto { label_name } => {
+                let label = self.scoped_label(label_name)?;
+                self.emit(&format!("@{label}\n0;JMP"));
+            }
+            VMCommand::IfGoto { label_name } => {
+                let label = self.scoped_label(label_name)?;
+                self.emit(POP_D);
+                self.emit(&format!("@{label}\nD;JNE"));
+            }
+            VMCommand::Function {
+                name,
+                local_var_count,
+            } => {
+                self.instructions
+                    .push(AssemblyInstruction::Label(name.clone()));
+                for _ in 0..*local_var_count {
+                    self.emit("D=0");
+                    self.emit(PUSH_D);
+                }
+            }
+            VMCommand::Call {
+                function_name,
+                argument_count,
+            } => self.call(function_name, *argument_count)?,
+            VMCommand::Return => self.ret(),
+        }
+
+        Ok(())
+    }
+}
+
+/// Lowers a VM program to Hack assembly, preceded by bootstrap code which sets SP to 256 and
+/// calls `Sys.init`. Static variables are placed at the same addresses the VM uses, so RAM
+/// contents can be compared between the two levels.
+pub fn program_to_assembly(program: &Program) -> Result<Vec<AssemblyInstruction>, String> {
+    let mut translator = Translator {
+        program,
+        instructions: vec![],
+        function_name: "",
+        labels: HashSet::new(),
+        label_count: 0,
+    };
+
+    translator.emit(
+        "
+        @256
+        D=A
+        @SP
+        M=D",
+    );
+    translator.call("Sys.init", 0)?;
+
+    for file in &program.files {
+        let commands = file.commands(&program.all_commands);
+        let static_start = *file.static_segment.start();
+        translator.enter_scope(&file.name, commands);
+        for (i, command) in commands.iter().enumerate() {
+            if let VMCommand::Function { name, .. } = command {
+                translator.enter_scope(name, &commands[i + 1..]);
+            }
+            translator.command(static_start, command)?;
+        }
+    }
+
+    Ok(translator.instructions)
+}
+
+pub fn program_to_instructions(program: &Program) -> Result<Vec<Instruction>, String> {
+    assemble(&program_to_assembly(program)?)
+}
+
+pub fn program_to_asm(program: &Program) -> Result<String, String> {
+    Ok(format_assembly(&program_to_assembly(program)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::{AnyHardware, Hardware};
+    use crate::hardware_parse::assemble_hack_file;
+    use crate::vm::VM;
+    use crate::vm_parse::parse_commands;
+    use crate::wasm_hardware::WasmHardware;
+
+    fn test_program() -> Program {
+        let sys = r#"
+            function Sys.init 0
+            push constant 10
+            call Sys.fib 1
+            pop static 0
+            push constant 7
+            neg
+            push constant 3
+            call Math.mod 2
+            pop static 1
+            push constant 5
+            push constant 3
+            gt
+            push constant 1
+            push constant 3
+            eq
+            or
+            pop static 2
+            label HALT
+            goto HALT
+
+            function Sys.fib 0
+            push argument 0
+            push constant 2
+            lt
+            if-goto BASE
+            push argument 0
+            push constant 1
+            sub
+            call Sys.fib 1
+            push argument 0
+            push constant 2
+            sub
+            call Sys.fib 1
+            add
+            return
+            label BASE
+            push argument 0
+            return"#;
+        let math = r#"
+            // x mod y for non-negative y, with the sign of x
+            function Math.mod 1
+            push argument 0
+            pop local 0
+            label LOOP
+            push local 0
+            push argument 1
+            lt
+            push local 0
+            push constant 0
+            lt
+            not
+            and
+            if-goto END
+            push local 0
+            push constant 0
+            lt
+            if-goto NEGATIVE
+            push local 0
+            push argument 1
+            sub
+            pop local 0
+            goto LOOP
+            label NEGATIVE
+            push local 0
+            push argument 1
+            add
+            pop local 0
+            goto LOOP
+            label END
+            push local 0
+            return"#;
+
+        VM::from_all_file_commands(vec![
+            ("Sys".to_owned(), parse_commands(sys).unwrap().1),
+            ("Math".to_owned(), parse_commands(math).unwrap().1),
+        ])
+        .program
+    }
+
+    #[test]
+    fn test_hardware() {
+        let program = test_program();
+        let instructions = program_to_instructions(&program).unwrap();
+        let mut hardware = Hardware::default();
+        hardware.load_program(&instructions);
+        hardware.run(100000);
+
+        assert_eq!(hardware.get_ram_value(16), 55);
+        assert_eq!(hardware.get_ram_value(17), 2);
+        assert_eq!(hardware.get_ram_value(18), -1);
+    }
+
+    #[test]
+    fn test_wasm_hardware() {
+        let program = test_program();
+        let instructions = program_to_instructions(&program).unwrap();
+        let mut hardware = WasmHardware::from_instructions(&instructions);
+        hardware.run(100000);
+
+        assert_eq!(hardware.get_ram_value(16), 55);
+        assert_eq!(hardware.get_ram_value(17), 2);
+        assert_eq!(hardware.get_ram_value(18), -1);
+    }
+
+    #[test]
+    fn test_asm_round_trip() {
+        let program = test_program();
+        let asm = program_to_asm(&program).unwrap();
+
+        assert_eq!(
+            assemble_hack_file(&asm).unwrap(),
+            program_to_instructions(&program).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_compare_overflow() {
+        let values: [Word; 6] = [20000, -20000, 32767, -32768, 0, -1];
+        let push = |value: Word| match value {
+            -32768 => "push constant 32767\nneg\npush constant 1\nsub\n".to_owned(),
+            value if value < 0 => format!("push constant {}\nneg\n", -value),
+            value => format!("push constant {value}\n"),
+        };
+        let mut sys = "function Sys.init 0\n".to_owned();
+        let mut static_count = 0;
+        for x in values {
+            for y in values {
+                for command in ["gt", "lt", "eq"] {
+                    sys += &format!(
+                        "{}{}{command}\npop static {static_count}\n",
+                        push(x),
+                        push(y)
+                    );
+                    static_count += 1;
+                }
+            }
+        }
+        sys += "label HALT\ngoto HALT";
+        let mut vm =
+            VM::from_all_file_commands(vec![("Sys".to_owned(), parse_commands(&sys).unwrap().1)]);
+        vm.run(100000);
+        let instructions = program_to_instructions(&vm.program).unwrap();
+        let mut hardware = Hardware::default();
+        hardware.load_program(&instructions);
+        hardware.run(100000);
+
+        for address in 16..16 + static_count {
+            assert_eq!(
+                hardware.get_ram_value(address),
+                vm.get_ram_value(address),
+                "static {}",
+                address - 16
+            );
+        }
+        // 20000 gt -20000
+        assert_eq!(vm.get_ram_value(19), -1);
+    }
+
+    #[test]
+    fn test_label_collision() {
+        let sys = r#"
+            function Sys.init 0
+            push constant 1
+            push constant 2
+            lt
+            call Sys.id 1
+            pop static 0
+            label cmp.1
+            label cmp.2
+            label cmp.3
+            label ret.1
+            label ret.2
+            label ret.3
+            goto ret.3
+
+            function Sys.id 0
+            push argument 0
+            return"#;
+        let program =
+            VM::from_all_file_commands(vec![("Sys".to_owned(), parse_commands(sys).unwrap().1)])
+                .program;
+        let asm = program_to_asm(&program).unwrap();
+        let instructions = assemble_hack_file(&asm).unwrap();
+        let mut hardware = Hardware::default();
+        hardware.load_program(&instructions);
+        hardware.run(1000);
+
+        assert_eq!(hardware.get_ram_value(16), -1);
+    }
+
+    #[test]
+    fn test_undefined_function() {
+        let program = VM::from_all_file_commands(vec![(
+            "Sys".to_owned(),
+            parse_commands("function Sys.init 0\ncall Output.printInt 1")
+                .unwrap()
+                .1,
+        )])
+        .program;
+
+        assert!(program_to_instructions(&program).is_err());
+    }
+}
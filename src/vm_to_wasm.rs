@@ -317,17 +317,30 @@ fn drop_stack_to_ram(stack_size: &mut usize, wasm_instructions: &mut Vec<Instruc
     }
 }
 
+/// Where each label, function and call site ended up, which commands need to jump to each other.
+struct ProgramContext<'a> {
+    program: &'a Program,
+    label_indices: HashMap<String, i32>,
+    function_indices: HashMap<String, i32>,
+    call_sites: HashMap<String, Vec<i32>>,
+}
+
 fn command_to_wasm2(
     command: &VMCommand,
     case_index: usize,
     jump_index: Index<'static>,
     static_segment_start: Word,
     current_function_name: Option<&String>,
-    label_indices: &HashMap<String, i32>,
-    function_indices: &HashMap<String, i32>,
-    call_sites: &HashMap<String, Vec<i32>>,
+    context: &ProgramContext,
     stack_size: &mut usize,
 ) -> Vec<Instruction<'static>> {
+    let ProgramContext {
+        label_indices,
+        function_indices,
+        call_sites,
+        ..
+    } = context;
+    let native_os_function = native_os_function(context.program, command);
     let mut wasm_instructions: Vec<Instruction<'static>> = vec![
         Instruction::I64Const(1),
         Instruction::LocalGet(index_ticks()),
@@ -591,18 +604,18 @@ fn command_to_wasm2(
             function_name,
             argument_count,
         } => {
-            match function_name.as_str() {
-                "Math.multiply" => {
+            match native_os_function {
+                Some("Math.multiply") => {
                     prepare_on_stack2_commutative(stack_size, &mut wasm_instructions);
                     wasm_instructions.extend([Instruction::I32Mul, Instruction::I32Extend16S]);
                     *stack_size += 1;
                 }
-                "Math.divide" => {
+                Some("Math.divide") => {
                     prepare_on_stack2(stack_size, &mut wasm_instructions);
                     wasm_instructions.extend([Instruction::I32DivS, Instruction::I32Extend16S]);
                     *stack_size += 1;
                 }
-                "Screen.clearScreen" => {
+                Some("Screen.clearScreen") => {
                     wasm_instructions.extend([
                         Instruction::I32Const(RAM::SCREEN as i32 * 4),
                         Instruction::I32Const(0),
@@ -614,7 +627,7 @@ fn command_to_wasm2(
                     ]);
                     *stack_size += 1;
                 }
-                "Screen.setColor" => {
+                Some("Screen.setColor") => {
                     prepare_on_stack1(stack_size, &mut wasm_instructions);
                     wasm_instructions.extend([
                         Instruction::GlobalSet(index_screen_color()),
@@ -622,7 +635,7 @@ fn command_to_wasm2(
                     ]);
                     *stack_size += 1;
                 }
-                "Screen.drawPixel" => {
+                Some("Screen.drawPixel") => {
                     prepare_on_stack2(stack_size, &mut wasm_instructions);
                     wasm_instructions.extend([
                         Instruction::I32Const(RAM::SCREEN_ROW_LENGTH as i32 * 4),
@@ -658,7 +671,7 @@ fn command_to_wasm2(
                     ]);
                     *stack_size += 1;
                 }
-                "Memory.init" => {
+                Some("Memory.init") => {
                     let heap_start = RAM::HEAP as i32;
                     let heap_end = RAM::SCREEN as i32;
 
@@ -681,7 +694,7 @@ fn command_to_wasm2(
                     ]);
                     *stack_size += 1;
                 }
-                "Memory.alloc" | "Array.new" => {
+                Some("Memory.alloc" | "Array.new") => {
                     let heap_start = RAM::HEAP as i32;
 
                     let continue_id = Id::new("alloc_continue", Span::from_offset(0));
@@ -789,7 +802,7 @@ fn command_to_wasm2(
 
                     *stack_size += 1;
                 }
-                "Memory.deAlloc" | "Array.dispose" => {
+                Some("Memory.deAlloc" | "Array.dispose") => {
                     prepare_on_stack1(stack_size, &mut wasm_instructions);
                     // Fragmented AF
                     wasm_instructions.extend([
@@ -989,6 +1002,19 @@ const OS_FUNCTIONS: &[&str] = &[
     "Array.dispose",
 ];
 
+/// The OS function `command` calls, if it's one which is compiled inline and the program's OS
+/// policy lets it run natively.
+fn native_os_function<'a>(program: &Program, command: &'a VMCommand) -> Option<&'a str> {
+    match command {
+        VMCommand::Call { function_name, .. }
+            if OS_FUNCTIONS.contains(&function_name.as_str()) && program.is_native(function_name) =>
+        {
+            Some(function_name)
+        }
+        _ => None,
+    }
+}
+
 fn program_to_dynamic_cases(
     program: &Program,
     loop_id: Id<'static>,
@@ -1018,7 +1044,7 @@ fn program_to_dynamic_cases(
                 }
                 function_indices.insert(name.clone(), i as i32);
             }
-            VMCommand::Call { function_name, .. } if !OS_FUNCTIONS.contains(&function_name.as_str()) => {
+            VMCommand::Call { function_name, .. } if native_os_function(program, command).is_none() => {
                 call_sites
                     .entry(function_name.clone())
                     .or_default()
@@ -1028,6 +1054,12 @@ fn program_to_dynamic_cases(
         }
     }
 
+    let context = ProgramContext {
+        program,
+        label_indices,
+        function_indices,
+        call_sites,
+    };
     let mut cases = vec![];
     let mut current_case = vec![];
     let mut stack_size = 0;
@@ -1053,9 +1085,7 @@ fn program_to_dynamic_cases(
             jump_index,
             static_segment_start,
             current_function_name,
-            &label_indices,
-            &function_indices,
-            &call_sites,
+            &context,
             &mut stack_size,
         );
 
@@ -1111,7 +1141,7 @@ fn program_to_static_cases(
                 case_starts.insert(i);
                 case_index += 1;
             }
-            VMCommand::Call { function_name, .. } if !OS_FUNCTIONS.contains(&function_name.as_str()) => {
+            VMCommand::Call { function_name, .. } if native_os_function(program, command).is_none() => {
                 call_sites
                     .entry(function_name.clone())
                     .or_default()
@@ -1124,6 +1154,12 @@ fn program_to_static_cases(
     }
     case_starts.insert(program.all_commands.len());
 
+    let context = ProgramContext {
+        program,
+        label_indices,
+        function_indices,
+        call_sites,
+    };
     let mut cases = vec![];
     let mut current_case = vec![];
     let mut stack_size = 0;
@@ -1145,7 +1181,7 @@ fn program_to_static_cases(
 
         match command {
             VMCommand::Goto { label_name } | VMCommand::IfGoto { label_name } => {
-                let target_index = label_indices
+                let target_index = context.label_indices
                     [&format!("{}.{}", current_function_name.unwrap(), label_name)]
                     as i32;
                 if target_index > cases.len() as i32 {
@@ -1156,7 +1192,7 @@ fn program_to_static_cases(
                 }
             }
             VMCommand::Call { function_name, .. } => {
-                if let Some(target_index) = function_indices.get(function_name)
+                if let Some(target_index) = context.function_indices.get(function_name)
                     && *target_index > cases.len() as i32
                 {
                     jump_index = Index::Num(
@@ -1174,9 +1210,7 @@ fn program_to_static_cases(
             jump_index,
             static_segment_start,
             current_function_name,
-            &label_indices,
-            &function_indices,
-            &call_sites,
+            &context,
             &mut stack_size,
         );
 
@@ -0,0 +1,416 @@
+use std::fmt::Display;
+
+use hashbrown::HashMap;
+
+use crate::{
+    hardware::Word,
+    os::os_argument_count,
+    vm::{PopSegment, Program, PushSegment, VMCommand},
+};
+
+/// The last address of the static segment; the stack starts right after it.
+const STATIC_END: Word = 255;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found in a program before running it, at the command it's about.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub command_index: usize,
+    pub file_name: String,
+    pub line_number: usize,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(
+            f,
+            "{}, line {}: {severity}: {}",
+            self.file_name, self.line_number, self.message
+        )
+    }
+}
+
+struct Validator<'a> {
+    program: &'a Program,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Validator<'_> {
+    fn report(&mut self, severity: Severity, command_index: usize, message: String) {
+        let (file_name, line_number) = self
+            .program
+            .source_line(command_index)
+            .map_or((String::new(), 0), |(file, source_line)| {
+                (file.name.clone(), source_line.line_number)
+            });
+        self.diagnostics.push(Diagnostic {
+            severity,
+            command_index,
+            file_name,
+            line_number,
+            message,
+        });
+    }
+
+    /// The commands of each function, after its `function` command.
+    fn function_bodies(&self) -> Vec<(usize, std::ops::Range<usize>)> {
+        let metadata = &self.program.function_metadata;
+        (0..metadata.len())
+            .map(|function_index| {
+                let start = metadata[function_index].command_index;
+                let end = metadata
+                    .get(function_index + 1)
+                    .map_or(self.program.all_commands.len(), |next| next.command_index);
+                let body_start = match self.program.all_commands.get(start) {
+                    Some(VMCommand::Function { .. }) => start + 1,
+                    _ => start,
+                };
+                (function_index, body_start..end)
+            })
+            .collect()
+    }
+
+    fn check_commands(&mut self) {
+        let program = self.program;
+        let mut call_argument_counts = HashMap::new();
+        for file in &program.files {
+            let static_start = *file.static_segment.start();
+            for (offset, command) in file.commands(&program.all_commands).iter().enumerate() {
+                let command_index = file.starting_command_index + offset;
+                match command {
+                    VMCommand::Push {
+                        segment: PushSegment::Static,
+                        offset,
+                    }
+                    | VMCommand::Pop {
+                        segment: PopSegment::Static,
+                        offset,
+                    } if static_start + offset > STATIC_END => self.report(
+                        Severity::Error,
+                        command_index,
+                        format!(
+                            "static {offset} is at address {}, past the end of the static segment",
+                            static_start + offset
+                        ),
+                    ),
+                    VMCommand::Call {
+                        function_name,
+                        argument_count,
+                    } => {
+                        self.check_call(command_index, function_name, *argument_count);
+                        let &mut (first_index, first_count) = call_argument_counts
+                            .entry(function_name)
+                            .or_insert((command_index, *argument_count));
+                        if first_count != *argument_count {
+                            let (file_name, line_number) = program
+                                .source_line(first_index)
+                                .map_or((String::new(), 0), |(file, source_line)| {
+                                    (file.name.clone(), source_line.line_number)
+                                });
+                            self.report(
+                                Severity::Warning,
+                                command_index,
+                                format!(
+                                    "{function_name} is called with {argument_count} arguments \
+                                     here but with {first_count} at {file_name}, line {line_number}"
+                                ),
+                            );
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn check_call(&mut self, command_index: usize, function_name: &str, argument_count: Word) {
+        let program = self.program;
+        let native_argument_count =
+            os_argument_count(function_name).filter(|_| program.is_native(function_name));
+        let expected = match (
+            native_argument_count,
+            program.function_name_to_index.get(function_name),
+        ) {
+            (Some(native_argument_count), _) => native_argument_count,
+            (None, Some(&function_index)) => {
+                program.function_metadata[function_index].argument_count
+            }
+            (None, None) => {
+                self.report(
+                    Severity::Error,
+                    command_index,
+                    format!("call to undefined function {function_name}"),
+                );
+                return;
+            }
+        };
+
+        // Functions may ignore their last arguments, but not read ones which weren't passed.
+        let too_few = argument_count < expected;
+        let wrong_native = native_argument_count.is_some() && argument_count != expected;
+        if too_few || wrong_native {
+            self.report(
+                Severity::Error,
+                command_index,
+                format!(
+                    "{function_name} takes {expected} arguments but is called with {argument_count}"
+                ),
+            );
+        }
+    }
+
+    /// Follows every path through each function, tracking how many values its commands leave on
+    /// the stack, to check that labels exist, that nothing pops more than the function pushed
+    /// and that `return` has exactly the return value to return.
+    fn check_functions(&mut self) {
+        for (function_index, body) in self.function_bodies() {
+            let metadata = &self.program.function_metadata[function_index];
+            // Commands before the first function, as in the course's early VM tests, aren't a
+            // function.
+            if metadata.name.is_empty() {
+                continue;
+            }
+
+            let mut depths: Vec<Option<i32>> = vec![None; body.len()];
+            let mut to_visit = vec![(body.start, 0)];
+            let mut reported = vec![false; body.len()];
+            while let Some((command_index, depth)) = to_visit.pop() {
+                if !body.contains(&command_index) {
+                    self.report(
+                        Severity::Warning,
+                        body.end - 1,
+                        format!("{} can run past its end without returning", metadata.name),
+                    );
+                    continue;
+                }
+                let slot = command_index - body.start;
+                match depths[slot] {
+                    Some(previous) if previous != depth => {
+                        if !std::mem::replace(&mut reported[slot], true) {
+                            self.report(
+                                Severity::Warning,
+                                command_index,
+                                format!(
+                                    "the stack has {previous} or {depth} values here depending \
+                                     on the path taken"
+                                ),
+                            );
+                        }
+                        continue;
+                    }
+                    Some(_) => continue,
+                    None => depths[slot] = Some(depth),
+                }
+
+                let command = &self.program.all_commands[command_index];
+                let (popped, pushed) = match command {
+                    VMCommand::Push { .. } => (0, 1),
+                    VMCommand::Pop { .. } | VMCommand::IfGoto { .. } => (1, 0),
+                    VMCommand::Add
+                    | VMCommand::Sub
+                    | VMCommand::Eq
+                    | VMCommand::Gt
+                    | VMCommand::Lt
+                    | VMCommand::And
+                    | VMCommand::Or => (2, 1),
+                    VMCommand::Neg | VMCommand::Not => (1, 1),
+                    VMCommand::Call { argument_count, .. } => (*argument_count as i32, 1),
+                    VMCommand::Return => (1, 0),
+                    VMCommand::Label { .. } | VMCommand::Goto { .. } => (0, 0),
+                    VMCommand::Function { .. } => (0, 0),
+                };
+                if depth < popped {
+                    self.report(
+                        Severity::Error,
+                        command_index,
+                        format!("{command} needs {popped} values but the stack has {depth}"),
+                    );
+                    continue;
+                }
+                let next_depth = depth - popped + pushed;
+
+                match command {
+                    VMCommand::Return => {
+                        if depth > 1 {
+                            self.report(
+                                Severity::Warning,
+                                command_index,
+                                "return leaves values on the stack besides the return value"
+                                    .to_owned(),
+                            );
+                        }
+                    }
+                    VMCommand::Goto { label_name } | VMCommand::IfGoto { label_name } => {
+                        match metadata.label_command_index(label_name) {
+                            Some(target) => to_visit.push((target, next_depth)),
+                            None => self.report(
+                                Severity::Error,
+                                command_index,
+                                format!("unknown label {label_name} in {}", metadata.name),
+                            ),
+                        }
+                        if let VMCommand::IfGoto { .. } = command {
+                            to_visit.push((command_index + 1, next_depth));
+                        }
+                    }
+                    _ => to_visit.push((command_index + 1, next_depth)),
+                }
+            }
+        }
+    }
+}
+
+/// Finds mistakes which would otherwise only show up when running the program: calls to undefined
+/// functions or with too few arguments, jumps to missing labels, statics past the static segment
+/// and unbalanced stacks. The diagnostics are sorted by command.
+pub fn validate(program: &Program) -> Vec<Diagnostic> {
+    let mut validator = Validator {
+        program,
+        diagnostics: vec![],
+    };
+    validator.check_commands();
+    validator.check_functions();
+
+    let mut diagnostics = validator.diagnostics;
+    diagnostics.sort_by_key(|diagnostic| diagnostic.command_index);
+    diagnostics.dedup();
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    fn messages(files: &[(&str, &str)]) -> Vec<(Severity, usize, String)> {
+        let vm = VM::from_file_contents(
+            files
+                .iter()
+                .map(|(name, source)| (name.to_string(), source.to_string()))
+                .collect(),
+        )
+        .unwrap();
+        validate(&vm.program)
+            .into_iter()
+            .map(|diagnostic| {
+                (
+                    diagnostic.severity,
+                    diagnostic.line_number,
+                    diagnostic.message,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        let sys = "function Sys.init 0
+call Main.main 0
+pop temp 0
+call Main.missing 0
+pop temp 0
+push constant 1
+call Main.add 1
+pop temp 0
+push constant 1
+push constant 2
+push constant 3
+call Math.multiply 3
+pop temp 0
+push constant 1
+push constant 2
+call Main.add 2
+pop temp 0
+label END
+goto END";
+        let main = "function Main.main 0
+push constant 1
+if-goto ELSE
+push constant 2
+label ELSE
+push constant 3
+pop static 0
+goto MISSING
+function Main.add 0
+push argument 0
+push argument 1
+add
+add
+return
+function Main.leave 0
+push constant 1
+push constant 2
+return";
+        let big = "function Big.init 0
+push constant 0
+pop static 239
+push constant 0
+return";
+        assert_eq!(
+            messages(&[("Sys.vm", sys), ("Main.vm", main), ("Big.vm", big)]),
+            vec![
+                (
+                    Severity::Error,
+                    4,
+                    "call to undefined function Main.missing".to_owned()
+                ),
+                (
+                    Severity::Error,
+                    7,
+                    "Main.add takes 2 arguments but is called with 1".to_owned()
+                ),
+                (
+                    Severity::Error,
+                    12,
+                    "Math.multiply takes 2 arguments but is called with 3".to_owned()
+                ),
+                (
+                    Severity::Warning,
+                    16,
+                    "Main.add is called with 2 arguments here but with 1 at Sys, line 7".to_owned()
+                ),
+                (
+                    Severity::Warning,
+                    5,
+                    "the stack has 1 or 0 values here depending on the path taken".to_owned()
+                ),
+                (
+                    Severity::Error,
+                    8,
+                    "unknown label MISSING in Main.main".to_owned()
+                ),
+                (
+                    Severity::Error,
+                    13,
+                    "add needs 2 values but the stack has 1".to_owned()
+                ),
+                (
+                    Severity::Warning,
+                    18,
+                    "return leaves values on the stack besides the return value".to_owned()
+                ),
+                (
+                    Severity::Error,
+                    3,
+                    "static 239 is at address 256, past the end of the static segment".to_owned()
+                ),
+            ]
+        );
+
+        assert_eq!(
+            messages(&[("Sys.vm", "function Sys.init 0\nlabel END\ngoto END")]),
+            vec![]
+        );
+    }
+}
@@ -3,7 +3,13 @@ use std::sync::{Arc, OnceLock};
 use crate::any_wasm::{AnyWasmHandle, Val};
 
 use crate::hardware::Word;
-use crate::{hardware::AnyHardware, hardware_parse::assemble_hack_file};
+use crate::{
+    condition::Condition,
+    coverage::Coverage,
+    hardware::AnyHardware,
+    hardware_parse::{AssemblyError, assemble_hack_file, parse_binary_file},
+    watchpoint::{WatchHit, Watchpoint},
+};
 
 #[cfg(not(target_arch = "wasm32"))]
 pub type WasmHardware = GenericWasmHardware<crate::any_wasm::WasmtimeHandle>;
@@ -23,6 +29,7 @@ struct State<H: AnyWasmHandle> {
 pub struct GenericWasmHardware<H: AnyWasmHandle> {
     rom: Box<[crate::hardware::Instruction; crate::hardware::MEM_SIZE]>,
     state: Arc<OnceLock<State<H>>>,
+    ticks: u64,
 }
 
 impl<H: AnyWasmHandle> GenericWasmHardware<H> {
@@ -56,23 +63,19 @@ impl<H: AnyWasmHandle> GenericWasmHardware<H> {
             rom[i] = *instruction;
         }
 
-        Self { rom, state }
+        Self {
+            rom,
+            state,
+            ticks: 0,
+        }
     }
 
-    pub fn from_file_contents(contents: &str) -> Self {
-        let instructions = assemble_hack_file(contents).unwrap().1;
-
-        Self::from_instructions(&instructions)
+    pub fn from_file_contents(contents: &str) -> Result<Self, AssemblyError> {
+        Ok(Self::from_instructions(&assemble_hack_file(contents)?))
     }
 
-    pub fn from_hack_file_contents(contents: &str) -> Self {
-        let instructions = contents
-            .lines()
-            .map(|l| crate::hardware::UWord::from_str_radix(l.trim(), 2).unwrap())
-            .map(crate::hardware::Instruction::new)
-            .collect::<Vec<_>>();
-
-        Self::from_instructions(&instructions)
+    pub fn from_hack_file_contents(contents: &str) -> Result<Self, AssemblyError> {
+        Ok(Self::from_instructions(&parse_binary_file(contents)?))
     }
 }
 
@@ -141,6 +144,12 @@ impl AnyHardware for WasmHardware {
         state.handle.get_global_value_i32(&state.pc) as Word
     }
 
+    fn set_pc(&mut self, pc: Word) {
+        let state = Arc::get_mut(&mut self.state).unwrap().get_mut().unwrap();
+
+        state.handle.set_global_value_i32(&state.pc, pc as i32);
+    }
+
     fn step(&mut self) -> bool {
         todo!()
     }
@@ -162,9 +171,10 @@ impl AnyHardware for WasmHardware {
             &[Val::I32(step_count as i32)],
             &mut returns,
         );
-        let [Val::I32(_)] = returns else {
+        let [Val::I32(ticks)] = returns else {
             panic!("Return type changed");
         };
+        self.ticks += u64::from(ticks as u32);
 
         false
     }
@@ -176,5 +186,49 @@ impl AnyHardware for WasmHardware {
         state.handle.set_global_value_i32(&state.d, 0);
         state.handle.set_global_value_i32(&state.pc, 0);
         state.handle.fill_memory(&state.memory, 0);
+        self.ticks = 0;
+    }
+
+    // The WASM module runs whole basic blocks at once, so it can't record single steps.
+    fn set_recording(&mut self, _capacity: Option<usize>) {}
+
+    fn timeline(&mut self) -> Option<(usize, usize)> {
+        None
+    }
+
+    fn run_back(&mut self, _step_count: u64) -> bool {
+        true
+    }
+
+    // Likewise, it doesn't count single instructions.
+    fn set_coverage(&mut self, _covering: bool) {}
+
+    fn coverage(&mut self) -> Option<&Coverage> {
+        None
+    }
+
+    fn add_watchpoint(&mut self, _watchpoint: Watchpoint) -> Result<(), String> {
+        Err("the WASM backend can't watch memory".to_owned())
+    }
+
+    fn watch_hit(&mut self) -> Option<WatchHit> {
+        None
+    }
+
+    fn add_condition(&mut self, _condition: Condition) -> Result<(), String> {
+        Err("the WASM backend can't check breakpoint conditions".to_owned())
+    }
+
+    fn ticks(&mut self) -> u64 {
+        self.ticks
+    }
+
+    fn set_registers(&mut self, a: Word, d: Word, pc: Word, ticks: u64) {
+        let state = Arc::get_mut(&mut self.state).unwrap().get_mut().unwrap();
+
+        state.handle.set_global_value_i32(&state.a, a as i32);
+        state.handle.set_global_value_i32(&state.d, d as i32);
+        state.handle.set_global_value_i32(&state.pc, pc as i32);
+        self.ticks = ticks;
     }
 }
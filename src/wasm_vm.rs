@@ -2,9 +2,11 @@ use std::sync::{Arc, OnceLock};
 
 use crate::any_wasm::{AnyWasmHandle, Val};
 
-use crate::{hardware::Word, vm::Program};
+use crate::{hardware::Word, os::OsPolicy, vm::Program};
 
-use crate::vm::{VM, VMCommand};
+use crate::snapshot::Snapshot;
+use crate::vm::{Register, VM, VMCommand};
+use crate::vm_parse::VMParseError;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub type WasmVm = GenericWasmVm<crate::any_wasm::WasmtimeHandle>;
@@ -70,14 +72,22 @@ impl<H: AnyWasmHandle> GenericWasmVm<H> {
         Self { program, state, total_steps: 0, fast_to_slow, /* slow_to_fast */ }
     }
 
+    /// Compiles the program again with the native OS functions `os_policy` allows, which starts
+    /// it over.
+    pub fn set_os_policy(&mut self, os_policy: OsPolicy) {
+        let mut program = self.program.clone();
+        program.os_policy = os_policy;
+        *self = Self::from_program(program);
+    }
+
     pub fn is_ready(&self) -> bool {
         self.state.get().is_some()
     }
 
-    pub fn from_file_contents(contents: Vec<(String, String)>) -> Self {
-        let vm = VM::from_file_contents(contents);
+    pub fn from_file_contents(contents: Vec<(String, String)>) -> Result<Self, Vec<VMParseError>> {
+        let vm = VM::from_file_contents(contents)?;
 
-        Self::from_program(vm.program)
+        Ok(Self::from_program(vm.program))
     }
 
     pub fn from_all_file_commands(all_file_commands: Vec<(String, Vec<VMCommand>)>) -> Self {
@@ -219,6 +229,50 @@ impl<H: AnyWasmHandle> GenericWasmVm<H> {
         false
     }
 
+    /// The slow path used for stepping: runs one basic block at a time for up to `step_count`
+    /// commands, until `is_done` holds between two blocks. Returns whether it did.
+    fn run_blocks_until(&mut self, step_count: u64, mut is_done: impl FnMut(&mut Self) -> bool) -> bool {
+        let end = self.total_steps.saturating_add(step_count);
+        while self.total_steps < end {
+            let total_steps = self.total_steps;
+            self.run(1);
+            if is_done(self) {
+                return true;
+            }
+            if self.total_steps == total_steps {
+                return false;
+            }
+        }
+
+        false
+    }
+
+    /// Runs a `call` until it returns. Blocks end at calls, so without one this runs the rest of
+    /// the current block. Frames further down the stack have higher LCL addresses, which is how
+    /// the depth is compared without a call stack.
+    pub fn step_over(&mut self, step_count: u64) -> bool {
+        let lcl = self.get_ram_value(Register::LCL.address());
+        self.run_blocks_until(step_count, |vm| vm.get_ram_value(Register::LCL.address()) <= lcl)
+    }
+
+    /// Runs until the current function returns.
+    pub fn step_out(&mut self, step_count: u64) -> bool {
+        let lcl = self.get_ram_value(Register::LCL.address());
+        self.run_blocks_until(step_count, |vm| vm.get_ram_value(Register::LCL.address()) < lcl)
+    }
+
+    /// Runs until the start of the basic block containing `command_index`, since the module can't
+    /// stop in the middle of one. It only stops at blocks which are jumped back to, or which
+    /// follow a call, so the target may never be reached.
+    pub fn run_to(&mut self, command_index: usize, step_count: u64) -> bool {
+        let block_index = self
+            .fast_to_slow
+            .partition_point(|&start| start <= command_index as i32)
+            .saturating_sub(1);
+        let block_start = self.fast_to_slow[block_index] as usize;
+        self.run_blocks_until(step_count, |vm| vm.current_command_index() == block_start)
+    }
+
     pub fn get_ram_value(&mut self, address: Word) -> Word {
         let state = Arc::get_mut(&mut self.state).unwrap().get_mut().unwrap();
 
@@ -241,6 +295,36 @@ impl<H: AnyWasmHandle> GenericWasmVm<H> {
         state.handle.set_global_value_i32(&state.pc, state.start_pc);
     }
 
+    /// Restores a snapshot taken by the interpreter. The WASM module can only resume at the start
+    /// of a basic block, and its return addresses are block indices rather than command indices,
+    /// so the ones saved on the stack are translated. The native OS state isn't carried over.
+    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        let mut vm = VM::new(self.program.clone());
+        vm.restore_snapshot(snapshot)?;
+        let block_index = |command_index: usize| {
+            self.fast_to_slow
+                .binary_search(&(command_index as i32))
+                .map_err(|_| format!("command {command_index} doesn't start a basic block"))
+        };
+        let pc = block_index(vm.current_command_index())?;
+        let mut ram = vm.copy_ram();
+        let mut frame = ram[Register::LCL];
+        for _ in 1..vm.run_state.call_stack.len() {
+            let return_address = ram.read_at(frame, -5).map_err(|e| e.to_string())?;
+            ram.write_at(frame, -5, block_index(return_address as usize)? as Word)
+                .map_err(|e| e.to_string())?;
+            frame = ram.read_at(frame, -4).map_err(|e| e.to_string())?;
+        }
+
+        let state = Arc::get_mut(&mut self.state).unwrap().get_mut().unwrap();
+        for (address, value) in ram.contents.iter().enumerate() {
+            state.handle.set_memory_at(&state.memory, address, *value as i32);
+        }
+        state.handle.set_global_value_i32(&state.pc, pc as i32);
+
+        Ok(())
+    }
+
     pub fn copy_ram(&mut self) -> crate::hardware::RAM {
         let state = Arc::get_mut(&mut self.state).unwrap().get_mut().unwrap();
         let data = state.handle.raw_memory(&state.memory);
@@ -912,6 +996,35 @@ mod tests {
         assert_eq!(vm.get_ram_value(4), 4);
     }
 
+    #[test]
+    fn test_step_over_and_out() {
+        let mut vm = WasmVm::from_file_contents(vec![
+            (
+                "Sys.vm".to_owned(),
+                "function Sys.init 0\npush constant 3\ncall Main.wait 1\npop temp 0\nlabel END\ngoto END"
+                    .to_owned(),
+            ),
+            (
+                "Main.vm".to_owned(),
+                "function Main.wait 0\nlabel LOOP\npush argument 0\npush constant 1\nsub\npop argument 0\npush argument 0\nif-goto LOOP\npush constant 0\nreturn"
+                    .to_owned(),
+            ),
+        ])
+        .unwrap();
+        // The call to Main.wait only stops in its loop, at the block starting with the label.
+        assert!(vm.run_to(9, 100));
+        assert_eq!(vm.current_command_index(), 7);
+        let lcl = vm.get_ram_value(Register::LCL.address());
+        assert!(vm.step_over(100));
+        assert_eq!(vm.current_command_index(), 7);
+        assert_eq!(vm.get_ram_value(Register::LCL.address()), lcl);
+        assert!(vm.step_out(100));
+        assert_eq!(vm.current_command_index(), 3);
+        assert_eq!(vm.get_ram_value(Register::SP.address()), 257);
+
+        assert!(!vm.step_out(100));
+    }
+
     #[test]
     fn test_memory() {
         let all_file_commands = vec![(
@@ -0,0 +1,175 @@
+use std::ops::Range;
+
+use crate::hardware::{RAM, Word};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Write,
+    Read,
+    /// A write which changed the value.
+    Change,
+}
+
+impl std::fmt::Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Access::Write => write!(f, "write"),
+            Access::Read => write!(f, "read"),
+            Access::Change => write!(f, "change"),
+        }
+    }
+}
+
+/// Stops a run after a step which accessed an address in `range`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: Range<Word>,
+    pub access: Access,
+}
+
+impl Watchpoint {
+    /// Parses `START..END` or `ADDRESS`, optionally followed by `:write`, `:read` or `:change`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (range, access) = s.split_once(':').unwrap_or((s, "write"));
+        let access = match access {
+            "write" => Access::Write,
+            "read" => Access::Read,
+            "change" => Access::Change,
+            _ => return Err(format!("expected write, read or change, got {access}")),
+        };
+        let address = |s: &str| s.parse::<Word>().map_err(|e| format!("{s}: {e}"));
+        let range = match range.split_once("..") {
+            Some((start, end)) => address(start)?..address(end)?,
+            None => {
+                let address = address(range)?;
+                address..address.saturating_add(1)
+            }
+        };
+
+        Ok(Self { range, access })
+    }
+}
+
+impl std::fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Range { start, end } = self.range;
+        if end == start.saturating_add(1) {
+            write!(f, "{start}:{}", self.access)
+        } else {
+            write!(f, "{start}..{end}:{}", self.access)
+        }
+    }
+}
+
+/// Where a watchpoint stopped a run. `location` is the address of the instruction, or the index
+/// of the VM command, which made the access. Reads have the same old and new value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    /// The index of the watchpoint.
+    pub index: usize,
+    pub access: Access,
+    pub address: Word,
+    pub old_value: Word,
+    pub new_value: Word,
+    pub location: usize,
+}
+
+impl std::fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.access {
+            Access::Read => write!(f, "read of RAM[{}] = {}", self.address, self.new_value),
+            access => write!(
+                f,
+                "{access} of RAM[{}]: {} -> {}",
+                self.address, self.old_value, self.new_value
+            ),
+        }
+    }
+}
+
+/// Whether the RAM has to log writes and reads for `watchpoints` to be checked.
+pub fn logging_needed(watchpoints: &[Watchpoint]) -> (bool, bool) {
+    (
+        !watchpoints.is_empty(),
+        watchpoints
+            .iter()
+            .any(|watchpoint| watchpoint.access == Access::Read),
+    )
+}
+
+/// Checks the accesses made by a single step, given the writes and reads the RAM logged during
+/// it, returning the first watchpoint which was hit.
+pub fn check(
+    watchpoints: &[Watchpoint],
+    ram: &RAM,
+    writes: &[(Word, Word)],
+    reads: &[Word],
+    location: usize,
+) -> Option<WatchHit> {
+    // The value an address had before the step is the one logged by its first write.
+    let old_value = |address: Word| {
+        writes
+            .iter()
+            .find(|&&(written, _)| written == address)
+            .map_or(ram.contents[address as usize], |&(_, value)| value)
+    };
+    watchpoints
+        .iter()
+        .enumerate()
+        .find_map(|(index, watchpoint)| {
+            let address = match watchpoint.access {
+                Access::Write => writes
+                    .iter()
+                    .map(|&(address, _)| address)
+                    .find(|address| watchpoint.range.contains(address)),
+                Access::Read => reads
+                    .iter()
+                    .copied()
+                    .find(|address| watchpoint.range.contains(address)),
+                Access::Change => writes.iter().map(|&(address, _)| address).find(|&address| {
+                    watchpoint.range.contains(&address)
+                        && old_value(address) != ram.contents[address as usize]
+                }),
+            }?;
+
+            Some(WatchHit {
+                index,
+                access: watchpoint.access,
+                address,
+                old_value: old_value(address),
+                new_value: ram.contents[address as usize],
+                location,
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Watchpoint::parse("300"),
+            Ok(Watchpoint {
+                range: 300..301,
+                access: Access::Write,
+            })
+        );
+        assert_eq!(
+            Watchpoint::parse("16..32:change"),
+            Ok(Watchpoint {
+                range: 16..32,
+                access: Access::Change,
+            })
+        );
+        for watchpoint in ["300:write", "16..32:read"] {
+            assert_eq!(
+                Watchpoint::parse(watchpoint).unwrap().to_string(),
+                watchpoint
+            );
+        }
+        assert!(Watchpoint::parse("16..32:execute").is_err());
+        assert!(Watchpoint::parse("x").is_err());
+    }
+}